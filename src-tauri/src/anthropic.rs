@@ -0,0 +1,255 @@
+use serde::Serialize;
+
+use crate::models::{ChatRequest, ImageData, Message, Usage};
+use crate::router::StreamEvent;
+
+pub const MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+pub const API_VERSION: &str = "2023-06-01";
+
+// The Messages API requires max_tokens, unlike OpenRouter.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Serialize)]
+pub struct AnthropicMessage {
+  role: String,
+  content: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct AnthropicChatRequest {
+  model: String,
+  max_tokens: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  system: Option<String>,
+  messages: Vec<AnthropicMessage>,
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  temperature: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stop_sequences: Option<Vec<String>>,
+}
+
+pub fn build_payload(req: &ChatRequest, model: &str, stream: bool) -> AnthropicChatRequest {
+  let (system, messages) = to_anthropic_messages(&req.messages, &req.all_images());
+  AnthropicChatRequest {
+    model: model.to_string(),
+    max_tokens: req.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+    system,
+    messages,
+    stream,
+    temperature: req.temperature,
+    top_p: req.top_p,
+    stop_sequences: req.stop.clone().filter(|stop| !stop.is_empty()),
+  }
+}
+
+fn image_block(image: &ImageData) -> serde_json::Value {
+  serde_json::json!({
+    "type": "image",
+    "source": { "type": "base64", "media_type": image.mime, "data": image.base64 }
+  })
+}
+
+// System prompts are a top-level field in the Messages API, so they are pulled
+// out of the conversation and joined.
+fn to_anthropic_messages(messages: &[Message], images: &[&ImageData]) -> (Option<String>, Vec<AnthropicMessage>) {
+  let system: Vec<&str> = messages
+    .iter()
+    .filter(|m| m.role == "system")
+    .map(|m| m.content.as_str())
+    .collect();
+  let system = if system.is_empty() { None } else { Some(system.join("\n\n")) };
+
+  let conversation: Vec<&Message> = messages.iter().filter(|m| m.role != "system").collect();
+  let last_user_index = conversation.iter().rposition(|m| m.role == "user");
+
+  let mut result = Vec::new();
+  for (idx, msg) in conversation.iter().enumerate() {
+    let content = if !images.is_empty() && Some(idx) == last_user_index {
+      let mut blocks: Vec<serde_json::Value> = images.iter().map(|img| image_block(img)).collect();
+      blocks.push(serde_json::json!({ "type": "text", "text": msg.content }));
+      serde_json::Value::Array(blocks)
+    } else {
+      serde_json::json!(msg.content)
+    };
+    result.push(AnthropicMessage {
+      role: msg.role.clone(),
+      content,
+    });
+  }
+
+  if !images.is_empty() && last_user_index.is_none() {
+    result.push(AnthropicMessage {
+      role: "user".to_string(),
+      content: images.iter().map(|img| image_block(img)).collect(),
+    });
+  }
+
+  (system, result)
+}
+
+fn finish_reason(stop_reason: &str) -> String {
+  match stop_reason {
+    "end_turn" | "stop_sequence" => "stop",
+    "max_tokens" => "length",
+    "tool_use" => "tool_calls",
+    other => other,
+  }
+  .to_string()
+}
+
+pub fn parse_stream_data(data: &str) -> Vec<StreamEvent> {
+  let Ok(value) = serde_json::from_str::<serde_json::Value>(data) else {
+    return Vec::new();
+  };
+
+  match value["type"].as_str() {
+    Some("message_start") => match value["message"]["usage"]["input_tokens"].as_i64() {
+      Some(input) => vec![StreamEvent::Usage(Usage::new(input, 0))],
+      None => Vec::new(),
+    },
+    Some("content_block_delta") => match value["delta"]["text"].as_str() {
+      Some(text) if !text.is_empty() => vec![StreamEvent::Delta(text.to_string())],
+      _ => Vec::new(),
+    },
+    Some("message_delta") => {
+      let mut events = Vec::new();
+      if let Some(output) = value["usage"]["output_tokens"].as_i64() {
+        events.push(StreamEvent::Usage(Usage::new(0, output)));
+      }
+      if let Some(reason) = value["delta"]["stop_reason"].as_str() {
+        events.push(StreamEvent::Finish(finish_reason(reason)));
+      }
+      events
+    }
+    Some("message_stop") => vec![StreamEvent::Done],
+    Some("error") => {
+      let message = value["error"]["message"]
+        .as_str()
+        .unwrap_or("Anthropic stream error.")
+        .to_string();
+      vec![StreamEvent::Error(message)]
+    }
+    _ => Vec::new(),
+  }
+}
+
+pub fn completion_text(body: &serde_json::Value) -> String {
+  body["content"]
+    .as_array()
+    .map(|blocks| {
+      blocks
+        .iter()
+        .filter(|block| block["type"] == "text")
+        .filter_map(|block| block["text"].as_str())
+        .collect::<String>()
+    })
+    .unwrap_or_default()
+}
+
+pub fn usage(body: &serde_json::Value) -> Option<Usage> {
+  let input = body["usage"]["input_tokens"].as_i64()?;
+  let output = body["usage"]["output_tokens"].as_i64()?;
+  Some(Usage::new(input, output))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(role: &str, content: &str) -> Message {
+    Message {
+      role: role.to_string(),
+      content: content.to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn build_payload_lifts_system_prompt() {
+    let req = ChatRequest {
+      messages: vec![message("system", "Be brief."), message("user", "Hi")],
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "claude-3-5-sonnet-latest", true)).unwrap();
+    assert_eq!(payload["system"], "Be brief.");
+    assert_eq!(payload["max_tokens"], DEFAULT_MAX_TOKENS);
+    assert_eq!(payload["messages"].as_array().unwrap().len(), 1);
+    assert_eq!(payload["messages"][0]["role"], "user");
+  }
+
+  #[test]
+  fn build_payload_attaches_image_to_last_user() {
+    let req = ChatRequest {
+      messages: vec![message("user", "First"), message("assistant", "Ack"), message("user", "Second")],
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "abc".to_string(),
+      }),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "claude", false)).unwrap();
+    let last = &payload["messages"][2]["content"];
+    assert_eq!(last[0]["type"], "image");
+    assert_eq!(last[0]["source"]["media_type"], "image/png");
+    assert_eq!(last[1]["text"], "Second");
+    assert!(payload["messages"][0]["content"].is_string());
+  }
+
+  #[test]
+  fn stop_sequences_are_sent_and_reported_as_stop() {
+    let req = ChatRequest {
+      messages: vec![message("user", "Count")],
+      stop: Some(vec!["5".to_string()]),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "claude", true)).unwrap();
+    assert_eq!(payload["stop_sequences"], serde_json::json!(["5"]));
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "claude", true)).unwrap();
+    assert!(payload.get("stop_sequences").is_none());
+
+    let hit = r#"{"type":"message_delta","delta":{"stop_reason":"stop_sequence","stop_sequence":"5"}}"#;
+    assert_eq!(parse_stream_data(hit), vec![StreamEvent::Finish("stop".to_string())]);
+  }
+
+  #[test]
+  fn parse_stream_data_translates_events() {
+    let delta = r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#;
+    assert_eq!(parse_stream_data(delta), vec![StreamEvent::Delta("Hello".to_string())]);
+
+    let stop = r#"{"type":"message_delta","delta":{"stop_reason":"max_tokens"},"usage":{"output_tokens":5}}"#;
+    assert_eq!(
+      parse_stream_data(stop),
+      vec![
+        StreamEvent::Usage(Usage::new(0, 5)),
+        StreamEvent::Finish("length".to_string())
+      ]
+    );
+
+    let start = r#"{"type":"message_start","message":{"usage":{"input_tokens":9,"output_tokens":1}}}"#;
+    assert_eq!(parse_stream_data(start), vec![StreamEvent::Usage(Usage::new(9, 0))]);
+
+    assert_eq!(parse_stream_data(r#"{"type":"message_stop"}"#), vec![StreamEvent::Done]);
+    assert!(parse_stream_data(r#"{"type":"ping"}"#).is_empty());
+  }
+
+  #[test]
+  fn parse_stream_data_surfaces_errors() {
+    let data = r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+    assert_eq!(parse_stream_data(data), vec![StreamEvent::Error("Overloaded".to_string())]);
+  }
+
+  #[test]
+  fn completion_text_joins_text_blocks() {
+    let body = serde_json::json!({
+      "content": [
+        { "type": "text", "text": "Hello, " },
+        { "type": "tool_use", "id": "x" },
+        { "type": "text", "text": "world" }
+      ]
+    });
+    assert_eq!(completion_text(&body), "Hello, world");
+  }
+}
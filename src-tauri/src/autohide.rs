@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Some window managers report a blur while a freshly shown window is still
+// taking focus; hiding then would undo the shortcut that just showed it.
+const SHOW_GRACE: Duration = Duration::from_millis(400);
+
+// Spotlight-style hiding when the main window loses focus (`hide_on_blur`).
+// Read from the window event handler, so everything here is lock-free or brief.
+#[derive(Default)]
+pub struct AutoHide {
+  enabled: AtomicBool,
+  // Native dialogs (file pickers, color pickers) take focus from the window;
+  // the UI holds auto-hide while one is open. Counted so nested holds work.
+  holds: AtomicUsize,
+  shown_at: Mutex<Option<Instant>>,
+}
+
+impl AutoHide {
+  pub fn set_enabled(&self, enabled: bool) {
+    self.enabled.store(enabled, Ordering::SeqCst);
+  }
+
+  pub fn hold(&self) {
+    self.holds.fetch_add(1, Ordering::SeqCst);
+  }
+
+  // Extra releases are ignored, so a UI that reloads mid-dialog cannot wrap.
+  pub fn release(&self) {
+    let _ = self
+      .holds
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |holds| holds.checked_sub(1));
+  }
+
+  // Called whenever the app itself shows the window.
+  pub fn shown(&self) {
+    *self.shown_at.lock().unwrap() = Some(Instant::now());
+  }
+
+  pub fn should_hide(&self) -> bool {
+    self.should_hide_at(Instant::now())
+  }
+
+  fn should_hide_at(&self, now: Instant) -> bool {
+    if !self.enabled.load(Ordering::SeqCst) || self.holds.load(Ordering::SeqCst) > 0 {
+      return false;
+    }
+    let shown_at = *self.shown_at.lock().unwrap();
+    shown_at.is_none_or(|at| now.saturating_duration_since(at) >= SHOW_GRACE)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hides_only_when_enabled_unheld_and_settled() {
+    let auto_hide = AutoHide::default();
+    assert!(!auto_hide.should_hide());
+    auto_hide.set_enabled(true);
+    assert!(auto_hide.should_hide());
+
+    auto_hide.hold();
+    auto_hide.hold();
+    auto_hide.release();
+    assert!(!auto_hide.should_hide());
+    auto_hide.release();
+    auto_hide.release();
+    assert!(auto_hide.should_hide());
+
+    auto_hide.shown();
+    let now = Instant::now();
+    assert!(!auto_hide.should_hide_at(now));
+    assert!(auto_hide.should_hide_at(now + SHOW_GRACE));
+  }
+}
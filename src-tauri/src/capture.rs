@@ -1,23 +1,313 @@
 use std::io::Cursor;
 
 use base64::Engine;
-use screenshots::image::{DynamicImage, ImageFormat};
+use screenshots::image::imageops::{self, FilterType};
+use screenshots::image::{self, DynamicImage, ImageFormat, ImageOutputFormat, RgbaImage};
+use screenshots::Screen;
 
-use crate::models::ImageData;
+use crate::models::{CaptureFormat, CaptureOptions, DisplayInfo, ImageData, WindowInfo};
 
-pub fn capture_primary_display() -> anyhow::Result<ImageData> {
-  let screens = screenshots::Screen::all()?;
-  let screen = screens
-    .get(0)
-    .ok_or_else(|| anyhow::anyhow!("no screens found"))?;
-  let image = screen.capture()?;
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+// Each step keeps three quarters of the width; ten steps reach about 6%.
+const MAX_SHRINK_STEPS: usize = 10;
 
-  let mut png = Vec::new();
-  DynamicImage::ImageRgba8(image).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
-  let base64 = base64::engine::general_purpose::STANDARD.encode(png);
+pub fn list_displays() -> anyhow::Result<Vec<DisplayInfo>> {
+  let screens = Screen::all()?;
+  Ok(
+    screens
+      .iter()
+      .enumerate()
+      .map(|(index, screen)| {
+        let info = &screen.display_info;
+        DisplayInfo {
+          index,
+          id: info.id,
+          x: info.x,
+          y: info.y,
+          width: info.width,
+          height: info.height,
+          is_primary: info.is_primary,
+        }
+      })
+      .collect(),
+  )
+}
+
+fn screen_at(index: usize) -> anyhow::Result<Screen> {
+  let screens = Screen::all()?;
+  if screens.is_empty() {
+    return Err(anyhow::anyhow!("no screens found"));
+  }
+  let count = screens.len();
+  screens
+    .into_iter()
+    .nth(index)
+    .ok_or_else(|| anyhow::anyhow!("out_of_range: display {index} does not exist ({count} found)"))
+}
+
+pub fn capture_display(index: usize, options: &CaptureOptions) -> anyhow::Result<ImageData> {
+  let image = screen_at(index)?.capture()?;
+  encode(downscale(image, options.max_width), options)
+}
+
+pub fn capture_primary_display(options: &CaptureOptions) -> anyhow::Result<ImageData> {
+  capture_display(0, options)
+}
+
+// Region coordinates are relative to the primary display's top-left corner.
+fn check_region(x: i32, y: i32, width: u32, height: u32, screen_width: u32, screen_height: u32) -> anyhow::Result<()> {
+  if width == 0 || height == 0 {
+    return Err(anyhow::anyhow!("region width and height must be greater than zero"));
+  }
+  let fits = |start: i32, len: u32, max: u32| start >= 0 && start as u64 + len as u64 <= max as u64;
+  if !fits(x, width, screen_width) || !fits(y, height, screen_height) {
+    return Err(anyhow::anyhow!(
+      "region {width}x{height} at ({x}, {y}) is outside the {screen_width}x{screen_height} display"
+    ));
+  }
+  Ok(())
+}
+
+pub fn capture_region(x: i32, y: i32, width: u32, height: u32) -> anyhow::Result<ImageData> {
+  let screen = screen_at(0)?;
+  let info = &screen.display_info;
+  check_region(x, y, width, height, info.width, info.height)?;
+  let image = screen.capture_area(x, y, width, height)?;
+  encode(image, &CaptureOptions::default())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+mod window {
+  use super::*;
+
+  fn find(id: u32) -> anyhow::Result<xcap::Window> {
+    xcap::Window::all()?
+      .into_iter()
+      .find(|window| window.id().ok() == Some(id))
+      .ok_or_else(|| anyhow::anyhow!("out_of_range: window {id} does not exist"))
+  }
+
+  // Minimised and untitled windows are skipped; they capture as blank frames.
+  pub fn list() -> anyhow::Result<Vec<WindowInfo>> {
+    let mut windows = Vec::new();
+    for window in xcap::Window::all()? {
+      let title = window.title()?;
+      if title.trim().is_empty() || window.is_minimized()? {
+        continue;
+      }
+      windows.push(WindowInfo {
+        id: window.id()?,
+        title,
+        app_name: window.app_name()?,
+        x: window.x()?,
+        y: window.y()?,
+        width: window.width()?,
+        height: window.height()?,
+      });
+    }
+    Ok(windows)
+  }
+
+  // xcap links a newer `image` than screenshots, so pixels are copied across.
+  pub fn capture(id: u32) -> anyhow::Result<RgbaImage> {
+    let image = find(id)?.capture_image()?;
+    let (width, height) = image.dimensions();
+    RgbaImage::from_raw(width, height, image.into_raw())
+      .ok_or_else(|| anyhow::anyhow!("window {id} returned a malformed image"))
+  }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+mod window {
+  use super::*;
+
+  fn unsupported() -> anyhow::Error {
+    anyhow::anyhow!("unsupported_platform: window capture is only available on Windows and macOS")
+  }
+
+  pub fn list() -> anyhow::Result<Vec<WindowInfo>> {
+    Err(unsupported())
+  }
+
+  pub fn capture(_id: u32) -> anyhow::Result<RgbaImage> {
+    Err(unsupported())
+  }
+}
+
+pub fn list_windows() -> anyhow::Result<Vec<WindowInfo>> {
+  window::list()
+}
+
+pub fn capture_window(id: u32, options: &CaptureOptions) -> anyhow::Result<ImageData> {
+  let image = window::capture(id)?;
+  encode(downscale(image, options.max_width), options)
+}
+
+// Clipboard images arrive as raw RGBA and are always sent on as PNG.
+pub fn capture_clipboard_image() -> anyhow::Result<ImageData> {
+  let image = arboard::Clipboard::new()
+    .and_then(|mut clipboard| clipboard.get_image())
+    .map_err(clipboard_error)?;
+  let (width, height) = (image.width as u32, image.height as u32);
+  let image = RgbaImage::from_raw(width, height, image.bytes.into_owned())
+    .ok_or_else(|| anyhow::anyhow!("clipboard returned a malformed {width}x{height} image"))?;
+  encode(image, &CaptureOptions::default())
+}
+
+// Text, files and an empty clipboard all surface as ContentNotAvailable.
+fn clipboard_error(err: arboard::Error) -> anyhow::Error {
+  match err {
+    arboard::Error::ContentNotAvailable => {
+      anyhow::anyhow!("no_image_in_clipboard: the clipboard does not hold an image")
+    }
+    err => anyhow::anyhow!("clipboard read failed: {err}"),
+  }
+}
+
+// Re-encodes an attachment as JPEG, narrowing it step by step until it
+// decodes to at most `max_bytes`.
+pub fn shrink_to_fit(image: &ImageData, max_bytes: usize) -> anyhow::Result<ImageData> {
+  let bytes = base64::engine::general_purpose::STANDARD.decode(image.base64.trim())?;
+  let mut pixels = image::load_from_memory(&bytes)?.into_rgba8();
+  let options = CaptureOptions {
+    format: CaptureFormat::Jpeg,
+    ..Default::default()
+  };
+  for _ in 0..MAX_SHRINK_STEPS {
+    let encoded = encode(pixels.clone(), &options)?;
+    if base64_decoded_len(&encoded.base64) <= max_bytes {
+      return Ok(encoded);
+    }
+    let width = (pixels.width() * 3 / 4).max(1);
+    pixels = downscale(pixels, Some(width));
+  }
+  anyhow::bail!("image is still larger than {max_bytes} bytes after downscaling")
+}
+
+// Decoded size of standard base64, from its length alone.
+pub fn base64_decoded_len(base64: &str) -> usize {
+  let base64 = base64.trim();
+  let padding = base64.bytes().rev().take_while(|&b| b == b'=').count().min(2);
+  (base64.len() / 4 * 3 + base64.len() % 4 * 3 / 4).saturating_sub(padding)
+}
+
+// Keeps the aspect ratio; images already within max_width are left untouched.
+fn downscale(image: RgbaImage, max_width: Option<u32>) -> RgbaImage {
+  match max_width {
+    Some(max_width) if max_width > 0 && image.width() > max_width => {
+      let height = (image.height() as u64 * max_width as u64 / image.width() as u64).max(1) as u32;
+      imageops::resize(&image, max_width, height, FilterType::Triangle)
+    }
+    _ => image,
+  }
+}
+
+fn encode(image: RgbaImage, options: &CaptureOptions) -> anyhow::Result<ImageData> {
+  let mut bytes = Vec::new();
+  let mut cursor = Cursor::new(&mut bytes);
+  let mime = match options.format {
+    CaptureFormat::Png => {
+      DynamicImage::ImageRgba8(image).write_to(&mut cursor, ImageFormat::Png)?;
+      "image/png"
+    }
+    CaptureFormat::Jpeg => {
+      // JPEG has no alpha channel.
+      let quality = options.quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100);
+      let rgb = DynamicImage::ImageRgba8(image).into_rgb8();
+      DynamicImage::ImageRgb8(rgb).write_to(&mut cursor, ImageOutputFormat::Jpeg(quality))?;
+      "image/jpeg"
+    }
+    CaptureFormat::Webp => {
+      // The bundled WebP encoder is lossless only, so quality is ignored.
+      DynamicImage::ImageRgba8(image).write_to(&mut cursor, ImageFormat::WebP)?;
+      "image/webp"
+    }
+  };
+  let base64 = base64::engine::general_purpose::STANDARD.encode(bytes);
 
   Ok(ImageData {
-    mime: "image/png".to_string(),
+    mime: mime.to_string(),
     base64,
   })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn check_region_accepts_rectangles_inside_the_display() {
+    assert!(check_region(0, 0, 1920, 1080, 1920, 1080).is_ok());
+    assert!(check_region(100, 200, 300, 400, 1920, 1080).is_ok());
+  }
+
+  #[test]
+  fn check_region_rejects_out_of_bounds_or_empty_rectangles() {
+    assert!(check_region(-1, 0, 10, 10, 1920, 1080).is_err());
+    assert!(check_region(1900, 0, 30, 10, 1920, 1080).is_err());
+    assert!(check_region(0, 1075, 10, 10, 1920, 1080).is_err());
+    assert!(check_region(0, 0, 0, 10, 1920, 1080).is_err());
+  }
+
+  #[test]
+  fn downscale_preserves_aspect_ratio() {
+    let scaled = downscale(RgbaImage::new(4000, 2250), Some(1920));
+    assert_eq!(scaled.dimensions(), (1920, 1080));
+
+    let untouched = downscale(RgbaImage::new(1280, 720), Some(1920));
+    assert_eq!(untouched.dimensions(), (1280, 720));
+  }
+
+  #[test]
+  fn encode_sets_mime_for_format() {
+    let options = CaptureOptions {
+      format: CaptureFormat::Jpeg,
+      quality: Some(60),
+      ..Default::default()
+    };
+    assert_eq!(encode(RgbaImage::new(4, 4), &options).unwrap().mime, "image/jpeg");
+    assert_eq!(encode(RgbaImage::new(4, 4), &CaptureOptions::default()).unwrap().mime, "image/png");
+  }
+
+  #[test]
+  fn base64_decoded_len_matches_decoding() {
+    for len in 0..8 {
+      let encoded = base64::engine::general_purpose::STANDARD.encode(vec![7u8; len]);
+      assert_eq!(base64_decoded_len(&encoded), len, "{encoded}");
+    }
+  }
+
+  #[test]
+  fn shrink_to_fit_narrows_until_the_image_fits() {
+    // Noise, so neither PNG nor JPEG can compress it away.
+    let mut seed = 1u32;
+    let noisy = RgbaImage::from_fn(256, 128, |_, _| {
+      seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+      let [r, g, b, _] = seed.to_le_bytes();
+      image::Rgba([r, g, b, 255])
+    });
+    let original = encode(noisy, &CaptureOptions::default()).unwrap();
+    let limit = base64_decoded_len(&original.base64) / 8;
+
+    let shrunk = shrink_to_fit(&original, limit).unwrap();
+    assert_eq!(shrunk.mime, "image/jpeg");
+    assert!(base64_decoded_len(&shrunk.base64) <= limit);
+    assert!(shrink_to_fit(&original, 10).is_err());
+  }
+
+  #[test]
+  fn clipboard_error_names_missing_image() {
+    let err = clipboard_error(arboard::Error::ContentNotAvailable);
+    assert!(err.to_string().starts_with("no_image_in_clipboard"), "{err}");
+    let err = clipboard_error(arboard::Error::ClipboardOccupied);
+    assert!(err.to_string().starts_with("clipboard read failed"), "{err}");
+  }
+
+  #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+  #[test]
+  fn window_capture_reports_unsupported_platform() {
+    let err = list_windows().err().expect("window listing is unsupported here");
+    assert!(err.to_string().starts_with("unsupported_platform"));
+    assert!(capture_window(1, &CaptureOptions::default()).is_err());
+  }
+}
@@ -2,6 +2,8 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::keys::KeyStorage;
+use crate::logger::LogLevel;
 use crate::models::ModelInfo;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -9,7 +11,183 @@ pub struct AppConfig {
   pub text_default_model: String,
   pub vision_default_model: String,
   pub fallback_model: String,
+  // Tried in order after the primary fails; when empty, fallback_model is the
+  // whole chain.
+  #[serde(default)]
+  pub fallback_chain: Vec<String>,
   pub models: Vec<ModelInfo>,
+  #[serde(default = "default_ollama_base_url")]
+  pub ollama_base_url: String,
+  #[serde(default = "default_shortcut")]
+  pub shortcut: String,
+  // Hides the main window from screenshots and recordings. Some Linux
+  // compositors render it black in recordings the user wants to keep.
+  #[serde(default = "default_content_protection")]
+  pub content_protection: bool,
+  // Hide the main window when it loses focus, like Spotlight.
+  #[serde(default)]
+  pub hide_on_blur: bool,
+  #[serde(default = "default_max_retries")]
+  pub max_retries: u32,
+  #[serde(default = "default_request_timeout_secs")]
+  pub request_timeout_secs: u64,
+  // Seconds between `ping` events on a chat stream; 0 turns them off.
+  #[serde(default = "default_ping_interval_secs")]
+  pub ping_interval_secs: u64,
+  // Seconds between SSE keep-alive comments, for proxies that close idle
+  // connections; 0 sends none.
+  #[serde(default = "default_sse_keepalive_secs")]
+  pub sse_keepalive_secs: u64,
+  // Seconds between writes of a streaming reply to its history row, so a crash
+  // leaves a partial; 0 writes only once the stream ends.
+  #[serde(default = "default_history_checkpoint_secs")]
+  pub history_checkpoint_secs: u64,
+  // Seconds a stream keeps reading upstream after its client disconnects, so
+  // GET /v1/chat/resume can pick it up; 0 stops it at once.
+  #[serde(default = "default_resume_grace_secs")]
+  pub resume_grace_secs: u64,
+  // Off keeps every chat out of history, as if each one set `no_store`.
+  #[serde(default = "default_history_enabled")]
+  pub history_enabled: bool,
+  // Messages below this level are not written to the log file.
+  #[serde(default)]
+  pub log_level: LogLevel,
+  // Read once when the router starts; changing it needs a restart.
+  #[serde(default = "default_max_concurrent_requests")]
+  pub max_concurrent_requests: usize,
+  // Largest request body the router accepts; read at startup. Images travel as
+  // base64 (about 4/3 of the file size), so raise this deliberately when sending
+  // several full-resolution captures in one chat.
+  #[serde(default = "default_max_request_bytes")]
+  pub max_request_bytes: usize,
+  // Largest decoded image a chat may attach; vision models reject or
+  // overcharge for bigger ones. With downscale_images on, oversized images are
+  // re-encoded smaller instead of rejected.
+  #[serde(default = "default_max_image_bytes")]
+  pub max_image_bytes: usize,
+  #[serde(default)]
+  pub downscale_images: bool,
+  // Browser origins allowed to call the router; any port on these matches.
+  #[serde(default = "default_allowed_origins")]
+  pub allowed_origins: Vec<String>,
+  // Use OPENROUTER_API_KEY / ANTHROPIC_API_KEY ahead of the stored key when both exist.
+  #[serde(default)]
+  pub prefer_env_key: bool,
+  // `keyring` (the OS credential store) or `file`, an encrypted file in the
+  // data dir for machines without one.
+  #[serde(default)]
+  pub key_storage: KeyStorage,
+  // History retention; unset keeps everything. Pinned items and presets are never pruned.
+  #[serde(default)]
+  pub history_retention_days: Option<u32>,
+  #[serde(default)]
+  pub max_history_rows: Option<u32>,
+  // Token budget for what a chat sends; older turns are dropped to fit. Unset
+  // sends the whole conversation.
+  #[serde(default)]
+  pub max_context_tokens: Option<usize>,
+  // Sent ahead of every conversation, before any preset prompt.
+  #[serde(default)]
+  pub global_system_prompt: Option<String>,
+  // Model ids (e.g. `openrouter:openai/o1`, prefix optional, any case) that get
+  // `developer` instead of `system` messages; every other model gets `system`.
+  #[serde(default)]
+  pub developer_role_models: Vec<String>,
+  // OpenRouter embedding model for semantic memory search, e.g.
+  // `openai/text-embedding-3-small`; unset leaves search lexical only.
+  #[serde(default)]
+  pub embedding_model: Option<String>,
+  // Cheap model for conversation titles; unset uses text_default_model.
+  #[serde(default)]
+  pub title_model: Option<String>,
+  // Sent to OpenRouter as HTTP-Referer and X-Title so requests are credited
+  // to this app on its dashboard; values that are not valid headers are
+  // replaced by the defaults.
+  #[serde(default = "default_openrouter_referer")]
+  pub openrouter_referer: String,
+  #[serde(default = "default_openrouter_app_title")]
+  pub openrouter_app_title: String,
+  // Where chat completions for `openrouter:` models go; point it at an
+  // OpenAI-compatible gateway (LiteLLM, Helicone, ...) to route through one.
+  #[serde(default = "default_openrouter_base_url")]
+  pub openrouter_base_url: String,
+  // Model ids the router may call, exact or with `*` wildcards (e.g.
+  // `openrouter:openai/*`); unset allows any. denied_models wins over both.
+  #[serde(default)]
+  pub allowed_models: Option<Vec<String>>,
+  #[serde(default)]
+  pub denied_models: Vec<String>,
+}
+
+fn default_ollama_base_url() -> String {
+  "http://localhost:11434".to_string()
+}
+
+fn default_max_retries() -> u32 {
+  2
+}
+
+fn default_request_timeout_secs() -> u64 {
+  120
+}
+
+fn default_ping_interval_secs() -> u64 {
+  5
+}
+
+fn default_sse_keepalive_secs() -> u64 {
+  15
+}
+
+fn default_history_checkpoint_secs() -> u64 {
+  2
+}
+
+fn default_resume_grace_secs() -> u64 {
+  30
+}
+
+fn default_max_concurrent_requests() -> usize {
+  8
+}
+
+fn default_max_request_bytes() -> usize {
+  32 * 1024 * 1024
+}
+
+fn default_max_image_bytes() -> usize {
+  20 * 1024 * 1024
+}
+
+fn default_allowed_origins() -> Vec<String> {
+  ["http://localhost", "http://127.0.0.1", "tauri://localhost", "https://tauri.localhost"]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+pub fn default_openrouter_referer() -> String {
+  "http://localhost".to_string()
+}
+
+pub fn default_openrouter_app_title() -> String {
+  "HaloDesk".to_string()
+}
+
+fn default_openrouter_base_url() -> String {
+  "https://openrouter.ai/api/v1".to_string()
+}
+
+fn default_content_protection() -> bool {
+  true
+}
+
+fn default_history_enabled() -> bool {
+  true
+}
+
+pub fn default_shortcut() -> String {
+  "CmdOrCtrl+Shift+Space".to_string()
 }
 
 impl Default for AppConfig {
@@ -18,26 +196,163 @@ impl Default for AppConfig {
       text_default_model: "openrouter:openai/gpt-4o-mini".to_string(),
       vision_default_model: "openrouter:openai/gpt-4o-mini-vision".to_string(),
       fallback_model: "openrouter:openai/gpt-4o-mini".to_string(),
+      fallback_chain: Vec::new(),
       models: vec![
         ModelInfo {
           id: "openrouter:openai/gpt-4o-mini".to_string(),
           label: "GPT-4o mini".to_string(),
           capability: "text".to_string(),
+          rate_limit_rpm: None,
         },
         ModelInfo {
           id: "openrouter:openai/gpt-4o-mini-vision".to_string(),
           label: "GPT-4o mini (vision)".to_string(),
           capability: "vision".to_string(),
+          rate_limit_rpm: None,
         }
       ],
+      ollama_base_url: default_ollama_base_url(),
+      shortcut: default_shortcut(),
+      content_protection: default_content_protection(),
+      hide_on_blur: false,
+      max_retries: default_max_retries(),
+      request_timeout_secs: default_request_timeout_secs(),
+      ping_interval_secs: default_ping_interval_secs(),
+      sse_keepalive_secs: default_sse_keepalive_secs(),
+      history_checkpoint_secs: default_history_checkpoint_secs(),
+      resume_grace_secs: default_resume_grace_secs(),
+      history_enabled: default_history_enabled(),
+      log_level: LogLevel::default(),
+      max_concurrent_requests: default_max_concurrent_requests(),
+      max_request_bytes: default_max_request_bytes(),
+      max_image_bytes: default_max_image_bytes(),
+      downscale_images: false,
+      allowed_origins: default_allowed_origins(),
+      prefer_env_key: false,
+      key_storage: KeyStorage::Keyring,
+      history_retention_days: None,
+      max_history_rows: None,
+      max_context_tokens: None,
+      global_system_prompt: None,
+      developer_role_models: Vec::new(),
+      embedding_model: None,
+      title_model: None,
+      openrouter_referer: default_openrouter_referer(),
+      openrouter_app_title: default_openrouter_app_title(),
+      openrouter_base_url: default_openrouter_base_url(),
+      allowed_models: None,
+      denied_models: Vec::new(),
+    }
+  }
+}
+
+impl AppConfig {
+  pub fn validate(&self) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    for (field, value) in [
+      ("text_default_model", &self.text_default_model),
+      ("vision_default_model", &self.vision_default_model),
+      ("fallback_model", &self.fallback_model),
+    ] {
+      if value.trim().is_empty() {
+        errors.push(format!("{field} must not be empty"));
+      }
+    }
+    if self.fallback_chain.iter().any(|model| model.trim().is_empty()) {
+      errors.push("fallback_chain entries must not be empty".to_string());
+    }
+    if self.max_concurrent_requests == 0 {
+      errors.push("max_concurrent_requests must be at least 1".to_string());
+    }
+    if self.max_request_bytes == 0 {
+      errors.push("max_request_bytes must be at least 1".to_string());
+    }
+    if self.max_image_bytes == 0 {
+      errors.push("max_image_bytes must be at least 1".to_string());
+    }
+    let patterns = self.allowed_models.iter().flatten().chain(&self.denied_models);
+    if patterns.into_iter().any(|pattern| pattern.trim().is_empty()) {
+      errors.push("allowed_models and denied_models entries must not be empty".to_string());
+    }
+    match reqwest::Url::parse(self.openrouter_base_url.trim()) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+      _ => errors.push("openrouter_base_url must be an http(s) URL".to_string()),
+    }
+    if self.history_retention_days == Some(0) {
+      errors.push("history_retention_days must be at least 1 when set".to_string());
+    }
+    if self.max_history_rows == Some(0) {
+      errors.push("max_history_rows must be at least 1 when set".to_string());
+    }
+    if self.max_context_tokens == Some(0) {
+      errors.push("max_context_tokens must be at least 1 when set".to_string());
+    }
+    for model in &self.models {
+      if model.rate_limit_rpm == Some(0) {
+        errors.push(format!("model {} rate_limit_rpm must be at least 1 when set", model.id));
+      }
+      if !matches!(model.capability.as_str(), "text" | "vision") {
+        errors.push(format!(
+          "model {} has capability '{}' (expected text or vision)",
+          model.id, model.capability
+        ));
+      }
+    }
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+
+  // `model_id` should carry its provider prefix so `openrouter:*` patterns
+  // also catch ids written without one.
+  pub fn model_allowed(&self, model_id: &str) -> bool {
+    let matches = |pattern: &String| glob_matches(pattern.trim(), model_id);
+    if self.denied_models.iter().any(matches) {
+      return false;
+    }
+    self.allowed_models.as_ref().is_none_or(|allowed| allowed.iter().any(matches))
+  }
+
+  pub fn fallback_models(&self) -> Vec<&str> {
+    if self.fallback_chain.is_empty() {
+      vec![self.fallback_model.trim()]
+    } else {
+      self.fallback_chain.iter().map(|model| model.trim()).collect()
+    }
+  }
+}
+
+// `*` matches any run of characters, including `/` and `:`; everything else
+// must match exactly.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap_or_default();
+  let Some(mut rest) = text.strip_prefix(first) else {
+    return false;
+  };
+  let middle: Vec<&str> = parts.collect();
+  let Some((last, middle)) = middle.split_last() else {
+    return rest.is_empty();
+  };
+  for part in middle {
+    match rest.find(part) {
+      Some(at) => rest = &rest[at + part.len()..],
+      None => return false,
     }
   }
+  rest.len() >= last.len() && rest.ends_with(last)
 }
 
 pub fn load_or_init(path: &Path) -> anyhow::Result<AppConfig> {
   if path.exists() {
     let data = std::fs::read_to_string(path)?;
-    let config: AppConfig = serde_json::from_str(&data)?;
+    let config: AppConfig = serde_json::from_str(&data)
+      .map_err(|err| anyhow::anyhow!("{} is not valid config JSON: {}", path.display(), err))?;
+    config
+      .validate()
+      .map_err(|errors| anyhow::anyhow!("invalid config in {}: {}", path.display(), errors.join("; ")))?;
     Ok(config)
   } else {
     let config = AppConfig::default();
@@ -51,3 +366,122 @@ pub fn save_config(path: &Path, config: &AppConfig) -> anyhow::Result<()> {
   std::fs::write(path, json)?;
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn default_config_is_valid() {
+    assert!(AppConfig::default().validate().is_ok());
+  }
+
+  #[test]
+  fn validate_rejects_empty_text_default_model() {
+    let config = AppConfig {
+      text_default_model: "  ".to_string(),
+      ..AppConfig::default()
+    };
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors, vec!["text_default_model must not be empty".to_string()]);
+  }
+
+  #[test]
+  fn validate_rejects_unknown_capability() {
+    let mut config = AppConfig::default();
+    config.models[0].capability = "audio".to_string();
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("audio"));
+  }
+
+  #[test]
+  fn fallback_models_prefers_chain_over_single_model() {
+    let mut config = AppConfig::default();
+    assert_eq!(config.fallback_models(), vec!["openrouter:openai/gpt-4o-mini"]);
+    config.fallback_chain = vec!["ollama:llama3".to_string(), " anthropic:claude-3-5-haiku-latest ".to_string()];
+    assert_eq!(config.fallback_models(), vec!["ollama:llama3", "anthropic:claude-3-5-haiku-latest"]);
+
+    config.fallback_chain.push(" ".to_string());
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors, vec!["fallback_chain entries must not be empty".to_string()]);
+  }
+
+  #[test]
+  fn validate_rejects_bad_openrouter_base_url() {
+    for url in ["", "openrouter.ai/api/v1", "ftp://gateway.local/v1"] {
+      let config = AppConfig {
+        openrouter_base_url: url.to_string(),
+        ..AppConfig::default()
+      };
+      assert_eq!(config.validate().unwrap_err(), vec!["openrouter_base_url must be an http(s) URL"], "{url}");
+    }
+    let config = AppConfig {
+      openrouter_base_url: "http://localhost:4000/v1/".to_string(),
+      ..AppConfig::default()
+    };
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn validate_rejects_zero_rate_limit() {
+    let mut config = AppConfig::default();
+    config.models[0].rate_limit_rpm = Some(0);
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("rate_limit_rpm"), "{}", errors[0]);
+  }
+
+  #[test]
+  fn glob_matches_exact_and_wildcard_patterns() {
+    assert!(glob_matches("ollama:llama3", "ollama:llama3"));
+    assert!(!glob_matches("ollama:llama3", "ollama:llama3.1"));
+    assert!(glob_matches("openrouter:openai/*", "openrouter:openai/gpt-4o-mini"));
+    assert!(!glob_matches("openrouter:openai/*", "openrouter:anthropic/claude-3.5-sonnet"));
+    assert!(glob_matches("*:free", "openrouter:meta/llama:free"));
+    assert!(glob_matches("openrouter:*mini*", "openrouter:openai/gpt-4o-mini-vision"));
+    assert!(!glob_matches("a*a", "a"));
+    assert!(glob_matches("*", "anything"));
+  }
+
+  #[test]
+  fn denied_models_win_over_allowed_models() {
+    let mut config = AppConfig::default();
+    assert!(config.model_allowed("anthropic:claude-3-5-haiku-latest"));
+
+    config.allowed_models = Some(vec!["openrouter:openai/*".to_string(), "ollama:llama3".to_string()]);
+    config.denied_models = vec!["openrouter:openai/o1*".to_string()];
+    assert!(config.model_allowed("openrouter:openai/gpt-4o-mini"));
+    assert!(config.model_allowed("ollama:llama3"));
+    assert!(!config.model_allowed("openrouter:openai/o1-preview"));
+    assert!(!config.model_allowed("anthropic:claude-3-5-haiku-latest"));
+
+    config.allowed_models = Some(Vec::new());
+    assert!(!config.model_allowed("ollama:llama3"));
+
+    config.denied_models.push(" ".to_string());
+    let errors = config.validate().unwrap_err();
+    assert_eq!(errors, vec!["allowed_models and denied_models entries must not be empty".to_string()]);
+  }
+
+  #[test]
+  fn content_protection_defaults_on_for_older_configs() {
+    let mut json = serde_json::to_value(AppConfig::default()).unwrap();
+    json.as_object_mut().unwrap().remove("content_protection");
+    let config: AppConfig = serde_json::from_value(json).unwrap();
+    assert!(config.content_protection);
+  }
+
+  #[test]
+  fn load_or_init_reports_invalid_config() {
+    let path = std::env::temp_dir().join(format!("halodesk-config-{}.json", uuid::Uuid::new_v4()));
+    let config = AppConfig {
+      fallback_model: String::new(),
+      ..AppConfig::default()
+    };
+    save_config(&path, &config).unwrap();
+    let err = load_or_init(&path).err().expect("invalid config should fail").to_string();
+    assert!(err.contains("fallback_model must not be empty"), "{err}");
+    let _ = std::fs::remove_file(&path);
+  }
+}
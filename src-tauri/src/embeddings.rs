@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+// Embedding models take a few thousand tokens at most; the head of a long
+// conversation is enough to place it.
+const MAX_INPUT_CHARS: usize = 8000;
+const TIMEOUT: Duration = Duration::from_secs(20);
+// Texts per embed_batch request.
+pub const MAX_BATCH_INPUTS: usize = 64;
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+  model: &'a str,
+  input: Vec<&'a str>,
+}
+
+pub async fn embed(
+  client: &reqwest::Client,
+  url: &str,
+  key: &str,
+  model: &str,
+  attribution: reqwest::header::HeaderMap,
+  text: &str,
+) -> Result<Vec<f32>, String> {
+  let mut vectors = embed_batch(client, url, key, model, attribution, &[text]).await?;
+  vectors.pop().ok_or_else(|| "OpenRouter embeddings reply had no vector.".to_string())
+}
+
+// Several texts in one request (at most MAX_BATCH_INPUTS); vectors come back
+// in input order.
+pub async fn embed_batch(
+  client: &reqwest::Client,
+  url: &str,
+  key: &str,
+  model: &str,
+  attribution: reqwest::header::HeaderMap,
+  texts: &[&str],
+) -> Result<Vec<Vec<f32>>, String> {
+  let input = texts.iter().map(|text| truncate(text)).collect();
+  let resp = client
+    .post(url)
+    .bearer_auth(key)
+    .headers(attribution)
+    .json(&EmbeddingRequest { model, input })
+    .timeout(TIMEOUT)
+    .send()
+    .await
+    .map_err(|err| err.to_string())?;
+  let status = resp.status();
+  if !status.is_success() {
+    let text = resp.text().await.unwrap_or_default();
+    return Err(format!("OpenRouter embeddings error ({status}): {text}"));
+  }
+  let body = resp.json::<serde_json::Value>().await.map_err(|err| err.to_string())?;
+  parse_embeddings(&body, texts.len()).ok_or_else(|| "OpenRouter embeddings reply was missing vectors.".to_string())
+}
+
+fn truncate(text: &str) -> &str {
+  match text.char_indices().nth(MAX_INPUT_CHARS) {
+    Some((end, _)) => &text[..end],
+    None => text,
+  }
+}
+
+// One vector per input, placed by each entry's `index`; None unless every
+// input got one.
+fn parse_embeddings(body: &serde_json::Value, count: usize) -> Option<Vec<Vec<f32>>> {
+  let mut vectors = vec![None; count];
+  for (position, entry) in body["data"].as_array()?.iter().enumerate() {
+    let index = entry["index"].as_u64().map_or(position, |index| index as usize);
+    let values = entry["embedding"].as_array()?;
+    let vector: Vec<f32> = values.iter().map(|v| v.as_f64().map(|v| v as f32)).collect::<Option<_>>()?;
+    if vector.is_empty() {
+      return None;
+    }
+    *vectors.get_mut(index)? = Some(vector);
+  }
+  vectors.into_iter().collect()
+}
+
+// Stored as little-endian f32s so a BLOB column holds the vector as-is.
+pub fn encode(vector: &[f32]) -> Vec<u8> {
+  vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub fn decode(blob: &[u8]) -> Option<Vec<f32>> {
+  let chunks = blob.chunks_exact(4);
+  if blob.is_empty() || !chunks.remainder().is_empty() {
+    return None;
+  }
+  Some(chunks.map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect())
+}
+
+// None when the vectors cannot be compared: different lengths (e.g. written
+// by another embedding model) or a zero vector.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f64> {
+  if a.len() != b.len() || a.is_empty() {
+    return None;
+  }
+  let (mut dot, mut norm_a, mut norm_b) = (0.0f64, 0.0f64, 0.0f64);
+  for (x, y) in a.iter().zip(b) {
+    let (x, y) = (f64::from(*x), f64::from(*y));
+    dot += x * y;
+    norm_a += x * x;
+    norm_b += y * y;
+  }
+  if norm_a == 0.0 || norm_b == 0.0 {
+    return None;
+  }
+  Some(dot / (norm_a.sqrt() * norm_b.sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cosine_similarity_ranks_direction_not_length() {
+    let similarity = |a: &[f32], b: &[f32]| cosine_similarity(a, b).unwrap();
+    assert!((similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-9);
+    assert!(similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-9);
+    assert!((similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-9);
+    assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), None);
+    assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), None);
+  }
+
+  #[test]
+  fn encode_round_trips_and_rejects_torn_blobs() {
+    let vector = vec![0.25, -1.5, 3.0];
+    assert_eq!(decode(&encode(&vector)), Some(vector));
+    assert_eq!(decode(&[0, 0, 0]), None);
+    assert_eq!(decode(&[]), None);
+  }
+
+  #[test]
+  fn parse_embeddings_orders_vectors_by_index() {
+    let body = serde_json::json!({ "data": [{ "embedding": [0.5, -0.5] }] });
+    assert_eq!(parse_embeddings(&body, 1), Some(vec![vec![0.5, -0.5]]));
+    let body = serde_json::json!({ "data": [
+      { "index": 1, "embedding": [2.0] },
+      { "index": 0, "embedding": [1.0] }
+    ]});
+    assert_eq!(parse_embeddings(&body, 2), Some(vec![vec![1.0], vec![2.0]]));
+    assert_eq!(parse_embeddings(&body, 3), None);
+    assert_eq!(parse_embeddings(&serde_json::json!({ "data": [] }), 1), None);
+    assert_eq!(truncate(&"é".repeat(MAX_INPUT_CHARS + 5)).chars().count(), MAX_INPUT_CHARS);
+  }
+}
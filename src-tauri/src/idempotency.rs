@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use dashmap::mapref::entry::Entry as MapEntry;
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+// Long enough to cover a client retrying over a flaky connection; entries
+// live in memory only, so a restart forgets them.
+const TTL: Duration = Duration::from_secs(60 * 60);
+const CAPACITY: usize = 512;
+
+// Chat replies by Idempotency-Key, in the non-streaming body shape. A key is
+// reserved when its request starts, so a concurrent retry cannot run it twice.
+pub struct IdempotencyCache {
+  entries: DashMap<String, Entry>,
+  ttl: Duration,
+  capacity: usize,
+}
+
+struct Entry {
+  stored_at: Instant,
+  // Of the request the key was first used with.
+  body_hash: [u8; 32],
+  // None while that request is still running.
+  reply: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+  // The caller runs the request, then completes or releases the key.
+  Reserved,
+  Replay(serde_json::Value),
+  InFlight,
+  // The key was used with a different request.
+  Mismatch,
+}
+
+impl Default for IdempotencyCache {
+  fn default() -> Self {
+    Self::new(TTL, CAPACITY)
+  }
+}
+
+impl IdempotencyCache {
+  pub fn new(ttl: Duration, capacity: usize) -> Self {
+    Self {
+      entries: DashMap::new(),
+      ttl,
+      capacity: capacity.max(1),
+    }
+  }
+
+  pub fn claim(&self, key: &str, body_hash: [u8; 32]) -> Claim {
+    self.claim_at(key, body_hash, Instant::now())
+  }
+
+  fn claim_at(&self, key: &str, body_hash: [u8; 32], now: Instant) -> Claim {
+    self.make_room(key, now);
+    let pending = Entry {
+      stored_at: now,
+      body_hash,
+      reply: None,
+    };
+    match self.entries.entry(key.to_string()) {
+      MapEntry::Occupied(entry) if now.saturating_duration_since(entry.get().stored_at) < self.ttl => {
+        let entry = entry.get();
+        if entry.body_hash != body_hash {
+          return Claim::Mismatch;
+        }
+        match &entry.reply {
+          Some(reply) => Claim::Replay(reply.clone()),
+          None => Claim::InFlight,
+        }
+      }
+      MapEntry::Occupied(mut entry) => {
+        entry.insert(pending);
+        Claim::Reserved
+      }
+      MapEntry::Vacant(entry) => {
+        entry.insert(pending);
+        Claim::Reserved
+      }
+    }
+  }
+
+  // Keeps the reply for replays; the TTL runs from here.
+  pub fn complete(&self, key: &str, reply: serde_json::Value) {
+    if let Some(mut entry) = self.entries.get_mut(key) {
+      entry.stored_at = Instant::now();
+      entry.reply = Some(reply);
+    }
+  }
+
+  // Frees a key whose request failed, so the client can retry it.
+  pub fn release(&self, key: &str) {
+    self.entries.remove_if(key, |_, entry| entry.reply.is_none());
+  }
+
+  // When full, expired entries go first, then the oldest.
+  fn make_room(&self, key: &str, now: Instant) {
+    if self.entries.len() < self.capacity || self.entries.contains_key(key) {
+      return;
+    }
+    self
+      .entries
+      .retain(|_, entry| now.saturating_duration_since(entry.stored_at) < self.ttl);
+    if self.entries.len() >= self.capacity {
+      let oldest = self
+        .entries
+        .iter()
+        .min_by_key(|entry| entry.stored_at)
+        .map(|entry| entry.key().clone());
+      if let Some(oldest) = oldest {
+        self.entries.remove(&oldest);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BODY: [u8; 32] = [1; 32];
+
+  fn stored(cache: &IdempotencyCache, key: &str, reply: serde_json::Value, at: Instant) {
+    assert_eq!(cache.claim_at(key, BODY, at), Claim::Reserved);
+    cache.complete(key, reply);
+    cache.entries.get_mut(key).unwrap().stored_at = at;
+  }
+
+  #[test]
+  fn entries_expire_after_ttl() {
+    let cache = IdempotencyCache::new(Duration::from_secs(10), 4);
+    let start = Instant::now();
+    stored(&cache, "k", serde_json::json!({ "text": "hi" }), start);
+    let replay = cache.claim_at("k", BODY, start + Duration::from_secs(9));
+    assert_eq!(replay, Claim::Replay(serde_json::json!({ "text": "hi" })));
+    assert_eq!(cache.claim_at("k", BODY, start + Duration::from_secs(10)), Claim::Reserved);
+  }
+
+  #[test]
+  fn full_cache_evicts_oldest() {
+    let cache = IdempotencyCache::new(Duration::from_secs(60), 2);
+    let start = Instant::now();
+    stored(&cache, "a", serde_json::json!(1), start);
+    stored(&cache, "b", serde_json::json!(2), start + Duration::from_secs(1));
+    stored(&cache, "c", serde_json::json!(3), start + Duration::from_secs(2));
+    let now = start + Duration::from_secs(3);
+    assert!(!cache.entries.contains_key("a"));
+    assert_eq!(cache.claim_at("b", BODY, now), Claim::Replay(serde_json::json!(2)));
+    assert_eq!(cache.claim_at("c", BODY, now), Claim::Replay(serde_json::json!(3)));
+  }
+
+  #[test]
+  fn keys_are_reserved_until_completed_or_released() {
+    let cache = IdempotencyCache::default();
+    assert_eq!(cache.claim("k", BODY), Claim::Reserved);
+    assert_eq!(cache.claim("k", BODY), Claim::InFlight);
+    assert_eq!(cache.claim("k", [2; 32]), Claim::Mismatch);
+
+    cache.release("k");
+    assert_eq!(cache.claim("k", BODY), Claim::Reserved);
+    cache.complete("k", serde_json::json!("done"));
+    cache.release("k");
+    assert_eq!(cache.claim("k", BODY), Claim::Replay(serde_json::json!("done")));
+    assert_eq!(cache.claim("k", [2; 32]), Claim::Mismatch);
+  }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Context;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+const NONCE_LEN: usize = 12;
+
+// Provider keys encrypted with AES-256-GCM, for machines without a system
+// keyring. The cipher key is derived from the machine id, so the file is
+// useless elsewhere, but anyone who can run code as this user here can still
+// read it; the keyring stays the default for that reason.
+#[derive(Clone)]
+pub struct KeyFile {
+  path: PathBuf,
+  cipher_key: [u8; 32],
+}
+
+impl KeyFile {
+  pub fn new(path: &Path) -> Self {
+    Self::with_secret(path, &machine_secret())
+  }
+
+  fn with_secret(path: &Path, secret: &[u8]) -> Self {
+    let cipher_key = Sha256::new().chain_update(b"halodesk-keyfile-v1").chain_update(secret).finalize().into();
+    KeyFile {
+      path: path.to_path_buf(),
+      cipher_key,
+    }
+  }
+
+  pub fn get(&self, account: &str) -> anyhow::Result<Option<String>> {
+    let entries = self.read()?;
+    let Some(sealed) = entries.get(account) else {
+      return Ok(None);
+    };
+    let sealed = base64::engine::general_purpose::STANDARD
+      .decode(sealed)
+      .with_context(|| format!("key file entry '{account}' is not base64"))?;
+    anyhow::ensure!(sealed.len() > NONCE_LEN, "key file entry '{account}' is truncated");
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    // Binding the account name stops an entry being copied under another provider.
+    let payload = Payload {
+      msg: ciphertext,
+      aad: account.as_bytes(),
+    };
+    let plain = self
+      .cipher()
+      .decrypt(Nonce::from_slice(nonce), payload)
+      .map_err(|_| anyhow::anyhow!("key file entry '{account}' cannot be decrypted on this machine"))?;
+    Ok(Some(String::from_utf8(plain).context("decrypted key is not UTF-8")?))
+  }
+
+  pub fn set(&self, account: &str, key: &str) -> anyhow::Result<()> {
+    let mut entries = self.read()?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+      msg: key.as_bytes(),
+      aad: account.as_bytes(),
+    };
+    let ciphertext = self
+      .cipher()
+      .encrypt(&nonce, payload)
+      .map_err(|_| anyhow::anyhow!("cannot encrypt key"))?;
+    let sealed = [nonce.as_slice(), &ciphertext].concat();
+    entries.insert(account.to_string(), base64::engine::general_purpose::STANDARD.encode(sealed));
+    self.write(&entries)
+  }
+
+  // Ok(false) when there was nothing to delete.
+  pub fn delete(&self, account: &str) -> anyhow::Result<bool> {
+    let mut entries = self.read()?;
+    if entries.remove(account).is_none() {
+      return Ok(false);
+    }
+    self.write(&entries)?;
+    Ok(true)
+  }
+
+  fn cipher(&self) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.cipher_key))
+  }
+
+  fn read(&self) -> anyhow::Result<BTreeMap<String, String>> {
+    match std::fs::read_to_string(&self.path) {
+      Ok(text) => serde_json::from_str(&text).with_context(|| format!("key file {} is corrupt", self.path.display())),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+      Err(err) => Err(err).with_context(|| format!("cannot read key file {}", self.path.display())),
+    }
+  }
+
+  // Written beside the target and renamed over it, so a crash never leaves
+  // half a file behind.
+  fn write(&self, entries: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let tmp = self.path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+      .open(&tmp)
+      .with_context(|| format!("cannot write key file {}", tmp.display()))?;
+    file.write_all(serde_json::to_string_pretty(entries)?.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, &self.path).with_context(|| format!("cannot replace key file {}", self.path.display()))?;
+    Ok(())
+  }
+}
+
+// The systemd/D-Bus machine id where there is one (the Linux boxes this store
+// exists for); elsewhere the host and user names stand in.
+fn machine_secret() -> Vec<u8> {
+  for path in ["/etc/machine-id", "/var/lib/dbus/machine-id"] {
+    if let Ok(id) = std::fs::read_to_string(path) {
+      if !id.trim().is_empty() {
+        return id.trim().as_bytes().to_vec();
+      }
+    }
+  }
+  let var = |names: &[&str]| names.iter().find_map(|name| std::env::var(name).ok()).unwrap_or_default();
+  format!("{}/{}", var(&["COMPUTERNAME", "HOSTNAME"]), var(&["USERNAME", "USER"])).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn scratch_path() -> PathBuf {
+    std::env::temp_dir().join(format!("halodesk-keys-{}.enc", uuid::Uuid::new_v4()))
+  }
+
+  #[test]
+  fn keys_round_trip_through_the_encrypted_file() {
+    let path = scratch_path();
+    let file = KeyFile::with_secret(&path, b"machine-a");
+    assert_eq!(file.get("openrouter").unwrap(), None);
+
+    file.set("openrouter", "sk-or-first").unwrap();
+    file.set("anthropic", "sk-ant-second").unwrap();
+    file.set("openrouter", "sk-or-replaced").unwrap();
+    let reopened = KeyFile::with_secret(&path, b"machine-a");
+    assert_eq!(reopened.get("openrouter").unwrap().as_deref(), Some("sk-or-replaced"));
+    assert_eq!(reopened.get("anthropic").unwrap().as_deref(), Some("sk-ant-second"));
+
+    let raw = std::fs::read_to_string(&path).unwrap();
+    assert!(!raw.contains("sk-or") && !raw.contains("sk-ant"), "{raw}");
+
+    assert!(reopened.delete("anthropic").unwrap());
+    assert!(!reopened.delete("anthropic").unwrap());
+    assert_eq!(file.get("anthropic").unwrap(), None);
+    assert_eq!(file.get("openrouter").unwrap().as_deref(), Some("sk-or-replaced"));
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn entries_do_not_open_on_another_machine_or_account() {
+    let path = scratch_path();
+    KeyFile::with_secret(&path, b"machine-a").set("openrouter", "sk-or-secret").unwrap();
+    let err = KeyFile::with_secret(&path, b"machine-b").get("openrouter").unwrap_err();
+    assert!(err.to_string().contains("cannot be decrypted"), "{err}");
+
+    // An entry moved under another account name fails its authentication tag.
+    let mut entries: BTreeMap<String, String> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let sealed = entries.remove("openrouter").unwrap();
+    entries.insert("anthropic".to_string(), sealed);
+    std::fs::write(&path, serde_json::to_string(&entries).unwrap()).unwrap();
+    assert!(KeyFile::with_secret(&path, b"machine-a").get("anthropic").is_err());
+    let _ = std::fs::remove_file(&path);
+  }
+}
@@ -0,0 +1,201 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keyfile::KeyFile;
+use crate::router::Provider;
+
+// Accounts are named after the provider, which keeps the original
+// `HaloRouter`/`openrouter` entry readable without any migration.
+const SERVICE: &str = "HaloRouter";
+
+// Name of the encrypted key file inside the data dir.
+pub const KEY_FILE: &str = "keys.enc";
+
+// Error prefix the UI can match to suggest `key_storage: "file"`.
+pub const KEYRING_UNAVAILABLE: &str = "keyring_unavailable";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStorage {
+  #[default]
+  Keyring,
+  File,
+}
+
+// Where provider keys are read and written, per AppConfig::key_storage.
+// Switching storage does not move keys; they are entered again.
+#[derive(Clone)]
+pub enum KeyStore {
+  Keyring,
+  File(KeyFile),
+}
+
+impl KeyStore {
+  pub fn new(storage: KeyStorage, key_file: &Path) -> Self {
+    match storage {
+      KeyStorage::Keyring => KeyStore::Keyring,
+      KeyStorage::File => KeyStore::File(KeyFile::new(key_file)),
+    }
+  }
+}
+
+pub fn hosted_provider(name: &str) -> Result<Provider, String> {
+  Provider::ALL
+    .into_iter()
+    .find(|p| !p.is_local() && p.name() == name.trim())
+    .ok_or_else(|| format!("Unknown provider '{name}'."))
+}
+
+// Without a Secret Service daemon (or with a locked one) every keyring call
+// fails the same way; say so instead of passing the platform error through.
+fn keyring_error(err: keyring::Error) -> String {
+  match err {
+    keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_) => format!(
+      "{KEYRING_UNAVAILABLE}: no system keyring is reachable ({err}). Set key_storage to \"file\" or use an \
+       environment variable instead."
+    ),
+    err => err.to_string(),
+  }
+}
+
+fn entry(provider: Provider) -> Result<keyring::Entry, String> {
+  keyring::Entry::new(SERVICE, provider.name()).map_err(keyring_error)
+}
+
+// Ok(None) when the store answered but holds no (or a blank) key.
+fn stored_key(store: &KeyStore, provider: Provider) -> Result<Option<String>, String> {
+  let key = match store {
+    KeyStore::Keyring => match entry(provider)?.get_password() {
+      Ok(key) => Some(key),
+      Err(keyring::Error::NoEntry) => None,
+      Err(err) => return Err(keyring_error(err)),
+    },
+    KeyStore::File(file) => file.get(provider.name()).map_err(|err| format!("{err:#}"))?,
+  };
+  Ok(key.filter(|key| !key.trim().is_empty()))
+}
+
+pub fn env_var(provider: Provider) -> Option<&'static str> {
+  match provider {
+    Provider::OpenRouter => Some("OPENROUTER_API_KEY"),
+    Provider::Anthropic => Some("ANTHROPIC_API_KEY"),
+    Provider::Ollama => None,
+  }
+}
+
+fn env_key(provider: Provider) -> Option<String> {
+  std::env::var(env_var(provider)?).ok()
+}
+
+fn pick_key(keyring: Option<String>, env: Option<String>, prefer_env: bool) -> Option<String> {
+  let keyring = keyring.filter(|key| !key.trim().is_empty());
+  let env = env.filter(|key| !key.trim().is_empty());
+  if prefer_env {
+    env.or(keyring)
+  } else {
+    keyring.or(env)
+  }
+}
+
+// The stored key wins unless `prefer_env` is set; either source covers for
+// the other, so machines without a Secret Service daemon can use the env var.
+pub fn get_key(store: &KeyStore, provider: Provider, prefer_env: bool) -> Option<String> {
+  pick_key(stored_key(store, provider).ok().flatten(), env_key(provider), prefer_env)
+}
+
+pub fn set_key(store: &KeyStore, provider: Provider, key: &str) -> Result<(), String> {
+  match store {
+    KeyStore::Keyring => entry(provider)?.set_password(key).map_err(keyring_error),
+    KeyStore::File(file) => file.set(provider.name(), key).map_err(|err| format!("{err:#}")),
+  }
+}
+
+pub fn has_key(store: &KeyStore, provider: Provider) -> bool {
+  get_key(store, provider, false).is_some()
+}
+
+// Like has_key, but an unreachable store is an error rather than "no key"
+// unless the env var covers for it.
+pub fn check_key(store: &KeyStore, provider: Provider) -> Result<bool, String> {
+  let env = env_key(provider).filter(|key| !key.trim().is_empty());
+  match stored_key(store, provider) {
+    Ok(stored) => Ok(stored.is_some() || env.is_some()),
+    Err(_) if env.is_some() => Ok(true),
+    Err(err) => Err(err),
+  }
+}
+
+// Ok(stored) when the store answered, whether or not it held a key.
+pub fn probe_store(store: &KeyStore, provider: Provider) -> Result<bool, String> {
+  stored_key(store, provider).map(|key| key.is_some())
+}
+
+pub fn delete_key(store: &KeyStore, provider: Provider) -> Result<(), String> {
+  match store {
+    KeyStore::Keyring => match entry(provider)?.delete_password() {
+      Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+      Err(err) => Err(keyring_error(err)),
+    },
+    KeyStore::File(file) => file.delete(provider.name()).map(|_| ()).map_err(|err| format!("{err:#}")),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hosted_provider_accepts_known_names() {
+    assert_eq!(hosted_provider("openrouter"), Ok(Provider::OpenRouter));
+    assert_eq!(hosted_provider("anthropic"), Ok(Provider::Anthropic));
+  }
+
+  #[test]
+  fn pick_key_follows_preference_and_skips_blanks() {
+    let keyring = || Some("from-keyring".to_string());
+    let env = || Some("from-env".to_string());
+    assert_eq!(pick_key(keyring(), env(), false).as_deref(), Some("from-keyring"));
+    assert_eq!(pick_key(keyring(), env(), true).as_deref(), Some("from-env"));
+    assert_eq!(pick_key(None, env(), false).as_deref(), Some("from-env"));
+    assert_eq!(pick_key(keyring(), Some(" ".to_string()), true).as_deref(), Some("from-keyring"));
+    assert_eq!(pick_key(None, None, true), None);
+  }
+
+  #[test]
+  fn file_store_backs_the_key_commands() {
+    let path = std::env::temp_dir().join(format!("halodesk-keys-{}.enc", uuid::Uuid::new_v4()));
+    let store = KeyStore::new(KeyStorage::File, &path);
+    let provider = Provider::OpenRouter;
+    assert_eq!(probe_store(&store, provider), Ok(false));
+
+    set_key(&store, provider, "sk-ant-test").unwrap();
+    assert_eq!(probe_store(&store, provider), Ok(true));
+    assert_eq!(stored_key(&store, provider).unwrap().as_deref(), Some("sk-ant-test"));
+    assert_eq!(check_key(&store, provider), Ok(true));
+
+    delete_key(&store, provider).unwrap();
+    delete_key(&store, provider).unwrap();
+    assert_eq!(probe_store(&store, provider), Ok(false));
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn unreachable_keyring_is_reported_as_unavailable() {
+    let err = keyring_error(keyring::Error::PlatformFailure("no secret service".into()));
+    assert!(err.starts_with("keyring_unavailable: "), "{err}");
+    assert!(!keyring_error(keyring::Error::TooLong("user".to_string(), 255)).starts_with(KEYRING_UNAVAILABLE));
+  }
+
+  #[test]
+  fn key_storage_reads_lowercase_names() {
+    assert_eq!(serde_json::from_str::<KeyStorage>("\"file\"").unwrap(), KeyStorage::File);
+    assert_eq!(serde_json::to_string(&KeyStorage::Keyring).unwrap(), "\"keyring\"");
+  }
+
+  #[test]
+  fn hosted_provider_rejects_unknown_and_local_providers() {
+    assert!(hosted_provider("openai").is_err());
+    assert!(hosted_provider("ollama").is_err());
+  }
+}
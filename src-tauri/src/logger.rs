@@ -1,27 +1,312 @@
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Mutex;
 
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+  #[default]
+  Text,
+  Json,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum LogLevel {
+  Trace,
+  Debug,
+  #[default]
+  Info,
+  Warn,
+  Error,
+}
+
+impl LogLevel {
+  const ALL: [LogLevel; 5] = [LogLevel::Trace, LogLevel::Debug, LogLevel::Info, LogLevel::Warn, LogLevel::Error];
+
+  fn name(self) -> &'static str {
+    match self {
+      LogLevel::Trace => "TRACE",
+      LogLevel::Debug => "DEBUG",
+      LogLevel::Info => "INFO",
+      LogLevel::Warn => "WARN",
+      LogLevel::Error => "ERROR",
+    }
+  }
+
+  // Called for every log line, so it compares in place rather than formatting names.
+  pub fn parse(level: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|candidate| candidate.name().eq_ignore_ascii_case(level))
+  }
+}
+
+struct Rotation {
+  max_bytes: u64,
+  max_backups: usize,
+}
+
+struct LogFile {
+  file: File,
+  written: u64,
+}
 
 pub struct Logger {
-  file: Mutex<std::fs::File>,
+  path: PathBuf,
+  file: Mutex<LogFile>,
+  format: LogFormat,
+  rotation: Option<Rotation>,
+  // A `LogLevel` as u8, so the threshold can change while other threads log.
+  min_level: AtomicU8,
+}
+
+fn open_append(path: &Path) -> std::io::Result<LogFile> {
+  let file = OpenOptions::new().create(true).append(true).open(path)?;
+  let written = file.metadata()?.len();
+  Ok(LogFile { file, written })
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(format!(".{index}"));
+  PathBuf::from(name)
 }
 
 impl Logger {
   pub fn new(path: &Path) -> anyhow::Result<Self> {
-    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Self::new_with_format(path, LogFormat::Text)
+  }
+
+  pub fn new_with_format(path: &Path, format: LogFormat) -> anyhow::Result<Self> {
     Ok(Self {
-      file: Mutex::new(file),
+      path: path.to_path_buf(),
+      file: Mutex::new(open_append(path)?),
+      format,
+      rotation: None,
+      min_level: AtomicU8::new(LogLevel::Trace as u8),
     })
   }
 
+  // Once the log passes max_bytes it becomes `<path>.1`, older backups shift up,
+  // and anything past max_backups is dropped.
+  pub fn new_with_rotation(path: &Path, max_bytes: u64, max_backups: usize) -> anyhow::Result<Self> {
+    let mut logger = Self::new(path)?;
+    logger.rotation = Some(Rotation { max_bytes, max_backups });
+    Ok(logger)
+  }
+
+  pub fn tail(&self, lines: usize) -> std::io::Result<Vec<String>> {
+    tail_lines(&self.path, lines)
+  }
+
+  pub fn with_format(mut self, format: LogFormat) -> Self {
+    self.format = format;
+    self
+  }
+
+  pub fn set_min_level(&self, level: LogLevel) {
+    self.min_level.store(level as u8, Ordering::Relaxed);
+  }
+
+  // Levels this logger does not know are always written.
+  pub fn enabled(&self, level: &str) -> bool {
+    LogLevel::parse(level).is_none_or(|level| level as u8 >= self.min_level.load(Ordering::Relaxed))
+  }
+
   pub fn log(&self, level: &str, message: &str) {
-    let ts = Utc::now().to_rfc3339();
-    let line = format!("[{ts}] {level}: {message}\n");
-    if let Ok(mut file) = self.file.lock() {
-      let _ = file.write_all(line.as_bytes());
+    self.log_fields(level, message, serde_json::Value::Null);
+  }
+
+  // `fields` should be a JSON object; anything else is logged under "fields".
+  pub fn log_fields(&self, level: &str, message: &str, fields: serde_json::Value) {
+    if !self.enabled(level) {
+      return;
+    }
+    let line = format_line(self.format, &Utc::now().to_rfc3339(), level, message, fields);
+    if let Ok(mut log) = self.file.lock() {
+      if let Some(rotation) = &self.rotation {
+        if log.written > 0 && log.written + line.len() as u64 > rotation.max_bytes {
+          if let Ok(fresh) = self.rotate(rotation) {
+            *log = fresh;
+          }
+        }
+      }
+      if log.file.write_all(line.as_bytes()).is_ok() {
+        log.written += line.len() as u64;
+      }
     }
   }
+
+  // Called with the file lock held so concurrent writers never race a rename.
+  fn rotate(&self, rotation: &Rotation) -> std::io::Result<LogFile> {
+    if rotation.max_backups == 0 {
+      let _ = std::fs::remove_file(&self.path);
+    } else {
+      let _ = std::fs::remove_file(backup_path(&self.path, rotation.max_backups));
+      for index in (1..rotation.max_backups).rev() {
+        let from = backup_path(&self.path, index);
+        if from.exists() {
+          std::fs::rename(&from, backup_path(&self.path, index + 1))?;
+        }
+      }
+      std::fs::rename(&self.path, backup_path(&self.path, 1))?;
+    }
+    open_append(&self.path)
+  }
+}
+
+const TAIL_CHUNK: u64 = 8 * 1024;
+
+// Reads backwards from the end of the file so large logs are never loaded whole.
+pub fn tail_lines(path: &Path, lines: usize) -> std::io::Result<Vec<String>> {
+  let mut file = match File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(err) => return Err(err),
+  };
+  if lines == 0 {
+    return Ok(Vec::new());
+  }
+
+  let mut pos = file.metadata()?.len();
+  let mut buf: Vec<u8> = Vec::new();
+  // One extra newline is needed because the last line ends with one.
+  while pos > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= lines {
+    let step = TAIL_CHUNK.min(pos);
+    pos -= step;
+    let mut chunk = vec![0; step as usize];
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(&mut chunk)?;
+    chunk.extend_from_slice(&buf);
+    buf = chunk;
+  }
+
+  let text = String::from_utf8_lossy(&buf);
+  let all: Vec<&str> = text.lines().collect();
+  let skip = all.len().saturating_sub(lines);
+  Ok(all[skip..].iter().map(|line| line.to_string()).collect())
+}
+
+fn format_line(format: LogFormat, ts: &str, level: &str, message: &str, fields: serde_json::Value) -> String {
+  match format {
+    LogFormat::Text => match fields {
+      serde_json::Value::Null => format!("[{ts}] {level}: {message}\n"),
+      serde_json::Value::Object(map) if map.is_empty() => format!("[{ts}] {level}: {message}\n"),
+      fields => format!("[{ts}] {level}: {message} {fields}\n"),
+    },
+    LogFormat::Json => {
+      let mut entry = serde_json::Map::new();
+      entry.insert("ts".to_string(), ts.into());
+      entry.insert("level".to_string(), level.into());
+      entry.insert("msg".to_string(), message.into());
+      match fields {
+        serde_json::Value::Null => {}
+        serde_json::Value::Object(map) => {
+          for (key, value) in map {
+            entry.entry(key).or_insert(value);
+          }
+        }
+        other => {
+          entry.insert("fields".to_string(), other);
+        }
+      }
+      format!("{}\n", serde_json::Value::Object(entry))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn text_format_keeps_plain_lines() {
+    let line = format_line(LogFormat::Text, "t", "INFO", "hello", serde_json::Value::Null);
+    assert_eq!(line, "[t] INFO: hello\n");
+    let line = format_line(LogFormat::Text, "t", "INFO", "hello", serde_json::json!({ "model": "m" }));
+    assert_eq!(line, "[t] INFO: hello {\"model\":\"m\"}\n");
+  }
+
+  #[test]
+  fn json_format_merges_fields() {
+    let fields = serde_json::json!({ "model": "m", "latency_ms": 12, "msg": "ignored" });
+    let line = format_line(LogFormat::Json, "t", "INFO", "done", fields);
+    let value: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+    assert_eq!(value["ts"], "t");
+    assert_eq!(value["level"], "INFO");
+    assert_eq!(value["msg"], "done");
+    assert_eq!(value["model"], "m");
+    assert_eq!(value["latency_ms"], 12);
+  }
+
+  #[test]
+  fn tail_lines_returns_last_lines() {
+    let path = std::env::temp_dir().join(format!("halodesk-log-{}.log", uuid::Uuid::new_v4()));
+    assert!(tail_lines(&path, 10).unwrap().is_empty());
+
+    let body: String = (0..5000).map(|i| format!("line {i}\n")).collect();
+    std::fs::write(&path, body).unwrap();
+    assert_eq!(tail_lines(&path, 3).unwrap(), vec!["line 4997", "line 4998", "line 4999"]);
+    assert_eq!(tail_lines(&path, 10_000).unwrap().len(), 5000);
+    assert!(tail_lines(&path, 0).unwrap().is_empty());
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn rotation_renames_log_past_threshold() {
+    let path = std::env::temp_dir().join(format!("halodesk-log-{}.log", uuid::Uuid::new_v4()));
+    let logger = Logger::new_with_rotation(&path, 160, 2).unwrap();
+    for i in 0..6 {
+      logger.log("INFO", &format!("line number {i} padded out a bit"));
+    }
+    assert!(backup_path(&path, 1).exists());
+    assert!(backup_path(&path, 2).exists());
+    assert!(!backup_path(&path, 3).exists());
+    assert!(std::fs::metadata(&path).unwrap().len() <= 160);
+    let newest = std::fs::read_to_string(&path).unwrap();
+    assert!(newest.contains("line number 5"));
+    for file in [path.clone(), backup_path(&path, 1), backup_path(&path, 2)] {
+      let _ = std::fs::remove_file(file);
+    }
+  }
+
+  #[test]
+  fn messages_below_min_level_are_dropped() {
+    let path = std::env::temp_dir().join(format!("halodesk-log-{}.log", uuid::Uuid::new_v4()));
+    let logger = Logger::new(&path).unwrap();
+    logger.set_min_level(LogLevel::Info);
+    logger.log("DEBUG", "noisy detail");
+    logger.log("INFO", "kept");
+    logger.log("error", "also kept");
+    logger.log("NOTICE", "unknown levels are kept");
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(!contents.contains("noisy detail"));
+    assert_eq!(contents.lines().count(), 3);
+
+    logger.set_min_level(LogLevel::Debug);
+    logger.log("DEBUG", "now visible");
+    assert!(std::fs::read_to_string(&path).unwrap().contains("now visible"));
+    assert_eq!(LogLevel::parse("warn"), Some(LogLevel::Warn));
+    for level in LogLevel::ALL {
+      assert_eq!(serde_json::to_value(level).unwrap(), level.name());
+    }
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn new_with_format_writes_json_lines() {
+    let path = std::env::temp_dir().join(format!("halodesk-log-{}.log", uuid::Uuid::new_v4()));
+    let logger = Logger::new_with_format(&path, LogFormat::Json).unwrap();
+    logger.log("WARN", "first");
+    logger.log_fields("INFO", "second", serde_json::json!({ "id": 1 }));
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["level"], "WARN");
+    assert_eq!(lines[1]["id"], 1);
+    let _ = std::fs::remove_file(&path);
+  }
 }
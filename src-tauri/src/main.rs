@@ -1,27 +1,58 @@
 ﻿#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod anthropic;
+mod autohide;
 mod capture;
 mod config;
+mod embeddings;
+mod idempotency;
+mod keyfile;
+mod keys;
 mod logger;
+mod markdown;
+mod metrics;
 mod models;
+mod ocr;
+mod ollama;
+mod pricing;
+mod ratelimit;
+mod resume;
+mod reveal;
 mod router;
+mod selftest;
 mod storage;
+mod tokens;
 
 use std::{path::PathBuf, sync::Arc, time::Instant};
 
 use anyhow::Context;
-use tauri::{GlobalShortcutManager, Manager, State};
-use tokio::sync::RwLock;
+use dashmap::DashMap;
+use tauri::{AppHandle, GlobalShortcutManager, Manager, RunEvent, State, WindowEvent};
+use tokio::sync::{watch, RwLock, Semaphore};
 
 use config::{load_or_init, save_config, AppConfig};
-use router::{run_router, RouterState};
+use metrics::Metrics;
+use router::{build_http_client, generate_token, run_router, Provider, RouterState};
 use storage::init_db;
 
+const LOG_MAX_BYTES: u64 = 5 * 1024 * 1024;
+const LOG_MAX_BACKUPS: usize = 3;
+// Points config, database, log and images at another directory, e.g. for portable installs.
+const DATA_DIR_ENV: &str = "HALODESK_DATA_DIR";
+
 struct AppState {
   router_port: u16,
+  router_token: String,
+  router_shutdown: watch::Sender<bool>,
+  router_task: std::sync::Mutex<Option<tauri::async_runtime::JoinHandle<()>>>,
+  data_dir: PathBuf,
   config_path: PathBuf,
+  db_path: PathBuf,
   config: Arc<RwLock<AppConfig>>,
+  db: Arc<tokio::sync::Mutex<rusqlite::Connection>>,
   log_path: PathBuf,
+  logger: Arc<logger::Logger>,
+  auto_hide: Arc<autohide::AutoHide>,
 }
 
 #[tauri::command]
@@ -29,35 +60,179 @@ fn router_port(state: State<'_, AppState>) -> u16 {
   state.router_port
 }
 
+#[tauri::command]
+fn router_token(state: State<'_, AppState>) -> String {
+  state.router_token.clone()
+}
+
 #[tauri::command]
 async fn get_config(state: State<'_, AppState>) -> Result<AppConfig, String> {
   Ok(state.config.read().await.clone())
 }
 
 #[tauri::command]
-async fn set_config(state: State<'_, AppState>, config: AppConfig) -> Result<(), String> {
+async fn set_config(app: AppHandle, state: State<'_, AppState>, config: AppConfig) -> Result<(), String> {
+  config.validate().map_err(|errors| errors.join("; "))?;
+  let mut current = state.config.write().await;
+  if config.content_protection != current.content_protection {
+    apply_content_protection(&app, config.content_protection)?;
+  }
+  if config.shortcut != current.shortcut {
+    replace_shortcut(&app, &current.shortcut, &config.shortcut)?;
+  }
   save_config(&state.config_path, &config).map_err(|e| e.to_string())?;
-  *state.config.write().await = config;
+  state.logger.set_min_level(config.log_level);
+  state.auto_hide.set_enabled(config.hide_on_blur);
+  *current = config;
   Ok(())
 }
 
 #[tauri::command]
-fn set_openrouter_key(key: String) -> Result<(), String> {
-  let entry = keyring::Entry::new("HaloRouter", "openrouter").map_err(|e| e.to_string())?;
-  entry.set_password(&key).map_err(|e| e.to_string())
+async fn set_log_level(state: State<'_, AppState>, level: logger::LogLevel) -> Result<(), String> {
+  let mut config = state.config.write().await;
+  let mut updated = config.clone();
+  updated.log_level = level;
+  save_config(&state.config_path, &updated).map_err(|e| e.to_string())?;
+  state.logger.set_min_level(level);
+  *config = updated;
+  Ok(())
+}
+
+#[tauri::command]
+async fn set_content_protection(app: AppHandle, state: State<'_, AppState>, enabled: bool) -> Result<(), String> {
+  let mut config = state.config.write().await;
+  apply_content_protection(&app, enabled)?;
+  let mut updated = config.clone();
+  updated.content_protection = enabled;
+  save_config(&state.config_path, &updated).map_err(|e| e.to_string())?;
+  *config = updated;
+  Ok(())
+}
+
+#[tauri::command]
+async fn set_hide_on_blur(state: State<'_, AppState>, enabled: bool) -> Result<(), String> {
+  let mut config = state.config.write().await;
+  let mut updated = config.clone();
+  updated.hide_on_blur = enabled;
+  save_config(&state.config_path, &updated).map_err(|e| e.to_string())?;
+  state.auto_hide.set_enabled(enabled);
+  *config = updated;
+  Ok(())
+}
+
+// The UI holds auto-hide around native dialogs, which take focus from the
+// window, and releases it once they close.
+#[tauri::command]
+fn hold_hide_on_blur(state: State<'_, AppState>, held: bool) {
+  if held {
+    state.auto_hide.hold();
+  } else {
+    state.auto_hide.release();
+  }
+}
+
+fn apply_content_protection(app: &AppHandle, enabled: bool) -> Result<(), String> {
+  let window = app.get_window("main").ok_or("main window not found")?;
+  window.set_content_protected(enabled).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn set_shortcut(app: AppHandle, state: State<'_, AppState>, accelerator: String) -> Result<(), String> {
+  let mut config = state.config.write().await;
+  if config.shortcut == accelerator {
+    return Ok(());
+  }
+  replace_shortcut(&app, &config.shortcut, &accelerator)?;
+
+  let mut updated = config.clone();
+  updated.shortcut = accelerator;
+  save_config(&state.config_path, &updated).map_err(|e| e.to_string())?;
+  *config = updated;
+  Ok(())
+}
+
+fn replace_shortcut(app: &AppHandle, current: &str, accelerator: &str) -> Result<(), String> {
+  let mut gsm = app.global_shortcut_manager();
+  let _ = gsm.unregister(current);
+  if let Err(err) = register_toggle_shortcut(app, accelerator) {
+    // Put the previous shortcut back so the window stays reachable.
+    let _ = register_toggle_shortcut(app, current);
+    return Err(err.to_string());
+  }
+  Ok(())
+}
+
+async fn key_store(state: &AppState) -> keys::KeyStore {
+  let storage = state.config.read().await.key_storage;
+  keys::KeyStore::new(storage, &state.data_dir.join(keys::KEY_FILE))
+}
+
+#[tauri::command]
+async fn set_provider_key(state: State<'_, AppState>, provider: String, key: String) -> Result<(), String> {
+  keys::set_key(&key_store(&state).await, keys::hosted_provider(&provider)?, &key)
+}
+
+#[tauri::command]
+async fn has_provider_key(state: State<'_, AppState>, provider: String) -> Result<bool, String> {
+  let store = key_store(&state).await;
+  Ok(keys::hosted_provider(&provider).is_ok_and(|provider| keys::has_key(&store, provider)))
+}
+
+#[tauri::command]
+async fn delete_provider_key(state: State<'_, AppState>, provider: String) -> Result<(), String> {
+  keys::delete_key(&key_store(&state).await, keys::hosted_provider(&provider)?)
+}
+
+#[tauri::command]
+async fn set_openrouter_key(state: State<'_, AppState>, key: String) -> Result<(), String> {
+  keys::set_key(&key_store(&state).await, Provider::OpenRouter, &key)
+}
+
+#[tauri::command]
+async fn delete_openrouter_key(state: State<'_, AppState>) -> Result<(), String> {
+  keys::delete_key(&key_store(&state).await, Provider::OpenRouter)
+}
+
+// Errors with `keyring_unavailable` when the keyring cannot be reached and no
+// env var covers for it, so the UI can offer the file store.
+#[tauri::command]
+async fn has_openrouter_key(state: State<'_, AppState>) -> Result<bool, String> {
+  keys::check_key(&key_store(&state).await, Provider::OpenRouter)
+}
+
+#[tauri::command]
+fn capture_primary_display(options: Option<models::CaptureOptions>) -> Result<models::ImageData, String> {
+  capture::capture_primary_display(&options.unwrap_or_default()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn has_openrouter_key() -> bool {
-  keyring::Entry::new("HaloRouter", "openrouter")
-    .and_then(|e| e.get_password())
-    .map(|p| !p.is_empty())
-    .unwrap_or(false)
+fn capture_display(index: usize, options: Option<models::CaptureOptions>) -> Result<models::ImageData, String> {
+  capture::capture_display(index, &options.unwrap_or_default()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn capture_primary_display() -> Result<models::ImageData, String> {
-  capture::capture_primary_display().map_err(|e| e.to_string())
+fn capture_region(x: i32, y: i32, width: u32, height: u32) -> Result<models::ImageData, String> {
+  capture::capture_region(x, y, width, height).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn capture_clipboard_image() -> Result<models::ImageData, String> {
+  capture::capture_clipboard_image().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_displays() -> Result<Vec<models::DisplayInfo>, String> {
+  capture::list_displays().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn list_windows() -> Result<Vec<models::WindowInfo>, String> {
+  capture::list_windows().map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn capture_window(id: u32, options: Option<models::CaptureOptions>) -> Result<models::ImageData, String> {
+  capture::capture_window(id, &options.unwrap_or_default()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -65,84 +240,224 @@ fn get_log_path(state: State<'_, AppState>) -> String {
   state.log_path.display().to_string()
 }
 
+#[tauri::command]
+fn get_config_path(state: State<'_, AppState>) -> String {
+  state.config_path.display().to_string()
+}
+
+#[tauri::command]
+fn get_db_path(state: State<'_, AppState>) -> String {
+  state.db_path.display().to_string()
+}
+
+#[tauri::command]
+fn reveal_in_explorer(state: State<'_, AppState>, path: String) -> Result<(), String> {
+  let path = reveal::resolve_in(&state.data_dir, &path)?;
+  reveal::reveal(&path).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn export_conversation_markdown(state: State<'_, AppState>, history_id: String) -> Result<String, String> {
+  match storage::load_chat(&state.db, &history_id).await {
+    Ok(Some(chat)) => Ok(markdown::conversation_markdown(&chat)),
+    Ok(None) => Err(format!("No history item with id '{history_id}'.")),
+    Err(err) => Err(err.to_string()),
+  }
+}
+
+#[tauri::command]
+async fn run_self_test(state: State<'_, AppState>) -> Result<models::SelfTestReport, String> {
+  let config = state.config.read().await.clone();
+  Ok(selftest::run(&state.data_dir, &config).await)
+}
+
+fn register_toggle_shortcut(app: &AppHandle, accelerator: &str) -> tauri::Result<()> {
+  let handle = app.clone();
+  app.global_shortcut_manager().register(accelerator, move || {
+    if let Some(window) = handle.get_window("main") {
+      let visible = window.is_visible().unwrap_or(true);
+      if visible {
+        let _ = window.hide();
+      } else {
+        if let Some(state) = handle.try_state::<AppState>() {
+          state.auto_hide.shown();
+        }
+        let _ = window.show();
+        let _ = window.set_focus();
+      }
+    }
+  })
+}
+
+fn resolve_data_dir(app: &tauri::App) -> anyhow::Result<PathBuf> {
+  let data_dir = match std::env::var_os(DATA_DIR_ENV).filter(|value| !value.is_empty()) {
+    Some(dir) => PathBuf::from(dir),
+    None => app.path_resolver().app_data_dir().context("missing app data dir")?,
+  };
+  std::fs::create_dir_all(&data_dir).with_context(|| format!("cannot create data dir {}", data_dir.display()))?;
+  selftest::ensure_writable(&data_dir)?;
+  Ok(data_dir)
+}
+
 fn main() {
   tauri::Builder::default()
     .setup(|app| {
       (|| -> anyhow::Result<()> {
-        let data_dir = app
-          .path_resolver()
-          .app_data_dir()
-          .context("missing app data dir")?;
-        std::fs::create_dir_all(&data_dir)?;
+        let data_dir = resolve_data_dir(app)?;
 
         let config_path = data_dir.join("config.json");
         let db_path = data_dir.join("halodesk.sqlite3");
         let log_path = data_dir.join("halodesk.log");
+        let images_dir = data_dir.join("images");
 
         let config = load_or_init(&config_path)?;
+        let shortcut = config.shortcut.clone();
+        let content_protection = config.content_protection;
+        let auto_hide = Arc::new(autohide::AutoHide::default());
+        auto_hide.set_enabled(config.hide_on_blur);
+        let log_level = config.log_level;
+        let chat_slots = Arc::new(Semaphore::new(config.max_concurrent_requests));
+        let (retention_days, max_history_rows) = (config.history_retention_days, config.max_history_rows);
         let config = Arc::new(RwLock::new(config));
 
         let db = init_db(&db_path)?;
         let db = Arc::new(tokio::sync::Mutex::new(db));
 
-        let logger = Arc::new(logger::Logger::new(&log_path)?);
+        let logger = logger::Logger::new_with_rotation(&log_path, LOG_MAX_BYTES, LOG_MAX_BACKUPS)?;
+        // HALODESK_LOG_FORMAT=json switches to newline-delimited JSON for log tooling.
+        let logger = match std::env::var("HALODESK_LOG_FORMAT").as_deref() {
+          Ok("json") => logger.with_format(logger::LogFormat::Json),
+          _ => logger,
+        };
+        logger.set_min_level(log_level);
+        let logger = Arc::new(logger);
         logger.log("INFO", "HaloDesk starting up");
 
+        let prune_db = db.clone();
+        let prune_logger = logger.clone();
+        let prune_images_dir = images_dir.clone();
+        tauri::async_runtime::spawn(async move {
+          match storage::prune_history(&prune_db, retention_days, max_history_rows).await {
+            Ok(0) => {}
+            Ok(deleted) => prune_logger.log("INFO", &format!("pruned {deleted} history rows")),
+            Err(err) => prune_logger.log("WARN", &format!("history prune failed: {err}")),
+          }
+          if let Err(err) = storage::sweep_images(&prune_db, &prune_images_dir).await {
+            prune_logger.log("WARN", &format!("image sweep failed: {err}"));
+          }
+        });
+
         let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
         let port = listener.local_addr()?.port();
+        let token = generate_token();
 
         let router_state = RouterState {
           started_at: Instant::now(),
           config: config.clone(),
-          db,
+          config_path: config_path.clone(),
+          db: db.clone(),
           logger: logger.clone(),
           port,
+          inflight: DashMap::new(),
+          http_client: build_http_client()?,
+          metrics: Metrics::default(),
+          chat_slots,
+          token: token.clone(),
+          images_dir,
+          key_file: data_dir.join(keys::KEY_FILE),
+          rate_limits: ratelimit::RateLimiter::default(),
+          idempotency: idempotency::IdempotencyCache::default(),
+          prices: Arc::new(pricing::PriceBook::default()),
+          resume: resume::ResumeBuffers::default(),
         };
 
-        tauri::async_runtime::spawn(async move {
-          if let Err(err) = run_router(listener, router_state).await {
+        let (router_shutdown, shutdown_rx) = watch::channel(false);
+        let router_task = tauri::async_runtime::spawn(async move {
+          if let Err(err) = run_router(listener, router_state, shutdown_rx).await {
             eprintln!("router error: {err}");
           }
         });
 
         app.manage(AppState {
           router_port: port,
+          router_token: token,
+          router_shutdown,
+          router_task: std::sync::Mutex::new(Some(router_task)),
+          data_dir,
           config_path,
+          db_path,
           config,
+          db,
           log_path,
+          logger: logger.clone(),
+          auto_hide,
         });
 
         if let Some(window) = app.get_window("main") {
-          let _ = window.set_content_protected(true);
+          let _ = window.set_content_protected(content_protection);
         }
 
-        let handle = app.handle();
-        let mut gsm = handle.global_shortcut_manager();
-        let _ = gsm.register("CmdOrCtrl+Shift+Space", move || {
-          if let Some(window) = handle.get_window("main") {
-            let visible = window.is_visible().unwrap_or(true);
-            if visible {
-              let _ = window.hide();
-            } else {
-              let _ = window.show();
-              let _ = window.set_focus();
-            }
-          }
-        });
+        if let Err(err) = register_toggle_shortcut(&app.handle(), &shortcut) {
+          logger.log("WARN", &format!("failed to register shortcut {shortcut}: {err}"));
+        }
 
         Ok(())
       })()
       .map_err(|e| e.into())
     })
+    .on_window_event(|event| {
+      if let WindowEvent::Focused(false) = event.event() {
+        let window = event.window();
+        let Some(state) = window.try_state::<AppState>() else {
+          return;
+        };
+        if window.label() == "main" && window.is_visible().unwrap_or(false) && state.auto_hide.should_hide() {
+          let _ = window.hide();
+        }
+      }
+    })
     .invoke_handler(tauri::generate_handler![
       router_port,
+      router_token,
       get_config,
       set_config,
+      set_shortcut,
+      set_content_protection,
+      set_hide_on_blur,
+      hold_hide_on_blur,
+      set_log_level,
+      set_provider_key,
+      has_provider_key,
+      delete_provider_key,
       set_openrouter_key,
+      delete_openrouter_key,
       has_openrouter_key,
       capture_primary_display,
-      get_log_path
+      capture_display,
+      capture_region,
+      capture_clipboard_image,
+      list_displays,
+      list_windows,
+      capture_window,
+      get_log_path,
+      get_config_path,
+      get_db_path,
+      reveal_in_explorer,
+      run_self_test,
+      export_conversation_markdown
     ])
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while building tauri application")
+    .run(|app, event| {
+      // Closing the window exits the app; let the router drain first so
+      // in-flight chats are written to history.
+      if let RunEvent::Exit = event {
+        let state = app.state::<AppState>();
+        let _ = state.router_shutdown.send(true);
+        let task = state.router_task.lock().ok().and_then(|mut task| task.take());
+        if let Some(task) = task {
+          let _ = tauri::async_runtime::block_on(task);
+        }
+      }
+    });
 }
@@ -0,0 +1,103 @@
+use chrono::DateTime;
+
+use crate::storage::StoredChat;
+
+fn role_label(role: &str) -> String {
+  match role {
+    "user" => "User".to_string(),
+    "assistant" => "Assistant".to_string(),
+    "system" => "System".to_string(),
+    "developer" => "Developer".to_string(),
+    "tool" => "Tool".to_string(),
+    other => other.to_string(),
+  }
+}
+
+// Dates read as `2024-03-01 14:05 UTC`; anything unparseable is shown as stored.
+fn display_date(created_at: &str) -> String {
+  DateTime::parse_from_rfc3339(created_at)
+    .map(|date| date.naive_utc().format("%Y-%m-%d %H:%M UTC").to_string())
+    .unwrap_or_else(|_| created_at.to_string())
+}
+
+// Attachments belong to the row, and the router sends them with the last user
+// message, so that is where their placeholders go. Image files stay local.
+pub fn conversation_markdown(chat: &StoredChat) -> String {
+  let image_turn = chat.messages.iter().rposition(|m| m.role == "user");
+  let mut out = String::from("# Conversation\n\n");
+  for (index, message) in chat.messages.iter().enumerate() {
+    out.push_str(&format!("**{}:**\n\n", role_label(&message.role)));
+    let content = message.content.trim();
+    if !content.is_empty() {
+      out.push_str(content);
+      out.push_str("\n\n");
+    }
+    if image_turn == Some(index) {
+      for image in &chat.images {
+        out.push_str(&format!("*[Image attached: {} ({})]*\n\n", image.id, image.mime));
+      }
+    }
+  }
+  out.push_str("---\n\n");
+  out.push_str(&format!("- Model: {}\n", chat.model.as_deref().unwrap_or("unknown")));
+  out.push_str(&format!("- Provider: {}\n", chat.provider.as_deref().unwrap_or("unknown")));
+  out.push_str(&format!("- Date: {}\n", display_date(&chat.created_at)));
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::{Message, StoredImage};
+
+  fn message(role: &str, content: &str) -> Message {
+    Message {
+      role: role.to_string(),
+      content: content.to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn renders_roles_images_and_footer() {
+    let chat = StoredChat {
+      messages: vec![
+        message("system", "Be brief."),
+        message("user", "What is this?"),
+        message("assistant", "A cat.\n"),
+      ],
+      thread_id: None,
+      images: vec![StoredImage {
+        id: "abc.png".to_string(),
+        mime: "image/png".to_string(),
+        bytes: 10,
+      }],
+      created_at: "2024-03-01T15:05:09+01:00".to_string(),
+      model: Some("openrouter:openai/gpt-4o-mini".to_string()),
+      provider: Some("openrouter".to_string()),
+    };
+    let expected = "# Conversation\n\n\
+      **System:**\n\nBe brief.\n\n\
+      **User:**\n\nWhat is this?\n\n*[Image attached: abc.png (image/png)]*\n\n\
+      **Assistant:**\n\nA cat.\n\n\
+      ---\n\n\
+      - Model: openrouter:openai/gpt-4o-mini\n\
+      - Provider: openrouter\n\
+      - Date: 2024-03-01 14:05 UTC\n";
+    assert_eq!(conversation_markdown(&chat), expected);
+  }
+
+  #[test]
+  fn missing_metadata_is_marked_unknown() {
+    let chat = StoredChat {
+      messages: vec![message("user", "hi")],
+      thread_id: None,
+      images: Vec::new(),
+      created_at: "yesterday".to_string(),
+      model: None,
+      provider: None,
+    };
+    let markdown = conversation_markdown(&chat);
+    assert!(markdown.ends_with("- Model: unknown\n- Provider: unknown\n- Date: yesterday\n"), "{markdown}");
+  }
+}
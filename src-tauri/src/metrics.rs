@@ -0,0 +1,105 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+use crate::router::Provider;
+
+// Counters since startup. Relaxed ordering is enough: each value is read on
+// its own and nothing synchronises through them.
+#[derive(Default)]
+pub struct Metrics {
+  chats: AtomicU64,
+  completed: AtomicU64,
+  latency_ms_total: AtomicU64,
+  errors: DashMap<String, AtomicU64>,
+  providers: DashMap<&'static str, AtomicU64>,
+}
+
+impl Metrics {
+  pub fn record_chat(&self) {
+    self.chats.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_completion(&self, provider: Provider, latency_ms: i64) {
+    self.completed.fetch_add(1, Ordering::Relaxed);
+    self
+      .latency_ms_total
+      .fetch_add(latency_ms.max(0) as u64, Ordering::Relaxed);
+    self
+      .providers
+      .entry(provider.name())
+      .or_default()
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_error(&self, code: &str) {
+    self
+      .errors
+      .entry(code.to_string())
+      .or_default()
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self, uptime_ms: u128) -> serde_json::Value {
+    let completed = self.completed.load(Ordering::Relaxed);
+    let avg_latency_ms = self
+      .latency_ms_total
+      .load(Ordering::Relaxed)
+      .checked_div(completed)
+      .unwrap_or(0);
+    let errors: serde_json::Map<String, serde_json::Value> = self
+      .errors
+      .iter()
+      .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed).into()))
+      .collect();
+    let providers: serde_json::Map<String, serde_json::Value> = self
+      .providers
+      .iter()
+      .map(|entry| (entry.key().to_string(), entry.value().load(Ordering::Relaxed).into()))
+      .collect();
+    let errors_total: u64 = errors.values().filter_map(|v| v.as_u64()).sum();
+
+    serde_json::json!({
+      "uptime_ms": uptime_ms,
+      "chats_total": self.chats.load(Ordering::Relaxed),
+      "chats_completed": completed,
+      "avg_latency_ms": avg_latency_ms,
+      "errors_total": errors_total,
+      "errors": errors,
+      "providers": providers
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn snapshot_reports_counts_and_average_latency() {
+    let metrics = Metrics::default();
+    metrics.record_chat();
+    metrics.record_chat();
+    metrics.record_chat();
+    metrics.record_completion(Provider::OpenRouter, 100);
+    metrics.record_completion(Provider::Ollama, 300);
+    metrics.record_error("bad_params");
+
+    let snapshot = metrics.snapshot(42);
+    assert_eq!(snapshot["uptime_ms"], 42);
+    assert_eq!(snapshot["chats_total"], 3);
+    assert_eq!(snapshot["chats_completed"], 2);
+    assert_eq!(snapshot["avg_latency_ms"], 200);
+    assert_eq!(snapshot["errors_total"], 1);
+    assert_eq!(snapshot["errors"]["bad_params"], 1);
+    assert_eq!(snapshot["providers"]["openrouter"], 1);
+    assert_eq!(snapshot["providers"]["ollama"], 1);
+  }
+
+  #[test]
+  fn snapshot_handles_no_completions() {
+    let snapshot = Metrics::default().snapshot(0);
+    assert_eq!(snapshot["avg_latency_ms"], 0);
+    assert_eq!(snapshot["errors"], serde_json::json!({}));
+  }
+}
@@ -1,9 +1,31 @@
 ﻿use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Message {
   pub role: String,
   pub content: String,
+  // The calls an assistant turn made, sent back with the conversation so the
+  // `tool` replies that follow can refer to them.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub tool_calls: Option<Vec<ToolCall>>,
+  // On a `tool` message: the id of the call it answers.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub tool_call_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ToolFunction {
+  pub name: String,
+  pub arguments: String,
+}
+
+// An assistant tool call in OpenAI's shape; `arguments` is the raw JSON string.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ToolCall {
+  pub id: String,
+  #[serde(default)]
+  pub r#type: String,
+  pub function: ToolFunction,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -12,13 +34,218 @@ pub struct ImageData {
   pub base64: String,
 }
 
+// An attachment saved under the app's images dir; `id` is also the file name.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredImage {
+  pub id: String,
+  pub mime: String,
+  pub bytes: u64,
+}
+
+// An image the model returned: saved under the images dir when it arrived
+// inline as a data URL, otherwise the link the provider gave.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum OutputImage {
+  Stored(StoredImage),
+  Linked { url: String },
+}
+
 #[derive(Serialize, Deserialize, Clone)]
+pub struct DisplayInfo {
+  pub index: usize,
+  pub id: u32,
+  pub x: i32,
+  pub y: i32,
+  pub width: u32,
+  pub height: u32,
+  pub is_primary: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WindowInfo {
+  pub id: u32,
+  pub title: String,
+  pub app_name: String,
+  pub x: i32,
+  pub y: i32,
+  pub width: u32,
+  pub height: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+  #[default]
+  Png,
+  Jpeg,
+  Webp,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CaptureOptions {
+  pub max_width: Option<u32>,
+  pub format: CaptureFormat,
+  pub quality: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct ChatRequest {
   pub preset_id: Option<String>,
   pub messages: Vec<Message>,
   pub image: Option<ImageData>,
+  pub images: Option<Vec<ImageData>>,
   pub model_override: Option<String>,
   pub stream: Option<bool>,
+  pub temperature: Option<f32>,
+  pub max_tokens: Option<u32>,
+  pub top_p: Option<f32>,
+  // Sequences that end generation, at most four; sent as OpenRouter's `stop`,
+  // Anthropic's `stop_sequences` or Ollama's `options.stop`.
+  pub stop: Option<Vec<String>>,
+  pub thread_id: Option<String>,
+  // Echo the prompt back without calling any provider; for UI work and tests.
+  pub dry_run: Option<bool>,
+  // Forwarded untouched to OpenRouter, e.g. `{"type":"json_object"}`.
+  pub response_format: Option<serde_json::Value>,
+  // OpenRouter tool definitions and choice, forwarded untouched.
+  pub tools: Option<serde_json::Value>,
+  pub tool_choice: Option<serde_json::Value>,
+  // OpenRouter's `provider` object (order, allow_fallbacks, ...); must be an object.
+  pub provider_routing: Option<serde_json::Value>,
+  // Keeps this chat (and any images it carries or produces) out of history.
+  pub no_store: Option<bool>,
+  // Assigned by the router when the chat starts; tags its log lines and is
+  // returned as `request_id`.
+  #[serde(skip)]
+  pub request_id: String,
+}
+
+impl ChatRequest {
+  // `image` predates `images`; both are honoured, single image first.
+  pub fn all_images(&self) -> Vec<&ImageData> {
+    self.image.iter().chain(self.images.iter().flatten()).collect()
+  }
+
+  pub fn has_images(&self) -> bool {
+    self.image.is_some() || self.images.as_ref().is_some_and(|images| !images.is_empty())
+  }
+
+  pub fn skips_history(&self) -> bool {
+    self.no_store.unwrap_or(false)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+  pub prompt_tokens: i64,
+  pub completion_tokens: i64,
+  pub total_tokens: i64,
+}
+
+impl Usage {
+  pub fn new(prompt_tokens: i64, completion_tokens: i64) -> Self {
+    Self {
+      prompt_tokens,
+      completion_tokens,
+      total_tokens: prompt_tokens + completion_tokens,
+    }
+  }
+
+  // Some providers report prompt and completion counts in separate events.
+  pub fn merge(self, other: Usage) -> Self {
+    Self::new(
+      if other.prompt_tokens > 0 { other.prompt_tokens } else { self.prompt_tokens },
+      if other.completion_tokens > 0 { other.completion_tokens } else { self.completion_tokens },
+    )
+  }
+}
+
+// Model choices a preset can pin; unset fields fall back to the app defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RoutingPolicy {
+  // Also read as `text_model`, the name the preset editor uses.
+  #[serde(alias = "text_model")]
+  pub model: Option<String>,
+  pub vision_model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Preset {
+  pub id: String,
+  pub name: String,
+  pub system_prompt: String,
+  pub constraints: serde_json::Value,
+  pub routing_policy: RoutingPolicy,
+}
+
+// Body of `POST /v1/presets`; without an id a new preset is created.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PresetSaveRequest {
+  #[serde(default)]
+  pub id: Option<String>,
+  pub name: String,
+  #[serde(default)]
+  pub system_prompt: String,
+  // Must be an object when given; unset stores `{}`.
+  #[serde(default)]
+  pub constraints: Option<serde_json::Value>,
+  #[serde(default)]
+  pub routing_policy: RoutingPolicy,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PresetsResponse {
+  pub items: Vec<Preset>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatCancelRequest {
+  pub request_id: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ChatRegenerateRequest {
+  pub history_id: String,
+  pub model_override: Option<String>,
+  pub stream: Option<bool>,
+  pub dry_run: Option<bool>,
+}
+
+// The conversation to title; only its first user turn and the reply to it are used.
+#[derive(Serialize, Deserialize)]
+pub struct ChatTitleRequest {
+  pub messages: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenCountRequest {
+  pub messages: Vec<Message>,
+  pub model_override: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TokenCountResponse {
+  pub tokens: usize,
+  pub method: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct OcrBlock {
+  pub text: String,
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+  pub confidence: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OcrResponse {
+  pub text: String,
+  pub blocks: Vec<OcrBlock>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -26,6 +253,9 @@ pub struct ModelInfo {
   pub id: String,
   pub label: String,
   pub capability: String,
+  // Requests per minute the router lets through to this model; unset is unlimited.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub rate_limit_rpm: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,20 +277,267 @@ pub struct MemoryStoreResponse {
   pub stored_at: String,
 }
 
+// Ids in the order the items were sent.
+#[derive(Serialize, Deserialize)]
+pub struct MemoryStoreBatchResponse {
+  pub ids: Vec<String>,
+}
+
+// Fields left out of `payload` keep their stored values.
 #[derive(Serialize, Deserialize)]
+pub struct MemoryUpdateRequest {
+  pub r#type: String,
+  pub id: String,
+  pub payload: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryUpdateResponse {
+  pub id: String,
+  pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryDeleteRequest {
+  pub r#type: String,
+  pub id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryCompactResponse {
+  pub bytes_before: u64,
+  pub bytes_after: u64,
+}
+
+// Export rows mirror the table columns; `*_json` columns stay as the stored
+// strings so a round trip is byte-for-byte.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryRow {
+  pub id: String,
+  pub created_at: String,
+  pub messages_json: String,
+  pub model: Option<String>,
+  pub provider: Option<String>,
+  pub prompt_tokens: Option<i64>,
+  pub completion_tokens: Option<i64>,
+  pub thread_id: Option<String>,
+  pub latency_ms: Option<i64>,
+  pub tool_calls_json: Option<String>,
+  pub deleted_at: Option<String>,
+  // References only; the image files themselves are not part of an export.
+  pub images_json: Option<String>,
+  #[serde(default)]
+  pub partial: bool,
+  #[serde(default)]
+  pub favorite: bool,
+  #[serde(default)]
+  pub cost_usd: Option<f64>,
+  #[serde(default)]
+  pub output_images_json: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PinnedRow {
+  pub id: String,
+  pub created_at: String,
+  pub text: String,
+  pub tags_json: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PresetRow {
+  pub id: String,
+  pub created_at: String,
+  pub name: String,
+  pub system_prompt: Option<String>,
+  pub constraints_json: Option<String>,
+  pub routing_policy_json: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SettingRow {
+  pub id: String,
+  pub created_at: String,
+  pub key: String,
+  pub value_json: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemoryExport {
+  // Schema version of the exporting database; newer documents are refused.
+  pub version: usize,
+  pub exported_at: String,
+  pub history: Vec<HistoryRow>,
+  pub pinned: Vec<PinnedRow>,
+  pub presets: Vec<PresetRow>,
+  pub settings: Vec<SettingRow>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+  // Replace every row in the database with the document.
+  Overwrite,
+  // Keep existing rows; clashing ids get fresh ones and settings are matched by key.
+  #[default]
+  Merge,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryImportRequest {
+  #[serde(default)]
+  pub mode: ImportMode,
+  pub data: MemoryExport,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct MemoryImportResponse {
+  pub history: usize,
+  pub pinned: usize,
+  pub presets: usize,
+  pub settings: usize,
+  pub reassigned_ids: usize,
+}
+
+// One outbound model call, or a chat rejected before any call was made.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuditRecord {
+  pub id: String,
+  pub created_at: String,
+  pub model: Option<String>,
+  pub provider: Option<String>,
+  pub has_image: bool,
+  pub latency_ms: i64,
+  // ok, error, timeout or cancelled.
+  pub status: String,
+  pub error_code: Option<String>,
+  pub prompt_tokens: Option<i64>,
+  pub completion_tokens: Option<i64>,
+  pub cost_usd: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditResponse {
+  pub items: Vec<AuditRecord>,
+}
+
+// History rows per model; rows stored without a model count as "unknown".
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ModelUsageCount {
+  pub model: String,
+  pub provider: String,
+  pub count: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ModelsUsedResponse {
+  pub items: Vec<ModelUsageCount>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SelfTestStep {
+  pub name: String,
+  pub ok: bool,
+  pub message: String,
+  pub latency_ms: i64,
+}
+
+// `ok` only when every step passed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SelfTestReport {
+  pub ok: bool,
+  pub steps: Vec<SelfTestStep>,
+}
+
+// Unset fields keep their current model.
+#[derive(Serialize, Deserialize, Default)]
+pub struct DefaultModelsRequest {
+  #[serde(default)]
+  pub text_default_model: Option<String>,
+  #[serde(default)]
+  pub vision_default_model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SettingsSetRequest {
+  pub value: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryThreadRequest {
+  pub thread_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryThreadResponse {
+  pub thread_id: String,
+  pub items: Vec<MemoryItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryRestoreRequest {
+  pub id: String,
+}
+
+// Unset `favorite` flips the current value.
+#[derive(Serialize, Deserialize)]
+pub struct MemoryFavoriteRequest {
+  pub id: String,
+  pub favorite: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MemoryTrashResponse {
+  pub items: Vec<MemoryItem>,
+}
+
+// Unset empties the whole trash.
+#[derive(Serialize, Deserialize, Default)]
+pub struct MemoryPurgeRequest {
+  pub older_than_days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+  // Full-text match on the words themselves.
+  #[default]
+  Lexical,
+  // Ranked by embedding similarity; history and pinned items only.
+  Semantic,
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct MemoryQueryRequest {
   pub query: String,
+  #[serde(default)]
+  pub mode: SearchMode,
   pub limit: Option<i64>,
+  pub offset: Option<i64>,
+  // RFC3339 bounds on created_at, both inclusive.
+  pub from: Option<String>,
+  pub to: Option<String>,
+  // Pinned items must carry every listed tag (exact, case-sensitive match);
+  // history and presets have no tags, so a tag filter leaves them out.
+  pub tags: Option<Vec<String>>,
+  // Only favorited history; favorites sort first either way.
+  #[serde(default)]
+  pub favorites_only: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MemoryQueryResponse {
   pub items: Vec<MemoryItem>,
+  pub total: i64,
   pub took_ms: i64,
+  // The mode actually used; a semantic query falls back to lexical when it
+  // cannot be embedded.
+  pub mode: SearchMode,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MemoryItem {
   pub r#type: String,
   pub payload: serde_json::Value,
+  pub score: f64,
 }
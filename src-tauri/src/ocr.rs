@@ -0,0 +1,146 @@
+use base64::Engine;
+use thiserror::Error;
+
+use crate::models::{ImageData, OcrBlock, OcrResponse};
+
+#[derive(Debug, Error)]
+pub enum OcrError {
+  #[error("{0}")]
+  Invalid(String),
+  #[error("OCR support was not compiled in; rebuild with the `ocr` feature")]
+  Unavailable,
+  #[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+  #[error("{0}")]
+  Internal(String),
+}
+
+#[cfg(feature = "ocr")]
+fn recognize_tsv(bytes: &[u8]) -> Result<String, OcrError> {
+  let internal = |err: &dyn std::fmt::Display| OcrError::Internal(err.to_string());
+  let mut engine = tesseract::Tesseract::new(None, Some("eng"))
+    .map_err(|e| internal(&e))?
+    .set_image_from_mem(bytes)
+    .map_err(|_| OcrError::Invalid("image could not be decoded".to_string()))?
+    .recognize()
+    .map_err(|e| internal(&e))?;
+  engine.get_tsv_text(0).map_err(|e| internal(&e))
+}
+
+#[cfg(not(feature = "ocr"))]
+fn recognize_tsv(_bytes: &[u8]) -> Result<String, OcrError> {
+  Err(OcrError::Unavailable)
+}
+
+pub fn extract_text(image: &ImageData) -> Result<OcrResponse, OcrError> {
+  let bytes = base64::engine::general_purpose::STANDARD
+    .decode(image.base64.trim())
+    .map_err(|e| OcrError::Invalid(format!("image is not valid base64: {e}")))?;
+  if bytes.is_empty() {
+    return Err(OcrError::Invalid("image is empty".to_string()));
+  }
+  Ok(parse_tsv(&recognize_tsv(&bytes)?))
+}
+
+// Tesseract's TSV has one row per word (level 5) plus rows for the page,
+// blocks, paragraphs and lines. Words are regrouped into lines so each block
+// is a readable span with a single bounding box.
+fn parse_tsv(tsv: &str) -> OcrResponse {
+  let mut lines: Vec<((u32, u32, u32), Vec<OcrBlock>)> = Vec::new();
+  for row in tsv.lines() {
+    let cols: Vec<&str> = row.split('\t').collect();
+    if cols.len() < 12 || cols[0] != "5" {
+      continue;
+    }
+    let text = cols[11..].join("\t");
+    let text = text.trim();
+    if text.is_empty() {
+      continue;
+    }
+    let num = |idx: usize| cols[idx].parse::<u32>().unwrap_or(0);
+    let word = OcrBlock {
+      text: text.to_string(),
+      x: num(6),
+      y: num(7),
+      width: num(8),
+      height: num(9),
+      confidence: cols[10].parse().unwrap_or(0.0),
+    };
+    let key = (num(2), num(3), num(4));
+    match lines.last_mut() {
+      Some((last, words)) if *last == key => words.push(word),
+      _ => lines.push((key, vec![word])),
+    }
+  }
+
+  let blocks: Vec<OcrBlock> = lines.into_iter().map(|(_, words)| merge_words(words)).collect();
+  let text = blocks.iter().map(|b| b.text.as_str()).collect::<Vec<_>>().join("\n");
+  OcrResponse { text, blocks }
+}
+
+fn merge_words(words: Vec<OcrBlock>) -> OcrBlock {
+  let left = words.iter().map(|w| w.x).min().unwrap_or(0);
+  let top = words.iter().map(|w| w.y).min().unwrap_or(0);
+  let right = words.iter().map(|w| w.x + w.width).max().unwrap_or(0);
+  let bottom = words.iter().map(|w| w.y + w.height).max().unwrap_or(0);
+  let confidence = words.iter().map(|w| w.confidence).sum::<f32>() / words.len() as f32;
+  OcrBlock {
+    text: words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>().join(" "),
+    x: left,
+    y: top,
+    width: right - left,
+    height: bottom - top,
+    confidence,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HEADER: &str = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext";
+
+  #[test]
+  fn parse_tsv_groups_words_into_lines() {
+    let tsv = [
+      HEADER,
+      "1\t1\t0\t0\t0\t0\t0\t0\t800\t600\t-1\t",
+      "4\t1\t1\t1\t1\t0\t10\t20\t120\t16\t-1\t",
+      "5\t1\t1\t1\t1\t1\t10\t20\t50\t16\t90\tHello",
+      "5\t1\t1\t1\t1\t2\t70\t22\t60\t14\t80\tworld",
+      "5\t1\t1\t1\t2\t1\t10\t50\t40\t16\t95\tBye",
+      "5\t1\t1\t1\t2\t2\t60\t50\t5\t16\t10\t ",
+    ]
+    .join("\n");
+    let res = parse_tsv(&tsv);
+    assert_eq!(res.text, "Hello world\nBye");
+    assert_eq!(
+      res.blocks[0],
+      OcrBlock {
+        text: "Hello world".to_string(),
+        x: 10,
+        y: 20,
+        width: 120,
+        height: 16,
+        confidence: 85.0,
+      }
+    );
+    assert_eq!(res.blocks[1].text, "Bye");
+  }
+
+  #[test]
+  fn parse_tsv_without_words_is_empty() {
+    let res = parse_tsv(&format!("{HEADER}\n1\t1\t0\t0\t0\t0\t0\t0\t800\t600\t-1\t"));
+    assert_eq!(res.text, "");
+    assert!(res.blocks.is_empty());
+    assert_eq!(parse_tsv("").text, "");
+  }
+
+  #[test]
+  fn extract_text_rejects_bad_base64() {
+    let image = ImageData {
+      mime: "image/png".to_string(),
+      base64: "not base64!".to_string(),
+    };
+    assert!(matches!(extract_text(&image), Err(OcrError::Invalid(_))));
+  }
+}
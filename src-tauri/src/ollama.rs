@@ -0,0 +1,186 @@
+use serde::Serialize;
+
+use crate::models::{ChatRequest, ImageData, Message, Usage};
+use crate::router::StreamEvent;
+
+#[derive(Serialize)]
+pub struct OllamaMessage {
+  role: String,
+  content: String,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  images: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct OllamaOptions {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  temperature: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  num_predict: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stop: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct OllamaChatRequest {
+  model: String,
+  messages: Vec<OllamaMessage>,
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  options: Option<OllamaOptions>,
+}
+
+pub fn chat_url(base_url: &str) -> String {
+  format!("{}/api/chat", base_url.trim_end_matches('/'))
+}
+
+pub fn build_payload(req: &ChatRequest, model: &str, stream: bool) -> OllamaChatRequest {
+  let stop = req.stop.clone().filter(|stop| !stop.is_empty());
+  let options = if req.temperature.is_none() && req.top_p.is_none() && req.max_tokens.is_none() && stop.is_none() {
+    None
+  } else {
+    Some(OllamaOptions {
+      temperature: req.temperature,
+      top_p: req.top_p,
+      num_predict: req.max_tokens,
+      stop,
+    })
+  };
+  OllamaChatRequest {
+    model: model.to_string(),
+    messages: to_ollama_messages(&req.messages, &req.all_images()),
+    stream,
+    options,
+  }
+}
+
+fn to_ollama_messages(messages: &[Message], images: &[&ImageData]) -> Vec<OllamaMessage> {
+  let last_user_index = messages.iter().rposition(|m| m.role == "user");
+  let mut result: Vec<OllamaMessage> = messages
+    .iter()
+    .enumerate()
+    .map(|(idx, msg)| OllamaMessage {
+      role: msg.role.clone(),
+      content: msg.content.clone(),
+      images: if Some(idx) == last_user_index {
+        images.iter().map(|img| img.base64.clone()).collect()
+      } else {
+        Vec::new()
+      },
+    })
+    .collect();
+
+  if !images.is_empty() && last_user_index.is_none() {
+    result.push(OllamaMessage {
+      role: "user".to_string(),
+      content: String::new(),
+      images: images.iter().map(|img| img.base64.clone()).collect(),
+    });
+  }
+
+  result
+}
+
+pub fn parse_stream_line(line: &str) -> Vec<StreamEvent> {
+  let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+    return Vec::new();
+  };
+
+  if let Some(error) = value["error"].as_str() {
+    return vec![StreamEvent::Error(error.to_string())];
+  }
+
+  let mut events = Vec::new();
+  if let Some(content) = value["message"]["content"].as_str() {
+    if !content.is_empty() {
+      events.push(StreamEvent::Delta(content.to_string()));
+    }
+  }
+  if value["done"].as_bool() == Some(true) {
+    if let Some(usage) = usage(&value) {
+      events.push(StreamEvent::Usage(usage));
+    }
+    let reason = value["done_reason"].as_str().unwrap_or("stop");
+    events.push(StreamEvent::Finish(reason.to_string()));
+    events.push(StreamEvent::Done);
+  }
+  events
+}
+
+pub fn completion_text(body: &serde_json::Value) -> String {
+  body["message"]["content"].as_str().unwrap_or("").to_string()
+}
+
+pub fn usage(body: &serde_json::Value) -> Option<Usage> {
+  let prompt = body["prompt_eval_count"].as_i64()?;
+  let completion = body["eval_count"].as_i64()?;
+  Some(Usage::new(prompt, completion))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chat_url_trims_trailing_slash() {
+    assert_eq!(chat_url("http://localhost:11434/"), "http://localhost:11434/api/chat");
+  }
+
+  #[test]
+  fn build_payload_maps_options_and_images() {
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "What is this?".to_string(),
+        ..Default::default()
+      }],
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "abc".to_string(),
+      }),
+      max_tokens: Some(64),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "llava", true)).unwrap();
+    assert_eq!(payload["messages"][0]["images"][0], "abc");
+    assert_eq!(payload["options"]["num_predict"], 64);
+    assert!(payload["options"].get("temperature").is_none());
+    assert!(payload["options"].get("stop").is_none());
+
+    let req = ChatRequest {
+      stop: Some(vec!["END".to_string()]),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "llama3", true)).unwrap();
+    assert_eq!(payload["options"]["stop"], serde_json::json!(["END"]));
+
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "llama3", false)).unwrap();
+    assert!(payload.get("options").is_none());
+  }
+
+  #[test]
+  fn parse_stream_line_emits_delta_then_done() {
+    let line = r#"{"model":"llama3","message":{"role":"assistant","content":"Hi"},"done":false}"#;
+    assert_eq!(parse_stream_line(line), vec![StreamEvent::Delta("Hi".to_string())]);
+
+    let last = r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"done_reason":"length","prompt_eval_count":7,"eval_count":4}"#;
+    assert_eq!(
+      parse_stream_line(last),
+      vec![
+        StreamEvent::Usage(Usage::new(7, 4)),
+        StreamEvent::Finish("length".to_string()),
+        StreamEvent::Done
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_stream_line_surfaces_errors() {
+    assert_eq!(
+      parse_stream_line(r#"{"error":"model not found"}"#),
+      vec![StreamEvent::Error("model not found".to_string())]
+    );
+  }
+}
@@ -0,0 +1,128 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::models::Usage;
+
+// OpenRouter reprices rarely; a stale table only skews cost, never a chat.
+const MAX_AGE: Duration = Duration::from_secs(6 * 60 * 60);
+
+// USD per token, as OpenRouter's models endpoint quotes it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelPrice {
+  pub prompt: f64,
+  pub completion: f64,
+}
+
+// Prices by model id (`openrouter:…`), filled from the models endpoint.
+#[derive(Default)]
+pub struct PriceBook {
+  prices: DashMap<String, ModelPrice>,
+  fetched_at: Mutex<Option<Instant>>,
+}
+
+impl PriceBook {
+  pub fn replace(&self, prices: Vec<(String, ModelPrice)>) {
+    self.prices.clear();
+    for (id, price) in prices {
+      self.prices.insert(id, price);
+    }
+    *self.fetched_at.lock().unwrap() = Some(Instant::now());
+  }
+
+  // True for the one caller that should fetch a missing or stale table; the
+  // claim counts as a fetch so a failing endpoint is not hammered.
+  pub fn claim_refresh(&self) -> bool {
+    let mut fetched_at = self.fetched_at.lock().unwrap();
+    if fetched_at.is_some_and(|at| at.elapsed() < MAX_AGE) {
+      return false;
+    }
+    *fetched_at = Some(Instant::now());
+    true
+  }
+
+  // None until the table has loaded and for models it does not price.
+  pub fn cost(&self, model_id: &str, usage: &Usage) -> Option<f64> {
+    cost_usd(usage, self.prices.get(model_id).map(|price| *price))
+  }
+}
+
+// Unknown without a price; a model listed as free costs 0.
+pub fn cost_usd(usage: &Usage, price: Option<ModelPrice>) -> Option<f64> {
+  let price = price?;
+  Some(usage.prompt_tokens.max(0) as f64 * price.prompt + usage.completion_tokens.max(0) as f64 * price.completion)
+}
+
+pub fn parse_prices(body: &serde_json::Value) -> Vec<(String, ModelPrice)> {
+  let Some(entries) = body["data"].as_array() else {
+    return Vec::new();
+  };
+  // Quoted as decimal strings. Models with an unreadable rate (or "-1" for
+  // variable pricing) are left out, so their cost stays unknown.
+  let rate = |value: &serde_json::Value| -> Option<f64> {
+    let parsed = match value {
+      serde_json::Value::String(text) => text.parse().ok()?,
+      other => other.as_f64()?,
+    };
+    (parsed.is_finite() && parsed >= 0.0).then_some(parsed)
+  };
+  entries
+    .iter()
+    .filter_map(|entry| {
+      let id = entry["id"].as_str()?;
+      let pricing = &entry["pricing"];
+      let price = ModelPrice {
+        prompt: rate(&pricing["prompt"])?,
+        completion: rate(&pricing["completion"])?,
+      };
+      Some((format!("openrouter:{id}"), price))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(actual: f64, expected: f64) {
+    assert!((actual - expected).abs() < 1e-12, "{actual} != {expected}");
+  }
+
+  #[test]
+  fn cost_multiplies_tokens_by_rates() {
+    let price = ModelPrice {
+      prompt: 0.00000015,
+      completion: 0.0000006,
+    };
+    assert_close(cost_usd(&Usage::new(1000, 500), Some(price)).unwrap(), 0.00045);
+    assert_close(cost_usd(&Usage::new(2_000_000, 0), Some(price)).unwrap(), 0.3);
+    assert_eq!(cost_usd(&Usage::new(1000, 500), None), None);
+  }
+
+  #[test]
+  fn parse_prices_keeps_listed_free_models_and_skips_unknown_rates() {
+    let body = serde_json::json!({ "data": [
+      { "id": "openai/gpt-4o-mini", "pricing": { "prompt": "0.00000015", "completion": "0.0000006" } },
+      { "id": "meta/llama:free", "pricing": { "prompt": "0", "completion": "0" } },
+      { "id": "openrouter/auto", "pricing": { "prompt": "-1", "completion": "-1" } },
+      { "id": "no/pricing" }
+    ]});
+    let prices = parse_prices(&body);
+    assert_eq!(prices.len(), 2);
+    assert_eq!(prices[0].0, "openrouter:openai/gpt-4o-mini");
+    assert_close(prices[0].1.prompt, 0.00000015);
+    assert_close(prices[0].1.completion, 0.0000006);
+    assert_eq!(prices[1], ("openrouter:meta/llama:free".to_string(), ModelPrice::default()));
+
+    let book = PriceBook::default();
+    let usage = Usage::new(10, 10);
+    assert!(book.claim_refresh());
+    assert!(!book.claim_refresh());
+    assert_eq!(book.cost("openrouter:openai/gpt-4o-mini", &usage), None);
+    book.replace(prices);
+    assert_close(book.cost("openrouter:openai/gpt-4o-mini", &usage).unwrap(), 0.0000075);
+    assert_eq!(book.cost("openrouter:meta/llama:free", &usage), Some(0.0));
+    assert_eq!(book.cost("openrouter:openrouter/auto", &usage), None);
+  }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+// One token bucket per model id. A bucket holds up to `rpm` requests and
+// refills continuously at `rpm` per minute, so an idle model can take a burst
+// of a full minute's allowance.
+#[derive(Default)]
+pub struct RateLimiter {
+  buckets: DashMap<String, Bucket>,
+}
+
+struct Bucket {
+  tokens: f64,
+  refilled_at: Instant,
+}
+
+impl RateLimiter {
+  // `rpm` must be at least 1. Err carries how long until a request would pass.
+  pub fn try_acquire(&self, model_id: &str, rpm: u32) -> Result<(), Duration> {
+    self.try_acquire_at(model_id, rpm, Instant::now())
+  }
+
+  fn try_acquire_at(&self, model_id: &str, rpm: u32, now: Instant) -> Result<(), Duration> {
+    let capacity = f64::from(rpm);
+    let mut bucket = self.buckets.entry(model_id.to_string()).or_insert_with(|| Bucket {
+      tokens: capacity,
+      refilled_at: now,
+    });
+    let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+    // Clamping here also applies a lowered cap as soon as the config changes.
+    bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+    bucket.refilled_at = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / capacity))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bucket_allows_burst_then_refills_over_time() {
+    let limiter = RateLimiter::default();
+    let start = Instant::now();
+    for _ in 0..3 {
+      assert!(limiter.try_acquire_at("m", 3, start).is_ok());
+    }
+    // Three per minute refills one token every 20 seconds.
+    let wait = limiter.try_acquire_at("m", 3, start).unwrap_err();
+    assert_eq!(wait.as_secs(), 20);
+
+    let wait = limiter.try_acquire_at("m", 3, start + Duration::from_secs(15)).unwrap_err();
+    assert_eq!(wait.as_secs(), 5);
+    assert!(limiter.try_acquire_at("m", 3, start + Duration::from_secs(20)).is_ok());
+    assert!(limiter.try_acquire_at("m", 3, start + Duration::from_secs(20)).is_err());
+  }
+
+  #[test]
+  fn bucket_never_exceeds_capacity_and_models_are_independent() {
+    let limiter = RateLimiter::default();
+    let start = Instant::now();
+    assert!(limiter.try_acquire_at("a", 2, start).is_ok());
+    assert!(limiter.try_acquire_at("b", 1, start).is_ok());
+    assert!(limiter.try_acquire_at("b", 1, start).is_err());
+
+    let later = start + Duration::from_secs(3600);
+    assert!(limiter.try_acquire_at("a", 2, later).is_ok());
+    assert!(limiter.try_acquire_at("a", 2, later).is_ok());
+    assert!(limiter.try_acquire_at("a", 2, later).is_err());
+  }
+}
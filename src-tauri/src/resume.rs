@@ -0,0 +1,112 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::watch;
+
+// How long a finished stream can still be resumed; buffers live in memory
+// only, so a restart forgets them.
+const KEEP_AFTER_FINISH: Duration = Duration::from_secs(5 * 60);
+
+// Everything a stream has sent that a reconnecting client may have missed.
+// Delta ids are positions in `deltas`, counted from 1.
+#[derive(Clone, Default)]
+pub struct Replay {
+  pub deltas: Vec<String>,
+  pub done: Option<serde_json::Value>,
+  finished_at: Option<Instant>,
+}
+
+// Replay buffers by chat request id, for SSE reconnects with Last-Event-ID.
+#[derive(Default)]
+pub struct ResumeBuffers {
+  streams: DashMap<String, Arc<watch::Sender<Replay>>>,
+}
+
+impl ResumeBuffers {
+  pub fn open(&self, request_id: &str) -> ResumeWriter {
+    self.prune();
+    let sender = Arc::new(watch::Sender::new(Replay::default()));
+    self.streams.insert(request_id.to_string(), sender.clone());
+    ResumeWriter { sender }
+  }
+
+  // Follows a stream from its start; None once it has expired or never existed.
+  pub fn subscribe(&self, request_id: &str) -> Option<watch::Receiver<Replay>> {
+    self.prune();
+    self.streams.get(request_id).map(|sender| sender.subscribe())
+  }
+
+  // True while some resumer is following the stream.
+  pub fn is_followed(&self, request_id: &str) -> bool {
+    self.streams.get(request_id).is_some_and(|sender| sender.receiver_count() > 0)
+  }
+
+  fn prune(&self) {
+    self
+      .streams
+      .retain(|_, sender| sender.borrow().finished_at.is_none_or(|at| at.elapsed() < KEEP_AFTER_FINISH));
+  }
+}
+
+// The stream's side of a buffer. Dropped without `finish`, the stream was
+// abandoned (its client went away), and resumers are told so.
+pub struct ResumeWriter {
+  sender: Arc<watch::Sender<Replay>>,
+}
+
+impl ResumeWriter {
+  // Records a delta and returns its event id.
+  pub fn delta(&self, text: &str) -> u64 {
+    let mut id = 0;
+    self.sender.send_modify(|replay| {
+      replay.deltas.push(text.to_string());
+      id = replay.deltas.len() as u64;
+    });
+    id
+  }
+
+  pub fn finish(&self, done: &serde_json::Value) {
+    self.sender.send_modify(|replay| {
+      replay.done = Some(done.clone());
+      replay.finished_at = Some(Instant::now());
+    });
+  }
+}
+
+impl Drop for ResumeWriter {
+  fn drop(&mut self) {
+    self.sender.send_if_modified(|replay| {
+      if replay.done.is_some() {
+        return false;
+      }
+      let text = replay.deltas.concat();
+      replay.done = Some(serde_json::json!({ "finish_reason": "aborted_by_client", "text": text }));
+      replay.finished_at = Some(Instant::now());
+      true
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dropped_writer_finishes_the_replay() {
+    let buffers = ResumeBuffers::default();
+    let writer = buffers.open("req-1");
+    assert_eq!(writer.delta("Hel"), 1);
+    assert_eq!(writer.delta("lo"), 2);
+    let rx = buffers.subscribe("req-1").unwrap();
+    assert!(rx.borrow().done.is_none());
+
+    drop(writer);
+    let replay = rx.borrow().clone();
+    assert_eq!(replay.deltas, vec!["Hel", "lo"]);
+    let done = replay.done.unwrap();
+    assert_eq!(done["finish_reason"], "aborted_by_client");
+    assert_eq!(done["text"], "Hello");
+    assert!(buffers.subscribe("req-2").is_none());
+  }
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Only files under the app data dir may be revealed; symlinks and `..` are
+// resolved before the check.
+pub fn resolve_in(data_dir: &Path, path: &str) -> Result<PathBuf, String> {
+  let root = data_dir.canonicalize().map_err(|e| format!("app data dir unavailable: {e}"))?;
+  let target = Path::new(path)
+    .canonicalize()
+    .map_err(|e| format!("cannot reveal {path}: {e}"))?;
+  let (root, target) = (strip_verbatim(root), strip_verbatim(target));
+  if !target.starts_with(&root) {
+    return Err(format!("{path} is outside the app data dir"));
+  }
+  Ok(target)
+}
+
+// canonicalize() on Windows returns `\\?\C:\...` (or `\\?\UNC\server\...`),
+// which explorer's `/select,` does not understand; turn it back into the
+// ordinary form. Other paths pass through unchanged.
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+  let Some(text) = path.to_str() else {
+    return path;
+  };
+  if let Some(rest) = text.strip_prefix(r"\\?\UNC\") {
+    return PathBuf::from(format!(r"\\{rest}"));
+  }
+  match text.strip_prefix(r"\\?\") {
+    Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+    _ => path,
+  }
+}
+
+// Opens the OS file manager with the file selected where the platform allows it.
+pub fn reveal(path: &Path) -> io::Result<()> {
+  let mut command = reveal_command(path);
+  // explorer.exe exits non-zero even on success, so only a failed spawn counts.
+  // The child is still waited on, off the caller's thread, so it is reaped.
+  let mut child = command.spawn()?;
+  std::thread::spawn(move || child.wait());
+  Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn reveal_command(path: &Path) -> Command {
+  let mut command = Command::new("explorer");
+  command.arg(format!("/select,{}", path.display()));
+  command
+}
+
+#[cfg(target_os = "macos")]
+fn reveal_command(path: &Path) -> Command {
+  let mut command = Command::new("open");
+  command.arg("-R").arg(path);
+  command
+}
+
+// xdg-open has no way to select a file, so open the folder holding it.
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn reveal_command(path: &Path) -> Command {
+  let mut command = Command::new("xdg-open");
+  command.arg(if path.is_dir() { path } else { path.parent().unwrap_or(path) });
+  command
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolve_in_rejects_paths_outside_data_dir() {
+    let root = std::env::temp_dir().join(format!("halodesk-reveal-{}", uuid::Uuid::new_v4()));
+    let data_dir = root.join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let inside = data_dir.join("config.json");
+    let outside = root.join("secret.txt");
+    std::fs::write(&inside, "{}").unwrap();
+    std::fs::write(&outside, "").unwrap();
+
+    let resolved = resolve_in(&data_dir, inside.to_str().unwrap()).unwrap();
+    assert_eq!(resolved, inside.canonicalize().unwrap());
+
+    let escaped = data_dir.join("..").join("secret.txt");
+    assert!(resolve_in(&data_dir, escaped.to_str().unwrap()).is_err());
+    assert!(resolve_in(&data_dir, outside.to_str().unwrap()).is_err());
+    assert!(resolve_in(&data_dir, data_dir.join("missing").to_str().unwrap()).is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn strip_verbatim_restores_plain_windows_paths() {
+    let strip = |path: &str| strip_verbatim(PathBuf::from(path));
+    assert_eq!(strip(r"\\?\C:\Users\me\config.json"), PathBuf::from(r"C:\Users\me\config.json"));
+    assert_eq!(strip(r"\\?\UNC\server\share\a.txt"), PathBuf::from(r"\\server\share\a.txt"));
+    // Verbatim forms with no plain equivalent are left alone.
+    assert_eq!(strip(r"\\?\Volume{1234}\a.txt"), PathBuf::from(r"\\?\Volume{1234}\a.txt"));
+    assert_eq!(strip("/home/me/config.json"), PathBuf::from("/home/me/config.json"));
+  }
+}
@@ -1,50 +1,235 @@
-﻿use std::net::TcpListener;
+﻿use std::borrow::Cow;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use async_stream::stream;
-use axum::extract::State;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{DefaultBodyLimit, Path, Query, Request, State};
 use axum::http::StatusCode;
+use axum::middleware::{self, Next};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
+use dashmap::DashMap;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use tokio::sync::{Mutex, RwLock};
+use sha2::{Digest, Sha256};
+use tokio::sync::{oneshot, watch, Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio_stream::StreamExt;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::compression::{CompressionLayer, DefaultPredicate};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 
-use crate::config::AppConfig;
-use crate::models::{ChatRequest, ImageData, MemoryQueryRequest, MemoryStoreRequest, Message, ModelsResponse};
+use crate::anthropic;
+use crate::capture;
+use crate::config::{default_openrouter_app_title, default_openrouter_referer, save_config, AppConfig};
+use crate::embeddings;
+use crate::idempotency::{Claim, IdempotencyCache};
+use crate::keys;
+use crate::metrics::Metrics;
+use crate::models::{
+  AuditResponse, ChatCancelRequest, ChatRegenerateRequest, ChatRequest, ChatTitleRequest, DefaultModelsRequest,
+  ImageData, MemoryDeleteRequest, MemoryFavoriteRequest, MemoryImportRequest, MemoryPurgeRequest, MemoryQueryRequest,
+  MemoryRestoreRequest, MemoryStoreRequest, MemoryThreadRequest, MemoryThreadResponse, MemoryTrashResponse,
+  MemoryUpdateRequest, Message, ModelInfo, ModelsResponse, ModelsUsedResponse, OutputImage, PresetSaveRequest,
+  PresetsResponse, RoutingPolicy, SearchMode, SettingsSetRequest, TokenCountRequest, ToolCall, Usage,
+};
+use crate::ocr;
+use crate::ollama;
+use crate::pricing::{self, PriceBook};
+use crate::ratelimit::RateLimiter;
+use crate::resume::ResumeBuffers;
 use crate::storage;
+use crate::tokens;
 
 pub struct RouterState {
   pub started_at: Instant,
   pub config: Arc<RwLock<AppConfig>>,
+  pub config_path: PathBuf,
   pub db: Arc<Mutex<rusqlite::Connection>>,
   pub logger: Arc<crate::logger::Logger>,
   pub port: u16,
+  pub inflight: DashMap<String, oneshot::Sender<()>>,
+  // Shared by every upstream call so connections are pooled; handlers must
+  // not build their own clients.
+  pub http_client: reqwest::Client,
+  pub metrics: Metrics,
+  // One permit per in-flight chat, sized from max_concurrent_requests.
+  pub chat_slots: Arc<Semaphore>,
+  // Bearer token every /v1 call must present; handed to the UI over IPC.
+  pub token: String,
+  // Chat attachments kept for history; see storage::save_images.
+  pub images_dir: PathBuf,
+  // Encrypted key file, used when key_storage is `file`.
+  pub key_file: PathBuf,
+  // Buckets for models with a rate_limit_rpm; checked before each upstream call.
+  pub rate_limits: RateLimiter,
+  // Chat replies keyed by the client's Idempotency-Key header.
+  pub idempotency: IdempotencyCache,
+  // OpenRouter per-token prices for cost_usd; shared with the refresh task.
+  pub prices: Arc<PriceBook>,
+  // Recent streams' deltas, for clients reconnecting with Last-Event-ID.
+  pub resume: ResumeBuffers,
 }
 
-pub async fn run_router(listener: TcpListener, state: RouterState) -> anyhow::Result<()> {
+pub fn generate_token() -> String {
+  format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+pub fn build_http_client() -> reqwest::Result<reqwest::Client> {
+  reqwest::Client::builder()
+    .connect_timeout(Duration::from_secs(10))
+    .pool_idle_timeout(Duration::from_secs(90))
+    .pool_max_idle_per_host(4)
+    .tcp_keepalive(Duration::from_secs(60))
+    .build()
+}
+
+struct InflightGuard {
+  state: Arc<RouterState>,
+  request_id: String,
+}
+
+impl Drop for InflightGuard {
+  fn drop(&mut self) {
+    self.state.inflight.remove(&self.request_id);
+  }
+}
+
+// How long in-flight chats get to record history once shutdown starts.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// A dropped sender means nobody can ask for shutdown, so keep serving.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+  if shutdown.wait_for(|stop| *stop).await.is_err() {
+    std::future::pending::<()>().await;
+  }
+}
+
+fn cancel_inflight(state: &RouterState) {
+  let ids: Vec<String> = state.inflight.iter().map(|entry| entry.key().clone()).collect();
+  for id in ids {
+    if let Some((_, cancel)) = state.inflight.remove(&id) {
+      let _ = cancel.send(());
+    }
+  }
+}
+
+pub async fn run_router(
+  listener: TcpListener,
+  state: RouterState,
+  mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
   state
     .logger
     .log("INFO", &format!("Router starting on 127.0.0.1:{}", state.port));
+  let (allowed_origins, max_request_bytes) = {
+    let config = state.config.read().await;
+    (config.allowed_origins.clone(), config.max_request_bytes)
+  };
+  let cors = CorsLayer::new()
+    .allow_origin(AllowOrigin::predicate(move |origin, _| {
+      origin
+        .to_str()
+        .is_ok_and(|origin| origin_allowed(origin, &allowed_origins))
+    }))
+    .allow_methods(Any)
+    .allow_headers(Any);
+  let state = Arc::new(state);
   let app = Router::new()
     .route("/health", get(health))
+    .route("/health/deep", get(health_deep))
     .route("/v1/models", get(models))
+    .route("/v1/models/refresh", post(refresh_models))
+    .route("/v1/config/defaults", post(set_default_models))
     .route("/v1/chat", post(chat))
+    .route("/v1/chat/ws", get(chat_ws))
+    .route("/v1/chat/cancel", post(chat_cancel))
+    .route("/v1/chat/resume/:request_id", get(chat_resume))
+    .route("/v1/chat/regenerate", post(chat_regenerate))
+    .route("/v1/chat/count-tokens", post(count_tokens))
+    .route("/v1/chat/title", post(chat_title))
+    .route("/v1/chat/debug", post(chat_debug))
+    .route("/v1/ocr", post(ocr_image))
     .route("/v1/memory/store", post(memory_store))
+    .route("/v1/memory/store-batch", post(memory_store_batch))
     .route("/v1/memory/query", post(memory_query))
+    .route("/v1/memory/update", post(memory_update))
+    .route("/v1/memory/delete", post(memory_delete))
+    .route("/v1/memory/thread", post(memory_thread))
+    .route("/v1/memory/compact", post(memory_compact))
+    .route("/v1/memory/prune", post(memory_prune))
+    .route("/v1/memory/restore", post(memory_restore))
+    .route("/v1/memory/favorite", post(memory_favorite))
+    .route("/v1/memory/trash", get(memory_trash))
+    .route("/v1/memory/models-used", get(memory_models_used))
+    .route("/v1/memory/trash/purge", post(memory_purge_trash))
+    .route("/v1/memory/images/:id", get(memory_image))
+    .route("/v1/memory/export", get(memory_export))
+    .route("/v1/memory/import", post(memory_import))
+    .route("/v1/settings/:key", get(settings_get).put(settings_put))
+    .route("/v1/presets", get(presets_list).post(preset_save))
+    .route("/v1/presets/:id", get(preset_get).delete(preset_delete))
+    .route("/v1/logs", get(logs))
+    .route("/v1/audit", get(audit))
+    .route("/metrics", get(metrics))
     .route("/debug/status", get(debug_status))
-    .layer(CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any))
-    .with_state(Arc::new(state));
+    .layer(DefaultBodyLimit::max(max_request_bytes))
+    .layer(middleware::from_fn(payload_too_large))
+    .layer(middleware::from_fn_with_state(state.clone(), require_token))
+    .layer(middleware::from_fn_with_state(state.clone(), count_errors))
+    // The default predicate leaves SSE (and tiny or image bodies) alone, so
+    // stream deltas are never held back for compression.
+    .layer(CompressionLayer::new().compress_when(DefaultPredicate::new()))
+    .layer(cors)
+    .with_state(state.clone());
 
+  listener.set_nonblocking(true)?;
   let listener = tokio::net::TcpListener::from_std(listener)?;
-  axum::serve(listener, app).await?;
+  // Cancelled streams still store what they have, then their connections close
+  // and graceful shutdown can finish.
+  let signal = {
+    let state = state.clone();
+    let mut shutdown = shutdown.clone();
+    async move {
+      shutdown_requested(&mut shutdown).await;
+      state.logger.log(
+        "INFO",
+        &format!("Router shutting down with {} chat(s) in flight", state.inflight.len()),
+      );
+      cancel_inflight(&state);
+    }
+  };
+  let server = axum::serve(listener, app).with_graceful_shutdown(signal);
+  let deadline = async {
+    shutdown_requested(&mut shutdown).await;
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
+  };
+  tokio::select! {
+    result = server => result?,
+    _ = deadline => {
+      state.logger.log("WARN", "Router shutdown grace period expired; dropping open connections");
+      return Ok(());
+    }
+  }
+  state.logger.log("INFO", "Router shutdown complete");
   Ok(())
 }
 
+// Exact scheme and host, with or without a port, so `http://localhost.evil.com`
+// never slips through a prefix match.
+fn origin_allowed(origin: &str, allowed: &[String]) -> bool {
+  allowed.iter().any(|base| match origin.strip_prefix(base.trim_end_matches('/')) {
+    Some("") => true,
+    Some(rest) => rest
+      .strip_prefix(':')
+      .is_some_and(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit())),
+    None => false,
+  })
+}
+
 async fn health(State(state): State<Arc<RouterState>>) -> Json<serde_json::Value> {
   let uptime = state.started_at.elapsed().as_millis();
   Json(serde_json::json!({
@@ -54,6 +239,110 @@ async fn health(State(state): State<Arc<RouterState>>) -> Json<serde_json::Value
   }))
 }
 
+// Short so a dead network answers quickly; /health itself never waits on this.
+const DEEP_HEALTH_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn probe_status(status: Option<StatusCode>) -> &'static str {
+  match status {
+    Some(status) if status.is_success() => "ok",
+    Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => "unauthorized",
+    _ => "unreachable",
+  }
+}
+
+async fn health_deep(State(state): State<Arc<RouterState>>) -> Response {
+  let (key, url) = {
+    let config = state.config.read().await;
+    let key = get_provider_key(&state, &config, Provider::OpenRouter);
+    (key, openrouter_url(&config.openrouter_base_url, "key"))
+  };
+  let Ok(key) = key else {
+    let body = serde_json::json!({ "openrouter": "no_key", "latency_ms": null });
+    return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+  };
+
+  // /models is public, so the key endpoint is the cheapest call that checks the key.
+  let started = Instant::now();
+  let status = state
+    .http_client
+    .get(url)
+    .bearer_auth(key)
+    .timeout(DEEP_HEALTH_TIMEOUT)
+    .send()
+    .await
+    .ok()
+    .map(|resp| resp.status());
+  let openrouter = probe_status(status);
+  if openrouter != "ok" {
+    state.logger.log("WARN", &format!("deep health check: openrouter {openrouter}"));
+  }
+  let http_status = if openrouter == "ok" {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+  let body = serde_json::json!({ "openrouter": openrouter, "latency_ms": elapsed_ms(started) });
+  (http_status, Json(body)).into_response()
+}
+
+async fn metrics(State(state): State<Arc<RouterState>>) -> Json<serde_json::Value> {
+  Json(state.metrics.snapshot(state.started_at.elapsed().as_millis()))
+}
+
+// Every handler reports failures through error_response, which tags the
+// response with its code so errors are counted in one place.
+async fn count_errors(State(state): State<Arc<RouterState>>, request: Request, next: Next) -> Response {
+  let response = next.run(request).await;
+  if let Some(ErrorCode(code)) = response.extensions().get::<ErrorCode>() {
+    state.metrics.record_error(code);
+  }
+  response
+}
+
+// Extractors reject oversized bodies with a plain-text 413; give it the usual
+// JSON error shape instead.
+async fn payload_too_large(request: Request, next: Next) -> Response {
+  let response = next.run(request).await;
+  if response.status() != StatusCode::PAYLOAD_TOO_LARGE || response.extensions().get::<ErrorCode>().is_some() {
+    return response;
+  }
+  error_response(
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "payload_too_large",
+    "Request body is larger than max_request_bytes allows.",
+  )
+}
+
+fn tokens_match(given: &[u8], expected: &[u8]) -> bool {
+  given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// Any local process can reach the port, so /v1 is gated on the token the app
+// handed its own UI. /health stays open for liveness checks; /health/deep is
+// gated too, since each call spends the OpenRouter key on an upstream request.
+async fn require_token(State(state): State<Arc<RouterState>>, request: Request, next: Next) -> Response {
+  let path = request.uri().path();
+  if !path.starts_with("/v1/") && path != "/health/deep" {
+    return next.run(request).await;
+  }
+  let header = request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "));
+  // Browsers cannot set headers on a WebSocket handshake, so that route also
+  // takes the token as `?token=`.
+  let given = match header {
+    Some(token) => token.to_string(),
+    None if request.uri().path() == CHAT_WS_PATH => query_token(request.uri().query()).unwrap_or_default(),
+    None => String::new(),
+  };
+  if !tokens_match(given.as_bytes(), state.token.as_bytes()) {
+    return error_response(StatusCode::UNAUTHORIZED, "unauthorized", "Missing or invalid router token.");
+  }
+  next.run(request).await
+}
+
 async fn models(State(state): State<Arc<RouterState>>) -> Json<ModelsResponse> {
   let config = state.config.read().await.clone();
   Json(ModelsResponse {
@@ -63,479 +352,5483 @@ async fn models(State(state): State<Arc<RouterState>>) -> Json<ModelsResponse> {
   })
 }
 
-async fn memory_store(
-  State(state): State<Arc<RouterState>>,
-  Json(req): Json<MemoryStoreRequest>,
-) -> impl IntoResponse {
-  state.logger.log("INFO", "memory_store request");
-  match storage::memory_store(&state.db, req).await {
-    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
-    Err(err) => error_response(StatusCode::BAD_REQUEST, "memory_store_failed", &err.to_string()),
+// Existing entries win so hand-edited labels and capabilities survive a refresh.
+fn merge_models(existing: &mut Vec<ModelInfo>, fetched: Vec<ModelInfo>) -> usize {
+  let mut added = 0;
+  for model in fetched {
+    if !existing.iter().any(|m| m.id == model.id) {
+      existing.push(model);
+      added += 1;
+    }
   }
+  added
 }
 
-async fn memory_query(
-  State(state): State<Arc<RouterState>>,
-  Json(req): Json<MemoryQueryRequest>,
-) -> impl IntoResponse {
-  state.logger.log("INFO", &format!("memory_query: {}", req.query));
-  match storage::memory_query(&state.db, req).await {
-    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
-    Err(err) => error_response(StatusCode::BAD_REQUEST, "memory_query_failed", &err.to_string()),
+fn parse_openrouter_models(body: &serde_json::Value) -> Vec<ModelInfo> {
+  let Some(entries) = body["data"].as_array() else {
+    return Vec::new();
+  };
+  entries
+    .iter()
+    .filter_map(|entry| {
+      let id = entry["id"].as_str()?;
+      let architecture = &entry["architecture"];
+      let takes_images = architecture["input_modalities"]
+        .as_array()
+        .is_some_and(|m| m.iter().any(|v| v == "image"))
+        || architecture["modality"]
+          .as_str()
+          .and_then(|m| m.split("->").next())
+          .is_some_and(|input| input.contains("image"));
+      Some(ModelInfo {
+        id: format!("openrouter:{id}"),
+        label: entry["name"].as_str().unwrap_or(id).to_string(),
+        capability: if takes_images { "vision" } else { "text" }.to_string(),
+        rate_limit_rpm: None,
+      })
+    })
+    .collect()
+}
+
+// The models list also carries pricing; callers parse what they need.
+async fn fetch_openrouter_catalog(
+  client: &reqwest::Client,
+  base_url: &str,
+  key: &str,
+  timeout: Duration,
+) -> Result<serde_json::Value, String> {
+  let resp = client
+    .get(openrouter_url(base_url, "models"))
+    .bearer_auth(key)
+    .timeout(timeout)
+    .send()
+    .await
+    .map_err(|err| err.to_string())?;
+  if !resp.status().is_success() {
+    return Err(format!("OpenRouter error ({})", resp.status()));
   }
+  resp.json::<serde_json::Value>().await.map_err(|err| err.to_string())
 }
 
-async fn chat(
-  State(state): State<Arc<RouterState>>,
-  Json(req): Json<ChatRequest>,
-) -> impl IntoResponse {
-  state.logger.log(
-    "INFO",
-    &format!(
-      "chat request: messages={}, image={}, stream={}",
-      req.messages.len(),
-      req.image.is_some(),
-      req.stream.unwrap_or(true)
-    ),
-  );
-  let config = state.config.read().await.clone();
-  let model_id = match resolve_model(&req, &config) {
-    Ok(m) => m,
-    Err(msg) => return error_response(StatusCode::BAD_REQUEST, "model_missing", &msg),
-  };
+// Loads prices in the background the first time an OpenRouter chat needs
+// them (and again once stale), so no chat waits on the models endpoint.
+fn prefetch_prices(state: &RouterState, base_url: &str, key: &str, timeout: Duration) {
+  if !state.prices.claim_refresh() {
+    return;
+  }
+  let client = state.http_client.clone();
+  let prices = state.prices.clone();
+  let logger = state.logger.clone();
+  let (base_url, key) = (base_url.to_string(), key.to_string());
+  tokio::spawn(async move {
+    match fetch_openrouter_catalog(&client, &base_url, &key, timeout).await {
+      Ok(body) => prices.replace(pricing::parse_prices(&body)),
+      Err(err) => logger.log("WARN", &format!("model prices not loaded: {err}")),
+    }
+  });
+}
 
-  let (provider, model) = split_provider(&model_id);
-  if provider != "openrouter" {
-    state.logger.log("WARN", &format!("unsupported provider: {}", provider));
-    return error_response(
-      StatusCode::BAD_REQUEST,
-      "provider_unsupported",
-      "Only openrouter is supported in MVP.",
-    );
+// Unset without usage or a known price; local models are free.
+fn cost_usd(state: &RouterState, model_id: &str, usage: Option<&Usage>) -> Option<f64> {
+  let usage = usage?;
+  if split_provider(model_id).0.is_local() {
+    return Some(0.0);
   }
+  state.prices.cost(model_id, usage)
+}
 
-  let key = match get_openrouter_key() {
+async fn refresh_models(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  state.logger.log("INFO", "models refresh request");
+  let key = get_provider_key(&state, &*state.config.read().await, Provider::OpenRouter);
+  let key = match key {
     Ok(k) => k,
-    Err(msg) => return error_response(StatusCode::BAD_REQUEST, "key_missing", &msg),
+    Err(msg) => return error_response(StatusCode::UNAUTHORIZED, "key_missing", &msg),
   };
 
-  let stream = req.stream.unwrap_or(true);
-  if stream {
-    match stream_openrouter(state, req, &model_id, &model, &key).await {
-      Ok(sse) => sse.into_response(),
-      Err((status, message)) => error_response(status, "openrouter_error", &message),
+  // A failed fetch leaves the configured list untouched.
+  let (base_url, timeout) = {
+    let config = state.config.read().await;
+    (config.openrouter_base_url.clone(), Duration::from_secs(config.request_timeout_secs))
+  };
+  let fetched = match fetch_openrouter_catalog(&state.http_client, &base_url, &key, timeout).await {
+    Ok(body) => {
+      state.prices.replace(pricing::parse_prices(&body));
+      parse_openrouter_models(&body)
     }
-  } else {
-    match complete_openrouter(state, req, &model_id, &model, &key).await {
-      Ok(res) => (StatusCode::OK, Json(res)).into_response(),
-      Err((status, message)) => error_response(status, "openrouter_error", &message),
+    Err(err) => {
+      state.logger.log("WARN", &format!("models refresh failed: {}", err));
+      let config = state.config.read().await;
+      return Json(serde_json::json!({
+        "refreshed": false,
+        "added": 0,
+        "error": err,
+        "models": config.models
+      }))
+      .into_response();
     }
-  }
-}
+  };
 
-fn error_response(status: StatusCode, code: &str, message: &str) -> Response {
-  let body = Json(serde_json::json!({ "error": message, "code": code }));
-  (status, body).into_response()
+  let mut config = state.config.write().await;
+  let mut updated = config.clone();
+  let added = merge_models(&mut updated.models, fetched);
+  if let Err(err) = save_config(&state.config_path, &updated) {
+    return error_response(StatusCode::INTERNAL_SERVER_ERROR, "config_save_failed", &err.to_string());
+  }
+  *config = updated;
+  Json(serde_json::json!({
+    "refreshed": true,
+    "added": added,
+    "models": config.models
+  }))
+  .into_response()
 }
 
-fn split_provider(model_id: &str) -> (String, String) {
-  const PREFIX: &str = "openrouter:";
-  if model_id.starts_with(PREFIX) {
-    ("openrouter".to_string(), model_id[PREFIX.len()..].to_string())
-  } else {
-    ("openrouter".to_string(), model_id.to_string())
+// A default must be a listed model or an id with an explicit provider prefix
+// (the same ids a model override accepts), and must not be blocked.
+fn default_model_error(config: &AppConfig, field: &str, model_id: &str) -> Option<String> {
+  if model_id.is_empty() {
+    return Some(format!("{field} must not be empty"));
   }
+  let listed = config.models.iter().find(|model| model.id == model_id);
+  let prefixed = Provider::ALL.iter().any(|provider| {
+    model_id
+      .strip_prefix(provider.name())
+      .and_then(|rest| rest.strip_prefix(':'))
+      .is_some_and(|model| !model.is_empty())
+  });
+  if listed.is_none() && !prefixed {
+    return Some(format!("{field}: unknown model {model_id}"));
+  }
+  if field == "vision_default_model" && listed.is_some_and(|model| model.capability != "vision") {
+    return Some(format!("{field}: {model_id} does not accept images"));
+  }
+  if !config.model_allowed(&ChatTarget::new(model_id, 0).canonical_id()) {
+    return Some(format!("{field}: {model_id} is not allowed by the model policy"));
+  }
+  None
 }
 
-fn resolve_model(req: &ChatRequest, config: &AppConfig) -> Result<String, String> {
-  if let Some(override_id) = req.model_override.as_ref() {
-    if !override_id.trim().is_empty() {
-      return Ok(override_id.trim().to_string());
+async fn set_default_models(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<DefaultModelsRequest>,
+) -> impl IntoResponse {
+  let trimmed = |id: Option<String>| id.map(|id| id.trim().to_string());
+  let (text, vision) = (trimmed(req.text_default_model), trimmed(req.vision_default_model));
+  {
+    let config = state.config.read().await;
+    let errors: Vec<String> = [("text_default_model", &text), ("vision_default_model", &vision)]
+      .into_iter()
+      .filter_map(|(field, model_id)| default_model_error(&config, field, model_id.as_deref()?))
+      .collect();
+    if !errors.is_empty() {
+      return error_response(StatusCode::BAD_REQUEST, "invalid_default_model", &errors.join("; "));
     }
   }
 
-  if req.image.is_some() {
-    if config.vision_default_model.trim().is_empty() {
-      return Err("Vision default model not set.".to_string());
-    }
-    return Ok(config.vision_default_model.clone());
+  // Applied to the config as it is now, so a change made since validation is kept.
+  let mut config = state.config.write().await;
+  let mut updated = config.clone();
+  if let Some(text) = text {
+    updated.text_default_model = text;
   }
-
-  if config.text_default_model.trim().is_empty() {
-    return Err("Text default model not set.".to_string());
+  if let Some(vision) = vision {
+    updated.vision_default_model = vision;
   }
-  Ok(config.text_default_model.clone())
-}
-
-fn get_openrouter_key() -> Result<String, String> {
-  let entry = keyring::Entry::new("HaloRouter", "openrouter").map_err(|e| e.to_string())?;
-  let key = entry
-    .get_password()
-    .map_err(|_| "OpenRouter key missing. Set it in Settings.".to_string())?;
-  if key.trim().is_empty() {
-    Err("OpenRouter key missing. Set it in Settings.".to_string())
-  } else {
-    Ok(key)
+  if let Err(err) = save_config(&state.config_path, &updated) {
+    return error_response(StatusCode::INTERNAL_SERVER_ERROR, "config_save_failed", &err.to_string());
   }
-}
-
-async fn debug_status(State(state): State<Arc<RouterState>>) -> Json<serde_json::Value> {
-  let config = state.config.read().await.clone();
-  let key_set = keyring::Entry::new("HaloRouter", "openrouter")
-    .and_then(|e| e.get_password())
-    .map(|p| !p.trim().is_empty())
-    .unwrap_or(false);
-
+  state.logger.log(
+    "INFO",
+    &format!("default models: text={} vision={}", updated.text_default_model, updated.vision_default_model),
+  );
+  *config = updated;
   Json(serde_json::json!({
-    "status": "ok",
-    "port": state.port,
-    "key_set": key_set,
     "text_default": config.text_default_model,
-    "vision_default": config.vision_default_model,
-    "models_count": config.models.len()
+    "vision_default": config.vision_default_model
   }))
+  .into_response()
 }
 
-#[derive(serde::Serialize)]
-struct OpenRouterMessage {
-  role: String,
-  content: serde_json::Value,
-}
-
-#[derive(serde::Serialize)]
-struct OpenRouterChatRequest {
-  model: String,
-  messages: Vec<OpenRouterMessage>,
-  stream: bool,
+async fn memory_store(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryStoreRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", "memory_store request");
+  let text = embedding_text(&req.r#type, &req.payload);
+  match storage::memory_store(&state.db, req).await {
+    Ok(res) => {
+      if let Some((kind, text)) = text {
+        embed_in_background(&state, kind, res.id.clone(), text).await;
+      }
+      (StatusCode::OK, Json(res)).into_response()
+    }
+    Err(err) => storage_error_response("memory_store_failed", err),
+  }
 }
 
-fn to_openrouter_messages(messages: &[Message], image: Option<&ImageData>) -> Vec<OpenRouterMessage> {
-  let mut result = Vec::new();
-  let mut image_attached = false;
-  let last_user_index = messages.iter().rposition(|m| m.role == "user");
-
-  for (idx, msg) in messages.iter().enumerate() {
-    if Some(idx) == last_user_index && image.is_some() && !image_attached {
-      let img = image.unwrap();
-      let url = format!("data:{};base64,{}", img.mime, img.base64);
-      let content = serde_json::json!([
-        { "type": "text", "text": msg.content },
-        { "type": "image_url", "image_url": { "url": url } }
-      ]);
-      result.push(OpenRouterMessage {
-        role: msg.role.clone(),
-        content,
-      });
-      image_attached = true;
-    } else {
-      result.push(OpenRouterMessage {
-        role: msg.role.clone(),
-        content: serde_json::json!(msg.content),
-      });
+async fn memory_store_batch(
+  State(state): State<Arc<RouterState>>,
+  Json(items): Json<Vec<MemoryStoreRequest>>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_store_batch: {} items", items.len()));
+  let texts: Vec<_> = items.iter().map(|req| embedding_text(&req.r#type, &req.payload)).collect();
+  match storage::memory_store_batch(&state.db, items).await {
+    Ok(res) => {
+      let embedded = res.ids.iter().zip(texts);
+      let items = embedded.filter_map(|(id, text)| text.map(|(kind, text)| (kind, id.clone(), text)));
+      embed_all_in_background(&state, items.collect()).await;
+      (StatusCode::OK, Json(res)).into_response()
     }
+    Err(err) => storage_error_response("memory_store_batch_failed", err),
   }
+}
 
-  if image.is_some() && !image_attached {
-    let img = image.unwrap();
-    let url = format!("data:{};base64,{}", img.mime, img.base64);
-    let content = serde_json::json!([
-      { "type": "text", "text": "" },
-      { "type": "image_url", "image_url": { "url": url } }
-    ]);
-    result.push(OpenRouterMessage {
-      role: "user".to_string(),
-      content,
-    });
+// What gets embedded for an item that semantic search covers.
+fn embedding_text(kind: &str, payload: &serde_json::Value) -> Option<(&'static str, String)> {
+  match kind {
+    "pinned" => Some(("pinned", payload.get("text")?.as_str()?.to_string())),
+    "history" => match serde_json::from_value::<Vec<Message>>(payload.clone()) {
+      Ok(messages) => Some(("history", history_text(&messages, ""))),
+      Err(_) => Some(("history", payload.to_string())),
+    },
+    _ => None,
   }
-
-  result
 }
 
-async fn stream_openrouter(
-  state: Arc<RouterState>,
-  req: ChatRequest,
-  model_id: &str,
-  model: &str,
-  key: &str,
-) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, std::convert::Infallible>>>, (StatusCode, String)> {
-  let req_clone = req.clone();
-  let messages = to_openrouter_messages(&req.messages, req.image.as_ref());
+fn history_text(messages: &[Message], assistant: &str) -> String {
+  let mut parts: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+  parts.push(assistant);
+  parts.retain(|part| !part.trim().is_empty());
+  parts.join("\n")
+}
 
-  let client = reqwest::Client::new();
-  let mut headers = HeaderMap::new();
-  headers.insert(
-    AUTHORIZATION,
-    HeaderValue::from_str(&format!("Bearer {}", key))
-      .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
-  );
-  headers.insert("HTTP-Referer", HeaderValue::from_static("http://localhost"));
-  headers.insert("X-Title", HeaderValue::from_static("HaloDesk"));
+// What an embeddings call needs, when semantic search is set up.
+struct Embedder {
+  url: String,
+  model: String,
+  key: String,
+  attribution: HeaderMap,
+}
 
-  let payload = OpenRouterChatRequest {
-    model: model.to_string(),
-    messages,
-    stream: true,
+async fn embedder(state: &RouterState) -> Option<Embedder> {
+  let (url, model, key, attribution) = {
+    let config = state.config.read().await;
+    let key = get_provider_key(state, &config, Provider::OpenRouter);
+    let url = openrouter_url(&config.openrouter_base_url, "embeddings");
+    (url, config.embedding_model.clone(), key, openrouter_attribution(&config))
   };
+  let model = model.filter(|model| !model.trim().is_empty())?;
+  let key = key.ok()?;
+  Some(Embedder {
+    url,
+    model,
+    key,
+    attribution,
+  })
+}
 
-  let resp = client
-    .post("https://openrouter.ai/api/v1/chat/completions")
-    .headers(headers)
-    .json(&payload)
-    .send()
-    .await
-    .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+async fn embed_in_background(state: &RouterState, kind: &'static str, id: String, text: String) {
+  embed_all_in_background(state, vec![(kind, id, text)]).await;
+}
 
-  if !resp.status().is_success() {
-    let upstream_status = resp.status();
-    let text = resp
-      .text()
-      .await
-      .unwrap_or_else(|_| "OpenRouter request failed.".to_string());
-    let status = StatusCode::BAD_GATEWAY;
-    let message = format!("OpenRouter error ({}): {}", upstream_status, text);
-    state.logger.log("ERROR", &message);
-    return Err((status, message));
+// Stores never wait on the embeddings call; an item that fails to embed is
+// still found by lexical search. One task sends the items a batch at a time,
+// so a large store makes a few requests in turn rather than one per item.
+async fn embed_all_in_background(state: &RouterState, items: Vec<(&'static str, String, String)>) {
+  if items.is_empty() {
+    return;
   }
-
-  let mut bytes_stream = resp.bytes_stream();
-  let model_id = model_id.to_string();
-
-  let stream = stream! {
-    let meta = serde_json::json!({ "model": model_id, "provider": "openrouter" }).to_string();
-    yield Ok(Event::default().event("meta").data(meta));
-
-    let mut buffer = String::new();
-    let mut full = String::new();
-    let mut finish_reason = "stop".to_string();
-
-    while let Some(chunk) = bytes_stream.next().await {
-      let chunk = match chunk {
-        Ok(c) => c,
+  let Some(Embedder {
+    url,
+    model,
+    key,
+    attribution,
+  }) = embedder(state).await
+  else {
+    return;
+  };
+  let (client, db, logger) = (state.http_client.clone(), state.db.clone(), state.logger.clone());
+  tokio::spawn(async move {
+    for batch in items.chunks(embeddings::MAX_BATCH_INPUTS) {
+      let texts: Vec<&str> = batch.iter().map(|(_, _, text)| text.as_str()).collect();
+      let vectors = match embeddings::embed_batch(&client, &url, &key, &model, attribution.clone(), &texts).await {
+        Ok(vectors) => vectors,
         Err(err) => {
-          let done = serde_json::json!({ "finish_reason": "error", "error": err.to_string() }).to_string();
-          yield Ok(Event::default().event("done").data(done));
-          return;
+          let what = match batch {
+            [(kind, id, _)] => format!("{kind} {id}"),
+            _ => format!("{} items", batch.len()),
+          };
+          logger.log("WARN", &format!("{what} not embedded: {err}"));
+          continue;
         }
       };
-
-      buffer.push_str(&String::from_utf8_lossy(&chunk));
-      loop {
-        let boundary = buffer.find("\n\n");
-        if boundary.is_none() {
-          break;
-        }
-        let boundary = boundary.unwrap();
-        let block = buffer[..boundary].to_string();
-        buffer = buffer[boundary + 2..].to_string();
-
-        for line in block.lines() {
-          if let Some(data) = line.strip_prefix("data:") {
-            let data = data.trim();
-            if data == "[DONE]" {
-              let _ = storage::store_history(&state.db, &req_clone.messages, &full, &model_id, "openrouter").await;
-              let done = serde_json::json!({ "finish_reason": finish_reason }).to_string();
-              yield Ok(Event::default().event("done").data(done));
-              return;
-            }
-
-            if let Ok(value) = serde_json::from_str::<serde_json::Value>(data) {
-              if let Some(reason) = value["choices"][0]["finish_reason"].as_str() {
-                finish_reason = reason.to_string();
-              }
-
-              if let Some(delta) = value["choices"][0]["delta"]["content"].as_str() {
-                if !delta.is_empty() {
-                  full.push_str(delta);
-                  let payload = serde_json::json!({ "text": delta }).to_string();
-                  yield Ok(Event::default().event("delta").data(payload));
-                }
-              }
-            }
-          }
+      for ((kind, id, _), vector) in batch.iter().zip(vectors) {
+        if let Err(err) = storage::set_embedding(&db, kind, id, &vector).await {
+          logger.log("WARN", &format!("{kind} {id} not embedded: {err}"));
         }
       }
     }
-
-    let _ = storage::store_history(&state.db, &req_clone.messages, &full, &model_id, "openrouter").await;
-    let done = serde_json::json!({ "finish_reason": finish_reason }).to_string();
-    yield Ok(Event::default().event("done").data(done));
-  };
-
-  Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(15))))
+  });
 }
 
-async fn complete_openrouter(
-  state: Arc<RouterState>,
-  req: ChatRequest,
-  model_id: &str,
-  model: &str,
-  key: &str,
-) -> Result<serde_json::Value, (StatusCode, String)> {
-  let messages = to_openrouter_messages(&req.messages, req.image.as_ref());
-
-  let client = reqwest::Client::new();
-  let mut headers = HeaderMap::new();
-  headers.insert(
-    AUTHORIZATION,
-    HeaderValue::from_str(&format!("Bearer {}", key))
-      .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
-  );
-  headers.insert("HTTP-Referer", HeaderValue::from_static("http://localhost"));
-  headers.insert("X-Title", HeaderValue::from_static("HaloDesk"));
-
-  let payload = OpenRouterChatRequest {
-    model: model.to_string(),
-    messages,
-    stream: false,
-  };
-
-  let resp = client
-    .post("https://openrouter.ai/api/v1/chat/completions")
-    .headers(headers)
-    .json(&payload)
-    .send()
-    .await
-    .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
-
-  if !resp.status().is_success() {
-    let upstream_status = resp.status();
-    let text = resp
-      .text()
-      .await
-      .unwrap_or_else(|_| "OpenRouter request failed.".to_string());
-    let status = StatusCode::BAD_GATEWAY;
-    let message = format!("OpenRouter error ({}): {}", upstream_status, text);
-    state.logger.log("ERROR", &message);
-    return Err((status, message));
+async fn memory_query(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryQueryRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_query: {}", req.query));
+  if req.mode == SearchMode::Semantic {
+    match embed_query(&state, &req.query).await {
+      Ok(vector) => {
+        return match storage::memory_semantic(&state.db, req, &vector).await {
+          Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+          Err(err) => storage_error_response("memory_query_failed", err),
+        };
+      }
+      Err(err) => state.logger.log("WARN", &format!("semantic search unavailable, using lexical: {err}")),
+    }
   }
+  match storage::memory_query(&state.db, req).await {
+    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+    Err(err) => storage_error_response("memory_query_failed", err),
+  }
+}
 
-  let json_body = resp
-    .json::<serde_json::Value>()
-    .await
-    .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
-  let content = json_body["choices"][0]["message"]["content"]
-    .as_str()
-    .unwrap_or("")
-    .to_string();
-
-  storage::store_history(&state.db, &req.messages, &content, model_id, "openrouter")
+async fn embed_query(state: &RouterState, query: &str) -> Result<Vec<f32>, String> {
+  let embedder = embedder(state)
     .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-
-  Ok(serde_json::json!({
-    "text": content,
-    "model": model_id,
-    "provider": "openrouter"
-  }))
+    .ok_or("no embedding_model configured or no OpenRouter key")?;
+  let client = &state.http_client;
+  embeddings::embed(client, &embedder.url, &embedder.key, &embedder.model, embedder.attribution, query).await
 }
 
-#[cfg(test)]
+async fn memory_update(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryUpdateRequest>,
+) -> impl IntoResponse {
+  state
+    .logger
+    .log("INFO", &format!("memory_update: {} {}", req.r#type, req.id));
+  let text = embedding_text(&req.r#type, &req.payload);
+  match storage::memory_update(&state.db, req).await {
+    Ok(Some(res)) => {
+      if let Some((kind, text)) = text {
+        embed_in_background(&state, kind, res.id.clone(), text).await;
+      }
+      (StatusCode::OK, Json(res)).into_response()
+    }
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", "No memory item with that id."),
+    Err(err) => storage_error_response("memory_update_failed", err),
+  }
+}
+
+async fn memory_delete(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryDeleteRequest>,
+) -> impl IntoResponse {
+  state
+    .logger
+    .log("INFO", &format!("memory_delete: {} {}", req.r#type, req.id));
+  match storage::memory_delete(&state.db, req).await {
+    Ok(true) => (StatusCode::OK, Json(serde_json::json!({ "deleted": true }))).into_response(),
+    Ok(false) => error_response(StatusCode::NOT_FOUND, "not_found", "No memory item with that id."),
+    Err(err) => storage_error_response("memory_delete_failed", err),
+  }
+}
+
+async fn memory_thread(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryThreadRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_thread: {}", req.thread_id));
+  match storage::memory_thread(&state.db, &req.thread_id).await {
+    Ok(items) => {
+      let res = MemoryThreadResponse {
+        thread_id: req.thread_id,
+        items,
+      };
+      (StatusCode::OK, Json(res)).into_response()
+    }
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_thread_failed", &err.to_string()),
+  }
+}
+
+async fn memory_restore(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryRestoreRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_restore: {}", req.id));
+  match storage::memory_restore(&state.db, &req.id).await {
+    Ok(true) => (StatusCode::OK, Json(serde_json::json!({ "restored": true }))).into_response(),
+    Ok(false) => error_response(StatusCode::NOT_FOUND, "not_found", "No item in the trash with that id."),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_restore_failed", &err.to_string()),
+  }
+}
+
+async fn memory_favorite(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryFavoriteRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_favorite: {}", req.id));
+  match storage::memory_favorite(&state.db, &req.id, req.favorite).await {
+    Ok(Some(favorite)) => {
+      let body = serde_json::json!({ "id": req.id, "favorite": favorite });
+      (StatusCode::OK, Json(body)).into_response()
+    }
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", "No history item with that id."),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_favorite_failed", &err.to_string()),
+  }
+}
+
+async fn memory_trash(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  match storage::memory_trash(&state.db).await {
+    Ok(items) => (StatusCode::OK, Json(MemoryTrashResponse { items })).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_trash_failed", &err.to_string()),
+  }
+}
+
+async fn memory_models_used(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  match storage::models_used(&state.db).await {
+    Ok(items) => (StatusCode::OK, Json(ModelsUsedResponse { items })).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "models_used_failed", &err.to_string()),
+  }
+}
+
+async fn memory_purge_trash(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryPurgeRequest>,
+) -> impl IntoResponse {
+  match storage::purge_trash(&state.db, req.older_than_days).await {
+    Ok(purged) => {
+      state.logger.log("INFO", &format!("memory_purge_trash: {} history rows deleted", purged));
+      sweep_images(&state).await;
+      (StatusCode::OK, Json(serde_json::json!({ "deleted": purged }))).into_response()
+    }
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_purge_failed", &err.to_string()),
+  }
+}
+
+async fn memory_compact(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  state.logger.log("INFO", "memory_compact");
+  match storage::compact(&state.db).await {
+    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_compact_failed", &err.to_string()),
+  }
+}
+
+async fn memory_prune(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  let (max_age_days, max_rows) = {
+    let config = state.config.read().await;
+    (config.history_retention_days, config.max_history_rows)
+  };
+  match storage::prune_history(&state.db, max_age_days, max_rows).await {
+    Ok(deleted) => {
+      state.logger.log("INFO", &format!("memory_prune: {} history rows deleted", deleted));
+      sweep_images(&state).await;
+      (StatusCode::OK, Json(serde_json::json!({ "deleted": deleted }))).into_response()
+    }
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_prune_failed", &err.to_string()),
+  }
+}
+
+// Cleanup only; the rows are already gone, so a failure here is just logged.
+async fn sweep_images(state: &RouterState) {
+  match storage::sweep_images(&state.db, &state.images_dir).await {
+    Ok(0) => {}
+    Ok(removed) => state.logger.log("INFO", &format!("removed {removed} unreferenced images")),
+    Err(err) => state.logger.log("WARN", &format!("image sweep failed: {err}")),
+  }
+}
+
+async fn memory_image(State(state): State<Arc<RouterState>>, Path(id): Path<String>) -> impl IntoResponse {
+  match storage::load_image(&state.images_dir, &id) {
+    Ok(Some(image)) => (StatusCode::OK, Json(image)).into_response(),
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", "No stored image with that id."),
+    Err(err) => storage_error_response("memory_image_failed", err),
+  }
+}
+
+async fn memory_export(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  match storage::memory_export(&state.db).await {
+    Ok(res) => {
+      state.logger.log("INFO", &format!("memory_export: {} history rows", res.history.len()));
+      (StatusCode::OK, Json(res)).into_response()
+    }
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "memory_export_failed", &err.to_string()),
+  }
+}
+
+async fn memory_import(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<MemoryImportRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("memory_import: mode={:?}", req.mode));
+  match storage::memory_import(&state.db, req).await {
+    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+    Err(err) => storage_error_response("memory_import_failed", err),
+  }
+}
+
+async fn settings_get(State(state): State<Arc<RouterState>>, Path(key): Path<String>) -> impl IntoResponse {
+  match storage::settings_get(&state.db, &key).await {
+    Ok(Some(value)) => (StatusCode::OK, Json(serde_json::json!({ "key": key, "value": value }))).into_response(),
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", "No setting with that key."),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "settings_get_failed", &err.to_string()),
+  }
+}
+
+async fn settings_put(
+  State(state): State<Arc<RouterState>>,
+  Path(key): Path<String>,
+  Json(req): Json<SettingsSetRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("settings_set: {}", key));
+  match storage::settings_set(&state.db, &key, &req.value).await {
+    Ok(()) => (StatusCode::OK, Json(serde_json::json!({ "key": key, "value": req.value }))).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "settings_set_failed", &err.to_string()),
+  }
+}
+
+async fn presets_list(State(state): State<Arc<RouterState>>) -> impl IntoResponse {
+  match storage::list_presets(&state.db).await {
+    Ok(items) => (StatusCode::OK, Json(PresetsResponse { items })).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "presets_list_failed", &err.to_string()),
+  }
+}
+
+async fn preset_get(State(state): State<Arc<RouterState>>, Path(id): Path<String>) -> impl IntoResponse {
+  match storage::get_preset(&state.db, &id).await {
+    Ok(Some(preset)) => (StatusCode::OK, Json(preset)).into_response(),
+    Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", "No preset with that id."),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "preset_get_failed", &err.to_string()),
+  }
+}
+
+async fn preset_save(State(state): State<Arc<RouterState>>, Json(req): Json<PresetSaveRequest>) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("preset_save: {}", req.id.as_deref().unwrap_or("(new)")));
+  match storage::save_preset(&state.db, req).await {
+    Ok(preset) => (StatusCode::OK, Json(preset)).into_response(),
+    Err(err) => storage_error_response("preset_save_failed", err),
+  }
+}
+
+async fn preset_delete(State(state): State<Arc<RouterState>>, Path(id): Path<String>) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("preset_delete: {}", id));
+  match storage::delete_preset(&state.db, &id).await {
+    Ok(true) => (StatusCode::OK, Json(serde_json::json!({ "deleted": true }))).into_response(),
+    Ok(false) => error_response(StatusCode::NOT_FOUND, "not_found", "No preset with that id."),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "preset_delete_failed", &err.to_string()),
+  }
+}
+
+// A chat reply in transport-neutral form, rendered as SSE or JSON by `chat`
+// and as WebSocket messages by `chat_ws`.
+type ChatFrames = Pin<Box<dyn tokio_stream::Stream<Item = ChatFrame> + Send>>;
+
+enum ChatReply {
+  Frames(ChatFrames),
+  Complete(serde_json::Value),
+}
+
+impl ChatReply {
+  // Reports context trimming in the meta frame, or the body of a complete reply.
+  fn with_trimmed(self, trimmed: usize) -> Self {
+    match self {
+      ChatReply::Frames(frames) => ChatReply::Frames(Box::pin(frames.map(move |mut frame| {
+        if frame.event == "meta" {
+          frame.data["trimmed"] = trimmed.into();
+        }
+        frame
+      }))),
+      ChatReply::Complete(mut body) => {
+        body["trimmed"] = trimmed.into();
+        ChatReply::Complete(body)
+      }
+    }
+  }
+}
+
+async fn chat(
+  State(state): State<Arc<RouterState>>,
+  headers: HeaderMap,
+  Json(req): Json<ChatRequest>,
+) -> impl IntoResponse {
+  let key = match idempotency_key(&headers) {
+    Ok(key) => key,
+    Err(msg) => return error_response(StatusCode::BAD_REQUEST, "bad_idempotency_key", msg),
+  };
+  let keepalive_secs = state.config.read().await.sse_keepalive_secs;
+  let claim = match key {
+    Some(key) => match state.idempotency.claim(&key, request_hash(&req)) {
+      Claim::Reserved => Some(IdempotencyClaim {
+        state: state.clone(),
+        key,
+        completed: false,
+      }),
+      Claim::Replay(reply) => {
+        state.logger.log("INFO", "chat replayed from an earlier request with the same Idempotency-Key");
+        return replay_response(&reply, req.stream.unwrap_or(true), keepalive_secs);
+      }
+      Claim::InFlight => {
+        let msg = "A request with this Idempotency-Key is still running.";
+        return error_response(StatusCode::CONFLICT, "idempotency_key_in_use", msg);
+      }
+      Claim::Mismatch => {
+        let msg = "This Idempotency-Key was already used with a different request.";
+        return error_response(StatusCode::UNPROCESSABLE_ENTITY, "idempotency_key_reused", msg);
+      }
+    },
+    None => None,
+  };
+  match run_chat(state.clone(), req).await {
+    Ok(ChatReply::Frames(frames)) => match claim {
+      Some(claim) => sse_response(Box::pin(remember_frames(claim, frames)), keepalive_secs),
+      None => sse_response(frames, keepalive_secs),
+    },
+    Ok(ChatReply::Complete(body)) => {
+      if let Some(mut claim) = claim {
+        claim.complete(body.clone());
+      }
+      (StatusCode::OK, Json(body)).into_response()
+    }
+    Err(resp) => resp,
+  }
+}
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, &'static str> {
+  let Some(value) = headers.get(IDEMPOTENCY_KEY) else {
+    return Ok(None);
+  };
+  let key = value.to_str().map_err(|_| "Idempotency-Key must be visible ASCII.")?.trim();
+  if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+    return Err("Idempotency-Key must be 1 to 255 characters.");
+  }
+  Ok(Some(key.to_string()))
+}
+
+// `stream` only picks the replay's form, so it is left out of the hash.
+fn request_hash(req: &ChatRequest) -> [u8; 32] {
+  let req = ChatRequest {
+    stream: None,
+    ..req.clone()
+  };
+  Sha256::digest(serde_json::to_vec(&req).unwrap_or_default()).into()
+}
+
+// A reserved Idempotency-Key. Dropped before completing (the chat failed, was
+// cancelled or the client went away), it frees the key for a retry.
+struct IdempotencyClaim {
+  state: Arc<RouterState>,
+  key: String,
+  completed: bool,
+}
+
+impl IdempotencyClaim {
+  fn complete(&mut self, reply: serde_json::Value) {
+    self.state.idempotency.complete(&self.key, reply);
+    self.completed = true;
+  }
+}
+
+impl Drop for IdempotencyClaim {
+  fn drop(&mut self) {
+    if !self.completed {
+      self.state.idempotency.release(&self.key);
+    }
+  }
+}
+
+// Passes frames through and, if the stream finishes normally, keeps the reply
+// under the claimed key in the same shape complete_chat returns.
+fn remember_frames(mut claim: IdempotencyClaim, mut frames: ChatFrames) -> impl tokio_stream::Stream<Item = ChatFrame> {
+  stream! {
+    let mut reply = serde_json::json!({ "tool_calls": [], "images": [], "usage": null });
+    while let Some(frame) = frames.next().await {
+      match frame.event {
+        "meta" => {
+          for field in ["model", "provider", "fell_back", "chain_index", "thread_id", "stored", "trimmed"] {
+            reply[field] = frame.data[field].clone();
+          }
+        }
+        "tool_call" | "image" => {
+          let field = if frame.event == "image" { "images" } else { "tool_calls" };
+          if let Some(items) = reply[field].as_array_mut() {
+            items.push(frame.data.clone());
+          }
+        }
+        "usage" => reply["usage"] = frame.data.clone(),
+        "done" if !matches!(frame.data["finish_reason"].as_str(), Some("cancelled" | "timeout" | "error")) => {
+          for field in ["text", "latency_ms", "cost_usd"] {
+            reply[field] = frame.data[field].clone();
+          }
+          claim.complete(reply.clone());
+        }
+        _ => {}
+      }
+      yield frame;
+    }
+  }
+}
+
+// A stored reply in whichever form this request asked for; streams get the
+// whole text as a single delta.
+fn replay_response(reply: &serde_json::Value, stream: bool, keepalive_secs: u64) -> Response {
+  let mut response = if stream {
+    let mut meta = serde_json::json!({ "request_id": uuid::Uuid::new_v4().to_string() });
+    for field in ["model", "provider", "fell_back", "chain_index", "thread_id", "stored", "trimmed"] {
+      meta[field] = reply[field].clone();
+    }
+    let text = reply["text"].as_str().unwrap_or_default();
+    let mut frames = vec![
+      ChatFrame::new("meta", meta),
+      ChatFrame::new("delta", serde_json::json!({ "text": text })).with_id(1),
+    ];
+    for image in reply["images"].as_array().into_iter().flatten() {
+      frames.push(ChatFrame::new("image", image.clone()));
+    }
+    for call in reply["tool_calls"].as_array().into_iter().flatten() {
+      frames.push(ChatFrame::new("tool_call", call.clone()));
+    }
+    if !reply["usage"].is_null() {
+      frames.push(ChatFrame::new("usage", reply["usage"].clone()));
+    }
+    let latency_ms = reply["latency_ms"].as_i64().unwrap_or_default();
+    let mut done = done_payload("stop", latency_ms, text);
+    done["cost_usd"] = reply["cost_usd"].clone();
+    frames.push(ChatFrame::new("done", done));
+    sse_response(Box::pin(tokio_stream::iter(frames)), keepalive_secs)
+  } else {
+    (StatusCode::OK, Json(reply.clone())).into_response()
+  };
+  response
+    .headers_mut()
+    .insert("idempotent-replayed", HeaderValue::from_static("true"));
+  response
+}
+
+const CHAT_WS_PATH: &str = "/v1/chat/ws";
+
+// The first client message is a ChatRequest (always streamed); frames go out as
+// JSON with a `type` of meta, delta, done, ... and `{"type":"cancel"}` stops it.
+async fn chat_ws(State(state): State<Arc<RouterState>>, ws: WebSocketUpgrade) -> Response {
+  ws.on_upgrade(move |mut socket| async move { relay_chat(state, &mut socket).await })
+}
+
+// The parts of a WebSocket relay_chat uses, so tests can drive it over channels.
+trait ChatSocket {
+  // Next text message; None once the client has closed or dropped.
+  async fn recv_text(&mut self) -> Option<String>;
+  async fn send_text(&mut self, text: String) -> bool;
+  async fn close(&mut self);
+}
+
+impl ChatSocket for WebSocket {
+  async fn recv_text(&mut self) -> Option<String> {
+    loop {
+      match self.recv().await? {
+        Ok(WsMessage::Text(text)) => return Some(text),
+        Ok(WsMessage::Close(_)) | Err(_) => return None,
+        Ok(_) => continue,
+      }
+    }
+  }
+
+  async fn send_text(&mut self, text: String) -> bool {
+    self.send(WsMessage::Text(text)).await.is_ok()
+  }
+
+  async fn close(&mut self) {
+    let _ = self.send(WsMessage::Close(None)).await;
+  }
+}
+
+async fn relay_chat(state: Arc<RouterState>, socket: &mut impl ChatSocket) {
+  let Some(first) = socket.recv_text().await else {
+    return;
+  };
+  let mut req = match serde_json::from_str::<ChatRequest>(&first) {
+    Ok(req) => req,
+    Err(err) => {
+      let msg = format!("First message must be a chat request: {err}");
+      let resp = error_response(StatusCode::BAD_REQUEST, "bad_request", &msg);
+      socket.send_text(ws_error(&state, resp).await).await;
+      socket.close().await;
+      return;
+    }
+  };
+  req.stream = Some(true);
+  let mut frames = match run_chat(state.clone(), req).await {
+    Ok(ChatReply::Frames(frames)) => frames,
+    Ok(ChatReply::Complete(body)) => Box::pin(tokio_stream::once(ChatFrame::new("done", body))),
+    Err(resp) => {
+      socket.send_text(ws_error(&state, resp).await).await;
+      socket.close().await;
+      return;
+    }
+  };
+
+  // The stream is drained even after a cancel or disconnect so its history
+  // row and audit entry are written as for SSE.
+  let mut request_id: Option<String> = None;
+  let mut cancel_requested = false;
+  let mut connected = true;
+  loop {
+    tokio::select! {
+      frame = frames.next() => {
+        let Some(frame) = frame else { break };
+        if frame.event == "meta" {
+          request_id = frame.data["request_id"].as_str().map(String::from);
+          if cancel_requested {
+            cancel_chat(&state, request_id.as_deref());
+          }
+        }
+        if connected && !socket.send_text(frame.into_ws_text()).await {
+          connected = false;
+          cancel_chat(&state, request_id.as_deref());
+        }
+      }
+      text = socket.recv_text(), if connected => match text {
+        Some(text) if is_cancel_message(&text) => {
+          cancel_requested = true;
+          cancel_chat(&state, request_id.as_deref());
+        }
+        Some(_) => state.logger.log("WARN", "chat_ws: ignoring message other than cancel"),
+        None => {
+          connected = false;
+          cancel_chat(&state, request_id.as_deref());
+        }
+      },
+    }
+  }
+  if connected {
+    socket.close().await;
+  }
+}
+
+fn is_cancel_message(text: &str) -> bool {
+  serde_json::from_str::<serde_json::Value>(text).is_ok_and(|message| message["type"] == "cancel")
+}
+
+fn cancel_chat(state: &RouterState, request_id: Option<&str>) {
+  if let Some((_, cancel)) = request_id.and_then(|id| state.inflight.remove(id)) {
+    let _ = cancel.send(());
+  }
+}
+
+// Error responses as an `error` frame; counted here because the upgrade
+// response itself never passes through count_errors as a failure.
+async fn ws_error(state: &RouterState, resp: Response) -> String {
+  if let Some(ErrorCode(code)) = resp.extensions().get::<ErrorCode>() {
+    state.metrics.record_error(code);
+  }
+  let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap_or_default();
+  let data = serde_json::from_slice(&body).unwrap_or_else(|_| serde_json::json!({}));
+  ChatFrame::new("error", data).into_ws_text()
+}
+
+fn query_token(query: Option<&str>) -> Option<String> {
+  query?
+    .split('&')
+    .find_map(|pair| pair.strip_prefix("token="))
+    .map(String::from)
+}
+
+fn sse_response(frames: ChatFrames, keepalive_secs: u64) -> Response {
+  let events = frames.map(|frame| Ok::<_, std::convert::Infallible>(frame.into_event()));
+  if keepalive_secs == 0 {
+    return Sse::new(events).into_response();
+  }
+  Sse::new(events)
+    .keep_alive(KeepAlive::new().interval(Duration::from_secs(keepalive_secs)))
+    .into_response()
+}
+
+// Everything a chat does before its first byte goes out; Err is the response
+// for a chat rejected up front or whose every model failed.
+async fn run_chat(state: Arc<RouterState>, mut req: ChatRequest) -> Result<ChatReply, Response> {
+  let started = Instant::now();
+  state.metrics.record_chat();
+  let Ok(permit) = state.chat_slots.clone().try_acquire_owned() else {
+    let msg = "Too many chats in flight; try again shortly.";
+    return Err(reject_chat(&state, &req, started, StatusCode::TOO_MANY_REQUESTS, "too_many_requests", msg).await);
+  };
+  req.request_id = uuid::Uuid::new_v4().to_string();
+  if !state.config.read().await.history_enabled {
+    req.no_store = Some(true);
+  }
+  log_chat(
+    &state,
+    &req,
+    "INFO",
+    &format!(
+      "chat request: messages={}, images={}, stream={}",
+      req.messages.len(),
+      req.all_images().len(),
+      req.stream.unwrap_or(true)
+    ),
+  );
+  let (target, trimmed) = match prepare_chat(&state, &mut req).await {
+    Ok(prepared) => prepared,
+    Err((status, code, msg)) => return Err(reject_chat(&state, &req, started, status, code, &msg).await),
+  };
+  if req.dry_run.unwrap_or(false) {
+    return Ok(dry_run_chat(state, req, started, permit).await);
+  }
+
+  let config = state.config.read().await.clone();
+  let key = match get_provider_key(&state, &config, target.provider) {
+    Ok(k) => k,
+    Err(msg) => return Err(reject_chat(&state, &req, started, StatusCode::UNAUTHORIZED, "key_missing", &msg).await),
+  };
+
+  let stream = req.stream.unwrap_or(true);
+  if stream {
+    // Shared so either attempt can hold it; the slot frees once the stream ends.
+    let permit = Arc::new(permit);
+    let result = with_fallback(&state, &config, &req, started, target, key, |target, key| {
+      stream_chat(state.clone(), req.clone(), target, key, started, permit.clone())
+    })
+    .await;
+    match result {
+      Ok(frames) => Ok(ChatReply::Frames(Box::pin(frames)).with_trimmed(trimmed)),
+      Err((provider, err)) => Err(upstream_error_response(provider, err)),
+    }
+  } else {
+    let result = with_fallback(&state, &config, &req, started, target, key, |target, key| {
+      complete_chat(state.clone(), req.clone(), target, key, started)
+    })
+    .await;
+    result
+      .map(|body| ChatReply::Complete(body).with_trimmed(trimmed))
+      .map_err(|(provider, err)| upstream_error_response(provider, err))
+  }
+}
+
+// Everything a chat goes through before it is sent: validation, image checks,
+// preset and system prompts, model resolution and context trimming. chat_debug
+// shares it, so its payload is the one a real chat would send. Returns the
+// first model's target and how many messages were trimmed.
+async fn prepare_chat(
+  state: &RouterState,
+  req: &mut ChatRequest,
+) -> Result<(ChatTarget, usize), (StatusCode, &'static str, String)> {
+  validate_params(req).map_err(|msg| (StatusCode::BAD_REQUEST, "bad_params", msg))?;
+  // Checked before any system prompt is added, which would hide an empty request.
+  if req.messages.is_empty() && !req.has_images() {
+    let msg = "A chat needs at least one message or an image.".to_string();
+    return Err((StatusCode::BAD_REQUEST, "empty_conversation", msg));
+  }
+  validate_roles(&req.messages).map_err(|msg| (StatusCode::BAD_REQUEST, "bad_role", msg))?;
+  let config = state.config.read().await.clone();
+  check_images(state, req, config.max_image_bytes, config.downscale_images).await?;
+
+  let (policy, preset_prompt) = preset_context(state, req.preset_id.as_deref()).await?;
+  prepend_system_prompts(&mut req.messages, config.global_system_prompt.as_deref(), preset_prompt.as_deref());
+  if req.thread_id.as_deref().unwrap_or_default().trim().is_empty() {
+    req.thread_id = Some(uuid::Uuid::new_v4().to_string());
+  }
+
+  let model_id = resolve_model(req, &config, policy.as_ref())
+    .map_err(|msg| (StatusCode::BAD_REQUEST, "model_missing", msg))?;
+  let target = ChatTarget::new(&model_id, 0);
+  if !config.model_allowed(&target.canonical_id()) {
+    let msg = format!("Model '{model_id}' is not allowed by this router's configuration.");
+    return Err((StatusCode::FORBIDDEN, "model_not_allowed", msg));
+  }
+  let mut trimmed = 0;
+  if let Some(budget) = config.max_context_tokens {
+    // Counted with the first model's tokenizer; fallbacks get the same thread.
+    let mut messages = std::mem::take(&mut req.messages);
+    let model = target.model.clone();
+    let trim = tokio::task::spawn_blocking(move || {
+      let dropped = tokens::trim_messages(&mut messages, budget, &model);
+      (messages, dropped)
+    });
+    (req.messages, trimmed) = trim
+      .await
+      .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, "trim_failed", err.to_string()))?;
+    if trimmed > 0 {
+      log_chat(state, req, "INFO", &format!("dropped {trimmed} older messages to fit {budget} context tokens"));
+    }
+  }
+  provider_accepts_roles(target.provider, &req.messages).map_err(|msg| (StatusCode::BAD_REQUEST, "bad_role", msg))?;
+  Ok((target, trimmed))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Provider {
+  OpenRouter,
+  Anthropic,
+  Ollama,
+}
+
+impl Provider {
+  pub const ALL: [Provider; 3] = [Provider::OpenRouter, Provider::Anthropic, Provider::Ollama];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Provider::OpenRouter => "openrouter",
+      Provider::Anthropic => "anthropic",
+      Provider::Ollama => "ollama",
+    }
+  }
+
+  pub fn label(self) -> &'static str {
+    match self {
+      Provider::OpenRouter => "OpenRouter",
+      Provider::Anthropic => "Anthropic",
+      Provider::Ollama => "Ollama",
+    }
+  }
+
+  pub fn is_local(self) -> bool {
+    self == Provider::Ollama
+  }
+}
+
+struct ChatTarget {
+  provider: Provider,
+  model_id: String,
+  model: String,
+  // 0 for the resolved model, n for the nth model of its fallback chain.
+  chain_index: usize,
+}
+
+impl ChatTarget {
+  fn new(model_id: &str, chain_index: usize) -> Self {
+    let (provider, model) = split_provider(model_id);
+    Self {
+      provider,
+      model_id: model_id.to_string(),
+      model,
+      chain_index,
+    }
+  }
+
+  fn fell_back(&self) -> bool {
+    self.chain_index > 0
+  }
+
+  // `provider:model`, even when the configured id left the provider implied.
+  fn canonical_id(&self) -> String {
+    format!("{}:{}", self.provider.name(), self.model)
+  }
+}
+
+// Only transport failures and upstream 5xx are worth retrying on another model.
+enum UpstreamError {
+  Local(StatusCode, String),
+  Transport(String),
+  Status(StatusCode, String),
+  InvalidJson(String),
+  // Our own per-model cap, not an upstream 429; carries the wait until the next slot.
+  RateLimited(Duration),
+}
+
+impl UpstreamError {
+  fn should_fall_back(&self) -> bool {
+    match self {
+      UpstreamError::Local(..) | UpstreamError::InvalidJson(_) | UpstreamError::RateLimited(_) => false,
+      UpstreamError::Transport(_) => true,
+      UpstreamError::Status(status, _) => status.is_server_error(),
+    }
+  }
+
+  fn message(&self) -> &str {
+    match self {
+      UpstreamError::Local(_, message)
+      | UpstreamError::Transport(message)
+      | UpstreamError::Status(_, message)
+      | UpstreamError::InvalidJson(message) => message,
+      UpstreamError::RateLimited(_) => "Model rate limit reached; try again shortly.",
+    }
+  }
+
+  fn into_parts(self) -> (StatusCode, String) {
+    match self {
+      UpstreamError::Local(status, message) => (status, message),
+      UpstreamError::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, self.message().to_string()),
+      UpstreamError::Transport(message)
+      | UpstreamError::Status(_, message)
+      | UpstreamError::InvalidJson(message) => (StatusCode::BAD_GATEWAY, message),
+    }
+  }
+}
+
+fn upstream_error_code(provider: Provider, err: &UpstreamError) -> String {
+  match err {
+    UpstreamError::InvalidJson(_) => "invalid_json_output".to_string(),
+    UpstreamError::RateLimited(_) => "rate_limited".to_string(),
+    _ => format!("{}_error", provider.name()),
+  }
+}
+
+fn upstream_error_response(provider: Provider, err: UpstreamError) -> Response {
+  if let UpstreamError::RateLimited(wait) = err {
+    return rate_limited_response(wait);
+  }
+  let code = upstream_error_code(provider, &err);
+  let (status, message) = err.into_parts();
+  error_response(status, &code, &message)
+}
+
+// Every failed attempt is audited here; successes are audited where the reply ends.
+async fn with_fallback<T, F, Fut>(
+  state: &RouterState,
+  config: &AppConfig,
+  req: &ChatRequest,
+  started: Instant,
+  target: ChatTarget,
+  key: String,
+  attempt: F,
+) -> Result<T, (Provider, UpstreamError)>
+where
+  F: Fn(ChatTarget, String) -> Fut,
+  Fut: std::future::Future<Output = Result<T, UpstreamError>>,
+{
+  let local_only = target.provider.is_local();
+  let mut chain = fallback_chain(&target.model_id, config).into_iter().enumerate();
+  let (mut target, mut key) = (target, key);
+  loop {
+    let (model_id, provider) = (target.model_id.clone(), target.provider);
+    let err = match attempt(target, key).await {
+      Ok(value) => return Ok(value),
+      Err(err) => err,
+    };
+    let code = upstream_error_code(provider, &err);
+    audit_call(state, req, Some((&model_id, provider)), elapsed_ms(started), "error", Some(&code), None).await;
+    if !err.should_fall_back() {
+      return Err((provider, err));
+    }
+
+    // Entries that cannot be tried are skipped rather than ending the chain.
+    let next = chain.by_ref().find_map(|(index, fallback_id)| {
+      let fallback = ChatTarget::new(&fallback_id, index + 1);
+      // Never quietly move a local-only conversation to a hosted provider.
+      if local_only && !fallback.provider.is_local() {
+        return None;
+      }
+      if !config.model_allowed(&fallback.canonical_id()) {
+        return None;
+      }
+      provider_accepts_roles(fallback.provider, &req.messages).ok()?;
+      let fallback_key = get_provider_key(state, config, fallback.provider).ok()?;
+      Some((fallback, fallback_key))
+    });
+    let Some((fallback, fallback_key)) = next else {
+      return Err((provider, err));
+    };
+    log_fallback(state, req, &model_id, &fallback.model_id, &err);
+    (target, key) = (fallback, fallback_key);
+  }
+}
+
+// The configured chain minus blanks, repeats and the model that already failed.
+// Ids are compared canonically, so `gpt-4o` and `openrouter:gpt-4o` are one model.
+fn fallback_chain(primary_id: &str, config: &AppConfig) -> Vec<String> {
+  let mut seen = vec![ChatTarget::new(primary_id, 0).canonical_id()];
+  let mut chain = Vec::new();
+  for model_id in config.fallback_models() {
+    let canonical = ChatTarget::new(model_id, 0).canonical_id();
+    if !model_id.is_empty() && !seen.contains(&canonical) {
+      seen.push(canonical);
+      chain.push(model_id.to_string());
+    }
+  }
+  chain
+}
+
+fn log_fallback(state: &RouterState, req: &ChatRequest, failed_id: &str, fallback_id: &str, err: &UpstreamError) {
+  log_chat(
+    state,
+    req,
+    "WARN",
+    &format!(
+      "model {} failed ({}), falling back to {}",
+      failed_id,
+      err.message(),
+      fallback_id
+    ),
+  );
+}
+
+// Drops the reply being regenerated; None when no user turn is left to answer.
+fn regenerate_messages(mut messages: Vec<Message>) -> Option<Vec<Message>> {
+  if messages.last().is_some_and(|m| m.role == "assistant") {
+    messages.pop();
+  }
+  messages.iter().any(|m| m.role == "user").then_some(messages)
+}
+
+// Replays a stored conversation through chat(), so streaming, fallback and
+// history behave exactly as for a fresh request. The result is a new row.
+async fn chat_regenerate(
+  State(state): State<Arc<RouterState>>,
+  headers: HeaderMap,
+  Json(req): Json<ChatRegenerateRequest>,
+) -> Response {
+  state.logger.log("INFO", &format!("chat regenerate: {}", req.history_id));
+  let stored = match storage::load_chat(&state.db, &req.history_id).await {
+    Ok(Some(stored)) => stored,
+    Ok(None) => return error_response(StatusCode::NOT_FOUND, "not_found", "No history row with that id."),
+    Err(err) => return storage_error_response("regenerate_failed", err),
+  };
+  let Some(messages) = regenerate_messages(stored.messages) else {
+    return error_response(
+      StatusCode::BAD_REQUEST,
+      "nothing_to_regenerate",
+      "That conversation has no user turn to regenerate from.",
+    );
+  };
+  let images: Vec<ImageData> = stored
+    .images
+    .iter()
+    .filter_map(|image| storage::load_image(&state.images_dir, &image.id).ok().flatten())
+    .collect();
+  let chat_req = ChatRequest {
+    messages,
+    images: (!images.is_empty()).then_some(images),
+    model_override: req.model_override,
+    stream: req.stream,
+    thread_id: stored.thread_id,
+    dry_run: req.dry_run,
+    ..Default::default()
+  };
+  chat(State(state), headers, Json(chat_req)).await.into_response()
+}
+
+const LAST_EVENT_ID: &str = "last-event-id";
+
+// Re-sends a stream's deltas after Last-Event-ID (all of them without one),
+// then follows it live until done. Only streams still running, or finished
+// within the last few minutes, can be resumed; nothing survives a restart.
+// A stream whose client went away keeps running for resume_grace_secs; if
+// nobody resumed it by then it was stopped, and its replay ends with what had
+// arrived.
+async fn chat_resume(
+  State(state): State<Arc<RouterState>>,
+  Path(request_id): Path<String>,
+  headers: HeaderMap,
+) -> Response {
+  let after = match headers.get(LAST_EVENT_ID) {
+    None => 0,
+    Some(value) => match value.to_str().ok().and_then(|id| id.trim().parse::<usize>().ok()) {
+      Some(id) => id,
+      None => return error_response(StatusCode::BAD_REQUEST, "bad_last_event_id", "Last-Event-ID must be a delta id."),
+    },
+  };
+  let Some(mut replay) = state.resume.subscribe(&request_id) else {
+    return error_response(StatusCode::NOT_FOUND, "not_resumable", "No resumable stream with that request id.");
+  };
+  state.logger.log("INFO", &format!("chat {request_id} resumed after event {after}"));
+  let keepalive_secs = state.config.read().await.sse_keepalive_secs;
+  let frames = stream! {
+    let mut sent = after;
+    loop {
+      let (missed, done) = {
+        let current = replay.borrow_and_update();
+        (current.deltas.get(sent..).unwrap_or_default().to_vec(), current.done.clone())
+      };
+      for text in missed {
+        sent += 1;
+        let payload = serde_json::json!({ "text": text });
+        yield ChatFrame::new("delta", payload).with_id(sent as u64);
+      }
+      if let Some(done) = done {
+        yield ChatFrame::new("done", done);
+        break;
+      }
+      if replay.changed().await.is_err() {
+        break;
+      }
+    }
+  };
+  sse_response(Box::pin(frames), keepalive_secs)
+}
+
+async fn chat_cancel(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<ChatCancelRequest>,
+) -> impl IntoResponse {
+  state.logger.log("INFO", &format!("chat_cancel: {}", req.request_id));
+  match state.inflight.remove(&req.request_id) {
+    Some((_, cancel)) => {
+      let _ = cancel.send(());
+      (StatusCode::OK, Json(serde_json::json!({ "cancelled": true }))).into_response()
+    }
+    None => error_response(StatusCode::NOT_FOUND, "not_found", "No in-flight chat with that request_id."),
+  }
+}
+
+fn storage_error_response(code: &str, err: storage::StorageError) -> Response {
+  let status = match err {
+    storage::StorageError::Invalid(_) => StatusCode::BAD_REQUEST,
+    storage::StorageError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+  };
+  error_response(status, code, &err.to_string())
+}
+
+#[derive(Clone)]
+struct ErrorCode(String);
+
+const TITLE_PROMPT: &str = "Write a 3 to 6 word title for this conversation. Reply with the title only, \
+without quotes or punctuation at the end.";
+// Enough of each turn to tell what the conversation is about.
+const TITLE_CONTEXT_CHARS: usize = 1000;
+const TITLE_MAX_CHARS: usize = 80;
+const FALLBACK_TITLE_WORDS: usize = 6;
+
+// Runs as an ordinary unstored chat, so limits, retries and fallbacks apply;
+// any failure falls back to the start of the first user message.
+async fn chat_title(State(state): State<Arc<RouterState>>, Json(req): Json<ChatTitleRequest>) -> impl IntoResponse {
+  let Some(first_user) = req.messages.iter().position(|m| m.role == "user") else {
+    return error_response(StatusCode::BAD_REQUEST, "no_user_message", "A title needs a user message.");
+  };
+  let reply = req.messages[first_user + 1..].iter().find(|m| m.role == "assistant");
+  let excerpt = |message: &Message| message.content.chars().take(TITLE_CONTEXT_CHARS).collect::<String>();
+  let mut conversation = format!("User: {}", excerpt(&req.messages[first_user]));
+  if let Some(reply) = reply {
+    conversation.push_str(&format!("\nAssistant: {}", excerpt(reply)));
+  }
+  let title_req = ChatRequest {
+    messages: vec![
+      Message {
+        role: "system".to_string(),
+        content: TITLE_PROMPT.to_string(),
+        ..Default::default()
+      },
+      Message {
+        role: "user".to_string(),
+        content: conversation,
+        ..Default::default()
+      },
+    ],
+    model_override: state.config.read().await.title_model.clone(),
+    stream: Some(false),
+    max_tokens: Some(24),
+    no_store: Some(true),
+    ..Default::default()
+  };
+  let generated = match run_chat(state.clone(), title_req).await {
+    Ok(ChatReply::Complete(body)) => body["text"].as_str().and_then(clean_title),
+    Ok(ChatReply::Frames(_)) | Err(_) => None,
+  };
+  let title = generated.unwrap_or_else(|| {
+    state.logger.log("WARN", "title generation failed; using the first message");
+    fallback_title(&req.messages[first_user].content)
+  });
+  Json(serde_json::json!({ "title": title })).into_response()
+}
+
+// The OpenRouter request a chat would send, built the way run_chat builds it
+// but never sent. The key is not even read: the cURL command takes it from
+// $OPENROUTER_API_KEY, so pasting the output into a bug report is safe.
+async fn chat_debug(State(state): State<Arc<RouterState>>, Json(mut req): Json<ChatRequest>) -> Response {
+  let (target, trimmed) = match prepare_chat(&state, &mut req).await {
+    Ok(prepared) => prepared,
+    Err((status, code, msg)) => return error_response(status, code, &msg),
+  };
+  let model_id = &target.model_id;
+  if target.provider != Provider::OpenRouter {
+    let msg = format!("Model '{model_id}' goes to {}, not OpenRouter.", target.provider.label());
+    return error_response(StatusCode::BAD_REQUEST, "not_openrouter", &msg);
+  }
+  let config = state.config.read().await.clone();
+  let developer_role = uses_developer_role(&config, &target);
+  let req = match normalize_roles(&req, target.provider, developer_role) {
+    Ok(req) => req,
+    Err(msg) => return error_response(StatusCode::BAD_REQUEST, "bad_role", &msg),
+  };
+
+  let url = openrouter_url(&config.openrouter_base_url, "chat/completions");
+  let payload = build_payload(&req, &target.model, req.stream.unwrap_or(true));
+  let payload = match serde_json::to_value(&payload) {
+    Ok(payload) => payload,
+    Err(err) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "debug_failed", &err.to_string()),
+  };
+  let curl = curl_command(&url, &openrouter_attribution(&config), &payload);
+  let body = serde_json::json!({
+    "model": model_id,
+    "provider": target.provider.name(),
+    "url": url,
+    "trimmed": trimmed,
+    "payload": payload,
+    "curl": curl
+  });
+  Json(body).into_response()
+}
+
+fn curl_command(url: &str, headers: &HeaderMap, payload: &serde_json::Value) -> String {
+  // Single quotes keep the shell from expanding anything in the quoted text.
+  let quote = |text: &str| format!("'{}'", text.replace('\'', "'\\''"));
+  let mut parts = vec![format!("curl -sS {}", quote(url))];
+  parts.push(format!("-H {}", quote("Content-Type: application/json")));
+  for (name, value) in headers {
+    parts.push(format!("-H {}", quote(&format!("{}: {}", name, value.to_str().unwrap_or_default()))));
+  }
+  parts.push("-H \"Authorization: Bearer $OPENROUTER_API_KEY\"".to_string());
+  parts.push(format!("--data-raw {}", quote(&payload.to_string())));
+  parts.join(" \\\n  ")
+}
+
+// Models like to wrap titles in quotes, prefix them, or add a second line.
+fn clean_title(raw: &str) -> Option<String> {
+  let line = raw.lines().map(str::trim).find(|line| !line.is_empty())?;
+  let line = line.strip_prefix("Title:").unwrap_or(line);
+  let quotes: &[char] = &['"', '\'', '`', '*', '\u{201c}', '\u{201d}', '\u{2018}', '\u{2019}'];
+  let title = line.trim().trim_matches(quotes).trim().trim_end_matches('.');
+  let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+  if title.is_empty() {
+    return None;
+  }
+  Some(title.chars().take(TITLE_MAX_CHARS).collect())
+}
+
+fn fallback_title(first_message: &str) -> String {
+  let words: Vec<&str> = first_message.split_whitespace().collect();
+  if words.is_empty() {
+    return "New chat".to_string();
+  }
+  let kept = words[..words.len().min(FALLBACK_TITLE_WORDS)].join(" ");
+  let mut title: String = kept.chars().take(TITLE_MAX_CHARS).collect();
+  if words.len() > FALLBACK_TITLE_WORDS || title.len() < kept.len() {
+    title.push('…');
+  }
+  title
+}
+
+async fn count_tokens(
+  State(state): State<Arc<RouterState>>,
+  Json(req): Json<TokenCountRequest>,
+) -> impl IntoResponse {
+  let model_id = match req.model_override.as_deref().map(str::trim) {
+    Some(id) if !id.is_empty() => id.to_string(),
+    _ => state.config.read().await.text_default_model.clone(),
+  };
+  let (_, model) = split_provider(&model_id);
+  // Loading a BPE table and encoding long threads is CPU-bound.
+  match tokio::task::spawn_blocking(move || tokens::count_tokens(&model, &req.messages)).await {
+    Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "count_failed", &err.to_string()),
+  }
+}
+
+async fn ocr_image(State(state): State<Arc<RouterState>>, Json(image): Json<ImageData>) -> impl IntoResponse {
+  state.logger.log("INFO", "ocr");
+  // Recognition is CPU-bound and can take a second or two on a full display.
+  match tokio::task::spawn_blocking(move || ocr::extract_text(&image)).await {
+    Ok(Ok(res)) => (StatusCode::OK, Json(res)).into_response(),
+    Ok(Err(err)) => {
+      let (status, code) = match err {
+        ocr::OcrError::Invalid(_) => (StatusCode::BAD_REQUEST, "bad_image"),
+        ocr::OcrError::Unavailable => (StatusCode::NOT_IMPLEMENTED, "ocr_unavailable"),
+        ocr::OcrError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "ocr_failed"),
+      };
+      error_response(status, code, &err.to_string())
+    }
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "ocr_failed", &err.to_string()),
+  }
+}
+
+// `target` is unset for chats rejected before a model was resolved; latency is
+// measured from when the chat arrived.
+async fn audit_call(
+  state: &RouterState,
+  req: &ChatRequest,
+  target: Option<(&str, Provider)>,
+  latency_ms: i64,
+  status: &str,
+  error_code: Option<&str>,
+  usage: Option<&Usage>,
+) {
+  let entry = storage::AuditEntry {
+    model: target.map(|(model_id, _)| model_id).or(req.model_override.as_deref()),
+    provider: target.map(|(_, provider)| provider.name()),
+    has_image: req.has_images(),
+    latency_ms,
+    status,
+    error_code,
+    usage,
+    cost_usd: target.and_then(|(model_id, _)| cost_usd(state, model_id, usage)),
+  };
+  if let Err(err) = storage::audit_log(&state.db, entry).await {
+    log_chat(state, req, "WARN", &format!("audit row not stored: {err}"));
+  }
+}
+
+// Clients can post images straight to the router, skipping capture's limits.
+// Sizes come from the base64 length, so nothing is decoded unless an
+// oversized image is being shrunk.
+async fn check_images(
+  state: &RouterState,
+  req: &mut ChatRequest,
+  max_bytes: usize,
+  downscale: bool,
+) -> Result<(), (StatusCode, &'static str, String)> {
+  let request_id = req.request_id.clone();
+  let images = req.image.iter_mut().chain(req.images.iter_mut().flatten());
+  for (index, image) in images.enumerate() {
+    if !storage::is_image_mime(&image.mime) {
+      let msg = format!("Image {index} has type '{}'; expected PNG, JPEG, WebP or GIF.", image.mime);
+      return Err((StatusCode::BAD_REQUEST, "bad_image_type", msg));
+    }
+    let size = capture::base64_decoded_len(&image.base64);
+    if size <= max_bytes {
+      continue;
+    }
+    let too_large = |detail: &str| {
+      let msg = format!("Image {index} is {size} bytes; the limit is {max_bytes}{detail}.");
+      (StatusCode::PAYLOAD_TOO_LARGE, "image_too_large", msg)
+    };
+    if !downscale {
+      return Err(too_large(""));
+    }
+    let original = image.clone();
+    let shrunk = tokio::task::spawn_blocking(move || capture::shrink_to_fit(&original, max_bytes)).await;
+    match shrunk.map_err(anyhow::Error::from).and_then(|shrunk| shrunk) {
+      Ok(shrunk) => {
+        let fields = serde_json::json!({ "request_id": request_id });
+        let msg = format!("image {index} downscaled from {size} bytes to fit {max_bytes}");
+        state.logger.log_fields("INFO", &msg, fields);
+        *image = shrunk;
+      }
+      Err(err) => return Err(too_large(&format!(" and it could not be downscaled: {err}"))),
+    }
+  }
+  Ok(())
+}
+
+// The routing policy and system prompt a chat's preset contributes.
+async fn preset_context(
+  state: &RouterState,
+  preset_id: Option<&str>,
+) -> Result<(Option<RoutingPolicy>, Option<String>), (StatusCode, &'static str, String)> {
+  let Some(preset_id) = preset_id else {
+    return Ok((None, None));
+  };
+  match storage::get_preset(&state.db, preset_id).await {
+    Ok(Some(preset)) => Ok((Some(preset.routing_policy), Some(preset.system_prompt))),
+    Ok(None) => Err((StatusCode::NOT_FOUND, "preset_not_found", format!("Preset '{preset_id}' not found."))),
+    Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, "preset_lookup_failed", err.to_string())),
+  }
+}
+
+async fn reject_chat(
+  state: &RouterState,
+  req: &ChatRequest,
+  started: Instant,
+  status: StatusCode,
+  code: &str,
+  message: &str,
+) -> Response {
+  audit_call(state, req, None, elapsed_ms(started), "error", Some(code), None).await;
+  error_response(status, code, message)
+}
+
+// error_response plus a hint for when the model's bucket has a request again.
+fn rate_limited_response(wait: Duration) -> Response {
+  let message = UpstreamError::RateLimited(wait).message().to_string();
+  let retry_after_ms = wait.as_millis().max(1) as u64;
+  let body = Json(serde_json::json!({ "error": message, "code": "rate_limited", "retry_after_ms": retry_after_ms }));
+  let mut response = (StatusCode::TOO_MANY_REQUESTS, body).into_response();
+  response.extensions_mut().insert(ErrorCode("rate_limited".to_string()));
+  response
+}
+
+fn error_response(status: StatusCode, code: &str, message: &str) -> Response {
+  let body = Json(serde_json::json!({ "error": message, "code": code }));
+  let mut response = (status, body).into_response();
+  response.extensions_mut().insert(ErrorCode(code.to_string()));
+  response
+}
+
+// Unprefixed ids (and unknown prefixes, which are usually part of an
+// OpenRouter model name like `...:free`) go to OpenRouter.
+fn split_provider(model_id: &str) -> (Provider, String) {
+  for provider in Provider::ALL {
+    if let Some(model) = model_id
+      .strip_prefix(provider.name())
+      .and_then(|rest| rest.strip_prefix(':'))
+    {
+      return (provider, model.to_string());
+    }
+  }
+  (Provider::OpenRouter, model_id.to_string())
+}
+
+fn resolve_model(req: &ChatRequest, config: &AppConfig, policy: Option<&RoutingPolicy>) -> Result<String, String> {
+  if let Some(override_id) = req.model_override.as_ref() {
+    if !override_id.trim().is_empty() {
+      return Ok(override_id.trim().to_string());
+    }
+  }
+
+  if let Some(policy) = policy {
+    let pinned = if req.has_images() {
+      policy.vision_model.as_ref()
+    } else {
+      policy.model.as_ref()
+    };
+    if let Some(model_id) = pinned.filter(|m| !m.trim().is_empty()) {
+      return Ok(model_id.trim().to_string());
+    }
+  }
+
+  if req.has_images() {
+    if config.vision_default_model.trim().is_empty() {
+      return Err("Vision default model not set.".to_string());
+    }
+    return Ok(config.vision_default_model.clone());
+  }
+
+  if config.text_default_model.trim().is_empty() {
+    return Err("Text default model not set.".to_string());
+  }
+  Ok(config.text_default_model.clone())
+}
+
+// Callers that already send their own system message keep it; otherwise the
+// global prompt goes first and the preset's follows it.
+fn prepend_system_prompts(messages: &mut Vec<Message>, global: Option<&str>, preset: Option<&str>) {
+  if messages.first().is_some_and(|m| m.role == "system" || m.role == "developer") {
+    return;
+  }
+  let prompts = [global, preset]
+    .into_iter()
+    .flatten()
+    .filter(|prompt| !prompt.trim().is_empty())
+    .map(|prompt| Message {
+      role: "system".to_string(),
+      content: prompt.to_string(),
+      ..Default::default()
+    });
+  messages.splice(0..0, prompts);
+}
+
+// OpenAI's limit, which OpenRouter passes through.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+fn validate_params(req: &ChatRequest) -> Result<(), String> {
+  if let Some(temperature) = req.temperature {
+    if !(0.0..=2.0).contains(&temperature) {
+      return Err("temperature must be between 0.0 and 2.0.".to_string());
+    }
+  }
+  if let Some(top_p) = req.top_p {
+    if !(0.0..=1.0).contains(&top_p) {
+      return Err("top_p must be between 0.0 and 1.0.".to_string());
+    }
+  }
+  if req.max_tokens == Some(0) {
+    return Err("max_tokens must be greater than 0.".to_string());
+  }
+  if req.provider_routing.as_ref().is_some_and(|routing| !routing.is_object()) {
+    return Err("provider_routing must be a JSON object.".to_string());
+  }
+  if let Some(stop) = &req.stop {
+    if stop.len() > MAX_STOP_SEQUENCES {
+      return Err(format!("stop accepts at most {MAX_STOP_SEQUENCES} sequences."));
+    }
+    if stop.iter().any(|sequence| sequence.is_empty()) {
+      return Err("stop sequences must not be empty.".to_string());
+    }
+  }
+  Ok(())
+}
+
+const MESSAGE_ROLES: [&str; 5] = ["system", "developer", "user", "assistant", "tool"];
+
+fn validate_roles(messages: &[Message]) -> Result<(), String> {
+  if let Some(index) = messages.iter().position(|m| m.role.trim().is_empty()) {
+    return Err(format!("Message {index} has no role; expected one of {}.", MESSAGE_ROLES.join(", ")));
+  }
+  match messages.iter().find(|m| !MESSAGE_ROLES.contains(&m.role.as_str())) {
+    Some(m) => Err(format!(
+      "Unknown message role '{}'; expected one of {}.",
+      m.role,
+      MESSAGE_ROLES.join(", ")
+    )),
+    None => Ok(()),
+  }
+}
+
+// The role each provider is sent for one of MESSAGE_ROLES; None when it has no
+// equivalent. `developer` and `system` are interchangeable instructions.
+fn provider_role(provider: Provider, role: &str, developer_role: bool) -> Option<&'static str> {
+  match (provider, role) {
+    (_, "user") => Some("user"),
+    (_, "assistant") => Some("assistant"),
+    (Provider::OpenRouter, "system" | "developer") => Some(if developer_role { "developer" } else { "system" }),
+    (_, "system" | "developer") => Some("system"),
+    // Anthropic takes tool results as user content blocks, not plain messages.
+    (Provider::Anthropic, "tool") => None,
+    (_, "tool") => Some("tool"),
+    _ => None,
+  }
+}
+
+fn provider_accepts_roles(provider: Provider, messages: &[Message]) -> Result<(), String> {
+  match messages.iter().find(|m| provider_role(provider, &m.role, false).is_none()) {
+    Some(m) => Err(format!("{} does not accept '{}' messages.", provider.label(), m.role)),
+    None => Ok(()),
+  }
+}
+
+// Listed ids are compared canonically, like the model policy, so an entry may
+// leave its `openrouter:` prefix implied or differ in case.
+fn uses_developer_role(config: &AppConfig, target: &ChatTarget) -> bool {
+  let canonical = target.canonical_id();
+  config
+    .developer_role_models
+    .iter()
+    .any(|model_id| ChatTarget::new(model_id.trim(), 0).canonical_id().eq_ignore_ascii_case(&canonical))
+}
+
+// Borrows `req` unchanged unless some role has to be renamed for the provider.
+fn normalize_roles(
+  req: &ChatRequest,
+  provider: Provider,
+  developer_role: bool,
+) -> Result<Cow<'_, ChatRequest>, String> {
+  provider_accepts_roles(provider, &req.messages)?;
+  let role_for = |m: &Message| provider_role(provider, &m.role, developer_role).unwrap_or("user");
+  if req.messages.iter().all(|m| role_for(m) == m.role) {
+    return Ok(Cow::Borrowed(req));
+  }
+  let mut normalized = req.clone();
+  for message in &mut normalized.messages {
+    message.role = role_for(message).to_string();
+  }
+  Ok(Cow::Owned(normalized))
+}
+
+fn key_store(state: &RouterState, config: &AppConfig) -> keys::KeyStore {
+  keys::KeyStore::new(config.key_storage, &state.key_file)
+}
+
+fn get_provider_key(state: &RouterState, config: &AppConfig, provider: Provider) -> Result<String, String> {
+  if provider.is_local() {
+    return Ok(String::new());
+  }
+  keys::get_key(&key_store(state, config), provider, config.prefer_env_key).ok_or_else(|| {
+    let var = keys::env_var(provider).unwrap_or_default();
+    format!("{} key missing. Set it in Settings or via {var}.", provider.label())
+  })
+}
+
+const MAX_LOG_LINES: usize = 1000;
+
+#[derive(serde::Deserialize)]
+struct LogsQuery {
+  lines: Option<usize>,
+}
+
+async fn logs(State(state): State<Arc<RouterState>>, Query(query): Query<LogsQuery>) -> impl IntoResponse {
+  let lines = query.lines.unwrap_or(200).min(MAX_LOG_LINES);
+  match state.logger.tail(lines) {
+    Ok(lines) => (StatusCode::OK, Json(lines)).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "log_read_failed", &err.to_string()),
+  }
+}
+
+const MAX_AUDIT_ROWS: usize = 1000;
+
+#[derive(serde::Deserialize)]
+struct AuditQuery {
+  limit: Option<usize>,
+}
+
+async fn audit(State(state): State<Arc<RouterState>>, Query(query): Query<AuditQuery>) -> impl IntoResponse {
+  let limit = query.limit.unwrap_or(100).min(MAX_AUDIT_ROWS);
+  match storage::audit_recent(&state.db, limit).await {
+    Ok(items) => (StatusCode::OK, Json(AuditResponse { items })).into_response(),
+    Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "audit_read_failed", &err.to_string()),
+  }
+}
+
+async fn debug_status(State(state): State<Arc<RouterState>>) -> Json<serde_json::Value> {
+  let config = state.config.read().await.clone();
+  let key_set = keys::has_key(&key_store(&state, &config), Provider::OpenRouter);
+
+  Json(serde_json::json!({
+    "status": "ok",
+    "port": state.port,
+    "key_set": key_set,
+    "text_default": config.text_default_model,
+    "vision_default": config.vision_default_model,
+    "models_count": config.models.len()
+  }))
+}
+
+#[derive(serde::Serialize)]
+struct OpenRouterMessage {
+  role: String,
+  content: serde_json::Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tool_calls: Option<Vec<ToolCall>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tool_call_id: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct OpenRouterChatRequest {
+  model: String,
+  messages: Vec<OpenRouterMessage>,
+  stream: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  temperature: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  max_tokens: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  top_p: Option<f32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stop: Option<Vec<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  stream_options: Option<StreamOptions>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  response_format: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tools: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  tool_choice: Option<serde_json::Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  provider: Option<serde_json::Value>,
+}
+
+#[derive(serde::Serialize)]
+struct StreamOptions {
+  include_usage: bool,
+}
+
+fn build_payload(req: &ChatRequest, model: &str, stream: bool) -> OpenRouterChatRequest {
+  OpenRouterChatRequest {
+    model: model.to_string(),
+    messages: to_openrouter_messages(&req.messages, req.all_images()),
+    stream,
+    temperature: req.temperature,
+    max_tokens: req.max_tokens,
+    top_p: req.top_p,
+    stop: req.stop.clone().filter(|stop| !stop.is_empty()),
+    stream_options: stream.then_some(StreamOptions { include_usage: true }),
+    response_format: req.response_format.clone(),
+    tools: req.tools.clone(),
+    tool_choice: req.tool_choice.clone(),
+    provider: req.provider_routing.clone(),
+  }
+}
+
+// The smallest real completion, for the self-test; nothing is stored or audited.
+pub async fn probe_openrouter_chat(
+  client: &reqwest::Client,
+  key: &str,
+  model_id: &str,
+  config: &AppConfig,
+  timeout: Duration,
+) -> Result<(), String> {
+  let (provider, model) = split_provider(model_id);
+  if provider != Provider::OpenRouter {
+    return Err(format!("{model_id} is not an OpenRouter model."));
+  }
+  let req = ChatRequest {
+    messages: vec![Message {
+      role: "user".to_string(),
+      content: "Reply with OK.".to_string(),
+      ..Default::default()
+    }],
+    max_tokens: Some(16),
+    ..Default::default()
+  };
+  let resp = build_openrouter_request(client, key, config)
+    .map_err(|err| err.message().to_string())?
+    .json(&build_payload(&req, &model, false))
+    .timeout(timeout)
+    .send()
+    .await
+    .map_err(|err| err.to_string())?;
+  let resp = check_upstream_status(resp, Provider::OpenRouter.label())
+    .await
+    .map_err(|(_, message)| message)?;
+  let body = resp.json::<serde_json::Value>().await.map_err(|err| err.to_string())?;
+  if body["choices"][0]["message"].is_object() {
+    Ok(())
+  } else {
+    Err("OpenRouter reply had no message.".to_string())
+  }
+}
+
+// Only OpenRouter receives response_format, so only its replies are held to it.
+fn check_json_output(req: &ChatRequest, provider: Provider, content: &str) -> Result<(), UpstreamError> {
+  let json_mode = req
+    .response_format
+    .as_ref()
+    .is_some_and(|format| format["type"] == "json_object");
+  if !json_mode || provider != Provider::OpenRouter {
+    return Ok(());
+  }
+  serde_json::from_str::<serde_json::Value>(content)
+    .map(|_| ())
+    .map_err(|err| UpstreamError::InvalidJson(format!("Model returned malformed JSON: {err}")))
+}
+
+fn image_url_block(image: &ImageData) -> serde_json::Value {
+  let url = format!("data:{};base64,{}", image.mime, image.base64);
+  serde_json::json!({ "type": "image_url", "image_url": { "url": url } })
+}
+
+fn with_images(text: &str, images: &[&ImageData]) -> serde_json::Value {
+  let mut content = vec![serde_json::json!({ "type": "text", "text": text })];
+  content.extend(images.iter().map(|image| image_url_block(image)));
+  serde_json::Value::Array(content)
+}
+
+fn to_openrouter_messages<'a>(
+  messages: &[Message],
+  images: impl IntoIterator<Item = &'a ImageData>,
+) -> Vec<OpenRouterMessage> {
+  let images: Vec<&ImageData> = images.into_iter().collect();
+  let last_user_index = messages.iter().rposition(|m| m.role == "user");
+
+  let mut result: Vec<OpenRouterMessage> = messages
+    .iter()
+    .enumerate()
+    .map(|(idx, msg)| OpenRouterMessage {
+      role: msg.role.clone(),
+      content: if !images.is_empty() && Some(idx) == last_user_index {
+        with_images(&msg.content, &images)
+      } else {
+        serde_json::json!(msg.content)
+      },
+      tool_calls: msg.tool_calls.clone().map(|calls| calls.into_iter().map(function_call).collect()),
+      tool_call_id: msg.tool_call_id.clone(),
+    })
+    .collect();
+
+  if !images.is_empty() && last_user_index.is_none() {
+    result.push(OpenRouterMessage {
+      role: "user".to_string(),
+      content: with_images("", &images),
+      tool_calls: None,
+      tool_call_id: None,
+    });
+  }
+
+  result
+}
+
+// Clients may echo a call back without its `type`; OpenRouter requires it.
+fn function_call(mut call: ToolCall) -> ToolCall {
+  if call.r#type.is_empty() {
+    call.r#type = "function".to_string();
+  }
+  call
+}
+
+// HTTP-Referer and X-Title for every OpenRouter call.
+fn openrouter_attribution(config: &AppConfig) -> HeaderMap {
+  let value = |configured: &str, default: String| {
+    HeaderValue::from_str(configured.trim())
+      .ok()
+      .filter(|value| !value.is_empty())
+      .unwrap_or_else(|| HeaderValue::from_str(&default).expect("default attribution is a valid header"))
+  };
+  let mut headers = HeaderMap::new();
+  headers.insert("HTTP-Referer", value(&config.openrouter_referer, default_openrouter_referer()));
+  headers.insert("X-Title", value(&config.openrouter_app_title, default_openrouter_app_title()));
+  headers
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, UpstreamError> {
+  HeaderValue::from_str(value).map_err(|err| UpstreamError::Local(StatusCode::BAD_REQUEST, err.to_string()))
+}
+
+// Rate limits and transient upstream failures; auth and validation errors never
+// succeed on a second try.
+fn should_retry(err: &UpstreamError) -> bool {
+  match err {
+    UpstreamError::Local(..) | UpstreamError::InvalidJson(_) | UpstreamError::RateLimited(_) => false,
+    UpstreamError::Transport(_) => true,
+    UpstreamError::Status(status, _) => matches!(status.as_u16(), 429 | 500 | 502 | 503),
+  }
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+  Duration::from_millis(250 * 2u64.pow(attempt.min(6)))
+}
+
+// Retries happen before any bytes reach the client, so streams are safe to retry here.
+async fn send_upstream(
+  state: &RouterState,
+  target: &ChatTarget,
+  key: &str,
+  req: &ChatRequest,
+  stream: bool,
+) -> Result<reqwest::Response, UpstreamError> {
+  let (max_retries, rate_limit_rpm) = {
+    let config = state.config.read().await;
+    let model = config.models.iter().find(|m| m.id == target.model_id);
+    (config.max_retries, model.and_then(|m| m.rate_limit_rpm))
+  };
+  // Counted once per chat attempt; our own retries below do not spend tokens.
+  if let Some(rpm) = rate_limit_rpm.filter(|rpm| *rpm > 0) {
+    state
+      .rate_limits
+      .try_acquire(&target.model_id, rpm)
+      .map_err(UpstreamError::RateLimited)?;
+  }
+  let mut attempt = 0;
+  loop {
+    match send_upstream_once(state, target, key, req, stream).await {
+      Err(err) if attempt < max_retries && should_retry(&err) => {
+        let delay = retry_delay(attempt);
+        attempt += 1;
+        log_chat(
+          state,
+          req,
+          "WARN",
+          &format!(
+            "{} request failed ({}), retry {}/{} in {}ms",
+            target.provider.label(),
+            err.message(),
+            attempt,
+            max_retries,
+            delay.as_millis()
+          ),
+        );
+        tokio::time::sleep(delay).await;
+      }
+      result => return result,
+    }
+  }
+}
+
+async fn send_upstream_once(
+  state: &RouterState,
+  target: &ChatTarget,
+  key: &str,
+  req: &ChatRequest,
+  stream: bool,
+) -> Result<reqwest::Response, UpstreamError> {
+  let (timeout, ollama_base_url, developer_role) = {
+    let config = state.config.read().await;
+    (
+      Duration::from_secs(config.request_timeout_secs),
+      config.ollama_base_url.clone(),
+      uses_developer_role(&config, target),
+    )
+  };
+  let req = &*normalize_roles(req, target.provider, developer_role)
+    .map_err(|msg| UpstreamError::Local(StatusCode::BAD_REQUEST, msg))?;
+  let client = &state.http_client;
+  let mut headers = HeaderMap::new();
+  let request = match target.provider {
+    Provider::OpenRouter => {
+      let config = state.config.read().await;
+      prefetch_prices(state, &config.openrouter_base_url, key, timeout);
+      build_openrouter_request(client, key, &config)?.json(&build_payload(req, &target.model, stream))
+    }
+    Provider::Anthropic => {
+      headers.insert("x-api-key", header_value(key)?);
+      headers.insert("anthropic-version", HeaderValue::from_static(anthropic::API_VERSION));
+      client
+        .post(anthropic::MESSAGES_URL)
+        .json(&anthropic::build_payload(req, &target.model, stream))
+    }
+    Provider::Ollama => client
+      .post(ollama::chat_url(&ollama_base_url))
+      .json(&ollama::build_payload(req, &target.model, stream)),
+  };
+
+  let label = target.provider.label();
+  let request = request.headers(headers);
+  // A whole-request timeout would cut long streams short, so streams only bound
+  // the wait for response headers and rely on an idle timeout per chunk.
+  let sent = if stream {
+    tokio::time::timeout(timeout, request.send())
+      .await
+      .map_err(|_| UpstreamError::Transport(format!("{} request timed out.", label)))?
+  } else {
+    request.timeout(timeout).send().await
+  };
+  let resp = sent.map_err(|err| UpstreamError::Transport(err.to_string()))?;
+  check_upstream_status(resp, label).await.map_err(|(status, message)| {
+    log_chat(state, req, "ERROR", &message);
+    UpstreamError::Status(status, message)
+  })
+}
+
+// A chat completions request with auth and attribution set; the caller adds
+// the body and timeout.
+fn build_openrouter_request(
+  client: &reqwest::Client,
+  key: &str,
+  config: &AppConfig,
+) -> Result<reqwest::RequestBuilder, UpstreamError> {
+  let mut headers = openrouter_attribution(config);
+  headers.insert(AUTHORIZATION, header_value(&format!("Bearer {}", key))?);
+  let url = openrouter_url(&config.openrouter_base_url, "chat/completions");
+  Ok(client.post(url).headers(headers))
+}
+
+// Every OpenRouter endpoint hangs off `openrouter_base_url`, so a gateway set
+// there also receives the models, key and embeddings calls.
+fn openrouter_url(base_url: &str, path: &str) -> String {
+  format!("{}/{path}", base_url.trim().trim_end_matches('/'))
+}
+
+// Non-2xx replies become the upstream status and a message quoting the body.
+async fn check_upstream_status(
+  resp: reqwest::Response,
+  label: &str,
+) -> Result<reqwest::Response, (StatusCode, String)> {
+  let status = resp.status();
+  if status.is_success() {
+    return Ok(resp);
+  }
+  let text = resp
+    .text()
+    .await
+    .unwrap_or_else(|_| format!("{} request failed.", label));
+  Err((status, format!("{} error ({}): {}", label, status, text)))
+}
+
+// One streamed fragment of a tool call. Only the first fragment for an index
+// carries the id and name; `arguments` arrives in pieces.
+#[derive(Debug, PartialEq)]
+pub struct ToolCallDelta {
+  index: usize,
+  id: Option<String>,
+  name: Option<String>,
+  arguments: String,
+}
+
+const MAX_TOOL_CALLS: usize = 64;
+
+// Indices come from upstream and grow one call at a time, so a fragment that
+// skips ahead (or past MAX_TOOL_CALLS) is dropped rather than trusted to size
+// the list.
+fn merge_tool_call(calls: &mut Vec<ToolCall>, delta: ToolCallDelta) {
+  if delta.index > calls.len() || delta.index >= MAX_TOOL_CALLS {
+    return;
+  }
+  if delta.index == calls.len() {
+    calls.push(ToolCall {
+      r#type: "function".to_string(),
+      ..Default::default()
+    });
+  }
+  let call = &mut calls[delta.index];
+  if let Some(id) = delta.id {
+    call.id = id;
+  }
+  if let Some(name) = delta.name {
+    call.function.name.push_str(&name);
+  }
+  call.function.arguments.push_str(&delta.arguments);
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent {
+  Delta(String),
+  // An image the model produced, as a data URL or link.
+  Image(String),
+  // Model "thinking" text; shown to the user but never stored in history.
+  Reasoning(String),
+  ToolCall(ToolCallDelta),
+  Usage(Usage),
+  Finish(String),
+  Error(String),
+  Done,
+}
+
+fn parse_openrouter_data(data: &str) -> Vec<StreamEvent> {
+  if data == "[DONE]" {
+    return vec![StreamEvent::Done];
+  }
+
+  let mut events = Vec::new();
+  if let Ok(value) = serde_json::from_str::<serde_json::Value>(data) {
+    if let Some(reason) = value["choices"][0]["finish_reason"].as_str() {
+      events.push(StreamEvent::Finish(reason.to_string()));
+    }
+    if let Some(usage) = openrouter_usage(&value) {
+      events.push(StreamEvent::Usage(usage));
+    }
+
+    if let Some(reasoning) = value["choices"][0]["delta"]["reasoning"].as_str() {
+      if !reasoning.is_empty() {
+        events.push(StreamEvent::Reasoning(reasoning.to_string()));
+      }
+    }
+    if let Some(delta) = value["choices"][0]["delta"]["content"].as_str() {
+      if !delta.is_empty() {
+        events.push(StreamEvent::Delta(delta.to_string()));
+      }
+    }
+    for url in openrouter_image_urls(&value["choices"][0]["delta"]) {
+      events.push(StreamEvent::Image(url));
+    }
+    if let Some(tool_calls) = value["choices"][0]["delta"]["tool_calls"].as_array() {
+      for (position, fragment) in tool_calls.iter().enumerate() {
+        events.push(StreamEvent::ToolCall(ToolCallDelta {
+          index: fragment["index"].as_u64().map_or(position, |index| index as usize),
+          id: fragment["id"].as_str().map(str::to_string),
+          name: fragment["function"]["name"].as_str().map(str::to_string),
+          arguments: fragment["function"]["arguments"].as_str().unwrap_or("").to_string(),
+        }));
+      }
+    }
+  }
+  events
+}
+
+// Image models put `{"type": "image_url", "image_url": {"url": ...}}` entries
+// in `images` on the message (or on a stream delta).
+fn openrouter_image_urls(message: &serde_json::Value) -> Vec<String> {
+  let Some(images) = message["images"].as_array() else {
+    return Vec::new();
+  };
+  images
+    .iter()
+    .filter_map(|image| image["image_url"]["url"].as_str().or(image["url"].as_str()))
+    .filter(|url| !url.is_empty())
+    .map(str::to_string)
+    .collect()
+}
+
+// Inline images are saved with the chat attachments; links are kept as they
+// are. None for a data URL that could not be saved.
+fn keep_output_image(state: &RouterState, req: &ChatRequest, url: &str) -> Option<OutputImage> {
+  if req.skips_history() {
+    return None;
+  }
+  let Some(data) = url.strip_prefix("data:") else {
+    return Some(OutputImage::Linked { url: url.to_string() });
+  };
+  let Some((mime, base64)) = data.split_once(";base64,") else {
+    log_chat(state, req, "WARN", "generated image not kept in history: not a base64 data URL");
+    return None;
+  };
+  let image = ImageData {
+    mime: mime.to_string(),
+    base64: base64.to_string(),
+  };
+  match storage::save_images(&state.images_dir, &[&image]) {
+    Ok(mut stored) => stored.pop().map(OutputImage::Stored),
+    Err(err) => {
+      log_chat(state, req, "WARN", &format!("generated image not kept in history: {err}"));
+      None
+    }
+  }
+}
+
+// The `image` event and the non-streaming `images` entries: the URL as the
+// model sent it, plus the stored id when it was saved.
+fn image_payload(url: &str, kept: Option<&OutputImage>) -> serde_json::Value {
+  let mut payload = serde_json::json!({ "url": url });
+  if let Some(OutputImage::Stored(image)) = kept {
+    payload["id"] = image.id.clone().into();
+  }
+  payload
+}
+
+fn openrouter_usage(body: &serde_json::Value) -> Option<Usage> {
+  let prompt = body["usage"]["prompt_tokens"].as_i64()?;
+  let completion = body["usage"]["completion_tokens"].as_i64()?;
+  Some(Usage::new(prompt, completion))
+}
+
+// OpenRouter and Anthropic stream SSE blocks; Ollama streams one JSON object per line.
+fn drain_stream_events(provider: Provider, buffer: &mut String) -> Vec<StreamEvent> {
+  let mut events = Vec::new();
+  if provider == Provider::Ollama {
+    while let Some(newline) = buffer.find('\n') {
+      let line: String = buffer.drain(..newline + 1).collect();
+      if !line.trim().is_empty() {
+        events.extend(ollama::parse_stream_line(line.trim()));
+      }
+    }
+    return events;
+  }
+
+  while let Some(boundary) = buffer.find("\n\n") {
+    let block: String = buffer.drain(..boundary + 2).collect();
+    for line in block.lines() {
+      if let Some(data) = line.strip_prefix("data:") {
+        let data = data.trim();
+        events.extend(match provider {
+          Provider::OpenRouter => parse_openrouter_data(data),
+          Provider::Anthropic => anthropic::parse_stream_data(data),
+          Provider::Ollama => Vec::new(),
+        });
+      }
+    }
+  }
+  events
+}
+
+fn elapsed_ms(started: Instant) -> i64 {
+  started.elapsed().as_millis() as i64
+}
+
+// Stores `reply` with the request side of the row filled in from `req`.
+async fn insert_history(
+  state: &RouterState,
+  req: &ChatRequest,
+  target: &ChatTarget,
+  reply: storage::HistoryEntry<'_>,
+) -> anyhow::Result<String> {
+  let attached = req.all_images();
+  let images = storage::save_images(&state.images_dir, &attached).unwrap_or_else(|err| {
+    log_chat(state, req, "WARN", &format!("chat images not stored: {err}"));
+    Vec::new()
+  });
+  if images.len() < attached.len() {
+    let skipped = attached.len() - images.len();
+    log_chat(state, req, "WARN", &format!("{skipped} chat image(s) not kept in history"));
+  }
+  // Local chats stay on the machine, so they are never sent out to be embedded.
+  let finished = (!reply.partial && !target.provider.is_local()).then(|| history_text(&req.messages, reply.assistant));
+  let entry = storage::HistoryEntry {
+    messages: &req.messages,
+    cost_usd: cost_usd(state, &target.model_id, reply.usage),
+    model: &target.model_id,
+    provider: target.provider.name(),
+    thread_id: req.thread_id.as_deref(),
+    images: &images,
+    ..reply
+  };
+  let id = storage::store_history(&state.db, entry).await?;
+  if let Some(text) = finished {
+    embed_in_background(state, "history", id.clone(), text).await;
+  }
+  Ok(id)
+}
+
+const CHECKPOINT_DELTAS: usize = 50;
+
+// A streamed reply's history row. With checkpoints on, the row is inserted
+// up front as partial and rewritten as text arrives, so a crash leaves what
+// was generated; otherwise it is written once when the stream ends.
+struct StreamHistory<'a> {
+  state: &'a RouterState,
+  req: &'a ChatRequest,
+  target: &'a ChatTarget,
+  id: Option<String>,
+  every: Duration,
+  last_write: Instant,
+  deltas: usize,
+  images: Vec<OutputImage>,
+}
+
+impl<'a> StreamHistory<'a> {
+  async fn begin(state: &'a RouterState, req: &'a ChatRequest, target: &'a ChatTarget, every: Duration) -> Self {
+    let mut history = Self {
+      state,
+      req,
+      target,
+      id: None,
+      every,
+      last_write: Instant::now(),
+      deltas: 0,
+      images: Vec::new(),
+    };
+    if !every.is_zero() && !req.skips_history() {
+      let placeholder = storage::HistoryEntry {
+        partial: true,
+        ..Default::default()
+      };
+      match insert_history(state, req, target, placeholder).await {
+        Ok(id) => history.id = Some(id),
+        Err(err) => log_chat(state, req, "WARN", &format!("partial history not stored: {err}")),
+      }
+    }
+    history
+  }
+
+  // Keeps an image for the row's next write and returns what was kept.
+  fn on_image(&mut self, url: &str) -> Option<&OutputImage> {
+    let kept = keep_output_image(self.state, self.req, url)?;
+    self.images.push(kept);
+    self.images.last()
+  }
+
+  // Counts one delta; true once a checkpoint is due.
+  fn on_delta(&mut self) -> bool {
+    self.deltas += 1;
+    self.id.is_some() && (self.deltas >= CHECKPOINT_DELTAS || self.last_write.elapsed() >= self.every)
+  }
+
+  // Also used when a stream fails, so the row keeps what arrived but stays partial.
+  async fn checkpoint(&mut self, text: &str, tool_calls: &[ToolCall], usage: Option<&Usage>, latency_ms: i64) {
+    if self.id.is_none() {
+      return;
+    }
+    if let Err(err) = self.write(text, tool_calls, usage, latency_ms, true).await {
+      log_chat(self.state, self.req, "WARN", &format!("history checkpoint failed: {err}"));
+    }
+  }
+
+  // The stream's last write; `partial` when it was cut short (a timeout or a
+  // cancel), so the row is not mistaken for a complete reply.
+  async fn finish(
+    &mut self,
+    text: &str,
+    tool_calls: &[ToolCall],
+    usage: Option<&Usage>,
+    latency_ms: i64,
+    partial: bool,
+  ) -> anyhow::Result<()> {
+    self.write(text, tool_calls, usage, latency_ms, partial).await
+  }
+
+  async fn write(
+    &mut self,
+    text: &str,
+    tool_calls: &[ToolCall],
+    usage: Option<&Usage>,
+    latency_ms: i64,
+    partial: bool,
+  ) -> anyhow::Result<()> {
+    if self.req.skips_history() {
+      return Ok(());
+    }
+    let reply = storage::HistoryEntry {
+      messages: &self.req.messages,
+      assistant: text,
+      usage,
+      cost_usd: cost_usd(self.state, &self.target.model_id, usage),
+      latency_ms: Some(latency_ms),
+      tool_calls,
+      output_images: &self.images,
+      partial,
+      ..Default::default()
+    };
+    self.deltas = 0;
+    self.last_write = Instant::now();
+    match &self.id {
+      Some(id) => {
+        storage::update_history(&self.state.db, id, reply).await?;
+        if !partial && !self.target.provider.is_local() {
+          let text = history_text(&self.req.messages, text);
+          embed_in_background(self.state, "history", id.clone(), text).await;
+        }
+        Ok(())
+      }
+      None => insert_history(self.state, self.req, self.target, reply).await.map(|_| ()),
+    }
+  }
+}
+
+// What a stream has produced so far. Dropping the stream (axum does when the
+// client disconnects) also drops the upstream body, which ends that request;
+// if the reply had not settled by then, the drop records what had arrived.
+struct StreamReply {
+  state: Arc<RouterState>,
+  req: Arc<ChatRequest>,
+  target: Arc<ChatTarget>,
+  request_id: String,
+  started: Instant,
+  history_id: Option<String>,
+  text: String,
+  tool_calls: Vec<ToolCall>,
+  usage: Option<Usage>,
+  // Set once the stream has written its own history row.
+  settled: bool,
+}
+
+impl Drop for StreamReply {
+  fn drop(&mut self) {
+    if self.settled {
+      return;
+    }
+    let latency_ms = elapsed_ms(self.started);
+    self.state.logger.log_fields(
+      "INFO",
+      "aborted_by_client",
+      serde_json::json!({
+        "request_id": self.request_id,
+        "model": self.target.model_id,
+        "latency_ms": latency_ms,
+        "chars": self.text.len()
+      }),
+    );
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+      return;
+    };
+    let (state, req, target) = (self.state.clone(), self.req.clone(), self.target.clone());
+    let history_id = self.history_id.take();
+    let text = std::mem::take(&mut self.text);
+    let tool_calls = std::mem::take(&mut self.tool_calls);
+    let usage = self.usage;
+    runtime.spawn(async move {
+      let entry = storage::HistoryEntry {
+        messages: &req.messages,
+        assistant: &text,
+        usage: usage.as_ref(),
+        cost_usd: cost_usd(&state, &target.model_id, usage.as_ref()),
+        latency_ms: Some(latency_ms),
+        tool_calls: &tool_calls,
+        partial: true,
+        ..Default::default()
+      };
+      let stored = match history_id {
+        _ if req.skips_history() => Ok(()),
+        Some(id) => storage::update_history(&state.db, &id, entry).await,
+        None => insert_history(&state, &req, &target, entry).await.map(|_| ()),
+      };
+      if let Err(err) = stored {
+        log_chat(&state, &req, "WARN", &format!("aborted chat history not stored: {err}"));
+      }
+      let audited = Some((target.model_id.as_str(), target.provider));
+      audit_call(&state, &req, audited, latency_ms, "cancelled", Some("aborted_by_client"), usage.as_ref()).await;
+    });
+  }
+}
+
+// Chat log lines carry the request id, which clients also get, so interleaved
+// chats can be told apart.
+fn log_chat(state: &RouterState, req: &ChatRequest, level: &str, message: &str) {
+  state.logger.log_fields(level, message, serde_json::json!({ "request_id": req.request_id }));
+}
+
+fn log_completion(
+  state: &RouterState,
+  req: &ChatRequest,
+  target: &ChatTarget,
+  latency_ms: i64,
+  finish_reason: &str,
+  usage: Option<&Usage>,
+) {
+  state.metrics.record_completion(target.provider, latency_ms);
+  state.logger.log_fields(
+    "INFO",
+    "chat complete",
+    serde_json::json!({
+      "request_id": req.request_id,
+      "model": target.model_id,
+      "provider": target.provider.name(),
+      "fell_back": target.fell_back(),
+      "chain_index": target.chain_index,
+      "finish_reason": finish_reason,
+      "latency_ms": latency_ms,
+      "usage": usage
+    }),
+  );
+}
+
+struct ChatFrame {
+  event: &'static str,
+  data: serde_json::Value,
+  // Deltas are numbered from 1 so a reconnect can name the last one it got.
+  id: Option<u64>,
+}
+
+impl ChatFrame {
+  fn new(event: &'static str, data: serde_json::Value) -> Self {
+    Self { event, data, id: None }
+  }
+
+  fn with_id(self, id: u64) -> Self {
+    Self { id: Some(id), ..self }
+  }
+
+  fn into_event(self) -> Event {
+    let event = Event::default().event(self.event).data(self.data.to_string());
+    match self.id {
+      Some(id) => event.id(id.to_string()),
+      None => event,
+    }
+  }
+
+  // WebSocket messages carry the SSE event name as `type` next to the payload.
+  fn into_ws_text(self) -> String {
+    let mut message = match self.data {
+      serde_json::Value::Object(fields) => fields,
+      other => serde_json::Map::from_iter([("data".to_string(), other)]),
+    };
+    message.insert("type".to_string(), self.event.into());
+    if let Some(id) = self.id {
+      message.insert("id".to_string(), id.into());
+    }
+    serde_json::Value::Object(message).to_string()
+  }
+}
+
+// `full` is exactly what insert_history stored, so simple clients can skip
+// accumulating deltas; `text` carries the same string for existing clients.
+fn done_payload(finish_reason: &str, latency_ms: i64, text: &str) -> serde_json::Value {
+  serde_json::json!({ "finish_reason": finish_reason, "latency_ms": latency_ms, "full": text, "text": text })
+}
+
+// What woke the stream loop.
+enum Wake<T> {
+  Upstream(Result<Option<T>, tokio::time::error::Elapsed>),
+  Ping,
+  Cancelled,
+}
+
+fn ping_timer(every: Duration) -> Option<tokio::time::Interval> {
+  if every.is_zero() {
+    return None;
+  }
+  // interval_at so the first ping waits a full period instead of firing at once.
+  let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  Some(interval)
+}
+
+async fn next_ping(ping: &mut Option<tokio::time::Interval>) {
+  match ping {
+    Some(interval) => {
+      interval.tick().await;
+    }
+    None => std::future::pending().await,
+  }
+}
+
+async fn stream_chat(
+  state: Arc<RouterState>,
+  req: ChatRequest,
+  target: ChatTarget,
+  key: String,
+  started: Instant,
+  permit: Arc<OwnedSemaphorePermit>,
+) -> Result<impl tokio_stream::Stream<Item = ChatFrame>, UpstreamError> {
+  let resp = send_upstream(&state, &target, &key, &req, true).await?;
+  let request_id = req.request_id.clone();
+  let mut bytes_stream = resp.bytes_stream();
+  let (idle_timeout, ping_every, checkpoint_every, resume_grace) = {
+    let config = state.config.read().await;
+    (
+      Duration::from_secs(config.request_timeout_secs),
+      Duration::from_secs(config.ping_interval_secs),
+      Duration::from_secs(config.history_checkpoint_secs),
+      Duration::from_secs(config.resume_grace_secs),
+    )
+  };
+
+  let (cancel_tx, mut cancel_rx) = oneshot::channel::<()>();
+  state.inflight.insert(request_id.clone(), cancel_tx);
+  let resume = state.resume.open(&request_id);
+  let guard = InflightGuard {
+    state: state.clone(),
+    request_id: request_id.clone(),
+  };
+  let req = Arc::new(req);
+  let target = Arc::new(target);
+  let mut reply = StreamReply {
+    state: state.clone(),
+    req: req.clone(),
+    target: target.clone(),
+    request_id: request_id.clone(),
+    started,
+    history_id: None,
+    text: String::new(),
+    tool_calls: Vec::new(),
+    usage: None,
+    settled: false,
+  };
+  let (pump_state, pump_id) = (state.clone(), request_id.clone());
+
+  let stream = stream! {
+    let _guard = guard;
+    let _permit = permit;
+    let provider = target.provider.name();
+    let audited = (target.model_id.as_str(), target.provider);
+    let meta = serde_json::json!({
+      "model": target.model_id,
+      "provider": provider,
+      "fell_back": target.fell_back(),
+      "chain_index": target.chain_index,
+      "request_id": request_id,
+      "thread_id": req.thread_id,
+      "stored": !req.skips_history()
+    });
+    yield ChatFrame::new("meta", meta);
+
+    let mut history = StreamHistory::begin(&state, &req, &target, checkpoint_every).await;
+    reply.history_id = history.id.clone();
+    let mut buffer = String::new();
+    let mut finish_reason = "stop".to_string();
+    let mut ping = ping_timer(ping_every);
+    // A deadline rather than a per-poll timeout, so pings don't keep resetting it.
+    let mut idle_deadline = tokio::time::Instant::now() + idle_timeout;
+
+    'read: loop {
+      // Biased so a cancel wins and pending upstream bytes go out before a ping.
+      let next = tokio::select! {
+        biased;
+        _ = &mut cancel_rx => Wake::Cancelled,
+        chunk = tokio::time::timeout_at(idle_deadline, bytes_stream.next()) => Wake::Upstream(chunk),
+        _ = next_ping(&mut ping) => Wake::Ping,
+      };
+      let chunk = match next {
+        Wake::Upstream(Ok(Some(chunk))) => chunk,
+        Wake::Upstream(Ok(None)) => break 'read,
+        Wake::Ping => {
+          let payload = serde_json::json!({ "elapsed_ms": elapsed_ms(started) });
+          yield ChatFrame::new("ping", payload);
+          continue 'read;
+        }
+        Wake::Upstream(Err(_)) => {
+          log_chat(&state, &req, "WARN", &format!("chat stalled for {}s", idle_timeout.as_secs()));
+          state.metrics.record_error("stream_timeout");
+          let latency_ms = elapsed_ms(started);
+          let usage = reply.usage.as_ref();
+          let _ = history.finish(&reply.text, &reply.tool_calls, usage, latency_ms, true).await;
+          reply.settled = true;
+          audit_call(&state, &req, Some(audited), latency_ms, "timeout", None, reply.usage.as_ref()).await;
+          let done = done_payload("timeout", latency_ms, &reply.text);
+          resume.finish(&done);
+          yield ChatFrame::new("done", done);
+          return;
+        }
+        Wake::Cancelled => {
+          log_chat(&state, &req, "INFO", "chat cancelled by client");
+          let latency_ms = elapsed_ms(started);
+          let usage = reply.usage.as_ref();
+          let _ = history.finish(&reply.text, &reply.tool_calls, usage, latency_ms, true).await;
+          reply.settled = true;
+          audit_call(&state, &req, Some(audited), latency_ms, "cancelled", None, reply.usage.as_ref()).await;
+          let done = done_payload("cancelled", latency_ms, &reply.text);
+          resume.finish(&done);
+          yield ChatFrame::new("done", done);
+          return;
+        }
+      };
+      let chunk = match chunk {
+        Ok(c) => c,
+        Err(err) => {
+          state.metrics.record_error("stream_error");
+          let latency_ms = elapsed_ms(started);
+          history.checkpoint(&reply.text, &reply.tool_calls, reply.usage.as_ref(), latency_ms).await;
+          reply.settled = true;
+          let usage = reply.usage.as_ref();
+          audit_call(&state, &req, Some(audited), latency_ms, "error", Some("stream_error"), usage).await;
+          let done = serde_json::json!({ "finish_reason": "error", "error": err.to_string() });
+          resume.finish(&done);
+          yield ChatFrame::new("done", done);
+          return;
+        }
+      };
+
+      idle_deadline = tokio::time::Instant::now() + idle_timeout;
+      buffer.push_str(&String::from_utf8_lossy(&chunk));
+      let mut checkpoint_due = false;
+      for event in drain_stream_events(target.provider, &mut buffer) {
+        match event {
+          StreamEvent::Delta(text) => {
+            reply.text.push_str(&text);
+            checkpoint_due |= history.on_delta();
+            let id = resume.delta(&text);
+            let payload = serde_json::json!({ "text": text });
+            yield ChatFrame::new("delta", payload).with_id(id);
+          }
+          StreamEvent::Image(url) => {
+            let payload = image_payload(&url, history.on_image(&url));
+            checkpoint_due |= history.id.is_some();
+            yield ChatFrame::new("image", payload);
+          }
+          StreamEvent::Reasoning(text) => {
+            let payload = serde_json::json!({ "text": text });
+            yield ChatFrame::new("reasoning", payload);
+          }
+          StreamEvent::ToolCall(delta) => merge_tool_call(&mut reply.tool_calls, delta),
+          StreamEvent::Usage(reported) => {
+            reply.usage = Some(reply.usage.map_or(reported, |current| current.merge(reported)));
+          }
+          StreamEvent::Finish(reason) => finish_reason = reason,
+          StreamEvent::Error(message) => {
+            log_chat(&state, &req, "ERROR", &format!("{} stream error: {}", target.provider.label(), message));
+            state.metrics.record_error("stream_error");
+            let latency_ms = elapsed_ms(started);
+            history.checkpoint(&reply.text, &reply.tool_calls, reply.usage.as_ref(), latency_ms).await;
+            reply.settled = true;
+            let usage = reply.usage.as_ref();
+            audit_call(&state, &req, Some(audited), latency_ms, "error", Some("stream_error"), usage).await;
+            let done = serde_json::json!({ "finish_reason": "error", "error": message });
+            resume.finish(&done);
+            yield ChatFrame::new("done", done);
+            return;
+          }
+          StreamEvent::Done => break 'read,
+        }
+      }
+      if checkpoint_due {
+        history.checkpoint(&reply.text, &reply.tool_calls, reply.usage.as_ref(), elapsed_ms(started)).await;
+      }
+    }
+
+    let latency_ms = elapsed_ms(started);
+    let _ = history.finish(&reply.text, &reply.tool_calls, reply.usage.as_ref(), latency_ms, false).await;
+    reply.settled = true;
+    log_completion(&state, &req, &target, latency_ms, &finish_reason, reply.usage.as_ref());
+    audit_call(&state, &req, Some(audited), latency_ms, "ok", None, reply.usage.as_ref()).await;
+    // Emitted once assembled: clients cannot act on half-streamed arguments.
+    for call in &reply.tool_calls {
+      let payload = serde_json::json!(call);
+      yield ChatFrame::new("tool_call", payload);
+    }
+    if let Some(usage) = reply.usage {
+      let payload = serde_json::json!(usage);
+      yield ChatFrame::new("usage", payload);
+    }
+    let mut done = done_payload(&finish_reason, latency_ms, &reply.text);
+    done["cost_usd"] = serde_json::json!(cost_usd(&state, &target.model_id, reply.usage.as_ref()));
+    resume.finish(&done);
+    yield ChatFrame::new("done", done);
+  };
+
+  Ok(outlive_client(pump_state, pump_id, resume_grace, Box::pin(stream)))
+}
+
+enum Pump {
+  Frame(Option<ChatFrame>),
+  ClientGone,
+  GraceOver,
+}
+
+// Runs a stream in its own task so it can outlive its client. Once the client
+// goes away, upstream is still read (into the resume buffer) for `grace`, and
+// for as long as a chat_resume follows it; after that the stream is dropped,
+// which abandons upstream and records the partial reply.
+fn outlive_client(
+  state: Arc<RouterState>,
+  request_id: String,
+  grace: Duration,
+  mut frames: ChatFrames,
+) -> impl tokio_stream::Stream<Item = ChatFrame> {
+  let (tx, rx) = tokio::sync::mpsc::channel(64);
+  tokio::spawn(async move {
+    let mut client = Some(tx);
+    let mut deadline = None;
+    loop {
+      let wake = tokio::select! {
+        biased;
+        _ = client_closed(client.as_ref()) => Pump::ClientGone,
+        _ = grace_over(deadline) => Pump::GraceOver,
+        frame = frames.next() => Pump::Frame(frame),
+      };
+      match wake {
+        Pump::Frame(Some(frame)) => {
+          let Some(tx) = &client else {
+            continue;
+          };
+          if tx.send(frame).await.is_ok() {
+            continue;
+          }
+        }
+        Pump::Frame(None) => break,
+        Pump::ClientGone => {}
+        Pump::GraceOver if state.resume.is_followed(&request_id) => {}
+        Pump::GraceOver => break,
+      }
+      if client.take().is_some() && !grace.is_zero() {
+        let msg = format!("chat {request_id} client gone; resumable for {}s", grace.as_secs());
+        state.logger.log("INFO", &msg);
+      }
+      deadline = Some(tokio::time::Instant::now() + grace);
+    }
+  });
+  tokio_stream::wrappers::ReceiverStream::new(rx)
+}
+
+async fn client_closed(client: Option<&tokio::sync::mpsc::Sender<ChatFrame>>) {
+  match client {
+    Some(tx) => tx.closed().await,
+    None => std::future::pending().await,
+  }
+}
+
+async fn grace_over(deadline: Option<tokio::time::Instant>) {
+  match deadline {
+    Some(at) => tokio::time::sleep_until(at).await,
+    None => std::future::pending().await,
+  }
+}
+
+const DRY_RUN: &str = "dryrun";
+const DRY_RUN_WORD_DELAY: Duration = Duration::from_millis(40);
+
+fn dry_run_reply(messages: &[Message]) -> String {
+  let last_user = messages.iter().rev().find(|m| m.role == "user");
+  format!("Echo: {}", last_user.map_or("", |m| m.content.as_str()))
+}
+
+async fn store_dry_run(state: &RouterState, req: &ChatRequest, reply: &str, latency_ms: i64, partial: bool) {
+  if req.skips_history() {
+    return;
+  }
+  let entry = storage::HistoryEntry {
+    messages: &req.messages,
+    assistant: reply,
+    model: DRY_RUN,
+    provider: DRY_RUN,
+    thread_id: req.thread_id.as_deref(),
+    latency_ms: Some(latency_ms),
+    partial,
+    ..Default::default()
+  };
+  if let Err(err) = storage::store_history(&state.db, entry).await {
+    log_chat(state, req, "WARN", &format!("dry run history not stored: {err}"));
+  }
+}
+
+// Mirrors the real event sequence so the UI can be exercised offline.
+async fn dry_run_chat(
+  state: Arc<RouterState>,
+  req: ChatRequest,
+  started: Instant,
+  permit: OwnedSemaphorePermit,
+) -> ChatReply {
+  let reply = dry_run_reply(&req.messages);
+  if !req.stream.unwrap_or(true) {
+    let latency_ms = elapsed_ms(started);
+    store_dry_run(&state, &req, &reply, latency_ms, false).await;
+    let body = serde_json::json!({
+      "text": reply,
+      "model": DRY_RUN,
+      "provider": DRY_RUN,
+      "fell_back": false,
+      "chain_index": 0,
+      "request_id": req.request_id,
+      "thread_id": req.thread_id,
+      "stored": !req.skips_history(),
+      "usage": null,
+      "latency_ms": latency_ms
+    });
+    return ChatReply::Complete(body);
+  }
+
+  // Cancellable like a real stream, so clients can exercise their cancel path.
+  let request_id = req.request_id.clone();
+  let (cancel_tx, mut cancel_rx) = oneshot::channel::<()>();
+  state.inflight.insert(request_id.clone(), cancel_tx);
+  let resume = state.resume.open(&request_id);
+  let guard = InflightGuard {
+    state: state.clone(),
+    request_id: request_id.clone(),
+  };
+
+  let stream = stream! {
+    let _guard = guard;
+    let _permit = permit;
+    let meta = serde_json::json!({
+      "model": DRY_RUN,
+      "provider": DRY_RUN,
+      "fell_back": false,
+      "chain_index": 0,
+      "request_id": request_id,
+      "thread_id": req.thread_id,
+      "stored": !req.skips_history()
+    });
+    yield ChatFrame::new("meta", meta);
+
+    let mut sent = String::new();
+    let mut finish_reason = "stop";
+    for word in reply.split_inclusive(' ') {
+      tokio::select! {
+        biased;
+        _ = &mut cancel_rx => {
+          finish_reason = "cancelled";
+          break;
+        }
+        _ = tokio::time::sleep(DRY_RUN_WORD_DELAY) => {}
+      }
+      sent.push_str(word);
+      let id = resume.delta(word);
+      let payload = serde_json::json!({ "text": word });
+      yield ChatFrame::new("delta", payload).with_id(id);
+    }
+
+    let latency_ms = elapsed_ms(started);
+    store_dry_run(&state, &req, &sent, latency_ms, finish_reason != "stop").await;
+    let done = done_payload(finish_reason, latency_ms, &sent);
+    resume.finish(&done);
+    yield ChatFrame::new("done", done);
+  };
+  ChatReply::Frames(Box::pin(stream))
+}
+
+async fn complete_chat(
+  state: Arc<RouterState>,
+  req: ChatRequest,
+  target: ChatTarget,
+  key: String,
+  started: Instant,
+) -> Result<serde_json::Value, UpstreamError> {
+  let resp = send_upstream(&state, &target, &key, &req, false).await?;
+
+  let json_body = resp
+    .json::<serde_json::Value>()
+    .await
+    .map_err(|err| UpstreamError::Local(StatusCode::BAD_GATEWAY, err.to_string()))?;
+  let (content, usage) = match target.provider {
+    Provider::OpenRouter => (
+      json_body["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap_or("")
+        .to_string(),
+      openrouter_usage(&json_body),
+    ),
+    Provider::Anthropic => (anthropic::completion_text(&json_body), anthropic::usage(&json_body)),
+    Provider::Ollama => (ollama::completion_text(&json_body), ollama::usage(&json_body)),
+  };
+  check_json_output(&req, target.provider, &content)?;
+  let tool_calls: Vec<ToolCall> = match target.provider {
+    Provider::OpenRouter => {
+      serde_json::from_value(json_body["choices"][0]["message"]["tool_calls"].clone()).unwrap_or_default()
+    }
+    Provider::Anthropic | Provider::Ollama => Vec::new(),
+  };
+  let image_urls = match target.provider {
+    Provider::OpenRouter => openrouter_image_urls(&json_body["choices"][0]["message"]),
+    Provider::Anthropic | Provider::Ollama => Vec::new(),
+  };
+  let mut images = Vec::new();
+  let mut output_images = Vec::new();
+  for url in &image_urls {
+    let kept = keep_output_image(&state, &req, url);
+    images.push(image_payload(url, kept.as_ref()));
+    output_images.extend(kept);
+  }
+
+  let provider = target.provider.name();
+  let latency_ms = elapsed_ms(started);
+  let reply = storage::HistoryEntry {
+    assistant: &content,
+    usage: usage.as_ref(),
+    latency_ms: Some(latency_ms),
+    tool_calls: &tool_calls,
+    output_images: &output_images,
+    ..Default::default()
+  };
+  if !req.skips_history() {
+    insert_history(&state, &req, &target, reply)
+      .await
+      .map_err(|err| UpstreamError::Local(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+  }
+  log_completion(&state, &req, &target, latency_ms, "stop", usage.as_ref());
+  audit_call(&state, &req, Some((&target.model_id, target.provider)), latency_ms, "ok", None, usage.as_ref()).await;
+
+  Ok(serde_json::json!({
+    "text": content,
+    "tool_calls": tool_calls,
+    "images": images,
+    "model": target.model_id,
+    "provider": provider,
+    "fell_back": target.fell_back(),
+    "chain_index": target.chain_index,
+    "request_id": req.request_id,
+    "thread_id": req.thread_id,
+    "stored": !req.skips_history(),
+    "usage": usage,
+    "cost_usd": cost_usd(&state, &target.model_id, usage.as_ref()),
+    "latency_ms": latency_ms
+  }))
+}
+
+#[cfg(test)]
 mod tests {
   use super::*;
   use crate::config::AppConfig;
 
-  fn base_config() -> AppConfig {
-    AppConfig {
-      text_default_model: "openrouter:text-default".to_string(),
-      vision_default_model: "openrouter:vision-default".to_string(),
-      fallback_model: "openrouter:fallback".to_string(),
-      models: vec![],
+  fn base_config() -> AppConfig {
+    AppConfig {
+      text_default_model: "openrouter:text-default".to_string(),
+      vision_default_model: "openrouter:vision-default".to_string(),
+      fallback_model: "openrouter:fallback".to_string(),
+      models: vec![],
+      ..AppConfig::default()
+    }
+  }
+
+  fn test_state() -> Arc<RouterState> {
+    Arc::new(test_router_state())
+  }
+
+  fn test_router_state() -> RouterState {
+    let log_path = std::env::temp_dir().join(format!("halodesk-test-{}.log", uuid::Uuid::new_v4()));
+    RouterState {
+      started_at: Instant::now(),
+      config: Arc::new(RwLock::new(base_config())),
+      config_path: std::env::temp_dir().join(format!("halodesk-test-{}.json", uuid::Uuid::new_v4())),
+      db: Arc::new(Mutex::new(storage::init_db(std::path::Path::new(":memory:")).unwrap())),
+      logger: Arc::new(crate::logger::Logger::new(&log_path).unwrap()),
+      port: 0,
+      inflight: DashMap::new(),
+      http_client: build_http_client().unwrap(),
+      metrics: Metrics::default(),
+      chat_slots: Arc::new(Semaphore::new(1)),
+      token: "test-token".to_string(),
+      images_dir: std::env::temp_dir().join(format!("halodesk-test-images-{}", uuid::Uuid::new_v4())),
+      key_file: std::env::temp_dir().join(format!("halodesk-test-keys-{}.enc", uuid::Uuid::new_v4())),
+      rate_limits: RateLimiter::default(),
+      idempotency: IdempotencyCache::default(),
+      prices: Arc::new(PriceBook::default()),
+      resume: ResumeBuffers::default(),
+    }
+  }
+
+  #[test]
+  fn split_provider_with_prefix() {
+    let (provider, model) = split_provider("openrouter:openai/gpt-4o-mini");
+    assert_eq!(provider.name(), "openrouter");
+    assert_eq!(model, "openai/gpt-4o-mini");
+  }
+
+  #[test]
+  fn split_provider_without_prefix() {
+    let (provider, model) = split_provider("openai/gpt-4o-mini");
+    assert_eq!(provider.name(), "openrouter");
+    assert_eq!(model, "openai/gpt-4o-mini");
+  }
+
+  #[test]
+  fn split_provider_handles_colon_in_model() {
+    let (provider, model) = split_provider("nvidia/nemotron-3-nano-30b-a3b:free");
+    assert_eq!(provider.name(), "openrouter");
+    assert_eq!(model, "nvidia/nemotron-3-nano-30b-a3b:free");
+  }
+
+  #[test]
+  fn split_provider_recognizes_anthropic() {
+    let (provider, model) = split_provider("anthropic:claude-3-5-sonnet-latest");
+    assert_eq!(provider, Provider::Anthropic);
+    assert_eq!(model, "claude-3-5-sonnet-latest");
+  }
+
+  #[test]
+  fn drain_stream_events_keeps_partial_blocks() {
+    let mut buffer = String::from(
+      "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\ndata: {\"choices\":[{\"finish_reason\":\"stop\",\"delta\":{}}]}\n\ndata: [DO",
+    );
+    let events = drain_stream_events(Provider::OpenRouter, &mut buffer);
+    assert_eq!(
+      events,
+      vec![StreamEvent::Delta("Hi".to_string()), StreamEvent::Finish("stop".to_string())]
+    );
+    assert_eq!(buffer, "data: [DO");
+
+    buffer.push_str("NE]\n\n");
+    assert_eq!(drain_stream_events(Provider::OpenRouter, &mut buffer), vec![StreamEvent::Done]);
+  }
+
+  #[test]
+  fn drain_stream_events_translates_anthropic() {
+    let mut buffer = String::from(
+      "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Yo\"}}\n\nevent: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+    );
+    assert_eq!(
+      drain_stream_events(Provider::Anthropic, &mut buffer),
+      vec![StreamEvent::Delta("Yo".to_string()), StreamEvent::Done]
+    );
+  }
+
+  #[test]
+  fn split_provider_recognizes_ollama() {
+    let (provider, model) = split_provider("ollama:llama3.1:8b");
+    assert_eq!(provider, Provider::Ollama);
+    assert_eq!(model, "llama3.1:8b");
+  }
+
+  #[test]
+  fn drain_stream_events_splits_ollama_lines() {
+    let mut buffer = String::from(
+      "{\"message\":{\"content\":\"A\"},\"done\":false}\n{\"message\":{\"content\":\"\"},\"done\":true}\n{\"mess",
+    );
+    assert_eq!(
+      drain_stream_events(Provider::Ollama, &mut buffer),
+      vec![
+        StreamEvent::Delta("A".to_string()),
+        StreamEvent::Finish("stop".to_string()),
+        StreamEvent::Done
+      ]
+    );
+    assert_eq!(buffer, "{\"mess");
+  }
+
+  #[test]
+  fn resolve_model_uses_override() {
+    let config = base_config();
+    let req = ChatRequest {
+      model_override: Some("openrouter:override".to_string()),
+      stream: Some(true),
+      ..Default::default()
+    };
+
+    let resolved = resolve_model(&req, &config, None).expect("override should resolve");
+    assert_eq!(resolved, "openrouter:override");
+  }
+
+  #[test]
+  fn resolve_model_uses_vision_default_when_image_present() {
+    let config = base_config();
+    let req = ChatRequest {
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "abc".to_string(),
+      }),
+      stream: Some(true),
+      ..Default::default()
+    };
+
+    let resolved = resolve_model(&req, &config, None).expect("vision default should resolve");
+    assert_eq!(resolved, "openrouter:vision-default");
+  }
+
+  #[test]
+  fn resolve_model_uses_text_default_without_image() {
+    let config = base_config();
+    let req = ChatRequest {
+      stream: Some(true),
+      ..Default::default()
+    };
+
+    let resolved = resolve_model(&req, &config, None).expect("text default should resolve");
+    assert_eq!(resolved, "openrouter:text-default");
+  }
+
+  #[test]
+  fn resolve_model_uses_preset_routing_policy() {
+    let config = base_config();
+    let policy = RoutingPolicy {
+      model: Some("anthropic:claude-3-5-sonnet-latest".to_string()),
+      vision_model: None,
+    };
+    let req = ChatRequest::default();
+    let resolved = resolve_model(&req, &config, Some(&policy)).unwrap();
+    assert_eq!(resolved, "anthropic:claude-3-5-sonnet-latest");
+
+    let req = ChatRequest {
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "abc".to_string(),
+      }),
+      ..Default::default()
+    };
+    let resolved = resolve_model(&req, &config, Some(&policy)).unwrap();
+    assert_eq!(resolved, "openrouter:vision-default");
+  }
+
+  #[test]
+  fn resolve_model_prefers_override_then_preset_then_config() {
+    let config = base_config();
+    let policy: RoutingPolicy =
+      serde_json::from_str(r#"{"text_model": "ollama:llama3", "vision_model": "ollama:llava"}"#).unwrap();
+    let text = ChatRequest::default();
+    let vision = ChatRequest {
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "abc".to_string(),
+      }),
+      ..Default::default()
+    };
+    let overridden = ChatRequest {
+      model_override: Some("openrouter:override".to_string()),
+      ..vision.clone()
+    };
+
+    assert_eq!(resolve_model(&overridden, &config, Some(&policy)).unwrap(), "openrouter:override");
+    assert_eq!(resolve_model(&text, &config, Some(&policy)).unwrap(), "ollama:llama3");
+    assert_eq!(resolve_model(&vision, &config, Some(&policy)).unwrap(), "ollama:llava");
+    assert_eq!(resolve_model(&text, &config, None).unwrap(), "openrouter:text-default");
+    assert_eq!(resolve_model(&vision, &config, None).unwrap(), "openrouter:vision-default");
+  }
+
+  fn user_hi() -> Vec<Message> {
+    vec![Message {
+      role: "user".to_string(),
+      content: "Hi".to_string(),
+      ..Default::default()
+    }]
+  }
+
+  #[test]
+  fn prepend_system_prompts_adds_them_once() {
+    let mut messages = user_hi();
+    prepend_system_prompts(&mut messages, None, Some("Be brief."));
+    prepend_system_prompts(&mut messages, None, Some("Be brief."));
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].role, "system");
+    assert_eq!(messages[0].content, "Be brief.");
+    assert_eq!(messages[1].role, "user");
+  }
+
+  #[test]
+  fn prepend_system_prompts_uses_global_prompt_without_preset() {
+    let mut messages = user_hi();
+    prepend_system_prompts(&mut messages, Some("Use markdown."), None);
+    let pairs: Vec<_> = messages.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+    assert_eq!(pairs, vec![("system", "Use markdown."), ("user", "Hi")]);
+
+    let mut messages = user_hi();
+    prepend_system_prompts(&mut messages, Some("  "), None);
+    assert_eq!(messages.len(), 1);
+  }
+
+  #[test]
+  fn prepend_system_prompts_puts_global_before_preset() {
+    let mut messages = user_hi();
+    prepend_system_prompts(&mut messages, Some("Use markdown."), Some("Review the code."));
+    let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["Use markdown.", "Review the code.", "Hi"]);
+    assert_eq!(messages[1].role, "system");
+
+    let mut messages = vec![Message {
+      role: "system".to_string(),
+      content: "Client prompt.".to_string(),
+      ..Default::default()
+    }];
+    prepend_system_prompts(&mut messages, Some("Use markdown."), Some("Review the code."));
+    assert_eq!(messages.len(), 1);
+  }
+
+  #[test]
+  fn parse_openrouter_models_derives_capability() {
+    let body = serde_json::json!({
+      "data": [
+        { "id": "openai/gpt-4o", "name": "GPT-4o", "architecture": { "input_modalities": ["text", "image"] } },
+        { "id": "meta/llama", "name": "Llama", "architecture": { "modality": "text->text" } },
+        { "id": "old/vision", "architecture": { "modality": "text+image->text" } }
+      ]
+    });
+    let models = parse_openrouter_models(&body);
+    assert_eq!(models.len(), 3);
+    assert_eq!(models[0].id, "openrouter:openai/gpt-4o");
+    assert_eq!(models[0].capability, "vision");
+    assert_eq!(models[1].capability, "text");
+    assert_eq!(models[2].label, "old/vision");
+    assert_eq!(models[2].capability, "vision");
+  }
+
+  #[test]
+  fn merge_models_keeps_existing_entries() {
+    let mut existing = AppConfig::default().models;
+    let count = existing.len();
+    let mut fetched = existing.clone();
+    fetched[0].label = "Renamed".to_string();
+    fetched.push(ModelInfo {
+      id: "openrouter:new/model".to_string(),
+      label: "New".to_string(),
+      capability: "text".to_string(),
+      rate_limit_rpm: None,
+    });
+    assert_eq!(merge_models(&mut existing, fetched), 1);
+    assert_eq!(existing.len(), count + 1);
+    assert_ne!(existing[0].label, "Renamed");
+  }
+
+  #[test]
+  fn should_retry_only_transient_failures() {
+    assert!(should_retry(&UpstreamError::Transport("reset".to_string())));
+    for code in [429, 500, 502, 503] {
+      let status = StatusCode::from_u16(code).unwrap();
+      assert!(should_retry(&UpstreamError::Status(status, String::new())), "{code}");
+    }
+    for code in [400, 401, 403, 404] {
+      let status = StatusCode::from_u16(code).unwrap();
+      assert!(!should_retry(&UpstreamError::Status(status, String::new())), "{code}");
+    }
+    assert!(!should_retry(&UpstreamError::Local(StatusCode::BAD_REQUEST, String::new())));
+  }
+
+  #[test]
+  fn retry_delay_doubles_each_attempt() {
+    assert_eq!(retry_delay(0).as_millis(), 250);
+    assert_eq!(retry_delay(1).as_millis(), 500);
+    assert_eq!(retry_delay(2).as_millis(), 1000);
+  }
+
+  #[test]
+  fn build_payload_omits_unset_params() {
+    let req = ChatRequest::default();
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", true)).unwrap();
+    assert!(payload.get("temperature").is_none());
+    assert!(payload.get("max_tokens").is_none());
+    assert!(payload.get("top_p").is_none());
+  }
+
+  #[test]
+  fn build_payload_requests_usage_when_streaming() {
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", true)).unwrap();
+    assert_eq!(payload["stream_options"]["include_usage"], true);
+
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", false)).unwrap();
+    assert!(payload.get("stream_options").is_none());
+  }
+
+  #[test]
+  fn parse_openrouter_data_reads_usage_chunk() {
+    let data = r#"{"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":5,"total_tokens":15}}"#;
+    assert_eq!(parse_openrouter_data(data), vec![StreamEvent::Usage(Usage::new(10, 5))]);
+  }
+
+  #[test]
+  fn parse_openrouter_data_emits_text_then_images() {
+    let data = r#"{"choices":[{"delta":{"content":"Here you go","images":[
+      {"type":"image_url","image_url":{"url":"data:image/png;base64,iVBORw0KGgo="}}]}}]}"#;
+    assert_eq!(
+      parse_openrouter_data(data),
+      vec![
+        StreamEvent::Delta("Here you go".to_string()),
+        StreamEvent::Image("data:image/png;base64,iVBORw0KGgo=".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn output_images_are_saved_or_linked() {
+    let state = test_state();
+    let req = ChatRequest::default();
+    let url = "data:image/png;base64,iVBORw0KGgo=";
+    let kept = keep_output_image(&state, &req, url);
+    let Some(OutputImage::Stored(image)) = &kept else {
+      panic!("expected a stored image, got {kept:?}");
+    };
+    assert_eq!(image.mime, "image/png");
+    assert!(state.images_dir.join(&image.id).exists());
+    assert_eq!(image_payload(url, kept.as_ref())["id"], image.id.as_str());
+
+    let link = "https://cdn.example/cat.png";
+    let kept = keep_output_image(&state, &req, link);
+    assert_eq!(kept, Some(OutputImage::Linked { url: link.to_string() }));
+    assert_eq!(image_payload(link, kept.as_ref()), serde_json::json!({ "url": link }));
+
+    assert_eq!(keep_output_image(&state, &req, "data:text/plain;base64,aGk="), None);
+    let _ = std::fs::remove_dir_all(&state.images_dir);
+  }
+
+  #[test]
+  fn parse_openrouter_data_separates_reasoning() {
+    let data = r#"{"choices":[{"delta":{"role":"assistant","content":"","reasoning":"Let me think."}}]}"#;
+    assert_eq!(
+      parse_openrouter_data(data),
+      vec![StreamEvent::Reasoning("Let me think.".to_string())]
+    );
+
+    let data = r#"{"choices":[{"delta":{"content":"Answer","reasoning":null}}]}"#;
+    assert_eq!(parse_openrouter_data(data), vec![StreamEvent::Delta("Answer".to_string())]);
+  }
+
+  #[test]
+  fn parse_openrouter_data_assembles_tool_calls_by_index() {
+    let chunks = [
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"call_a","type":"function","function":{"name":"get_weather","arguments":""}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":1,"id":"call_b","type":"function","function":{"name":"get_time","arguments":"{}"}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":"}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"Oslo\"}"}}]},"finish_reason":"tool_calls"}]}"#,
+    ];
+    let mut calls = Vec::new();
+    for chunk in chunks {
+      for event in parse_openrouter_data(chunk) {
+        if let StreamEvent::ToolCall(delta) = event {
+          merge_tool_call(&mut calls, delta);
+        }
+      }
+    }
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].id, "call_a");
+    assert_eq!(calls[0].r#type, "function");
+    assert_eq!(calls[0].function.name, "get_weather");
+    assert_eq!(calls[0].function.arguments, r#"{"city":"Oslo"}"#);
+    assert_eq!(calls[1].function.name, "get_time");
+    assert_eq!(calls[1].function.arguments, "{}");
+  }
+
+  #[test]
+  fn merge_tool_call_ignores_indices_that_skip_ahead() {
+    let delta = |index| ToolCallDelta {
+      index,
+      id: Some(format!("call_{index}")),
+      name: Some("f".to_string()),
+      arguments: String::new(),
+    };
+    let mut calls = Vec::new();
+    merge_tool_call(&mut calls, delta(usize::MAX));
+    merge_tool_call(&mut calls, delta(3));
+    assert!(calls.is_empty());
+
+    for index in 0..MAX_TOOL_CALLS + 1 {
+      merge_tool_call(&mut calls, delta(index));
+    }
+    assert_eq!(calls.len(), MAX_TOOL_CALLS);
+    assert_eq!(calls[1].id, "call_1");
+  }
+
+  #[test]
+  fn build_payload_forwards_tools() {
+    let req = ChatRequest {
+      tools: Some(serde_json::json!([{ "type": "function", "function": { "name": "get_weather" } }])),
+      tool_choice: Some(serde_json::json!("auto")),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", true)).unwrap();
+    assert_eq!(payload["tools"][0]["function"]["name"], "get_weather");
+    assert_eq!(payload["tool_choice"], "auto");
+
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", true)).unwrap();
+    assert!(payload.get("tools").is_none());
+    assert!(payload.get("tool_choice").is_none());
+  }
+
+  #[test]
+  fn build_payload_forwards_provider_routing() {
+    let routing = serde_json::json!({ "order": ["Anthropic", "Together"], "allow_fallbacks": false });
+    let req = ChatRequest {
+      provider_routing: Some(routing.clone()),
+      ..Default::default()
+    };
+    assert!(validate_params(&req).is_ok());
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", true)).unwrap();
+    assert_eq!(payload["provider"], routing);
+
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", true)).unwrap();
+    assert!(payload.get("provider").is_none());
+  }
+
+  #[test]
+  fn validate_params_rejects_non_object_provider_routing() {
+    let req = ChatRequest {
+      provider_routing: Some(serde_json::json!(["Anthropic"])),
+      ..Default::default()
+    };
+    assert!(validate_params(&req).is_err());
+  }
+
+  #[test]
+  fn build_payload_includes_set_params() {
+    let req = ChatRequest {
+      temperature: Some(0.5),
+      max_tokens: Some(256),
+      top_p: Some(0.25),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", false)).unwrap();
+    assert_eq!(payload["temperature"], 0.5);
+    assert_eq!(payload["max_tokens"], 256);
+    assert_eq!(payload["top_p"], 0.25);
+  }
+
+  #[test]
+  fn build_payload_forwards_response_format() {
+    let req = ChatRequest {
+      response_format: Some(serde_json::json!({ "type": "json_object" })),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", false)).unwrap();
+    assert_eq!(payload["response_format"], serde_json::json!({ "type": "json_object" }));
+
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", false)).unwrap();
+    assert!(payload.get("response_format").is_none());
+  }
+
+  #[test]
+  fn check_json_output_rejects_malformed_json_in_json_mode() {
+    let req = ChatRequest {
+      response_format: Some(serde_json::json!({ "type": "json_object" })),
+      ..Default::default()
+    };
+    assert!(check_json_output(&req, Provider::OpenRouter, r#"{"ok":true}"#).is_ok());
+    let err = check_json_output(&req, Provider::OpenRouter, "Sure! {\"ok\":").unwrap_err();
+    let res = upstream_error_response(Provider::OpenRouter, err);
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(res.extensions().get::<ErrorCode>().unwrap().0, "invalid_json_output");
+
+    assert!(check_json_output(&req, Provider::Anthropic, "plain text").is_ok());
+    assert!(check_json_output(&ChatRequest::default(), Provider::OpenRouter, "plain text").is_ok());
+  }
+
+  #[test]
+  fn build_payload_forwards_stop_only_when_set() {
+    let req = ChatRequest {
+      stop: Some(vec!["\n\n".to_string(), "END".to_string()]),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", true)).unwrap();
+    assert_eq!(payload["stop"], serde_json::json!(["\n\n", "END"]));
+
+    let req = ChatRequest {
+      stop: Some(Vec::new()),
+      ..Default::default()
+    };
+    let payload = serde_json::to_value(build_payload(&req, "openai/gpt-4o-mini", true)).unwrap();
+    assert!(payload.get("stop").is_none());
+    let payload = serde_json::to_value(build_payload(&ChatRequest::default(), "openai/gpt-4o-mini", true)).unwrap();
+    assert!(payload.get("stop").is_none());
+  }
+
+  #[test]
+  fn validate_params_limits_stop_sequences() {
+    let stop = |sequences: &[&str]| ChatRequest {
+      stop: Some(sequences.iter().map(|s| s.to_string()).collect()),
+      ..Default::default()
+    };
+    assert!(validate_params(&stop(&["a", "b", "c", "d"])).is_ok());
+    assert!(validate_params(&stop(&[])).is_ok());
+    assert!(validate_params(&stop(&["a", "b", "c", "d", "e"])).is_err());
+    assert!(validate_params(&stop(&["END", ""])).is_err());
+  }
+
+  #[test]
+  fn validate_params_rejects_out_of_range_temperature() {
+    let req = ChatRequest {
+      temperature: Some(2.5),
+      ..Default::default()
+    };
+    assert!(validate_params(&req).is_err());
+    let req = ChatRequest {
+      temperature: Some(2.0),
+      ..Default::default()
+    };
+    assert!(validate_params(&req).is_ok());
+  }
+
+  #[test]
+  fn fallback_skipped_for_client_and_local_errors() {
+    let err = UpstreamError::Status(StatusCode::BAD_REQUEST, "bad request".to_string());
+    assert!(!err.should_fall_back());
+    let local = UpstreamError::Local(StatusCode::BAD_REQUEST, "invalid header".to_string());
+    assert!(!local.should_fall_back());
+  }
+
+  #[test]
+  fn fallback_used_for_server_and_transport_errors() {
+    let server = UpstreamError::Status(StatusCode::BAD_GATEWAY, "upstream down".to_string());
+    assert!(server.should_fall_back());
+    let transport = UpstreamError::Transport("connection reset".to_string());
+    assert!(transport.should_fall_back());
+  }
+
+  #[test]
+  fn fallback_chain_uses_fallback_model_without_a_chain() {
+    let config = base_config();
+    assert_eq!(fallback_chain("openrouter:text-default", &config), vec!["openrouter:fallback"]);
+    assert!(fallback_chain("openrouter:fallback", &config).is_empty());
+  }
+
+  #[test]
+  fn fallback_chain_skips_primary_and_repeats() {
+    let config = AppConfig {
+      fallback_chain: ["ollama:a", "openrouter:text-default", "ollama:b", "ollama:a"].map(String::from).to_vec(),
+      ..base_config()
+    };
+    assert_eq!(fallback_chain("openrouter:text-default", &config), vec!["ollama:a", "ollama:b"]);
+    // The same model with and without its implied provider prefix.
+    assert_eq!(fallback_chain("text-default", &config), vec!["ollama:a", "ollama:b"]);
+    let config = AppConfig {
+      fallback_chain: ["fallback", "openrouter:fallback"].map(String::from).to_vec(),
+      ..base_config()
+    };
+    assert_eq!(fallback_chain("openrouter:text-default", &config), vec!["fallback"]);
+  }
+
+  #[tokio::test]
+  async fn with_fallback_walks_chain_until_a_model_succeeds() {
+    let state = test_state();
+    let config = AppConfig {
+      fallback_chain: ["ollama:second", "ollama:third", "ollama:fourth"].map(String::from).to_vec(),
+      ..base_config()
+    };
+    let tried = std::sync::Mutex::new(Vec::new());
+    let target = ChatTarget::new("ollama:first", 0);
+    let req = ChatRequest::default();
+    let result = with_fallback(&state, &config, &req, Instant::now(), target, String::new(), |target, _| {
+      tried.lock().unwrap().push(target.model_id.clone());
+      async move {
+        match target.model_id.as_str() {
+          "ollama:third" => Ok(target.chain_index),
+          _ => Err(UpstreamError::Status(StatusCode::BAD_GATEWAY, "down".to_string())),
+        }
+      }
+    })
+    .await;
+    assert_eq!(result.ok(), Some(2));
+    assert_eq!(*tried.lock().unwrap(), vec!["ollama:first", "ollama:second", "ollama:third"]);
+  }
+
+  #[tokio::test]
+  async fn with_fallback_keeps_local_chats_local() {
+    let state = test_state();
+    let config = AppConfig {
+      fallback_chain: ["openrouter:hosted", "ollama:backup"].map(String::from).to_vec(),
+      ..base_config()
+    };
+    let target = ChatTarget::new("ollama:first", 0);
+    let req = ChatRequest::default();
+    let result = with_fallback(&state, &config, &req, Instant::now(), target, String::new(), |target, _| async move {
+      match target.provider {
+        Provider::Ollama if target.chain_index == 0 => Err(UpstreamError::Transport("refused".to_string())),
+        _ => Ok(target.model_id),
+      }
+    })
+    .await;
+    assert_eq!(result.ok().as_deref(), Some("ollama:backup"));
+  }
+
+  #[tokio::test]
+  async fn chat_cancel_signals_inflight_request() {
+    let state = test_state();
+    let (tx, rx) = oneshot::channel();
+    state.inflight.insert("req-1".to_string(), tx);
+
+    let resp = chat_cancel(
+      State(state.clone()),
+      Json(ChatCancelRequest {
+        request_id: "req-1".to_string(),
+      }),
+    )
+    .await
+    .into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(rx.await.is_ok());
+    assert!(state.inflight.is_empty());
+  }
+
+  #[tokio::test]
+  async fn chat_cancel_unknown_request_is_not_found() {
+    let resp = chat_cancel(
+      State(test_state()),
+      Json(ChatCancelRequest {
+        request_id: "missing".to_string(),
+      }),
+    )
+    .await
+    .into_response();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn to_openrouter_messages_attaches_image_to_last_user() {
+    let messages = vec![
+      Message {
+        role: "user".to_string(),
+        content: "First".to_string(),
+        ..Default::default()
+      },
+      Message {
+        role: "assistant".to_string(),
+        content: "Ack".to_string(),
+        ..Default::default()
+      },
+      Message {
+        role: "user".to_string(),
+        content: "Second".to_string(),
+        ..Default::default()
+      },
+    ];
+    let image = ImageData {
+      mime: "image/png".to_string(),
+      base64: "abc".to_string(),
+    };
+    let result = to_openrouter_messages(&messages, Some(&image));
+    assert_eq!(result.len(), 3);
+    let last = &result[2];
+    assert_eq!(last.role, "user");
+    assert!(last.content.is_array());
+  }
+
+  #[test]
+  fn to_openrouter_messages_attaches_every_image() {
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "Compare these".to_string(),
+        ..Default::default()
+      }],
+      image: Some(ImageData {
+        mime: "image/png".to_string(),
+        base64: "one".to_string(),
+      }),
+      images: Some(vec![ImageData {
+        mime: "image/jpeg".to_string(),
+        base64: "two".to_string(),
+      }]),
+      ..Default::default()
+    };
+    let result = to_openrouter_messages(&req.messages, req.all_images());
+    let content = result[0].content.as_array().unwrap();
+    let urls: Vec<_> = content
+      .iter()
+      .filter(|block| block["type"] == "image_url")
+      .map(|block| block["image_url"]["url"].as_str().unwrap())
+      .collect();
+    assert_eq!(urls, vec!["data:image/png;base64,one", "data:image/jpeg;base64,two"]);
+    assert_eq!(content[0]["text"], "Compare these");
+  }
+
+  #[test]
+  fn provider_role_mapping_table() {
+    let cases = [
+      (Provider::OpenRouter, "system", false, Some("system")),
+      (Provider::OpenRouter, "system", true, Some("developer")),
+      (Provider::OpenRouter, "developer", false, Some("system")),
+      (Provider::OpenRouter, "developer", true, Some("developer")),
+      (Provider::OpenRouter, "tool", false, Some("tool")),
+      (Provider::Anthropic, "developer", true, Some("system")),
+      (Provider::Anthropic, "tool", false, None),
+      (Provider::Ollama, "developer", true, Some("system")),
+      (Provider::Ollama, "tool", false, Some("tool")),
+      (Provider::Ollama, "assistant", false, Some("assistant")),
+      (Provider::OpenRouter, "narrator", false, None),
+    ];
+    for (provider, role, developer_role, expected) in cases {
+      assert_eq!(provider_role(provider, role, developer_role), expected, "{provider:?} {role} {developer_role}");
+    }
+  }
+
+  #[test]
+  fn developer_role_models_match_canonical_ids() {
+    let config = AppConfig {
+      developer_role_models: vec!["OpenAI/o1".to_string(), " openrouter:openai/o3-mini ".to_string()],
+      ..base_config()
+    };
+    for model_id in ["openrouter:openai/o1", "openai/o1", "openai/o3-mini", "openrouter:OPENAI/O3-MINI"] {
+      assert!(uses_developer_role(&config, &ChatTarget::new(model_id, 0)), "{model_id}");
+    }
+    assert!(!uses_developer_role(&config, &ChatTarget::new("ollama:openai/o1", 0)));
+    assert!(!uses_developer_role(&config, &ChatTarget::new("openai/gpt-4o", 0)));
+  }
+
+  #[test]
+  fn normalize_roles_only_clones_when_a_role_changes() {
+    let req = hello_request("t");
+    assert!(matches!(normalize_roles(&req, Provider::OpenRouter, true), Ok(Cow::Borrowed(_))));
+
+    let req = ChatRequest {
+      messages: vec![
+        Message {
+          role: "system".to_string(),
+          content: "Be brief.".to_string(),
+          ..Default::default()
+        },
+        hello_request("t").messages.remove(0),
+      ],
+      ..Default::default()
+    };
+    let normalized = normalize_roles(&req, Provider::OpenRouter, true).unwrap();
+    let roles: Vec<_> = normalized.messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["developer", "user"]);
+    assert!(matches!(normalize_roles(&req, Provider::OpenRouter, false), Ok(Cow::Borrowed(_))));
+  }
+
+  #[tokio::test]
+  async fn chat_rejects_unknown_and_unsupported_roles() {
+    let state = test_state();
+    let with_role = |role: &str, model: &str| ChatRequest {
+      messages: vec![Message {
+        role: role.to_string(),
+        content: "result".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some(model.to_string()),
+      ..Default::default()
+    };
+    let req = with_role("narrator", "openrouter:x");
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "bad_role");
+
+    let resp = chat(State(state), HeaderMap::new(), Json(with_role("tool", "anthropic:claude"))).await.into_response();
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "bad_role");
+  }
+
+  #[tokio::test]
+  async fn chat_rejects_oversized_images_and_unknown_types() {
+    let state = test_state();
+    state.config.write().await.max_image_bytes = 8;
+    let with_image = |mime: &str, base64: &str| ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "what is this?".to_string(),
+        ..Default::default()
+      }],
+      images: Some(vec![ImageData {
+        mime: mime.to_string(),
+        base64: base64.to_string(),
+      }]),
+      model_override: Some("openrouter:x".to_string()),
+      ..Default::default()
+    };
+    // 12 bytes decoded.
+    let req = with_image("image/png", "iVBORw0KGgoAAAANSUhEUg==");
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "image_too_large");
+
+    let req = with_image("image/svg+xml", "PHN2Zz4=");
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "bad_image_type");
+
+    // Not a decodable image, so downscaling fails and the limit still applies.
+    state.config.write().await.downscale_images = true;
+    let req = with_image("image/png", "iVBORw0KGgoAAAANSUhEUg==");
+    let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "image_too_large");
+  }
+
+  #[tokio::test]
+  async fn chat_rejects_empty_conversations_and_blank_roles() {
+    let state = test_state();
+    state.config.write().await.global_system_prompt = Some("Be brief.".to_string());
+    let req = ChatRequest {
+      model_override: Some("openrouter:x".to_string()),
+      ..Default::default()
+    };
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "empty_conversation");
+
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: " ".to_string(),
+        content: "hello".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("openrouter:x".to_string()),
+      ..Default::default()
+    };
+    let err = validate_roles(&req.messages).unwrap_err();
+    assert!(err.starts_with("Message 0 has no role"), "{err}");
+    let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "bad_role");
+  }
+
+  #[test]
+  fn error_response_tags_code_for_metrics() {
+    let response = error_response(StatusCode::BAD_REQUEST, "bad_params", "nope");
+    let code = response.extensions().get::<ErrorCode>().map(|c| c.0.as_str());
+    assert_eq!(code, Some("bad_params"));
+  }
+
+  #[tokio::test]
+  async fn chat_rejects_when_no_slot_is_free() {
+    let state = test_state();
+    let bad_request = || ChatRequest {
+      temperature: Some(5.0),
+      ..Default::default()
+    };
+
+    let held = state.chat_slots.clone().try_acquire_owned().unwrap();
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(bad_request())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    drop(held);
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(bad_request())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    // Early returns release the slot too.
+    assert_eq!(state.chat_slots.available_permits(), 1);
+  }
+
+  #[tokio::test]
+  async fn audit_records_rejections_and_failed_attempts() {
+    let state = test_state();
+    let req = ChatRequest {
+      temperature: Some(5.0),
+      model_override: Some("openrouter:openai/gpt-4o-mini".to_string()),
+      ..Default::default()
+    };
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let config = AppConfig {
+      fallback_model: "ollama:llama3".to_string(),
+      ..base_config()
+    };
+    let req = ChatRequest::default();
+    let target = ChatTarget::new("openrouter:text-default", 0);
+    let result = with_fallback(&state, &config, &req, Instant::now(), target, String::new(), |target, _| async move {
+      match target.provider {
+        Provider::OpenRouter => Err(UpstreamError::Transport("connection reset".to_string())),
+        _ => Ok(target.model_id),
+      }
+    })
+    .await;
+    assert_eq!(result.ok().as_deref(), Some("ollama:llama3"));
+
+    let resp = audit(State(state), Query(AuditQuery { limit: None })).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let items = body["items"].as_array().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0]["model"], "openrouter:text-default");
+    assert_eq!(items[0]["error_code"], "openrouter_error");
+    assert_eq!(items[1]["model"], "openrouter:openai/gpt-4o-mini");
+    assert_eq!(items[1]["provider"], serde_json::Value::Null);
+    assert_eq!(items[1]["error_code"], "bad_params");
+  }
+
+  #[tokio::test]
+  async fn chat_returns_rate_limited_when_model_bucket_is_empty() {
+    let state = test_state();
+    state.config.write().await.models.push(ModelInfo {
+      id: "ollama:llama3".to_string(),
+      label: "Llama 3".to_string(),
+      capability: "text".to_string(),
+      rate_limit_rpm: Some(1),
+    });
+    assert!(state.rate_limits.try_acquire("ollama:llama3", 1).is_ok());
+
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "Hi".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("ollama:llama3".to_string()),
+      stream: Some(false),
+      ..Default::default()
+    };
+    let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "rate_limited");
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let retry_after_ms = body["retry_after_ms"].as_u64().unwrap();
+    assert!(retry_after_ms > 59_000 && retry_after_ms <= 60_000, "{retry_after_ms}");
+  }
+
+  fn hello_request(thread_id: &str) -> ChatRequest {
+    ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "Hi".to_string(),
+        ..Default::default()
+      }],
+      thread_id: Some(thread_id.to_string()),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn stream_history_checkpoints_partial_reply_then_clears_flag() {
+    let state = test_state();
+    let req = hello_request("t1");
+    let target = ChatTarget::new("openrouter:text-default", 0);
+    let mut history = StreamHistory::begin(&state, &req, &target, Duration::from_secs(3600)).await;
+
+    let items = storage::memory_thread(&state.db, "t1").await.unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].payload["partial"], true);
+
+    assert!((1..CHECKPOINT_DELTAS).all(|_| !history.on_delta()));
+    assert!(history.on_delta());
+    history.checkpoint("Hello the", &[], None, 10).await;
+    let items = storage::memory_thread(&state.db, "t1").await.unwrap();
+    assert_eq!(items[0].payload["messages"][1]["content"], "Hello the");
+    assert_eq!(items[0].payload["partial"], true);
+    assert!(!history.on_delta());
+
+    history.finish("Hello there", &[], None, 20, false).await.unwrap();
+    let items = storage::memory_thread(&state.db, "t1").await.unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].payload["messages"][1]["content"], "Hello there");
+    assert_eq!(items[0].payload["partial"], false);
+    assert_eq!(items[0].payload["model"], "openrouter:text-default");
+  }
+
+  #[tokio::test]
+  async fn stream_history_without_checkpoints_writes_once_at_the_end() {
+    let state = test_state();
+    let req = hello_request("t2");
+    let target = ChatTarget::new("openrouter:text-default", 0);
+    let mut history = StreamHistory::begin(&state, &req, &target, Duration::ZERO).await;
+    assert!(!history.on_delta());
+    history.checkpoint("Hel", &[], None, 5).await;
+    assert!(storage::memory_thread(&state.db, "t2").await.unwrap().is_empty());
+
+    history.finish("Hello", &[], None, 10, false).await.unwrap();
+    let items = storage::memory_thread(&state.db, "t2").await.unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].payload["partial"], false);
+  }
+
+  #[test]
+  fn origin_allowed_matches_localhost_on_any_port() {
+    let allowed = AppConfig::default().allowed_origins;
+    assert!(origin_allowed("http://localhost", &allowed));
+    assert!(origin_allowed("http://localhost:1420", &allowed));
+    assert!(origin_allowed("http://127.0.0.1:5173", &allowed));
+    assert!(origin_allowed("tauri://localhost", &allowed));
+    assert!(origin_allowed("https://tauri.localhost", &allowed));
+
+    assert!(!origin_allowed("https://example.com", &allowed));
+    assert!(!origin_allowed("http://localhost.evil.com", &allowed));
+    assert!(!origin_allowed("http://localhost:", &allowed));
+    assert!(!origin_allowed("http://localhost:80@evil.com", &allowed));
+    assert!(!origin_allowed("null", &allowed));
+  }
+
+  #[tokio::test]
+  async fn v1_routes_require_router_token() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(run_router(listener, test_router_state(), watch::channel(false).1));
+
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://127.0.0.1:{port}{path}");
+    let status = |resp: reqwest::Response| resp.status().as_u16();
+
+    let health = client.get(url("/health")).send().await.unwrap();
+    assert_eq!(status(health), 200);
+
+    let deep = client.get(url("/health/deep")).send().await.unwrap();
+    assert_eq!(status(deep), 401);
+
+    let missing = client.get(url("/v1/models")).send().await.unwrap();
+    assert_eq!(status(missing), 401);
+
+    let wrong = client.get(url("/v1/models")).bearer_auth("nope").send().await.unwrap();
+    assert_eq!(status(wrong), 401);
+
+    let ok = client.get(url("/v1/models")).bearer_auth("test-token").send().await.unwrap();
+    assert_eq!(status(ok), 200);
+  }
+
+  #[tokio::test]
+  async fn json_responses_are_compressed_but_streams_are_not() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(run_router(listener, test_router_state(), watch::channel(false).1));
+
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://127.0.0.1:{port}{path}");
+    let encoding = |resp: &reqwest::Response| {
+      resp
+        .headers()
+        .get("content-encoding")
+        .map(|value| value.to_str().unwrap().to_string())
+    };
+
+    let export = client
+      .get(url("/v1/memory/export"))
+      .bearer_auth("test-token")
+      .header("accept-encoding", "gzip")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(export.status().as_u16(), 200);
+    assert_eq!(encoding(&export).as_deref(), Some("gzip"));
+    assert_eq!(&export.bytes().await.unwrap()[..2], &[0x1f, 0x8b]);
+
+    let plain = client.get(url("/v1/memory/export")).bearer_auth("test-token").send().await.unwrap();
+    assert_eq!(encoding(&plain), None);
+    let body: serde_json::Value = plain.json().await.unwrap();
+    assert!(body["history"].is_array());
+
+    let stream = client
+      .post(url("/v1/chat"))
+      .bearer_auth("test-token")
+      .header("accept-encoding", "gzip")
+      .json(&serde_json::json!({
+        "messages": [{ "role": "user", "content": "hi" }],
+        "stream": true,
+        "dry_run": true
+      }))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(stream.status().as_u16(), 200);
+    assert_eq!(encoding(&stream), None);
+    assert!(stream.text().await.unwrap().contains("event: done"));
+  }
+
+  #[tokio::test]
+  async fn oversized_bodies_are_rejected() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = test_router_state();
+    state.config.write().await.max_request_bytes = 1024;
+    tokio::spawn(run_router(listener, state, watch::channel(false).1));
+
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{port}/v1/chat/count-tokens");
+    let body = |content: String| serde_json::json!({ "messages": [{ "role": "user", "content": content }] });
+
+    let small = client.post(&url).bearer_auth("test-token").json(&body("hi".to_string())).send().await.unwrap();
+    assert_eq!(small.status().as_u16(), 200);
+
+    let large = client
+      .post(&url)
+      .bearer_auth("test-token")
+      .json(&body("x".repeat(4096)))
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(large.status().as_u16(), 413);
+    let error: serde_json::Value = large.json().await.unwrap();
+    assert_eq!(error["code"], "payload_too_large");
+  }
+
+  #[tokio::test]
+  async fn sse_keepalive_follows_config_and_zero_disables_it() {
+    let idle = || -> ChatFrames { Box::pin(tokio_stream::pending()) };
+
+    let mut body = sse_response(idle(), 1).into_body().into_data_stream();
+    let chunk = tokio::time::timeout(Duration::from_secs(3), body.next()).await.unwrap();
+    assert!(chunk.unwrap().unwrap().starts_with(b":"));
+
+    let mut body = sse_response(idle(), 0).into_body().into_data_stream();
+    let chunk = tokio::time::timeout(Duration::from_millis(1500), body.next()).await;
+    assert!(chunk.is_err());
+  }
+
+  #[tokio::test]
+  async fn ping_timer_waits_a_full_period() {
+    assert!(ping_timer(Duration::ZERO).is_none());
+
+    let mut ping = ping_timer(Duration::from_millis(50));
+    let started = Instant::now();
+    next_ping(&mut ping).await;
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    let mut disabled = None;
+    let woke = tokio::time::timeout(Duration::from_millis(20), next_ping(&mut disabled)).await;
+    assert!(woke.is_err());
+  }
+
+  #[test]
+  fn probe_status_classifies_openrouter_replies() {
+    assert_eq!(probe_status(Some(StatusCode::OK)), "ok");
+    assert_eq!(probe_status(Some(StatusCode::UNAUTHORIZED)), "unauthorized");
+    assert_eq!(probe_status(Some(StatusCode::FORBIDDEN)), "unauthorized");
+    assert_eq!(probe_status(Some(StatusCode::BAD_GATEWAY)), "unreachable");
+    assert_eq!(probe_status(None), "unreachable");
+  }
+
+  #[test]
+  fn done_payload_carries_full_text() {
+    let done = done_payload("stop", 12, "Hello, world");
+    assert_eq!(done["finish_reason"], "stop");
+    assert_eq!(done["latency_ms"], 12);
+    assert_eq!(done["full"], "Hello, world");
+    assert_eq!(done["text"], "Hello, world");
+  }
+
+  #[tokio::test]
+  async fn run_router_cancels_inflight_chats_on_shutdown() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let state = test_router_state();
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    state.inflight.insert("req-1".to_string(), cancel_tx);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(run_router(listener, state, shutdown_rx));
+
+    shutdown_tx.send(true).unwrap();
+    assert!(cancel_rx.await.is_ok());
+    let finished = tokio::time::timeout(Duration::from_secs(2), server).await;
+    assert!(matches!(finished, Ok(Ok(Ok(())))));
+  }
+
+  #[tokio::test]
+  async fn dry_run_streams_echo_without_a_provider() {
+    let state = test_state();
+    // No provider key exists here, so any upstream attempt would fail with key_missing.
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "hello there".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("openrouter:never/called".to_string()),
+      dry_run: Some(true),
+      ..Default::default()
+    };
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(r#"data: {"text":"Echo: "}"#), "{body}");
+    assert!(body.contains(r#"data: {"text":"there"}"#), "{body}");
+    assert!(body.contains("event: done"), "{body}");
+
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(history.items[0].payload["provider"], DRY_RUN);
+    assert_eq!(state.chat_slots.available_permits(), 1);
+  }
+
+  #[test]
+  fn regenerate_messages_drops_final_reply() {
+    let message = |role: &str, content: &str| Message {
+      role: role.to_string(),
+      content: content.to_string(),
+      ..Default::default()
+    };
+    let messages = vec![message("user", "Hi"), message("assistant", "Hello")];
+    let kept = regenerate_messages(messages).unwrap();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].role, "user");
+
+    assert!(regenerate_messages(vec![message("system", "Be brief."), message("assistant", "Hello")]).is_none());
+    assert!(regenerate_messages(Vec::new()).is_none());
+  }
+
+  #[tokio::test]
+  async fn set_default_models_validates_before_saving() {
+    let state = test_state();
+    {
+      let mut config = state.config.write().await;
+      for (id, capability) in [("openrouter:listed-text", "text"), ("openrouter:listed-vision", "vision")] {
+        config.models.push(ModelInfo {
+          id: id.to_string(),
+          label: id.to_string(),
+          capability: capability.to_string(),
+          rate_limit_rpm: None,
+        });
+      }
+      config.denied_models = vec!["ollama:blocked".to_string()];
+    }
+    let set = |text: Option<&str>, vision: Option<&str>| {
+      let req = DefaultModelsRequest {
+        text_default_model: text.map(String::from),
+        vision_default_model: vision.map(String::from),
+      };
+      set_default_models(State(state.clone()), Json(req))
+    };
+
+    for (text, vision) in [
+      (Some("no-such-model"), None),
+      (Some("ollama:"), None),
+      (Some("ollama:blocked"), None),
+      (Some("openrouter:listed-text"), Some("openrouter:listed-text")),
+    ] {
+      let resp = set(text, vision).await.into_response();
+      assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{text:?} {vision:?}");
+      let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+      let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+      assert_eq!(body["code"], "invalid_default_model");
+    }
+    // A rejected request changes neither field.
+    assert_eq!(state.config.read().await.text_default_model, "openrouter:text-default");
+    assert!(!state.config_path.exists());
+
+    let resp = set(Some(" openrouter:listed-text "), None).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = set(None, Some("ollama:llava")).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let config = state.config.read().await.clone();
+    assert_eq!(config.text_default_model, "openrouter:listed-text");
+    assert_eq!(config.vision_default_model, "ollama:llava");
+    let saved: AppConfig = serde_json::from_str(&std::fs::read_to_string(&state.config_path).unwrap()).unwrap();
+    assert_eq!(saved.vision_default_model, "ollama:llava");
+    let _ = std::fs::remove_file(&state.config_path);
+  }
+
+  #[tokio::test]
+  async fn preset_routes_round_trip() {
+    let state = test_state();
+    let req = PresetSaveRequest {
+      name: "Translator".to_string(),
+      system_prompt: "Translate to French.".to_string(),
+      routing_policy: RoutingPolicy {
+        model: Some("ollama:llama3".to_string()),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let resp = preset_save(State(state.clone()), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let created: crate::models::Preset = serde_json::from_slice(&body).unwrap();
+
+    let resp = preset_get(State(state.clone()), Path(created.id.clone())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let fetched: crate::models::Preset = serde_json::from_slice(&body).unwrap();
+    assert_eq!(fetched.name, "Translator");
+    assert_eq!(fetched.system_prompt, "Translate to French.");
+    assert_eq!(fetched.routing_policy.model.as_deref(), Some("ollama:llama3"));
+
+    let resp = presets_list(State(state.clone())).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let listed: PresetsResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(listed.items.len(), 1);
+
+    let blank = PresetSaveRequest::default();
+    let resp = preset_save(State(state.clone()), Json(blank)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = preset_delete(State(state.clone()), Path(created.id.clone())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = preset_get(State(state.clone()), Path(created.id.clone())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = preset_delete(State(state), Path(created.id)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn chat_regenerate_replays_stored_conversation() {
+    let state = test_state();
+    let messages = vec![Message {
+      role: "user".to_string(),
+      content: "try again".to_string(),
+      ..Default::default()
+    }];
+    let entry = storage::HistoryEntry {
+      messages: &messages,
+      assistant: "first answer",
+      thread_id: Some("t1"),
+      ..Default::default()
+    };
+    let id = storage::store_history(&state.db, entry).await.unwrap();
+
+    let req = ChatRegenerateRequest {
+      history_id: id,
+      stream: Some(false),
+      dry_run: Some(true),
+      ..Default::default()
+    };
+    let resp = chat_regenerate(State(state.clone()), HeaderMap::new(), Json(req)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["text"], "Echo: try again");
+    assert_eq!(body["thread_id"], "t1");
+
+    let thread = storage::memory_thread(&state.db, "t1").await.unwrap();
+    assert_eq!(thread.len(), 2);
+
+    let missing = ChatRegenerateRequest {
+      history_id: "missing".to_string(),
+      ..Default::default()
+    };
+    let resp = chat_regenerate(State(state.clone()), HeaderMap::new(), Json(missing)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let system_only = vec![Message {
+      role: "system".to_string(),
+      content: "Be brief.".to_string(),
+      ..Default::default()
+    }];
+    let entry = storage::HistoryEntry {
+      messages: &system_only,
+      ..Default::default()
+    };
+    let id = storage::store_history(&state.db, entry).await.unwrap();
+    let req = ChatRegenerateRequest {
+      history_id: id,
+      ..Default::default()
+    };
+    let resp = chat_regenerate(State(state), HeaderMap::new(), Json(req)).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "nothing_to_regenerate");
+  }
+
+  fn store_request(kind: &str) -> Json<MemoryStoreRequest> {
+    Json(MemoryStoreRequest {
+      r#type: kind.to_string(),
+      payload: serde_json::json!({ "text": "note" }),
+    })
+  }
+
+  #[tokio::test]
+  async fn memory_store_rejects_unknown_type_as_bad_request() {
+    let resp = memory_store(State(test_state()), store_request("bogus")).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn memory_store_reports_database_failure_as_server_error() {
+    let state = test_state();
+    state.db.lock().await.execute_batch("DROP TABLE pinned;").unwrap();
+    let resp = memory_store(State(state), store_request("pinned")).await.into_response();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+  }
+
+  struct ChannelSocket {
+    inbound: tokio::sync::mpsc::UnboundedReceiver<String>,
+    outbound: tokio::sync::mpsc::UnboundedSender<String>,
+  }
+
+  impl ChatSocket for ChannelSocket {
+    async fn recv_text(&mut self) -> Option<String> {
+      self.inbound.recv().await
+    }
+
+    async fn send_text(&mut self, text: String) -> bool {
+      self.outbound.send(text).is_ok()
     }
+
+    async fn close(&mut self) {}
   }
 
-  #[test]
-  fn split_provider_with_prefix() {
-    let (provider, model) = split_provider("openrouter:openai/gpt-4o-mini");
-    assert_eq!(provider, "openrouter");
-    assert_eq!(model, "openai/gpt-4o-mini");
+  // Returns the client ends: what it sends, and the frames it receives.
+  fn spawn_relay(
+    state: Arc<RouterState>,
+  ) -> (
+    tokio::sync::mpsc::UnboundedSender<String>,
+    tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+  ) {
+    let (client_tx, inbound) = tokio::sync::mpsc::unbounded_channel();
+    let (outbound, mut server_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let (frames_tx, frames_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      let mut socket = ChannelSocket { inbound, outbound };
+      relay_chat(state, &mut socket).await;
+    });
+    tokio::spawn(async move {
+      while let Some(text) = server_rx.recv().await {
+        let _ = frames_tx.send(serde_json::from_str(&text).unwrap());
+      }
+    });
+    (client_tx, frames_rx)
   }
 
-  #[test]
-  fn split_provider_without_prefix() {
-    let (provider, model) = split_provider("openai/gpt-4o-mini");
-    assert_eq!(provider, "openrouter");
-    assert_eq!(model, "openai/gpt-4o-mini");
+  #[tokio::test]
+  async fn chat_ws_cancel_message_stops_the_stream() {
+    let state = test_state();
+    let (client, mut frames) = spawn_relay(state.clone());
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "one two three four five six seven eight nine ten".to_string(),
+        ..Default::default()
+      }],
+      dry_run: Some(true),
+      ..Default::default()
+    };
+    client.send(serde_json::to_string(&req).unwrap()).unwrap();
+
+    let meta = frames.recv().await.unwrap();
+    assert_eq!(meta["type"], "meta");
+    assert_eq!(meta["model"], DRY_RUN);
+    let delta = frames.recv().await.unwrap();
+    assert_eq!(delta["type"], "delta");
+    client.send(r#"{"type":"cancel"}"#.to_string()).unwrap();
+
+    let mut deltas = 1;
+    let done = loop {
+      let frame = frames.recv().await.unwrap();
+      match frame["type"].as_str() {
+        Some("delta") => deltas += 1,
+        Some("done") => break frame,
+        other => panic!("unexpected frame {other:?}"),
+      }
+    };
+    assert_eq!(done["finish_reason"], "cancelled");
+    assert!(deltas < 11, "{deltas} deltas");
+    assert!(frames.recv().await.is_none(), "relay should end after done");
+    assert!(state.inflight.is_empty());
+
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(history.items.len(), 1);
+    assert_eq!(history.items[0].payload["partial"], true);
+  }
+
+  #[tokio::test]
+  async fn chat_ws_reports_bad_first_message_as_error_frame() {
+    let (client, mut frames) = spawn_relay(test_state());
+    client.send("not json".to_string()).unwrap();
+    let frame = frames.recv().await.unwrap();
+    assert_eq!(frame["type"], "error");
+    assert_eq!(frame["code"], "bad_request");
+    assert!(frames.recv().await.is_none());
   }
 
   #[test]
-  fn split_provider_handles_colon_in_model() {
-    let (provider, model) = split_provider("nvidia/nemotron-3-nano-30b-a3b:free");
-    assert_eq!(provider, "openrouter");
-    assert_eq!(model, "nvidia/nemotron-3-nano-30b-a3b:free");
+  fn ws_frames_and_token_query() {
+    let frame = ChatFrame::new("delta", serde_json::json!({ "text": "hi" }));
+    let text: serde_json::Value = serde_json::from_str(&frame.into_ws_text()).unwrap();
+    assert_eq!(text, serde_json::json!({ "type": "delta", "text": "hi" }));
+    assert!(is_cancel_message(r#"{"type":"cancel"}"#));
+    assert!(!is_cancel_message("cancel"));
+    assert_eq!(query_token(Some("a=1&token=abc")).as_deref(), Some("abc"));
+    assert_eq!(query_token(None), None);
   }
 
   #[test]
-  fn resolve_model_uses_override() {
-    let config = base_config();
-    let req = ChatRequest {
-      preset_id: None,
-      messages: vec![],
-      image: None,
-      model_override: Some("openrouter:override".to_string()),
-      stream: Some(true),
+  fn embedding_text_covers_history_and_pinned_only() {
+    let note = serde_json::json!({ "text": "car keys" });
+    assert_eq!(embedding_text("pinned", &note), Some(("pinned", "car keys".to_string())));
+    let messages = serde_json::json!([
+      { "role": "user", "content": "hi" },
+      { "role": "assistant", "content": "hello" }
+    ]);
+    assert_eq!(embedding_text("history", &messages), Some(("history", "hi\nhello".to_string())));
+    assert_eq!(embedding_text("preset", &serde_json::json!({ "name": "x" })), None);
+  }
+
+  #[tokio::test]
+  async fn semantic_query_falls_back_to_lexical_without_embeddings() {
+    let state = test_state();
+    let resp = memory_store(State(state.clone()), store_request("pinned")).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = MemoryQueryRequest {
+      query: "note".to_string(),
+      mode: SearchMode::Semantic,
+      ..Default::default()
     };
+    let resp = memory_query(State(state), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["mode"], "lexical");
+    assert_eq!(body["items"][0]["payload"]["text"], "note");
+  }
 
-    let resolved = resolve_model(&req, &config).expect("override should resolve");
-    assert_eq!(resolved, "openrouter:override");
+  // An Ollama stand-in that always answers "hello" with 3 prompt and 2
+  // completion tokens; returns its base URL.
+  async fn spawn_mock_ollama(calls: Arc<std::sync::atomic::AtomicUsize>) -> String {
+    let upstream = Router::new().route(
+      "/api/chat",
+      post(move || {
+        calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        async {
+          Json(serde_json::json!({
+            "message": { "role": "assistant", "content": "hello" },
+            "done": true,
+            "prompt_eval_count": 3,
+            "eval_count": 2
+          }))
+        }
+      }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    format!("http://127.0.0.1:{port}")
   }
 
-  #[test]
-  fn resolve_model_uses_vision_default_when_image_present() {
-    let config = base_config();
-    let req = ChatRequest {
-      preset_id: None,
-      messages: vec![],
-      image: Some(ImageData {
-        mime: "image/png".to_string(),
-        base64: "abc".to_string(),
+  // A state with semantic search set up whose HTTPS requests (embeddings
+  // included) all go through a proxy that only counts connections; returns
+  // the state and that count.
+  async fn embedding_state() -> (Arc<RouterState>, Arc<std::sync::atomic::AtomicUsize>) {
+    let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let seen = connections.clone();
+    tokio::spawn(async move {
+      while let Ok((socket, _)) = proxy.accept().await {
+        seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        drop(socket);
+      }
+    });
+    let state = Arc::new(RouterState {
+      http_client: reqwest::Client::builder()
+        .proxy(reqwest::Proxy::https(proxy_url).unwrap())
+        .build()
+        .unwrap(),
+      ..test_router_state()
+    });
+    {
+      let mut config = state.config.write().await;
+      config.embedding_model = Some("openai/text-embedding-3-small".to_string());
+      config.key_storage = keys::KeyStorage::File;
+    }
+    let store = keys::KeyStore::new(keys::KeyStorage::File, &state.key_file);
+    keys::set_key(&store, Provider::OpenRouter, "sk-or-test").unwrap();
+    (state, connections)
+  }
+
+  // Waits for the first embeddings request, then a little longer for any
+  // stragglers, and returns how many were made.
+  async fn embedding_requests(connections: &std::sync::atomic::AtomicUsize) -> usize {
+    let first = async {
+      while connections.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(5), first).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    connections.load(std::sync::atomic::Ordering::SeqCst)
+  }
+
+  #[tokio::test]
+  async fn local_chats_are_not_embedded() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (state, connections) = embedding_state().await;
+    let calls = Arc::new(AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls.clone()).await;
+
+    for stream in [false, true] {
+      let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(stream))).await.into_response();
+      axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // A pinned note is embedded; once its request shows up, nothing came before it.
+    let resp = memory_store(State(state.clone()), store_request("pinned")).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(embedding_requests(&connections).await, 1);
+    let _ = std::fs::remove_file(&state.key_file);
+  }
+
+  #[tokio::test]
+  async fn batch_stores_embed_in_one_request() {
+    let (state, connections) = embedding_state().await;
+    let items = (0..5).map(|_| store_request("pinned").0).collect();
+    let resp = memory_store_batch(State(state.clone()), Json(items)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(embedding_requests(&connections).await, 1);
+    let _ = std::fs::remove_file(&state.key_file);
+  }
+
+  fn ollama_chat(stream: bool) -> ChatRequest {
+    ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "hi".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("ollama:x".to_string()),
+      stream: Some(stream),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn stop_sequences_reach_ollama_and_finish_as_stop() {
+    let sent = Arc::new(std::sync::Mutex::new(serde_json::Value::Null));
+    let seen = sent.clone();
+    let upstream = Router::new().route(
+      "/api/chat",
+      post(move |Json(body): Json<serde_json::Value>| {
+        *seen.lock().unwrap() = body;
+        async {
+          let lines = [
+            r#"{"message":{"content":"1 2 3 4"},"done":false}"#,
+            r#"{"message":{"content":""},"done":true,"done_reason":"stop"}"#,
+          ];
+          lines.map(|line| format!("{line}\n")).concat()
+        }
       }),
-      model_override: None,
-      stream: Some(true),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+    let state = test_state();
+    state.config.write().await.ollama_base_url = format!("http://127.0.0.1:{port}");
+    let mut req = ollama_chat(true);
+    req.stop = Some(vec!["5".to_string()]);
+    let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert_eq!(sent.lock().unwrap()["options"]["stop"], serde_json::json!(["5"]));
+    let done = body.split("event: done\ndata: ").nth(1).unwrap().lines().next().unwrap();
+    let done: serde_json::Value = serde_json::from_str(done).unwrap();
+    assert_eq!(done["finish_reason"], "stop");
+    assert_eq!(done["text"], "1 2 3 4");
+  }
+
+  #[tokio::test]
+  async fn no_store_chats_leave_no_history() {
+    let state = test_state();
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls.clone()).await;
+    let history_rows = || async {
+      storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap().items.len()
     };
 
-    let resolved = resolve_model(&req, &config).expect("vision default should resolve");
-    assert_eq!(resolved, "openrouter:vision-default");
+    let mut req = ollama_chat(false);
+    req.no_store = Some(true);
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["text"], "hello");
+    assert_eq!(body["stored"], false);
+
+    let mut req = ollama_chat(true);
+    req.no_store = Some(true);
+    req.dry_run = Some(true);
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("\"stored\":false"));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(history_rows().await, 0);
+
+    // The global switch overrides requests that would be stored.
+    state.config.write().await.history_enabled = false;
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(false))).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["stored"], false);
+    assert_eq!(history_rows().await, 0);
+
+    state.config.write().await.history_enabled = true;
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(false))).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["stored"], true);
+    assert_eq!(history_rows().await, 1);
   }
 
-  #[test]
-  fn resolve_model_uses_text_default_without_image() {
-    let config = base_config();
-    let req = ChatRequest {
-      preset_id: None,
-      messages: vec![],
-      image: None,
-      model_override: None,
-      stream: Some(true),
+  #[tokio::test]
+  async fn chat_log_lines_carry_the_request_id() {
+    let state = test_state();
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls).await;
+
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(false))).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let request_id = body["request_id"].as_str().unwrap().to_string();
+    assert!(!request_id.is_empty());
+
+    let lines = state.logger.tail(50).unwrap();
+    for message in ["chat request", "chat complete"] {
+      let line = lines.iter().find(|line| line.contains(message)).unwrap();
+      assert!(line.contains(&request_id), "{line}");
+    }
+
+    let mut streamed = ollama_chat(true);
+    streamed.dry_run = Some(true);
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(streamed)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let meta = body.lines().find(|line| line.contains("\"request_id\"")).unwrap();
+    let meta: serde_json::Value = serde_json::from_str(meta.trim_start_matches("data: ")).unwrap();
+    let logged = state.logger.tail(50).unwrap();
+    let meta_id = meta["request_id"].as_str().unwrap();
+    assert!(logged.iter().any(|line| line.contains("chat request") && line.contains(meta_id)));
+  }
+
+  #[tokio::test]
+  async fn long_threads_are_trimmed_to_the_context_budget() {
+    let state = test_state();
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls).await;
+    state.config.write().await.max_context_tokens = Some(20);
+    let message = |role: &str, content: String| Message {
+      role: role.to_string(),
+      content,
+      ..Default::default()
     };
+    let mut req = ollama_chat(false);
+    req.messages = vec![
+      message("system", "Be brief.".to_string()),
+      message("user", "x".repeat(400)),
+      message("assistant", "y".repeat(400)),
+      message("user", "latest".to_string()),
+    ];
 
-    let resolved = resolve_model(&req, &config).expect("text default should resolve");
-    assert_eq!(resolved, "openrouter:text-default");
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["trimmed"], 2);
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    let sent = history.items[0].payload["messages"].as_array().unwrap().clone();
+    let roles: Vec<&str> = sent.iter().filter_map(|m| m["role"].as_str()).collect();
+    assert_eq!(roles, vec!["system", "user", "assistant"]);
+    assert_eq!(sent[1]["content"], "latest");
+
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(false))).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["trimmed"], 0);
   }
 
-  #[test]
-  fn to_openrouter_messages_attaches_image_to_last_user() {
-    let messages = vec![
-      Message {
-        role: "user".to_string(),
-        content: "First".to_string(),
-      },
+  #[tokio::test]
+  async fn tool_results_go_back_with_the_calls_they_answer() {
+    let state = test_state();
+    let bodies = Arc::new(std::sync::Mutex::new(Vec::<serde_json::Value>::new()));
+    let seen = bodies.clone();
+    let upstream = Router::new().route(
+      "/chat/completions",
+      post(move |Json(body): Json<serde_json::Value>| {
+        let mut bodies = seen.lock().unwrap();
+        bodies.push(body);
+        let message = if bodies.len() == 1 {
+          serde_json::json!({
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "now", "arguments": "{}" } }]
+          })
+        } else {
+          serde_json::json!({ "role": "assistant", "content": "It is noon." })
+        };
+        async move { Json(serde_json::json!({ "choices": [{ "message": message, "finish_reason": "stop" }] })) }
+      }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    {
+      let mut config = state.config.write().await;
+      config.openrouter_base_url = format!("http://127.0.0.1:{port}");
+      config.key_storage = keys::KeyStorage::File;
+    }
+    let store = keys::KeyStore::new(keys::KeyStorage::File, &state.key_file);
+    keys::set_key(&store, Provider::OpenRouter, "sk-or-test").unwrap();
+    let send = |messages: Vec<Message>| {
+      let state = state.clone();
+      let req = ChatRequest {
+        messages,
+        model_override: Some("openrouter:some/model".to_string()),
+        stream: Some(false),
+        ..Default::default()
+      };
+      async move {
+        let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+      }
+    };
+
+    let question = Message {
+      role: "user".to_string(),
+      content: "What time is it?".to_string(),
+      ..Default::default()
+    };
+    let reply = send(vec![question.clone()]).await;
+    let calls: Vec<ToolCall> = serde_json::from_value(reply["tool_calls"].clone()).unwrap();
+    assert_eq!(calls.len(), 1);
+
+    let turn = vec![
+      question,
       Message {
         role: "assistant".to_string(),
-        content: "Ack".to_string(),
+        tool_calls: Some(calls),
+        ..Default::default()
       },
       Message {
-        role: "user".to_string(),
-        content: "Second".to_string(),
+        role: "tool".to_string(),
+        content: "12:00".to_string(),
+        tool_call_id: Some("call_1".to_string()),
+        ..Default::default()
       },
     ];
-    let image = ImageData {
-      mime: "image/png".to_string(),
-      base64: "abc".to_string(),
+    let reply = send(turn).await;
+    assert_eq!(reply["text"], "It is noon.");
+
+    let sent = bodies.lock().unwrap()[1]["messages"].clone();
+    assert_eq!(sent[1]["tool_calls"][0]["id"], "call_1");
+    assert_eq!(sent[1]["tool_calls"][0]["type"], "function");
+    assert_eq!(sent[1]["tool_calls"][0]["function"]["name"], "now");
+    assert_eq!(sent[2]["role"], "tool");
+    assert_eq!(sent[2]["tool_call_id"], "call_1");
+    assert!(sent[0].get("tool_calls").is_none() && sent[0].get("tool_call_id").is_none());
+    let _ = std::fs::remove_file(&state.key_file);
+  }
+
+  #[tokio::test]
+  async fn completions_record_cost_from_model_prices() {
+    let state = test_state();
+    let upstream = Router::new().route(
+      "/chat/completions",
+      post(|| async {
+        Json(serde_json::json!({
+          "choices": [{ "message": { "role": "assistant", "content": "hello" }, "finish_reason": "stop" }],
+          "usage": { "prompt_tokens": 3, "completion_tokens": 2 }
+        }))
+      }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    {
+      let mut config = state.config.write().await;
+      config.openrouter_base_url = format!("http://127.0.0.1:{port}");
+      config.ollama_base_url = spawn_mock_ollama(calls).await;
+      config.key_storage = keys::KeyStorage::File;
+    }
+    let store = keys::KeyStore::new(keys::KeyStorage::File, &state.key_file);
+    keys::set_key(&store, Provider::OpenRouter, "sk-or-test").unwrap();
+    let price = pricing::ModelPrice {
+      prompt: 0.001,
+      completion: 0.002,
     };
-    let result = to_openrouter_messages(&messages, Some(&image));
-    assert_eq!(result.len(), 3);
-    let last = &result[2];
-    assert_eq!(last.role, "user");
-    assert!(last.content.is_array());
+    state.prices.replace(vec![
+      ("openrouter:priced/model".to_string(), price),
+      ("openrouter:free/model".to_string(), pricing::ModelPrice::default()),
+    ]);
+    let cost = |req: ChatRequest| {
+      let state = state.clone();
+      async move {
+        let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["cost_usd"].as_f64()
+      }
+    };
+    let openrouter_chat = |model: &str| ChatRequest {
+      model_override: Some(format!("openrouter:{model}")),
+      ..ollama_chat(false)
+    };
+
+    let expected = 3.0 * 0.001 + 2.0 * 0.002;
+    assert_eq!(cost(openrouter_chat("priced/model")).await, Some(expected));
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(history.items[0].payload["cost_usd"].as_f64(), Some(expected));
+    let audit = storage::audit_recent(&state.db, 1).await.unwrap();
+    assert_eq!(audit[0].cost_usd, Some(expected));
+
+    // Only listed free models and local ones cost nothing; the rest are unknown.
+    assert_eq!(cost(openrouter_chat("free/model")).await, Some(0.0));
+    assert_eq!(cost(openrouter_chat("unlisted/model")).await, None);
+    assert_eq!(cost(ollama_chat(false)).await, Some(0.0));
+    let _ = std::fs::remove_file(&state.key_file);
+  }
+
+  #[tokio::test]
+  async fn repeated_idempotency_key_replays_without_calling_upstream() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let state = test_state();
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls.clone()).await;
+    let req = ollama_chat;
+    let mut headers = HeaderMap::new();
+    headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("retry-1"));
+
+    let first = chat(State(state.clone()), headers.clone(), Json(req(false))).await.into_response();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert!(first.headers().get("idempotent-replayed").is_none());
+    let first = axum::body::to_bytes(first.into_body(), usize::MAX).await.unwrap();
+
+    let second = chat(State(state.clone()), headers.clone(), Json(req(false))).await.into_response();
+    assert_eq!(second.headers()["idempotent-replayed"], "true");
+    let second = axum::body::to_bytes(second.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(first, second);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let streamed = chat(State(state.clone()), headers, Json(req(true))).await.into_response();
+    let streamed = axum::body::to_bytes(streamed.into_body(), usize::MAX).await.unwrap();
+    let streamed = String::from_utf8(streamed.to_vec()).unwrap();
+    assert!(streamed.contains(r#"data: {"text":"hello"}"#), "{streamed}");
+    assert!(streamed.contains("event: done"), "{streamed}");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    let mut bad = HeaderMap::new();
+    bad.insert(IDEMPOTENCY_KEY, HeaderValue::from_static(" "));
+    let rejected = chat(State(state), bad, Json(req(false))).await.into_response();
+    assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn idempotency_key_is_held_while_running_and_bound_to_its_body() {
+    let state = test_state();
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls).await;
+    let mut headers = HeaderMap::new();
+    headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("retry-2"));
+    let send = |req: ChatRequest| chat(State(state.clone()), headers.clone(), Json(req));
+
+    // A reply still streaming holds the key.
+    let streaming = send(ollama_chat(true)).await.into_response();
+    assert_eq!(streaming.status(), StatusCode::OK);
+    let busy = send(ollama_chat(false)).await.into_response();
+    assert_eq!(busy.status(), StatusCode::CONFLICT);
+    axum::body::to_bytes(streaming.into_body(), usize::MAX).await.unwrap();
+
+    let mut other = ollama_chat(false);
+    other.messages[0].content = "something else".to_string();
+    let reused = send(other).await.into_response();
+    assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let replayed = send(ollama_chat(false)).await.into_response();
+    assert_eq!(replayed.headers()["idempotent-replayed"], "true");
+
+    // A chat that fails frees its key for the retry.
+    let mut headers = HeaderMap::new();
+    headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("retry-3"));
+    let mut failing = ollama_chat(false);
+    failing.temperature = Some(9.0);
+    let failed = chat(State(state.clone()), headers.clone(), Json(failing.clone())).await.into_response();
+    assert_eq!(failed.status(), StatusCode::BAD_REQUEST);
+    let retried = chat(State(state), headers, Json(failing)).await.into_response();
+    assert_eq!(retried.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn dropped_stream_abandons_upstream_and_keeps_partial_history() {
+    // Sends one line, then holds the response open until the client goes away.
+    struct Dropped(Arc<std::sync::atomic::AtomicBool>);
+    impl Drop for Dropped {
+      fn drop(&mut self) {
+        self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+      }
+    }
+    let upstream_dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let flag = upstream_dropped.clone();
+    let upstream = Router::new().route(
+      "/api/chat",
+      post(move || {
+        let dropped = Dropped(flag.clone());
+        async move {
+          let lines = stream! {
+            let _dropped = dropped;
+            yield Ok::<_, std::convert::Infallible>(r#"{"message":{"content":"hel"},"done":false}"#.to_string() + "\n");
+            std::future::pending::<()>().await;
+          };
+          axum::body::Body::from_stream(lines)
+        }
+      }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+    let state = test_state();
+    {
+      let mut config = state.config.write().await;
+      config.ollama_base_url = format!("http://127.0.0.1:{port}");
+      config.resume_grace_secs = 0;
+    }
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(true))).await.into_response();
+    let mut body = resp.into_body().into_data_stream();
+    let mut seen = String::new();
+    while !seen.contains("event: delta") {
+      let chunk = body.next().await.unwrap().unwrap();
+      seen.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    drop(body);
+
+    let abandoned = async {
+      while !upstream_dropped.load(std::sync::atomic::Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(2), abandoned).await.expect("upstream still being read");
+
+    let audited = async {
+      loop {
+        let audit = storage::audit_recent(&state.db, 1).await.unwrap();
+        if let Some(record) = audit.into_iter().next() {
+          return record;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    let record = tokio::time::timeout(Duration::from_secs(2), audited).await.unwrap();
+    assert_eq!(record.status, "cancelled");
+    assert_eq!(record.error_code.as_deref(), Some("aborted_by_client"));
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(history.items.len(), 1);
+    assert_eq!(history.items[0].payload["partial"], true);
+    assert_eq!(history.items[0].payload["messages"][1]["content"], "hel");
+  }
+
+  #[tokio::test]
+  async fn stream_can_be_resumed_after_its_client_disconnects() {
+    let upstream = Router::new().route(
+      "/api/chat",
+      post(|| async {
+        let lines = stream! {
+          yield Ok::<_, std::convert::Infallible>(r#"{"message":{"content":"hel"},"done":false}"#.to_string() + "\n");
+          tokio::time::sleep(Duration::from_millis(300)).await;
+          yield Ok(r#"{"message":{"content":"lo"},"done":false}"#.to_string() + "\n");
+          yield Ok(r#"{"message":{"content":""},"done":true}"#.to_string() + "\n");
+        };
+        axum::body::Body::from_stream(lines)
+      }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+    let state = test_state();
+    state.config.write().await.ollama_base_url = format!("http://127.0.0.1:{port}");
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(ollama_chat(true))).await.into_response();
+    let mut body = resp.into_body().into_data_stream();
+    let mut seen = String::new();
+    while !seen.contains("event: delta") {
+      let chunk = body.next().await.unwrap().unwrap();
+      seen.push_str(&String::from_utf8_lossy(&chunk));
+    }
+    drop(body);
+    let meta = seen.lines().find(|line| line.contains("request_id")).unwrap();
+    let meta: serde_json::Value = serde_json::from_str(meta.trim_start_matches("data: ")).unwrap();
+    let request_id = meta["request_id"].as_str().unwrap().to_string();
+    // Reconnect only once the stream has noticed its client is gone.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(LAST_EVENT_ID, HeaderValue::from_static("1"));
+    let resp = chat_resume(State(state.clone()), Path(request_id), headers).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(r#"data: {"text":"lo"}"#), "{body}");
+    assert!(body.contains(r#""finish_reason":"stop""#), "{body}");
+    assert!(body.contains(r#""text":"hello""#), "{body}");
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(history.items[0].payload["partial"], false);
+  }
+
+  #[test]
+  fn openrouter_attribution_falls_back_on_bad_values() {
+    let headers = openrouter_attribution(&AppConfig::default());
+    assert_eq!(headers["HTTP-Referer"], "http://localhost");
+    assert_eq!(headers["X-Title"], "HaloDesk");
+
+    let config = AppConfig {
+      openrouter_referer: " https://example.app ".to_string(),
+      openrouter_app_title: "Bad\nTitle".to_string(),
+      ..AppConfig::default()
+    };
+    let headers = openrouter_attribution(&config);
+    assert_eq!(headers["HTTP-Referer"], "https://example.app");
+    assert_eq!(headers["X-Title"], "HaloDesk");
+  }
+
+  #[tokio::test]
+  async fn check_upstream_status_passes_success_and_quotes_errors() {
+    let upstream = Router::new()
+      .route("/ok", get(|| async { "fine" }))
+      .route("/busy", get(|| async { (StatusCode::TOO_MANY_REQUESTS, "slow down") }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    let client = reqwest::Client::new();
+    let fetch = |path: &str| client.get(format!("http://127.0.0.1:{port}{path}")).send();
+
+    let ok = check_upstream_status(fetch("/ok").await.unwrap(), "OpenRouter").await.unwrap();
+    assert_eq!(ok.text().await.unwrap(), "fine");
+
+    let (status, message) = check_upstream_status(fetch("/busy").await.unwrap(), "OpenRouter")
+      .await
+      .unwrap_err();
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(message, "OpenRouter error (429 Too Many Requests): slow down");
+  }
+
+  #[test]
+  fn clean_title_strips_quotes_prefixes_and_extra_lines() {
+    let raw = "\"Fixing a Rust borrow error\"\nHope that helps!";
+    assert_eq!(clean_title(raw).as_deref(), Some("Fixing a Rust borrow error"));
+    assert_eq!(clean_title("  Title: Weekend trip   planning. ").as_deref(), Some("Weekend trip planning"));
+    assert_eq!(clean_title("\u{201c}Tax questions\u{201d}").as_deref(), Some("Tax questions"));
+    assert_eq!(clean_title("\n\"\"\n"), None);
+    assert_eq!(fallback_title("How do I  sort a vec of structs by two keys?"), "How do I sort a vec…");
+    assert_eq!(fallback_title("Short one"), "Short one");
+    assert_eq!(fallback_title("   "), "New chat");
+  }
+
+  #[tokio::test]
+  async fn chat_title_uses_the_title_model_and_falls_back_on_failure() {
+    let state = test_state();
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    state.config.write().await.ollama_base_url = spawn_mock_ollama(calls.clone()).await;
+    state.config.write().await.title_model = Some("ollama:x".to_string());
+    let message = |role: &str, content: &str| Message {
+      role: role.to_string(),
+      content: content.to_string(),
+      ..Default::default()
+    };
+    let req = || ChatTitleRequest {
+      messages: vec![
+        message("user", "Can you help me plan a three day trip to Lisbon in May?"),
+        message("assistant", "Sure!"),
+      ],
+    };
+    let title = |resp: Response| async {
+      let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+      let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+      body["title"].as_str().unwrap().to_string()
+    };
+
+    let resp = chat_title(State(state.clone()), Json(req())).await.into_response();
+    assert_eq!(title(resp).await, "hello");
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    let history = storage::memory_query(&state.db, MemoryQueryRequest::default()).await.unwrap();
+    assert!(history.items.is_empty());
+
+    state.config.write().await.ollama_base_url = "http://127.0.0.1:9".to_string();
+    state.config.write().await.max_retries = 0;
+    let resp = chat_title(State(state.clone()), Json(req())).await.into_response();
+    assert_eq!(title(resp).await, "Can you help me plan a…");
+
+    let empty = ChatTitleRequest {
+      messages: vec![message("assistant", "hi")],
+    };
+    let resp = chat_title(State(state), Json(empty)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn chat_debug_builds_the_openrouter_request_without_the_key() {
+    let state = test_state();
+    {
+      let mut config = state.config.write().await;
+      config.global_system_prompt = Some("Be brief.".to_string());
+      config.key_storage = keys::KeyStorage::File;
+      config.openrouter_base_url = "https://gateway.example/v1/".to_string();
+    }
+    let store = keys::KeyStore::new(keys::KeyStorage::File, &state.key_file);
+    keys::set_key(&store, Provider::OpenRouter, "sk-or-very-secret").unwrap();
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "It's fine".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("openrouter:openai/gpt-4o-mini".to_string()),
+      temperature: Some(0.5),
+      ..Default::default()
+    };
+    let resp = chat_debug(State(state.clone()), Json(req)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!text.contains("sk-or-very-secret"), "{text}");
+    let body: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(body["url"], "https://gateway.example/v1/chat/completions");
+    assert_eq!(body["payload"]["model"], "openai/gpt-4o-mini");
+    assert_eq!(body["payload"]["temperature"], 0.5);
+    assert_eq!(body["payload"]["messages"][0]["content"], "Be brief.");
+    let curl = body["curl"].as_str().unwrap();
+    assert!(curl.starts_with("curl -sS 'https://gateway.example/v1/chat/completions'"), "{curl}");
+    assert!(curl.contains("\"Authorization: Bearer $OPENROUTER_API_KEY\""), "{curl}");
+    assert!(curl.contains("'x-title: "), "{curl}");
+    assert!(curl.contains("It'\\''s fine"), "{curl}");
+    let _ = std::fs::remove_file(&state.key_file);
+
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "hi".to_string(),
+        ..Default::default()
+      }],
+      model_override: Some("ollama:llama3".to_string()),
+      ..Default::default()
+    };
+    let resp = chat_debug(State(state.clone()), Json(req)).await;
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "not_openrouter");
+
+    // Images get the same checks as a real chat.
+    let req = ChatRequest {
+      images: Some(vec![ImageData {
+        mime: "application/pdf".to_string(),
+        base64: "JVBERi0=".to_string(),
+      }]),
+      ..Default::default()
+    };
+    let resp = chat_debug(State(state), Json(req)).await;
+    assert_eq!(resp.extensions().get::<ErrorCode>().unwrap().0, "bad_image_type");
+  }
+
+  #[test]
+  fn openrouter_requests_follow_the_base_url_override() {
+    let client = reqwest::Client::new();
+    let url = |config: &AppConfig| {
+      let Ok(request) = build_openrouter_request(&client, "key", config) else {
+        panic!("request should build");
+      };
+      request.build().unwrap().url().to_string()
+    };
+    let mut config = AppConfig::default();
+    assert_eq!(url(&config), "https://openrouter.ai/api/v1/chat/completions");
+    config.openrouter_base_url = "http://localhost:4000/v1/".to_string();
+    assert_eq!(url(&config), "http://localhost:4000/v1/chat/completions");
+  }
+
+  #[tokio::test]
+  async fn a_gateway_base_url_receives_every_openrouter_call() {
+    // Anything still bound for openrouter.ai would show up as a proxy connection.
+    let (state, connections) = embedding_state().await;
+    let paths = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let seen = paths.clone();
+    let gateway = Router::new().fallback(move |uri: axum::http::Uri| {
+      seen.lock().unwrap().push(uri.path().to_string());
+      async move {
+        Json(match uri.path() {
+          "/v1/chat/completions" => serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": "hello" }, "finish_reason": "stop" }]
+          }),
+          "/v1/embeddings" => serde_json::json!({ "data": [{ "index": 0, "embedding": [0.6, 0.8] }] }),
+          _ => serde_json::json!({ "data": [] }),
+        })
+      }
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, gateway).await });
+    state.config.write().await.openrouter_base_url = format!("http://127.0.0.1:{port}/v1/");
+
+    let req = ChatRequest {
+      messages: user_hi(),
+      model_override: Some("openrouter:some/model".to_string()),
+      stream: Some(false),
+      ..Default::default()
+    };
+    let resp = chat(State(state.clone()), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(health_deep(State(state.clone())).await.status(), StatusCode::OK);
+    let resp = refresh_models(State(state.clone())).await.into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let query = MemoryQueryRequest {
+      query: "hi".to_string(),
+      mode: SearchMode::Semantic,
+      ..Default::default()
+    };
+    let resp = memory_query(State(state.clone()), Json(query)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["mode"], "semantic");
+
+    // The price prefetch and the history embedding run in the background.
+    let called = |path: &str| paths.lock().unwrap().iter().filter(|seen| *seen == path).count();
+    let settled = async {
+      while called("/v1/models") < 2 || called("/v1/embeddings") < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    tokio::time::timeout(Duration::from_secs(5), settled).await.unwrap();
+    assert_eq!(called("/v1/chat/completions"), 1);
+    assert_eq!(called("/v1/key"), 1);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 0);
+    let _ = std::fs::remove_file(&state.key_file);
+  }
+
+  #[tokio::test]
+  async fn resume_replays_deltas_after_last_event_id() {
+    let state = test_state();
+    let req = ChatRequest {
+      messages: vec![Message {
+        role: "user".to_string(),
+        content: "one two".to_string(),
+        ..Default::default()
+      }],
+      dry_run: Some(true),
+      ..Default::default()
+    };
+    let Ok(ChatReply::Frames(mut frames)) = run_chat(state.clone(), req).await else {
+      panic!("expected a stream");
+    };
+    let meta = frames.next().await.unwrap();
+    let request_id = meta.data["request_id"].as_str().unwrap().to_string();
+    let first = frames.next().await.unwrap();
+    assert_eq!((first.event, first.id), ("delta", Some(1)));
+    // The original keeps streaming while the reconnect catches up.
+    let original = tokio::spawn(async move { while frames.next().await.is_some() {} });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(LAST_EVENT_ID, HeaderValue::from_static("1"));
+    let resp = chat_resume(State(state.clone()), Path(request_id.clone()), headers).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains(r#"{"text":"Echo: "}"#), "{body}");
+    assert!(body.contains("id: 2\n"), "{body}");
+    assert!(body.contains(r#"data: {"text":"two"}"#), "{body}");
+    assert!(body.contains(r#""finish_reason":"stop""#), "{body}");
+    original.await.unwrap();
+
+    let missing = chat_resume(State(state), Path("nope".to_string()), HeaderMap::new()).await;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn chat_rejects_models_outside_the_allowlist() {
+    let state = test_state();
+    state.config.write().await.allowed_models = Some(vec!["ollama:*".to_string()]);
+    let req = ChatRequest {
+      messages: user_hi(),
+      model_override: Some("openai/gpt-4o".to_string()),
+      ..Default::default()
+    };
+    let resp = chat(State(state), HeaderMap::new(), Json(req)).await.into_response();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "model_not_allowed");
   }
 }
@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::sync::Mutex;
+
+use crate::config::AppConfig;
+use crate::keys;
+use crate::models::{MemoryQueryRequest, MemoryStoreRequest, SelfTestReport, SelfTestStep};
+use crate::router::{self, Provider};
+use crate::storage;
+
+const CHAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Fail at startup rather than on the first config save or history write.
+pub fn ensure_writable(dir: &Path) -> anyhow::Result<()> {
+  let probe = dir.join(format!(".halodesk-write-test-{}", std::process::id()));
+  std::fs::write(&probe, b"").with_context(|| format!("data dir {} is not writable", dir.display()))?;
+  let _ = std::fs::remove_file(&probe);
+  Ok(())
+}
+
+// Every step runs even after a failure, so one report shows everything that is wrong.
+pub async fn run(data_dir: &Path, config: &AppConfig) -> SelfTestReport {
+  let key_store = keys::KeyStore::new(config.key_storage, &data_dir.join(keys::KEY_FILE));
+  let steps = vec![
+    step("data_dir", async { ensure_writable(data_dir).map(|()| data_dir.display().to_string()) }).await,
+    step("database", check_database(data_dir)).await,
+    step("keyring", async { check_key_store(&key_store) }).await,
+    step("openrouter_chat", check_chat(config, &key_store)).await,
+  ];
+  SelfTestReport {
+    ok: steps.iter().all(|step| step.ok),
+    steps,
+  }
+}
+
+async fn step<F>(name: &str, check: F) -> SelfTestStep
+where
+  F: Future<Output = anyhow::Result<String>>,
+{
+  let started = Instant::now();
+  let result = check.await;
+  let latency_ms = started.elapsed().as_millis() as i64;
+  let (ok, message) = match result {
+    Ok(message) => (true, message),
+    Err(err) => (false, format!("{err:#}")),
+  };
+  SelfTestStep {
+    name: name.to_string(),
+    ok,
+    message,
+    latency_ms,
+  }
+}
+
+// A scratch database next to the real one: same filesystem and migrations,
+// without leaving a test note in the user's memory.
+async fn check_database(data_dir: &Path) -> anyhow::Result<String> {
+  let path = data_dir.join(format!(".halodesk-self-test-{}.sqlite3", uuid::Uuid::new_v4()));
+  let result = round_trip(&path).await;
+  for suffix in ["", "-wal", "-shm"] {
+    let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+  }
+  result
+}
+
+async fn round_trip(path: &Path) -> anyhow::Result<String> {
+  let db = Mutex::new(storage::init_db(path)?);
+  let marker = format!("selftest{}", uuid::Uuid::new_v4().simple());
+  let store = MemoryStoreRequest {
+    r#type: "pinned".to_string(),
+    payload: serde_json::json!({ "text": marker }),
+  };
+  let stored = storage::memory_store(&db, store).await?;
+  let query = MemoryQueryRequest {
+    query: marker,
+    ..Default::default()
+  };
+  let found = storage::memory_query(&db, query).await?;
+  let matched = found.items.iter().any(|item| item.payload["id"] == stored.id.as_str());
+  anyhow::ensure!(matched, "stored row was not found by a query");
+  Ok("store and query round trip ok".to_string())
+}
+
+fn check_key_store(store: &keys::KeyStore) -> anyhow::Result<String> {
+  let stored = keys::probe_store(store, Provider::OpenRouter).map_err(anyhow::Error::msg)?;
+  let place = match store {
+    keys::KeyStore::Keyring => "keyring",
+    keys::KeyStore::File(_) => "key file",
+  };
+  Ok(if stored {
+    format!("{place} reachable; OpenRouter key stored")
+  } else {
+    format!("{place} reachable; no OpenRouter key stored")
+  })
+}
+
+// The text default when it is an OpenRouter model, otherwise the stock default.
+fn chat_model(config: &AppConfig) -> String {
+  let default = &config.text_default_model;
+  if default.starts_with("openrouter:") || !default.contains(':') {
+    default.clone()
+  } else {
+    AppConfig::default().text_default_model
+  }
+}
+
+async fn check_chat(config: &AppConfig, key_store: &keys::KeyStore) -> anyhow::Result<String> {
+  let key = keys::get_key(key_store, Provider::OpenRouter, config.prefer_env_key)
+    .context("no OpenRouter key stored or in OPENROUTER_API_KEY")?;
+  let model = chat_model(config);
+  let client = router::build_http_client()?;
+  router::probe_openrouter_chat(&client, &key, &model, config, CHAT_TIMEOUT)
+    .await
+    .map_err(anyhow::Error::msg)?;
+  Ok(format!("{model} replied"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn database_round_trip_cleans_up() {
+    let dir = std::env::temp_dir().join(format!("halodesk-selftest-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let step = step("database", check_database(&dir)).await;
+    assert!(step.ok, "{}", step.message);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[tokio::test]
+  async fn failed_step_keeps_its_message() {
+    let missing = Path::new("/nonexistent/halodesk");
+    let step = step("data_dir", async { ensure_writable(missing).map(|()| String::new()) }).await;
+    assert!(!step.ok);
+    assert!(step.message.contains("not writable"), "{}", step.message);
+  }
+
+  #[test]
+  fn chat_model_stays_on_openrouter() {
+    let mut config = AppConfig {
+      text_default_model: "openrouter:meta/llama".to_string(),
+      ..AppConfig::default()
+    };
+    assert_eq!(chat_model(&config), "openrouter:meta/llama");
+    config.text_default_model = "ollama:llama3".to_string();
+    assert_eq!(chat_model(&config), AppConfig::default().text_default_model);
+  }
+}
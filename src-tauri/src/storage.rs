@@ -1,14 +1,118 @@
-﻿use std::path::Path;
+﻿use std::collections::HashSet;
+use std::path::Path;
 use std::time::Instant;
 
-use chrono::Utc;
-use rusqlite::{params, Connection};
+use base64::Engine;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
 use tokio::sync::Mutex;
 
-use crate::models::{MemoryItem, MemoryQueryRequest, MemoryQueryResponse, MemoryStoreRequest, MemoryStoreResponse, Message};
+use crate::embeddings;
+use crate::models::{
+  AuditRecord, HistoryRow, ImageData, ImportMode, MemoryCompactResponse, MemoryDeleteRequest, MemoryExport,
+  MemoryImportRequest, MemoryImportResponse, MemoryItem, MemoryQueryRequest, MemoryQueryResponse,
+  MemoryStoreBatchResponse, MemoryStoreRequest, MemoryStoreResponse, MemoryUpdateRequest, MemoryUpdateResponse, Message,
+  ModelUsageCount, OutputImage, PinnedRow, Preset, PresetRow, PresetSaveRequest, SearchMode, SettingRow, StoredImage,
+  ToolCall, Usage,
+};
+
+// Lets callers tell bad input (a client problem) from database failures.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+  #[error("{0}")]
+  Invalid(String),
+  #[error(transparent)]
+  Internal(#[from] anyhow::Error),
+}
+
+impl From<rusqlite::Error> for StorageError {
+  fn from(err: rusqlite::Error) -> Self {
+    StorageError::Internal(err.into())
+  }
+}
+
+fn invalid(message: impl Into<String>) -> StorageError {
+  StorageError::Invalid(message.into())
+}
 
 pub fn init_db(path: &Path) -> anyhow::Result<Connection> {
   let conn = Connection::open(path)?;
+  // WAL lets readers (the UI, the compact/prune routes) proceed while the
+  // router writes history, and NORMAL sync is durable enough in WAL mode without
+  // an fsync per chat. The busy timeout retries instead of failing on brief locks.
+  conn.query_row("PRAGMA journal_mode=WAL", [], |row| row.get::<_, String>(0))?;
+  conn.pragma_update(None, "synchronous", "NORMAL")?;
+  conn.busy_timeout(std::time::Duration::from_millis(5000))?;
+  migrate(&conn)?;
+  Ok(conn)
+}
+
+type Migration = fn(&Connection) -> anyhow::Result<()>;
+
+// Append only; never edit a shipped entry. Databases from before versioning
+// start at 0 with any mix of these changes applied, so every step has to be
+// safe to re-run.
+const MIGRATIONS: &[Migration] = &[
+  create_base_tables,
+  |conn| {
+    ensure_column(conn, "history", "prompt_tokens", "INTEGER")?;
+    ensure_column(conn, "history", "completion_tokens", "INTEGER")
+  },
+  |conn| {
+    ensure_column(conn, "history", "thread_id", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS history_thread_idx ON history(thread_id, created_at);")?;
+    Ok(())
+  },
+  |conn| ensure_column(conn, "history", "latency_ms", "INTEGER"),
+  dedupe_settings,
+  init_fts,
+  |conn| ensure_column(conn, "history", "tool_calls_json", "TEXT"),
+  |conn| {
+    ensure_column(conn, "history", "deleted_at", "TEXT")?;
+    conn.execute_batch("CREATE INDEX IF NOT EXISTS history_deleted_idx ON history(deleted_at);")?;
+    Ok(())
+  },
+  create_audit_table,
+  |conn| ensure_column(conn, "history", "partial", "INTEGER NOT NULL DEFAULT 0"),
+  |conn| {
+    ensure_column(conn, "history", "embedding", "BLOB")?;
+    ensure_column(conn, "pinned", "embedding", "BLOB")
+  },
+  |conn| ensure_column(conn, "history", "favorite", "INTEGER NOT NULL DEFAULT 0"),
+  |conn| {
+    ensure_column(conn, "history", "cost_usd", "REAL")?;
+    ensure_column(conn, "audit", "cost_usd", "REAL")
+  },
+  |conn| ensure_column(conn, "history", "output_images_json", "TEXT"),
+  |conn| ensure_column(conn, "history", "images_json", "TEXT"),
+  |conn| {
+    for table in ["pinned", "presets", "settings"] {
+      ensure_column(conn, table, "updated_at", "TEXT")?;
+    }
+    Ok(())
+  },
+];
+
+fn schema_version(conn: &Connection) -> anyhow::Result<usize> {
+  let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+  Ok(version.max(0) as usize)
+}
+
+// Each step commits together with its version bump, so a failed upgrade
+// resumes from the last good step on the next launch.
+fn migrate(conn: &Connection) -> anyhow::Result<()> {
+  let current = schema_version(conn)?;
+  for (index, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+    let tx = conn.unchecked_transaction()?;
+    migration(&tx)?;
+    tx.pragma_update(None, "user_version", index + 1)?;
+    tx.commit()?;
+  }
+  Ok(())
+}
+
+fn create_base_tables(conn: &Connection) -> anyhow::Result<()> {
   conn.execute_batch(
     "
     CREATE TABLE IF NOT EXISTS history (
@@ -40,42 +144,442 @@ pub fn init_db(path: &Path) -> anyhow::Result<Connection> {
     );
     ",
   )?;
-  Ok(conn)
+  Ok(())
+}
+
+// Kept apart from history: audit rows are never searched, exported or pruned.
+fn create_audit_table(conn: &Connection) -> anyhow::Result<()> {
+  conn.execute_batch(
+    "
+    CREATE TABLE IF NOT EXISTS audit (
+      id TEXT PRIMARY KEY,
+      created_at TEXT NOT NULL,
+      model TEXT,
+      provider TEXT,
+      has_image INTEGER NOT NULL,
+      latency_ms INTEGER NOT NULL,
+      status TEXT NOT NULL,
+      error_code TEXT,
+      prompt_tokens INTEGER,
+      completion_tokens INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS audit_created_idx ON audit(created_at);
+    ",
+  )?;
+  Ok(())
+}
+
+// Unversioned databases may already have a column, so adding one checks first.
+fn ensure_column(conn: &Connection, table: &str, column: &str, definition: &str) -> anyhow::Result<()> {
+  let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+  let exists = stmt
+    .query_map([], |row| row.get::<_, String>(1))?
+    .collect::<rusqlite::Result<Vec<_>>>()?
+    .iter()
+    .any(|name| name == column);
+  if !exists {
+    conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"), [])?;
+  }
+  Ok(())
 }
 
-pub async fn store_history(
+const FTS_TABLES: [(&str, &str); 3] = [("history", "messages_json"), ("pinned", "text"), ("presets", "name")];
+
+// Settings used to be append-only; keep the newest row per key so the unique
+// index (which backs the upsert in settings_set) can be created.
+fn dedupe_settings(conn: &Connection) -> anyhow::Result<()> {
+  conn.execute_batch(
+    "
+    DELETE FROM settings WHERE EXISTS (
+      SELECT 1 FROM settings newer
+      WHERE newer.key = settings.key
+        AND (newer.created_at > settings.created_at
+          OR (newer.created_at = settings.created_at AND newer.rowid > settings.rowid))
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS settings_key_idx ON settings(key);
+    ",
+  )?;
+  Ok(())
+}
+
+fn upsert_setting(conn: &Connection, key: &str, value_json: &str) -> anyhow::Result<String> {
+  let id: String = conn.query_row(
+    "INSERT INTO settings (id, created_at, key, value_json) VALUES (?1, ?2, ?3, ?4)
+     ON CONFLICT(key) DO UPDATE SET created_at = excluded.created_at, value_json = excluded.value_json
+     RETURNING id",
+    params![uuid::Uuid::new_v4().to_string(), Utc::now().to_rfc3339(), key, value_json],
+    |row| row.get(0),
+  )?;
+  Ok(id)
+}
+
+pub async fn settings_set(db: &Mutex<Connection>, key: &str, value: &serde_json::Value) -> anyhow::Result<()> {
+  let conn = db.lock().await;
+  upsert_setting(&conn, key, &value.to_string())?;
+  Ok(())
+}
+
+pub async fn settings_get(db: &Mutex<Connection>, key: &str) -> anyhow::Result<Option<serde_json::Value>> {
+  let conn = db.lock().await;
+  let value_json: Option<String> = conn
+    .query_row("SELECT value_json FROM settings WHERE key = ?1", params![key], |row| row.get(0))
+    .optional()?;
+  Ok(value_json.map(|v| serde_json::from_str(&v).unwrap_or(serde_json::Value::String(v))))
+}
+
+fn init_fts(conn: &Connection) -> anyhow::Result<()> {
+  for (table, column) in FTS_TABLES {
+    let fts = format!("{table}_fts");
+    let exists: bool = conn.query_row(
+      "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+      params![fts],
+      |row| row.get(0),
+    )?;
+    conn.execute_batch(&format!(
+      "
+      CREATE VIRTUAL TABLE IF NOT EXISTS {fts} USING fts5({column}, content='{table}', content_rowid='rowid');
+      CREATE TRIGGER IF NOT EXISTS {fts}_insert AFTER INSERT ON {table} BEGIN
+        INSERT INTO {fts}(rowid, {column}) VALUES (new.rowid, new.{column});
+      END;
+      CREATE TRIGGER IF NOT EXISTS {fts}_delete AFTER DELETE ON {table} BEGIN
+        INSERT INTO {fts}({fts}, rowid, {column}) VALUES ('delete', old.rowid, old.{column});
+      END;
+      CREATE TRIGGER IF NOT EXISTS {fts}_update AFTER UPDATE ON {table} BEGIN
+        INSERT INTO {fts}({fts}, rowid, {column}) VALUES ('delete', old.rowid, old.{column});
+        INSERT INTO {fts}(rowid, {column}) VALUES (new.rowid, new.{column});
+      END;
+      "
+    ))?;
+    if !exists {
+      conn.execute(&format!("INSERT INTO {fts}({fts}) VALUES ('rebuild')"), [])?;
+    }
+  }
+  Ok(())
+}
+
+// Only history is pruned; pinned notes and presets are kept regardless. The row
+// cap counts live rows only, since trashed ones are purged from the trash.
+pub async fn prune_history(
   db: &Mutex<Connection>,
-  messages: &[Message],
-  assistant: &str,
-  model: &str,
-  provider: &str,
-) -> anyhow::Result<String> {
-  let mut all = messages.to_vec();
-  if !assistant.trim().is_empty() {
+  max_age_days: Option<u32>,
+  max_rows: Option<u32>,
+) -> anyhow::Result<usize> {
+  let conn = db.lock().await;
+  let mut deleted = 0;
+  if let Some(days) = max_age_days {
+    let cutoff = (Utc::now() - Duration::days(i64::from(days))).to_rfc3339();
+    deleted += conn.execute(
+      "DELETE FROM history WHERE favorite = 0 AND julianday(created_at) < julianday(?1)",
+      params![cutoff],
+    )?;
+  }
+  if let Some(rows) = max_rows {
+    deleted += conn.execute(
+      "DELETE FROM history WHERE rowid IN (
+         SELECT rowid FROM history WHERE favorite = 0 AND deleted_at IS NULL
+         ORDER BY julianday(created_at) DESC, rowid DESC LIMIT -1 OFFSET ?1
+       )",
+      params![rows],
+    )?;
+  }
+  Ok(deleted)
+}
+
+// Main file plus WAL; zero for in-memory databases.
+fn db_size(conn: &Connection) -> u64 {
+  let Some(path) = conn.path().filter(|path| !path.is_empty()) else {
+    return 0;
+  };
+  [path.to_string(), format!("{path}-wal")]
+    .iter()
+    .filter_map(|file| std::fs::metadata(file).ok())
+    .map(|meta| meta.len())
+    .sum()
+}
+
+// VACUUM may renumber the implicit rowids the FTS indexes point at, so they are
+// rebuilt before the WAL is folded back into the main file.
+pub async fn compact(db: &Mutex<Connection>) -> anyhow::Result<MemoryCompactResponse> {
+  let conn = db.lock().await;
+  let bytes_before = db_size(&conn);
+  conn.execute_batch("VACUUM;")?;
+  for (table, _) in FTS_TABLES {
+    let fts = format!("{table}_fts");
+    conn.execute(&format!("INSERT INTO {fts}({fts}) VALUES ('rebuild')"), [])?;
+  }
+  conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+  Ok(MemoryCompactResponse {
+    bytes_before,
+    bytes_after: db_size(&conn),
+  })
+}
+
+// Quote every term so user input can't inject FTS5 syntax, and prefix-match
+// each one to stay close to the old substring search.
+fn fts_query(query: &str) -> Option<String> {
+  let terms: Vec<String> = query
+    .split_whitespace()
+    .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+    .collect();
+  if terms.is_empty() {
+    None
+  } else {
+    Some(terms.join(" "))
+  }
+}
+
+#[derive(Default)]
+pub struct DateRange {
+  from: Option<String>,
+  to: Option<String>,
+}
+
+fn parse_date_bound(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, StorageError> {
+  value
+    .map(|raw| {
+      DateTime::parse_from_rfc3339(raw)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| invalid(format!("{name} must be an RFC3339 timestamp.")))
+    })
+    .transpose()
+}
+
+// Bounds are normalised to UTC so they compare cleanly against stored rows.
+fn date_range(from: Option<&str>, to: Option<&str>) -> Result<DateRange, StorageError> {
+  let from = parse_date_bound("from", from)?;
+  let to = parse_date_bound("to", to)?;
+  if let (Some(from), Some(to)) = (from, to) {
+    if from > to {
+      return Err(invalid("from must not be after to."));
+    }
+  }
+  let format = |date: DateTime<Utc>| date.to_rfc3339_opts(SecondsFormat::Micros, true);
+  Ok(DateRange {
+    from: from.map(format),
+    to: to.map(format),
+  })
+}
+
+struct SearchSql {
+  select: String,
+  args: Vec<Value>,
+}
+
+// One table's matches as (kind, id, score, favorite, created) rows, so all three
+// tables can be merged and paged together in a single UNION ALL.
+fn search_sql(table: &str, fts: Option<&str>, range: &DateRange, tags: &[String], favorites_only: bool) -> SearchSql {
+  let mut args = Vec::new();
+  let mut conditions = Vec::new();
+  let (from, score) = match fts {
+    Some(query) => {
+      conditions.push(format!("{table}_fts MATCH ?"));
+      args.push(Value::Text(query.to_string()));
+      (
+        format!("{table}_fts JOIN {table} t ON t.rowid = {table}_fts.rowid"),
+        format!("-bm25({table}_fts)"),
+      )
+    }
+    None => (format!("{table} t"), "0.0".to_string()),
+  };
+  push_filters(table, range, tags, favorites_only, &mut conditions, &mut args);
+  let filter = if conditions.is_empty() {
+    String::new()
+  } else {
+    format!(" WHERE {}", conditions.join(" AND "))
+  };
+  let favorite = if table == "history" { "t.favorite" } else { "0" };
+  SearchSql {
+    select: format!(
+      "SELECT '{table}' AS kind, t.id AS id, {score} AS score, {favorite} AS favorite, \
+       julianday(t.created_at) AS created FROM {from}{filter}"
+    ),
+    args,
+  }
+}
+
+fn push_filters(
+  table: &str,
+  range: &DateRange,
+  tags: &[String],
+  favorites_only: bool,
+  conditions: &mut Vec<String>,
+  args: &mut Vec<Value>,
+) {
+  // Trashed history stays out of search until it is restored.
+  if table == "history" {
+    conditions.push("t.deleted_at IS NULL".to_string());
+  }
+  // Only history rows can be favorites.
+  if favorites_only {
+    conditions.push(if table == "history" { "t.favorite = 1" } else { "0" }.to_string());
+  }
+  // julianday() copes with the mix of offsets and precisions in created_at.
+  match (&range.from, &range.to) {
+    (Some(from), Some(to)) => {
+      conditions.push("julianday(t.created_at) BETWEEN julianday(?) AND julianday(?)".to_string());
+      args.push(Value::Text(from.clone()));
+      args.push(Value::Text(to.clone()));
+    }
+    (Some(from), None) => {
+      conditions.push("julianday(t.created_at) >= julianday(?)".to_string());
+      args.push(Value::Text(from.clone()));
+    }
+    (None, Some(to)) => {
+      conditions.push("julianday(t.created_at) <= julianday(?)".to_string());
+      args.push(Value::Text(to.clone()));
+    }
+    (None, None) => {}
+  }
+  // Only pinned items have tags.
+  if !tags.is_empty() && table != "pinned" {
+    conditions.push("0".to_string());
+  }
+  for tag in tags {
+    conditions.push("EXISTS (SELECT 1 FROM json_each(t.tags_json) WHERE json_each.value = ?)".to_string());
+    args.push(Value::Text(tag.clone()));
+  }
+}
+
+#[derive(Default)]
+pub struct HistoryEntry<'a> {
+  pub messages: &'a [Message],
+  pub assistant: &'a str,
+  pub model: &'a str,
+  pub provider: &'a str,
+  pub usage: Option<&'a Usage>,
+  // USD for `usage` at the model's price; unset when usage is unknown.
+  pub cost_usd: Option<f64>,
+  pub thread_id: Option<&'a str>,
+  pub latency_ms: Option<i64>,
+  pub tool_calls: &'a [ToolCall],
+  pub images: &'a [StoredImage],
+  // Images in the reply, in the order they arrived.
+  pub output_images: &'a [OutputImage],
+  // Set while a streamed reply is still being written; see update_history.
+  pub partial: bool,
+}
+
+fn messages_json(entry: &HistoryEntry) -> serde_json::Result<String> {
+  let mut all = entry.messages.to_vec();
+  if !entry.assistant.trim().is_empty() {
     all.push(Message {
       role: "assistant".to_string(),
-      content: assistant.to_string(),
+      content: entry.assistant.to_string(),
+      ..Default::default()
     });
   }
+  serde_json::to_string(&all)
+}
+
+fn output_images_json(entry: &HistoryEntry) -> serde_json::Result<Option<String>> {
+  if entry.output_images.is_empty() {
+    Ok(None)
+  } else {
+    serde_json::to_string(entry.output_images).map(Some)
+  }
+}
 
-  let messages_json = serde_json::to_string(&all)?;
+fn tool_calls_json(entry: &HistoryEntry) -> serde_json::Result<Option<String>> {
+  if entry.tool_calls.is_empty() {
+    Ok(None)
+  } else {
+    serde_json::to_string(entry.tool_calls).map(Some)
+  }
+}
+
+pub async fn store_history(db: &Mutex<Connection>, entry: HistoryEntry<'_>) -> anyhow::Result<String> {
+  let messages_json = messages_json(&entry)?;
+  let tool_calls_json = tool_calls_json(&entry)?;
+  let output_images_json = output_images_json(&entry)?;
+  let images_json = if entry.images.is_empty() {
+    None
+  } else {
+    Some(serde_json::to_string(entry.images)?)
+  };
   let id = uuid::Uuid::new_v4().to_string();
   let created_at = Utc::now().to_rfc3339();
   let conn = db.lock().await;
   conn.execute(
-    "INSERT INTO history (id, created_at, messages_json, model, provider) VALUES (?1, ?2, ?3, ?4, ?5)",
-    params![id, created_at, messages_json, model, provider],
+    "INSERT INTO history (id, created_at, messages_json, model, provider, prompt_tokens, completion_tokens, thread_id,
+       latency_ms, tool_calls_json, images_json, partial, cost_usd, output_images_json)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+    params![
+      id,
+      created_at,
+      messages_json,
+      entry.model,
+      entry.provider,
+      entry.usage.map(|u| u.prompt_tokens),
+      entry.usage.map(|u| u.completion_tokens),
+      entry.thread_id,
+      entry.latency_ms,
+      tool_calls_json,
+      images_json,
+      entry.partial,
+      entry.cost_usd,
+      output_images_json
+    ],
   )?;
   Ok(id)
 }
 
+// Rewrites the reply side of a row made by store_history; model, provider,
+// thread and images are fixed at insert. Output images only accumulate, so an
+// entry without them keeps the ones already stored.
+pub async fn update_history(db: &Mutex<Connection>, id: &str, entry: HistoryEntry<'_>) -> anyhow::Result<()> {
+  let messages_json = messages_json(&entry)?;
+  let tool_calls_json = tool_calls_json(&entry)?;
+  let output_images_json = output_images_json(&entry)?;
+  let conn = db.lock().await;
+  conn.execute(
+    "UPDATE history SET messages_json = ?1, prompt_tokens = ?2, completion_tokens = ?3, latency_ms = ?4,
+       tool_calls_json = ?5, partial = ?6, cost_usd = ?7, output_images_json = COALESCE(?8, output_images_json)
+     WHERE id = ?9",
+    params![
+      messages_json,
+      entry.usage.map(|u| u.prompt_tokens),
+      entry.usage.map(|u| u.completion_tokens),
+      entry.latency_ms,
+      tool_calls_json,
+      entry.partial,
+      entry.cost_usd,
+      output_images_json,
+      id
+    ],
+  )?;
+  Ok(())
+}
+
 pub async fn memory_store(
   db: &Mutex<Connection>,
   req: MemoryStoreRequest,
-) -> anyhow::Result<MemoryStoreResponse> {
+) -> Result<MemoryStoreResponse, StorageError> {
+  let conn = db.lock().await;
+  store_item(&conn, req)
+}
+
+// All or nothing: one failing item rolls back the whole batch.
+pub async fn memory_store_batch(
+  db: &Mutex<Connection>,
+  items: Vec<MemoryStoreRequest>,
+) -> Result<MemoryStoreBatchResponse, StorageError> {
+  let mut conn = db.lock().await;
+  let tx = conn.transaction()?;
+  let mut ids = Vec::with_capacity(items.len());
+  for (index, req) in items.into_iter().enumerate() {
+    match store_item(&tx, req) {
+      Ok(res) => ids.push(res.id),
+      Err(StorageError::Invalid(msg)) => return Err(invalid(format!("item {index}: {msg}"))),
+      Err(err) => return Err(err),
+    }
+  }
+  tx.commit()?;
+  Ok(MemoryStoreBatchResponse { ids })
+}
+
+fn store_item(conn: &Connection, req: MemoryStoreRequest) -> Result<MemoryStoreResponse, StorageError> {
   let id = uuid::Uuid::new_v4().to_string();
   let created_at = Utc::now().to_rfc3339();
-  let conn = db.lock().await;
 
   match req.r#type.as_str() {
     "history" => {
@@ -139,122 +643,2171 @@ pub async fn memory_store(
         .get("value")
         .map(|v| v.to_string())
         .unwrap_or_else(|| "null".to_string());
-      conn.execute(
-        "INSERT INTO settings (id, created_at, key, value_json) VALUES (?1, ?2, ?3, ?4)",
-        params![id, created_at, key, value],
-      )?;
+      let id = upsert_setting(conn, key, &value)?;
+      return Ok(MemoryStoreResponse { id, stored_at: created_at });
     }
-    _ => return Err(anyhow::anyhow!("Unsupported memory type.")),
+    _ => return Err(invalid("Unsupported memory type.")),
   }
 
   Ok(MemoryStoreResponse { id, stored_at: created_at })
 }
 
-pub async fn memory_query(
+fn str_field<'a>(payload: &'a serde_json::Value, field: &str) -> Result<Option<&'a str>, StorageError> {
+  match payload.get(field) {
+    None => Ok(None),
+    Some(value) => value
+      .as_str()
+      .map(Some)
+      .ok_or_else(|| invalid(format!("{field} must be a string."))),
+  }
+}
+
+fn json_field(payload: &serde_json::Value, field: &str) -> Option<String> {
+  payload.get(field).map(|v| v.to_string())
+}
+
+// Mirrors memory_store's columns; history rows are a record of what was sent
+// and cannot be edited. None when no row has that id.
+pub async fn memory_update(
   db: &Mutex<Connection>,
-  req: MemoryQueryRequest,
-) -> anyhow::Result<MemoryQueryResponse> {
-  let start = Instant::now();
-  let limit = req.limit.unwrap_or(20);
-  let like = format!("%{}%", req.query);
+  req: MemoryUpdateRequest,
+) -> Result<Option<MemoryUpdateResponse>, StorageError> {
+  let table = memory_table(&req.r#type)?;
+  if !req.payload.is_object() {
+    return Err(invalid("payload must be a JSON object."));
+  }
+  let payload = &req.payload;
+  let updated_at = Utc::now().to_rfc3339();
   let conn = db.lock().await;
+  let updated = match table {
+    "pinned" => {
+      if payload.get("tags").is_some_and(|tags| !tags.is_array()) {
+        return Err(invalid("tags must be an array."));
+      }
+      // New text makes the stored embedding stale.
+      conn.execute(
+        "UPDATE pinned SET text = COALESCE(?1, text), tags_json = COALESCE(?2, tags_json),
+           embedding = CASE WHEN ?1 IS NULL THEN embedding END, updated_at = ?4
+         WHERE id = ?3",
+        params![str_field(payload, "text")?, json_field(payload, "tags"), req.id, updated_at],
+      )?
+    }
+    "presets" => conn.execute(
+      "UPDATE presets SET name = COALESCE(?1, name), system_prompt = COALESCE(?2, system_prompt),
+         constraints_json = COALESCE(?3, constraints_json), routing_policy_json = COALESCE(?4, routing_policy_json),
+         updated_at = ?6
+       WHERE id = ?5",
+      params![
+        str_field(payload, "name")?,
+        str_field(payload, "system_prompt")?,
+        json_field(payload, "constraints"),
+        json_field(payload, "routing_policy"),
+        req.id,
+        updated_at
+      ],
+    )?,
+    "settings" => {
+      let value = json_field(payload, "value").ok_or_else(|| invalid("value is required."))?;
+      conn.execute(
+        "UPDATE settings SET value_json = ?1, updated_at = ?3 WHERE id = ?2",
+        params![value, req.id, updated_at],
+      )?
+    }
+    _ => return Err(invalid("History items cannot be updated.")),
+  };
+  Ok((updated > 0).then_some(MemoryUpdateResponse { id: req.id, updated_at }))
+}
 
-  let mut items: Vec<MemoryItem> = Vec::new();
+fn memory_table(kind: &str) -> Result<&'static str, StorageError> {
+  match kind {
+    "history" => Ok("history"),
+    "pinned" => Ok("pinned"),
+    "preset" => Ok("presets"),
+    "settings" => Ok("settings"),
+    _ => Err(invalid("Unsupported memory type.")),
+  }
+}
 
-  let mut stmt = conn.prepare(
-    "SELECT id, created_at, messages_json, model, provider FROM history WHERE messages_json LIKE ?1 ORDER BY created_at DESC LIMIT ?2",
+// History goes to the trash (see purge_trash); other kinds are removed outright.
+pub async fn memory_delete(db: &Mutex<Connection>, req: MemoryDeleteRequest) -> Result<bool, StorageError> {
+  let table = memory_table(&req.r#type)?;
+  let conn = db.lock().await;
+  let deleted = if table == "history" {
+    conn.execute(
+      "UPDATE history SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+      params![Utc::now().to_rfc3339(), req.id],
+    )?
+  } else {
+    conn.execute(&format!("DELETE FROM {table} WHERE id = ?1"), params![req.id])?
+  };
+  Ok(deleted > 0)
+}
+
+#[derive(Default)]
+pub struct AuditEntry<'a> {
+  pub model: Option<&'a str>,
+  pub provider: Option<&'a str>,
+  pub has_image: bool,
+  pub latency_ms: i64,
+  pub status: &'a str,
+  pub error_code: Option<&'a str>,
+  pub usage: Option<&'a Usage>,
+  pub cost_usd: Option<f64>,
+}
+
+pub async fn audit_log(db: &Mutex<Connection>, entry: AuditEntry<'_>) -> anyhow::Result<()> {
+  let conn = db.lock().await;
+  conn.execute(
+    "INSERT INTO audit (id, created_at, model, provider, has_image, latency_ms, status, error_code, prompt_tokens,
+       completion_tokens, cost_usd) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+    params![
+      uuid::Uuid::new_v4().to_string(),
+      Utc::now().to_rfc3339(),
+      entry.model,
+      entry.provider,
+      entry.has_image,
+      entry.latency_ms,
+      entry.status,
+      entry.error_code,
+      entry.usage.map(|u| u.prompt_tokens),
+      entry.usage.map(|u| u.completion_tokens),
+      entry.cost_usd
+    ],
   )?;
-  let rows = stmt.query_map(params![like, limit], |row| {
-    Ok((
-      row.get::<_, String>(0)?,
-      row.get::<_, String>(1)?,
-      row.get::<_, String>(2)?,
-      row.get::<_, Option<String>>(3)?,
-      row.get::<_, Option<String>>(4)?,
-    ))
-  })?;
+  Ok(())
+}
 
-  for row in rows {
-    let (id, created_at, messages_json, model, provider) = row?;
-    let payload: serde_json::Value = serde_json::from_str(&messages_json)
-      .unwrap_or(serde_json::Value::String(messages_json));
-    items.push(MemoryItem {
-      r#type: "history".to_string(),
-      payload: serde_json::json!({
-        "id": id,
-        "created_at": created_at,
-        "messages": payload,
-        "model": model,
-        "provider": provider
-      }),
-    });
-  }
+// Newest first.
+pub async fn audit_recent(db: &Mutex<Connection>, limit: usize) -> anyhow::Result<Vec<AuditRecord>> {
+  let conn = db.lock().await;
+  let mut stmt = conn.prepare(
+    "SELECT id, created_at, model, provider, has_image, latency_ms, status, error_code, prompt_tokens,
+       completion_tokens, cost_usd FROM audit ORDER BY created_at DESC, rowid DESC LIMIT ?1",
+  )?;
+  let rows = stmt
+    .query_map(params![limit as i64], |row| {
+      Ok(AuditRecord {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        model: row.get(2)?,
+        provider: row.get(3)?,
+        has_image: row.get(4)?,
+        latency_ms: row.get(5)?,
+        status: row.get(6)?,
+        error_code: row.get(7)?,
+        prompt_tokens: row.get(8)?,
+        completion_tokens: row.get(9)?,
+        cost_usd: row.get(10)?,
+      })
+    })?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+  Ok(rows)
+}
 
+// Most used first. Rows from the generic /v1/memory/store path carry no model
+// and are bucketed as "unknown"; trashed rows are left out.
+pub async fn models_used(db: &Mutex<Connection>) -> anyhow::Result<Vec<ModelUsageCount>> {
+  let conn = db.lock().await;
   let mut stmt = conn.prepare(
-    "SELECT id, created_at, text, tags_json FROM pinned WHERE text LIKE ?1 ORDER BY created_at DESC LIMIT ?2",
+    "SELECT COALESCE(NULLIF(model, ''), 'unknown') AS m, COALESCE(NULLIF(provider, ''), 'unknown') AS p, COUNT(*)
+       FROM history WHERE deleted_at IS NULL GROUP BY m, p ORDER BY COUNT(*) DESC, m ASC",
   )?;
-  let rows = stmt.query_map(params![like, limit], |row| {
-    Ok((
-      row.get::<_, String>(0)?,
-      row.get::<_, String>(1)?,
-      row.get::<_, String>(2)?,
-      row.get::<_, Option<String>>(3)?,
-    ))
-  })?;
+  let rows = stmt
+    .query_map([], |row| {
+      Ok(ModelUsageCount {
+        model: row.get(0)?,
+        provider: row.get(1)?,
+        count: row.get(2)?,
+      })
+    })?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+  Ok(rows)
+}
 
-  for row in rows {
-    let (id, created_at, text, tags_json) = row?;
-    let tags: serde_json::Value = tags_json
-      .and_then(|t| serde_json::from_str(&t).ok())
-      .unwrap_or(serde_json::Value::Array(vec![]));
-    items.push(MemoryItem {
-      r#type: "pinned".to_string(),
-      payload: serde_json::json!({
-        "id": id,
-        "created_at": created_at,
-        "text": text,
-        "tags": tags
-      }),
+// A stored chat as /v1/chat/regenerate needs it.
+pub struct StoredChat {
+  pub messages: Vec<Message>,
+  pub thread_id: Option<String>,
+  pub images: Vec<StoredImage>,
+  pub created_at: String,
+  pub model: Option<String>,
+  pub provider: Option<String>,
+}
+
+pub async fn load_chat(db: &Mutex<Connection>, id: &str) -> Result<Option<StoredChat>, StorageError> {
+  let conn = db.lock().await;
+  let row = conn
+    .query_row(
+      "SELECT messages_json, thread_id, images_json, created_at, model, provider FROM history
+       WHERE id = ?1 AND deleted_at IS NULL",
+      params![id],
+      |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          row.get::<_, Option<String>>(1)?,
+          row.get::<_, Option<String>>(2)?,
+          row.get::<_, String>(3)?,
+          row.get::<_, Option<String>>(4)?,
+          row.get::<_, Option<String>>(5)?,
+        ))
+      },
+    )
+    .optional()?;
+  let Some((messages_json, thread_id, images_json, created_at, model, provider)) = row else {
+    return Ok(None);
+  };
+  // Rows stored through /v1/memory/store can hold any JSON, not just a chat.
+  let messages = serde_json::from_str(&messages_json)
+    .map_err(|_| invalid("That history row does not hold a chat conversation."))?;
+  let images = images_json
+    .and_then(|json| serde_json::from_str(&json).ok())
+    .unwrap_or_default();
+  Ok(Some(StoredChat {
+    messages,
+    thread_id,
+    images,
+    created_at,
+    model,
+    provider,
+  }))
+}
+
+pub async fn memory_restore(db: &Mutex<Connection>, id: &str) -> anyhow::Result<bool> {
+  let conn = db.lock().await;
+  let restored = conn.execute(
+    "UPDATE history SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
+    params![id],
+  )?;
+  Ok(restored > 0)
+}
+
+// Sets the flag, or flips it when `favorite` is None. None when no live
+// history row has that id; otherwise the new value.
+pub async fn memory_favorite(db: &Mutex<Connection>, id: &str, favorite: Option<bool>) -> anyhow::Result<Option<bool>> {
+  let conn = db.lock().await;
+  let favorite = conn
+    .query_row(
+      "UPDATE history SET favorite = COALESCE(?1, 1 - favorite) WHERE id = ?2 AND deleted_at IS NULL
+       RETURNING favorite",
+      params![favorite, id],
+      |row| row.get(0),
+    )
+    .optional()?;
+  Ok(favorite)
+}
+
+pub async fn memory_trash(db: &Mutex<Connection>) -> anyhow::Result<Vec<MemoryItem>> {
+  let conn = db.lock().await;
+  let mut stmt = conn.prepare(&format!(
+    "SELECT {HISTORY_COLUMNS} FROM history t WHERE t.deleted_at IS NOT NULL ORDER BY julianday(t.deleted_at) DESC"
+  ))?;
+  let items = stmt
+    .query_map([], |row| {
+      Ok(MemoryItem {
+        r#type: "history".to_string(),
+        payload: history_payload(row)?,
+        score: 0.0,
+      })
+    })?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+  Ok(items)
+}
+
+// Decoded size; bigger attachments are sent to the model but not kept.
+const MAX_STORED_IMAGE_BYTES: usize = 8 * 1024 * 1024;
+// Images are written before their history row, so fresh files are never swept.
+const IMAGE_SWEEP_GRACE: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+const IMAGE_TYPES: [(&str, &str); 4] = [
+  ("image/png", "png"),
+  ("image/jpeg", "jpg"),
+  ("image/webp", "webp"),
+  ("image/gif", "gif"),
+];
+
+pub fn is_image_mime(mime: &str) -> bool {
+  IMAGE_TYPES.iter().any(|(known, _)| *known == mime)
+}
+
+// Returns one entry per image actually written.
+pub fn save_images(dir: &Path, images: &[&ImageData]) -> anyhow::Result<Vec<StoredImage>> {
+  let mut stored = Vec::new();
+  for image in images {
+    let Some((mime, ext)) = IMAGE_TYPES.iter().find(|(mime, _)| *mime == image.mime) else {
+      continue;
+    };
+    let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(image.base64.trim()) else {
+      continue;
+    };
+    if bytes.is_empty() || bytes.len() > MAX_STORED_IMAGE_BYTES {
+      continue;
+    }
+    std::fs::create_dir_all(dir)?;
+    let id = format!("{}.{ext}", uuid::Uuid::new_v4());
+    std::fs::write(dir.join(&id), &bytes)?;
+    stored.push(StoredImage {
+      id,
+      mime: mime.to_string(),
+      bytes: bytes.len() as u64,
     });
   }
+  Ok(stored)
+}
 
-  let mut stmt = conn.prepare(
-    "SELECT id, created_at, name, system_prompt, constraints_json, routing_policy_json FROM presets WHERE name LIKE ?1 ORDER BY created_at DESC LIMIT ?2",
-  )?;
-  let rows = stmt.query_map(params![like, limit], |row| {
-    Ok((
-      row.get::<_, String>(0)?,
-      row.get::<_, String>(1)?,
-      row.get::<_, String>(2)?,
-      row.get::<_, Option<String>>(3)?,
-      row.get::<_, Option<String>>(4)?,
-      row.get::<_, Option<String>>(5)?,
-    ))
-  })?;
+// Ids are `<uuid>.<ext>`; anything else could point outside the images dir.
+fn image_mime(id: &str) -> Option<&'static str> {
+  let (stem, ext) = id.split_once('.')?;
+  uuid::Uuid::parse_str(stem).ok()?;
+  IMAGE_TYPES.iter().find(|(_, known)| *known == ext).map(|(mime, _)| *mime)
+}
 
-  for row in rows {
-    let (id, created_at, name, system_prompt, constraints_json, routing_json) = row?;
-    let constraints: serde_json::Value = constraints_json
+pub fn load_image(dir: &Path, id: &str) -> Result<Option<ImageData>, StorageError> {
+  let mime = image_mime(id).ok_or_else(|| invalid("Not a stored image id."))?;
+  match std::fs::read(dir.join(id)) {
+    Ok(bytes) => Ok(Some(ImageData {
+      mime: mime.to_string(),
+      base64: base64::engine::general_purpose::STANDARD.encode(bytes),
+    })),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(StorageError::Internal(err.into())),
+  }
+}
+
+// Removes image files no history row (trashed ones included) refers to.
+pub async fn sweep_images(db: &Mutex<Connection>, dir: &Path) -> anyhow::Result<usize> {
+  sweep_images_older_than(db, dir, IMAGE_SWEEP_GRACE).await
+}
+
+async fn sweep_images_older_than(
+  db: &Mutex<Connection>,
+  dir: &Path,
+  grace: std::time::Duration,
+) -> anyhow::Result<usize> {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+    Err(err) => return Err(err.into()),
+  };
+  let conn = db.lock().await;
+  let referenced: HashSet<String> = select_rows(
+    &conn,
+    "SELECT json_extract(image.value, '$.id') FROM history, json_each(history.images_json) AS image
+     WHERE history.images_json IS NOT NULL
+     UNION
+     SELECT json_extract(image.value, '$.id') FROM history, json_each(history.output_images_json) AS image
+     WHERE history.output_images_json IS NOT NULL",
+    |row| row.get::<_, Option<String>>(0),
+  )?
+  .into_iter()
+  .flatten()
+  .collect();
+
+  let mut removed = 0;
+  for entry in entries {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().to_string();
+    if image_mime(&name).is_none() || referenced.contains(&name) {
+      continue;
+    }
+    let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+    if age >= grace {
+      std::fs::remove_file(entry.path())?;
+      removed += 1;
+    }
+  }
+  Ok(removed)
+}
+
+pub async fn purge_trash(db: &Mutex<Connection>, older_than_days: Option<u32>) -> anyhow::Result<usize> {
+  let conn = db.lock().await;
+  let purged = match older_than_days {
+    Some(days) => {
+      let cutoff = (Utc::now() - Duration::days(i64::from(days))).to_rfc3339();
+      conn.execute(
+        "DELETE FROM history WHERE deleted_at IS NOT NULL AND julianday(deleted_at) < julianday(?1)",
+        params![cutoff],
+      )?
+    }
+    None => conn.execute("DELETE FROM history WHERE deleted_at IS NOT NULL", [])?,
+  };
+  Ok(purged)
+}
+
+const PRESET_COLUMNS: &str = "id, name, system_prompt, constraints_json, routing_policy_json";
+
+// Unreadable JSON columns (hand-edited or from old imports) fall back to empty values.
+fn preset_from_row(row: &rusqlite::Row) -> rusqlite::Result<Preset> {
+  let constraints_json: Option<String> = row.get(3)?;
+  let routing_json: Option<String> = row.get(4)?;
+  Ok(Preset {
+    id: row.get(0)?,
+    name: row.get(1)?,
+    system_prompt: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+    constraints: constraints_json
       .and_then(|c| serde_json::from_str(&c).ok())
-      .unwrap_or(serde_json::Value::Object(serde_json::Map::new()));
-    let routing: serde_json::Value = routing_json
+      .unwrap_or(serde_json::Value::Object(serde_json::Map::new())),
+    routing_policy: routing_json
       .and_then(|c| serde_json::from_str(&c).ok())
-      .unwrap_or(serde_json::Value::Object(serde_json::Map::new()));
-    items.push(MemoryItem {
-      r#type: "preset".to_string(),
-      payload: serde_json::json!({
-        "id": id,
-        "created_at": created_at,
-        "name": name,
-        "system_prompt": system_prompt,
-        "constraints": constraints,
-        "routing_policy": routing
-      }),
-    });
+      .unwrap_or_default(),
+  })
+}
+
+pub async fn get_preset(db: &Mutex<Connection>, id: &str) -> anyhow::Result<Option<Preset>> {
+  let conn = db.lock().await;
+  let preset = conn
+    .query_row(
+      &format!("SELECT {PRESET_COLUMNS} FROM presets WHERE id = ?1"),
+      params![id],
+      preset_from_row,
+    )
+    .optional()?;
+  Ok(preset)
+}
+
+pub async fn list_presets(db: &Mutex<Connection>) -> anyhow::Result<Vec<Preset>> {
+  let conn = db.lock().await;
+  let mut stmt = conn.prepare(&format!(
+    "SELECT {PRESET_COLUMNS} FROM presets ORDER BY name COLLATE NOCASE, created_at"
+  ))?;
+  let presets = stmt.query_map([], preset_from_row)?.collect::<Result<Vec<_>, _>>()?;
+  Ok(presets)
+}
+
+// Creates the preset, or replaces every field of the one with `req.id`.
+pub async fn save_preset(db: &Mutex<Connection>, req: PresetSaveRequest) -> Result<Preset, StorageError> {
+  let name = req.name.trim();
+  if name.is_empty() {
+    return Err(invalid("Preset name must not be empty."));
   }
+  let constraints = req
+    .constraints
+    .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new()));
+  if !constraints.is_object() {
+    return Err(invalid("Preset constraints must be a JSON object."));
+  }
+  let id = match req.id {
+    Some(id) if id.trim().is_empty() => return Err(invalid("Preset id must not be empty.")),
+    Some(id) => id,
+    None => uuid::Uuid::new_v4().to_string(),
+  };
+  let routing = serde_json::to_string(&req.routing_policy).map_err(anyhow::Error::from)?;
+  let conn = db.lock().await;
+  conn.execute(
+    "INSERT INTO presets (id, created_at, name, system_prompt, constraints_json, routing_policy_json)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+     ON CONFLICT(id) DO UPDATE SET name = excluded.name, system_prompt = excluded.system_prompt,
+       constraints_json = excluded.constraints_json, routing_policy_json = excluded.routing_policy_json",
+    params![id, Utc::now().to_rfc3339(), name, req.system_prompt, constraints.to_string(), routing],
+  )?;
+  Ok(Preset {
+    id,
+    name: name.to_string(),
+    system_prompt: req.system_prompt,
+    constraints,
+    routing_policy: req.routing_policy,
+  })
+}
+
+pub async fn delete_preset(db: &Mutex<Connection>, id: &str) -> anyhow::Result<bool> {
+  let conn = db.lock().await;
+  Ok(conn.execute("DELETE FROM presets WHERE id = ?1", params![id])? > 0)
+}
+
+const HISTORY_COLUMNS: &str = "t.id, t.created_at, t.messages_json, t.model, t.provider, t.prompt_tokens, \
+  t.completion_tokens, t.thread_id, t.latency_ms, t.tool_calls_json, t.deleted_at, t.images_json, t.partial, \
+  t.favorite, t.cost_usd, t.output_images_json";
+// Where anything selected after HISTORY_COLUMNS (a score, an embedding) starts.
+const HISTORY_COLUMN_COUNT: usize = 16;
+
+// Builds the history payload from a row whose leading columns are HISTORY_COLUMNS.
+fn history_payload(row: &rusqlite::Row) -> rusqlite::Result<serde_json::Value> {
+  let messages_json: String = row.get(2)?;
+  let messages: serde_json::Value =
+    serde_json::from_str(&messages_json).unwrap_or(serde_json::Value::String(messages_json));
+  let json_column = |idx: usize| -> rusqlite::Result<serde_json::Value> {
+    Ok(row
+      .get::<_, Option<String>>(idx)?
+      .and_then(|json| serde_json::from_str(&json).ok())
+      .unwrap_or(serde_json::Value::Null))
+  };
+  Ok(serde_json::json!({
+    "id": row.get::<_, String>(0)?,
+    "created_at": row.get::<_, String>(1)?,
+    "messages": messages,
+    "model": row.get::<_, Option<String>>(3)?,
+    "provider": row.get::<_, Option<String>>(4)?,
+    "prompt_tokens": row.get::<_, Option<i64>>(5)?,
+    "completion_tokens": row.get::<_, Option<i64>>(6)?,
+    "thread_id": row.get::<_, Option<String>>(7)?,
+    "latency_ms": row.get::<_, Option<i64>>(8)?,
+    "tool_calls": json_column(9)?,
+    "deleted_at": row.get::<_, Option<String>>(10)?,
+    "images": json_column(11)?,
+    "partial": row.get::<_, bool>(12)?,
+    "favorite": row.get::<_, bool>(13)?,
+    "cost_usd": row.get::<_, Option<f64>>(14)?,
+    "output_images": json_column(15)?
+  }))
+}
+
+pub async fn memory_thread(db: &Mutex<Connection>, thread_id: &str) -> anyhow::Result<Vec<MemoryItem>> {
+  let conn = db.lock().await;
+  let mut stmt = conn.prepare(&format!(
+    "SELECT {HISTORY_COLUMNS} FROM history t WHERE t.thread_id = ?1 AND t.deleted_at IS NULL ORDER BY t.created_at ASC"
+  ))?;
+  let items = stmt
+    .query_map(params![thread_id], |row| {
+      Ok(MemoryItem {
+        r#type: "history".to_string(),
+        payload: history_payload(row)?,
+        score: 0.0,
+      })
+    })?
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+  Ok(items)
+}
+
+pub async fn memory_query(
+  db: &Mutex<Connection>,
+  req: MemoryQueryRequest,
+) -> Result<MemoryQueryResponse, StorageError> {
+  let start = Instant::now();
+  // SQLite reads a negative LIMIT as no limit at all.
+  let limit = req.limit.unwrap_or(20).max(0);
+  let offset = req.offset.unwrap_or(0);
+  if offset < 0 {
+    return Err(invalid("offset must not be negative."));
+  }
+  let range = date_range(req.from.as_deref(), req.to.as_deref())?;
+  let fts = fts_query(&req.query);
+  let tags = req.tags.unwrap_or_default();
+  let conn = db.lock().await;
+
+  let searches = ["history", "pinned", "presets"]
+    .map(|table| search_sql(table, fts.as_deref(), &range, &tags, req.favorites_only));
+  let union = searches.iter().map(|search| search.select.as_str()).collect::<Vec<_>>().join(" UNION ALL ");
+  let args: Vec<Value> = searches.into_iter().flat_map(|search| search.args).collect();
+  let total = conn.query_row(
+    &format!("SELECT COUNT(*) FROM ({union})"),
+    params_from_iter(args.iter()),
+    |row| row.get(0),
+  )?;
+
+  // One page across all three tables: favorites first, then by score, newest first among equals.
+  let page_args = args.into_iter().chain([Value::Integer(limit), Value::Integer(offset)]);
+  let mut stmt = conn.prepare(&format!(
+    "{union} ORDER BY favorite DESC, score DESC, created DESC LIMIT ? OFFSET ?"
+  ))?;
+  let page = stmt
+    .query_map(params_from_iter(page_args), |row| {
+      Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, f64>(2)?))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+  let items = page
+    .into_iter()
+    .map(|(table, id, score)| search_item(&conn, &table, &id, score))
+    .collect::<rusqlite::Result<Vec<_>>>()?;
 
   Ok(MemoryQueryResponse {
     items,
+    total,
     took_ms: start.elapsed().as_millis() as i64,
+    mode: SearchMode::Lexical,
+  })
+}
+
+// Loads one row of a memory_query page.
+fn search_item(conn: &Connection, table: &str, id: &str, score: f64) -> rusqlite::Result<MemoryItem> {
+  let (kind, payload) = match table {
+    "history" => (
+      "history",
+      conn.query_row(
+        &format!("SELECT {HISTORY_COLUMNS} FROM history t WHERE t.id = ?1"),
+        params![id],
+        history_payload,
+      )?,
+    ),
+    "pinned" => (
+      "pinned",
+      conn.query_row(
+        "SELECT id, created_at, text, tags_json FROM pinned WHERE id = ?1",
+        params![id],
+        |row| Ok(pinned_payload(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+      )?,
+    ),
+    _ => (
+      "preset",
+      conn.query_row(
+        "SELECT id, created_at, name, system_prompt, constraints_json, routing_policy_json FROM presets WHERE id = ?1",
+        params![id],
+        preset_payload,
+      )?,
+    ),
+  };
+  Ok(MemoryItem {
+    r#type: kind.to_string(),
+    payload,
+    score,
   })
 }
+
+fn preset_payload(row: &rusqlite::Row) -> rusqlite::Result<serde_json::Value> {
+  let json_column = |idx: usize| -> rusqlite::Result<serde_json::Value> {
+    Ok(row
+      .get::<_, Option<String>>(idx)?
+      .and_then(|json| serde_json::from_str(&json).ok())
+      .unwrap_or(serde_json::Value::Object(serde_json::Map::new())))
+  };
+  Ok(serde_json::json!({
+    "id": row.get::<_, String>(0)?,
+    "created_at": row.get::<_, String>(1)?,
+    "name": row.get::<_, String>(2)?,
+    "system_prompt": row.get::<_, Option<String>>(3)?,
+    "constraints": json_column(4)?,
+    "routing_policy": json_column(5)?
+  }))
+}
+
+// Favorites first, then by score.
+fn sort_results(items: &mut [MemoryItem]) {
+  let favorite = |item: &MemoryItem| item.payload["favorite"] == true;
+  items.sort_by(|a, b| favorite(b).cmp(&favorite(a)).then(b.score.total_cmp(&a.score)));
+}
+
+fn pinned_payload(id: String, created_at: String, text: String, tags_json: Option<String>) -> serde_json::Value {
+  let tags: serde_json::Value = tags_json
+    .and_then(|t| serde_json::from_str(&t).ok())
+    .unwrap_or(serde_json::Value::Array(vec![]));
+  serde_json::json!({
+    "id": id,
+    "created_at": created_at,
+    "text": text,
+    "tags": tags
+  })
+}
+
+// `kind` is "history" or "pinned", the only items that are embedded.
+pub async fn set_embedding(db: &Mutex<Connection>, kind: &str, id: &str, vector: &[f32]) -> Result<(), StorageError> {
+  let table = memory_table(kind)?;
+  if !matches!(table, "history" | "pinned") {
+    return Err(invalid(format!("{kind} items are not embedded.")));
+  }
+  let conn = db.lock().await;
+  conn.execute(
+    &format!("UPDATE {table} SET embedding = ?1 WHERE id = ?2"),
+    params![embeddings::encode(vector), id],
+  )?;
+  Ok(())
+}
+
+// Ranks every embedded history and pinned row by cosine similarity to
+// `query`. Rows without a comparable embedding are left out, as are presets.
+pub async fn memory_semantic(
+  db: &Mutex<Connection>,
+  req: MemoryQueryRequest,
+  query: &[f32],
+) -> Result<MemoryQueryResponse, StorageError> {
+  let start = Instant::now();
+  let limit = req.limit.unwrap_or(20);
+  let offset = req.offset.unwrap_or(0);
+  if offset < 0 {
+    return Err(invalid("offset must not be negative."));
+  }
+  let range = date_range(req.from.as_deref(), req.to.as_deref())?;
+  let tags = req.tags.unwrap_or_default();
+  let conn = db.lock().await;
+  let score =
+    |blob: Vec<u8>| embeddings::decode(&blob).and_then(|vector| embeddings::cosine_similarity(query, &vector));
+
+  let mut items: Vec<MemoryItem> = Vec::new();
+  let (sql, args) = embedded_rows_sql("history", HISTORY_COLUMNS, &range, &tags, req.favorites_only);
+  let mut stmt = conn.prepare(&sql)?;
+  let rows = stmt.query_map(params_from_iter(args), |row| {
+    Ok((history_payload(row)?, row.get::<_, Vec<u8>>(HISTORY_COLUMN_COUNT)?))
+  })?;
+  for row in rows {
+    let (payload, blob) = row?;
+    if let Some(score) = score(blob) {
+      items.push(MemoryItem {
+        r#type: "history".to_string(),
+        payload,
+        score,
+      });
+    }
+  }
+
+  let columns = "t.id, t.created_at, t.text, t.tags_json";
+  let (sql, args) = embedded_rows_sql("pinned", columns, &range, &tags, req.favorites_only);
+  let mut stmt = conn.prepare(&sql)?;
+  let rows = stmt.query_map(params_from_iter(args), |row| {
+    Ok((
+      pinned_payload(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?),
+      row.get::<_, Vec<u8>>(4)?,
+    ))
+  })?;
+  for row in rows {
+    let (payload, blob) = row?;
+    if let Some(score) = score(blob) {
+      items.push(MemoryItem {
+        r#type: "pinned".to_string(),
+        payload,
+        score,
+      });
+    }
+  }
+
+  sort_results(&mut items);
+  let total = items.len() as i64;
+  let items = items
+    .into_iter()
+    .skip(offset as usize)
+    .take(limit.max(0) as usize)
+    .collect();
+  Ok(MemoryQueryResponse {
+    items,
+    total,
+    took_ms: start.elapsed().as_millis() as i64,
+    mode: SearchMode::Semantic,
+  })
+}
+
+fn embedded_rows_sql(
+  table: &str,
+  columns: &str,
+  range: &DateRange,
+  tags: &[String],
+  favorites_only: bool,
+) -> (String, Vec<Value>) {
+  let mut conditions = vec!["t.embedding IS NOT NULL".to_string()];
+  let mut args = Vec::new();
+  push_filters(table, range, tags, favorites_only, &mut conditions, &mut args);
+  let sql = format!("SELECT {columns}, t.embedding FROM {table} t WHERE {}", conditions.join(" AND "));
+  (sql, args)
+}
+
+fn select_rows<T>(
+  conn: &Connection,
+  sql: &str,
+  map: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+) -> rusqlite::Result<Vec<T>> {
+  let mut stmt = conn.prepare(sql)?;
+  let rows = stmt.query_map([], map)?.collect();
+  rows
+}
+
+pub async fn memory_export(db: &Mutex<Connection>) -> anyhow::Result<MemoryExport> {
+  let conn = db.lock().await;
+  let history = select_rows(
+    &conn,
+    "SELECT id, created_at, messages_json, model, provider, prompt_tokens, completion_tokens, thread_id, latency_ms,
+       tool_calls_json, deleted_at, images_json, partial, favorite, cost_usd, output_images_json
+     FROM history ORDER BY rowid",
+    |row| {
+      Ok(HistoryRow {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        messages_json: row.get(2)?,
+        model: row.get(3)?,
+        provider: row.get(4)?,
+        prompt_tokens: row.get(5)?,
+        completion_tokens: row.get(6)?,
+        thread_id: row.get(7)?,
+        latency_ms: row.get(8)?,
+        tool_calls_json: row.get(9)?,
+        deleted_at: row.get(10)?,
+        images_json: row.get(11)?,
+        partial: row.get(12)?,
+        favorite: row.get(13)?,
+        cost_usd: row.get(14)?,
+        output_images_json: row.get(15)?,
+      })
+    },
+  )?;
+  let pinned = select_rows(
+    &conn,
+    "SELECT id, created_at, text, tags_json FROM pinned ORDER BY rowid",
+    |row| {
+      Ok(PinnedRow {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        text: row.get(2)?,
+        tags_json: row.get(3)?,
+      })
+    },
+  )?;
+  let presets = select_rows(
+    &conn,
+    "SELECT id, created_at, name, system_prompt, constraints_json, routing_policy_json FROM presets ORDER BY rowid",
+    |row| {
+      Ok(PresetRow {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        name: row.get(2)?,
+        system_prompt: row.get(3)?,
+        constraints_json: row.get(4)?,
+        routing_policy_json: row.get(5)?,
+      })
+    },
+  )?;
+  let settings = select_rows(
+    &conn,
+    "SELECT id, created_at, key, value_json FROM settings ORDER BY rowid",
+    |row| {
+      Ok(SettingRow {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        key: row.get(2)?,
+        value_json: row.get(3)?,
+      })
+    },
+  )?;
+  Ok(MemoryExport {
+    version: schema_version(&conn)?,
+    exported_at: Utc::now().to_rfc3339(),
+    history,
+    pinned,
+    presets,
+    settings,
+  })
+}
+
+fn check_unique<'a>(table: &str, column: &str, values: impl IntoIterator<Item = &'a str>) -> Result<(), StorageError> {
+  let mut seen = HashSet::new();
+  for value in values {
+    if value.trim().is_empty() {
+      return Err(invalid(format!("{table} row has an empty {column}.")));
+    }
+    if !seen.insert(value) {
+      return Err(invalid(format!("{table} has duplicate {column} '{value}'.")));
+    }
+  }
+  Ok(())
+}
+
+fn check_json(table: &str, column: &str, value: Option<&str>) -> Result<(), StorageError> {
+  match value.map(serde_json::from_str::<serde_json::Value>) {
+    Some(Err(err)) => Err(invalid(format!("{table}.{column} is not valid JSON: {err}"))),
+    _ => Ok(()),
+  }
+}
+
+// Everything is checked before the transaction opens, so a bad document never
+// touches the database.
+fn validate_import(data: &MemoryExport) -> Result<(), StorageError> {
+  if data.version > MIGRATIONS.len() {
+    return Err(invalid(format!(
+      "Export uses schema version {}, newer than this app's {}.",
+      data.version,
+      MIGRATIONS.len()
+    )));
+  }
+  check_unique("history", "id", data.history.iter().map(|row| row.id.as_str()))?;
+  check_unique("pinned", "id", data.pinned.iter().map(|row| row.id.as_str()))?;
+  check_unique("presets", "id", data.presets.iter().map(|row| row.id.as_str()))?;
+  check_unique("settings", "id", data.settings.iter().map(|row| row.id.as_str()))?;
+  check_unique("settings", "key", data.settings.iter().map(|row| row.key.as_str()))?;
+  for row in &data.history {
+    check_json("history", "messages_json", Some(&row.messages_json))?;
+    check_json("history", "tool_calls_json", row.tool_calls_json.as_deref())?;
+    check_json("history", "images_json", row.images_json.as_deref())?;
+    check_json("history", "output_images_json", row.output_images_json.as_deref())?;
+  }
+  for row in &data.pinned {
+    check_json("pinned", "tags_json", row.tags_json.as_deref())?;
+  }
+  for row in &data.presets {
+    check_json("presets", "constraints_json", row.constraints_json.as_deref())?;
+    check_json("presets", "routing_policy_json", row.routing_policy_json.as_deref())?;
+  }
+  for row in &data.settings {
+    check_json("settings", "value_json", Some(&row.value_json))?;
+  }
+  Ok(())
+}
+
+// Keeps the exported id unless a row already uses it.
+fn import_id(conn: &Connection, table: &str, id: &str, reassigned: &mut usize) -> rusqlite::Result<String> {
+  let taken: bool = conn.query_row(
+    &format!("SELECT EXISTS(SELECT 1 FROM {table} WHERE id = ?1)"),
+    params![id],
+    |row| row.get(0),
+  )?;
+  if !taken {
+    return Ok(id.to_string());
+  }
+  *reassigned += 1;
+  Ok(uuid::Uuid::new_v4().to_string())
+}
+
+pub async fn memory_import(
+  db: &Mutex<Connection>,
+  req: MemoryImportRequest,
+) -> Result<MemoryImportResponse, StorageError> {
+  let data = req.data;
+  validate_import(&data)?;
+  let mut conn = db.lock().await;
+  let tx = conn.transaction()?;
+  if req.mode == ImportMode::Overwrite {
+    tx.execute_batch("DELETE FROM history; DELETE FROM pinned; DELETE FROM presets; DELETE FROM settings;")?;
+  }
+
+  let mut res = MemoryImportResponse::default();
+  for row in &data.history {
+    let id = import_id(&tx, "history", &row.id, &mut res.reassigned_ids)?;
+    tx.execute(
+      "INSERT INTO history (id, created_at, messages_json, model, provider, prompt_tokens, completion_tokens, thread_id,
+         latency_ms, tool_calls_json, deleted_at, images_json, partial, favorite, cost_usd, output_images_json)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+      params![
+        id,
+        row.created_at,
+        row.messages_json,
+        row.model,
+        row.provider,
+        row.prompt_tokens,
+        row.completion_tokens,
+        row.thread_id,
+        row.latency_ms,
+        row.tool_calls_json,
+        row.deleted_at,
+        row.images_json,
+        row.partial,
+        row.favorite,
+        row.cost_usd,
+        row.output_images_json
+      ],
+    )?;
+  }
+  for row in &data.pinned {
+    let id = import_id(&tx, "pinned", &row.id, &mut res.reassigned_ids)?;
+    tx.execute(
+      "INSERT INTO pinned (id, created_at, text, tags_json) VALUES (?1, ?2, ?3, ?4)",
+      params![id, row.created_at, row.text, row.tags_json],
+    )?;
+  }
+  for row in &data.presets {
+    let id = import_id(&tx, "presets", &row.id, &mut res.reassigned_ids)?;
+    tx.execute(
+      "INSERT INTO presets (id, created_at, name, system_prompt, constraints_json, routing_policy_json)
+       VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+      params![id, row.created_at, row.name, row.system_prompt, row.constraints_json, row.routing_policy_json],
+    )?;
+  }
+  // Settings are keyed, not appended: an existing key takes the imported value.
+  for row in &data.settings {
+    let updated = tx.execute(
+      "UPDATE settings SET created_at = ?1, value_json = ?2 WHERE key = ?3",
+      params![row.created_at, row.value_json, row.key],
+    )?;
+    if updated == 0 {
+      let id = import_id(&tx, "settings", &row.id, &mut res.reassigned_ids)?;
+      tx.execute(
+        "INSERT INTO settings (id, created_at, key, value_json) VALUES (?1, ?2, ?3, ?4)",
+        params![id, row.created_at, row.key, row.value_json],
+      )?;
+    }
+  }
+  tx.commit()?;
+
+  res.history = data.history.len();
+  res.pinned = data.pinned.len();
+  res.presets = data.presets.len();
+  res.settings = data.settings.len();
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::models::ToolFunction;
+
+  fn temp_db_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("halodesk-test-{}.sqlite3", uuid::Uuid::new_v4()))
+  }
+
+  fn pinned(text: &str) -> MemoryStoreRequest {
+    MemoryStoreRequest {
+      r#type: "pinned".to_string(),
+      payload: serde_json::json!({ "text": text, "tags": [] }),
+    }
+  }
+
+  fn query(text: &str) -> MemoryQueryRequest {
+    MemoryQueryRequest {
+      query: text.to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn fts_query_quotes_terms() {
+    assert_eq!(fts_query("  "), None);
+    assert_eq!(fts_query("rust \"tips"), Some("\"rust\"* \"\"\"tips\"*".to_string()));
+  }
+
+  #[tokio::test]
+  async fn memory_query_ranks_by_relevance() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    memory_store(&db, pinned("rust is fast but this note is mostly about other things entirely")).await.unwrap();
+    memory_store(&db, pinned("rust rust rust")).await.unwrap();
+    memory_store(&db, pinned("python only")).await.unwrap();
+
+    let res = memory_query(&db, query("rust")).await.unwrap();
+    assert_eq!(res.items.len(), 2);
+    assert_eq!(res.items[0].payload["text"], "rust rust rust");
+    assert!(res.items[0].score > res.items[1].score);
+  }
+
+  #[tokio::test]
+  async fn memory_query_tracks_updates_and_deletes() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let stored = memory_store(&db, pinned("original wording")).await.unwrap();
+    {
+      let conn = db.lock().await;
+      conn
+        .execute("UPDATE pinned SET text = 'revised wording' WHERE id = ?1", params![stored.id])
+        .unwrap();
+    }
+    assert!(memory_query(&db, query("original")).await.unwrap().items.is_empty());
+    assert_eq!(memory_query(&db, query("revised")).await.unwrap().items.len(), 1);
+
+    {
+      let conn = db.lock().await;
+      conn.execute("DELETE FROM pinned WHERE id = ?1", params![stored.id]).unwrap();
+    }
+    assert!(memory_query(&db, query("revised")).await.unwrap().items.is_empty());
+  }
+
+  #[tokio::test]
+  async fn memory_query_pages_history_newest_first() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    {
+      let conn = db.lock().await;
+      for i in 0..25 {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, ?2, ?3)",
+            params![
+              format!("h{i:02}"),
+              format!("2024-01-01T00:00:{i:02}Z"),
+              format!("[{{\"role\":\"user\",\"content\":\"note {i:02}\"}}]")
+            ],
+          )
+          .unwrap();
+      }
+    }
+
+    let res = memory_query(
+      &db,
+      MemoryQueryRequest {
+        query: "note".to_string(),
+        limit: Some(10),
+        offset: Some(10),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    let ids: Vec<String> = res
+      .items
+      .iter()
+      .map(|item| item.payload["id"].as_str().unwrap().to_string())
+      .collect();
+    let expected: Vec<String> = (5..15).rev().map(|i| format!("h{i:02}")).collect();
+    assert_eq!(ids, expected);
+    assert_eq!(res.total, 25);
+  }
+
+  #[tokio::test]
+  async fn memory_query_pages_across_item_types() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    {
+      let conn = db.lock().await;
+      for i in 0..3 {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, ?2, '[]')",
+            params![format!("h{i}"), format!("2024-01-0{}T00:00:00Z", 2 * i + 1)],
+          )
+          .unwrap();
+        conn
+          .execute(
+            "INSERT INTO pinned (id, created_at, text, tags_json) VALUES (?1, ?2, 'note', '[]')",
+            params![format!("p{i}"), format!("2024-01-0{}T00:00:00Z", 2 * i + 2)],
+          )
+          .unwrap();
+      }
+    }
+
+    let page = |offset: i64| MemoryQueryRequest {
+      limit: Some(2),
+      offset: Some(offset),
+      ..Default::default()
+    };
+    let mut ids = Vec::new();
+    for offset in [0, 2, 4] {
+      let res = memory_query(&db, page(offset)).await.unwrap();
+      assert_eq!(res.total, 6);
+      ids.extend(res.items.iter().map(|item| item.payload["id"].as_str().unwrap().to_string()));
+    }
+    assert_eq!(ids, ["p2", "h2", "p1", "h1", "p0", "h0"]);
+  }
+
+  #[tokio::test]
+  async fn memory_query_clamps_negative_limit() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    memory_store(&db, pinned("first")).await.unwrap();
+    memory_store(&db, pinned("second")).await.unwrap();
+    let req = MemoryQueryRequest {
+      limit: Some(-1),
+      ..Default::default()
+    };
+    let res = memory_query(&db, req).await.unwrap();
+    assert!(res.items.is_empty());
+    assert_eq!(res.total, 2);
+  }
+
+  #[tokio::test]
+  async fn memory_query_rejects_negative_offset() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let req = MemoryQueryRequest {
+      offset: Some(-1),
+      ..Default::default()
+    };
+    assert!(memory_query(&db, req).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn memory_query_filters_by_date_range() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    {
+      let conn = db.lock().await;
+      for (id, created_at) in [
+        ("h-old", "2024-01-01T00:00:00Z"),
+        ("h-in", "2024-02-15T12:00:00.250+00:00"),
+        ("h-new", "2024-04-01T00:00:00Z"),
+      ] {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, ?2, '[]')",
+            params![id, created_at],
+          )
+          .unwrap();
+      }
+      conn
+        .execute(
+          "INSERT INTO pinned (id, created_at, text, tags_json) VALUES ('p-old', '2024-01-01T01:00:00+01:00', 'note', '[]')",
+          [],
+        )
+        .unwrap();
+    }
+
+    let res = memory_query(
+      &db,
+      MemoryQueryRequest {
+        from: Some("2024-02-01T00:00:00Z".to_string()),
+        to: Some("2024-03-01T02:00:00+02:00".to_string()),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    let ids: Vec<&str> = res.items.iter().map(|item| item.payload["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, vec!["h-in"]);
+    assert_eq!(res.total, 1);
+
+    // Stored as `+01:00`, this is 2024-01-01T00:00:00Z and just inside the bound.
+    let res = memory_query(
+      &db,
+      MemoryQueryRequest {
+        to: Some("2024-01-01T00:00:00Z".to_string()),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.total, 2);
+  }
+
+  #[tokio::test]
+  async fn memory_query_filters_pinned_by_tags() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    for (text, tags) in [("deploy notes", vec!["work", "urgent"]), ("groceries", vec!["home"])] {
+      let req = MemoryStoreRequest {
+        r#type: "pinned".to_string(),
+        payload: serde_json::json!({ "text": text, "tags": tags }),
+      };
+      memory_store(&db, req).await.unwrap();
+    }
+    {
+      let conn = db.lock().await;
+      conn
+        .execute(
+          "INSERT INTO history (id, created_at, messages_json) VALUES ('h1', '2024-01-01T00:00:00Z', '[]')",
+          [],
+        )
+        .unwrap();
+    }
+
+    let tagged = |tags: &[&str]| MemoryQueryRequest {
+      tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+      ..Default::default()
+    };
+    // Untagged history is not returned once a tag filter is set.
+    let res = memory_query(&db, tagged(&["urgent"])).await.unwrap();
+    assert_eq!(res.items.len(), 1);
+    assert_eq!(res.items[0].payload["text"], "deploy notes");
+    assert_eq!(res.total, 1);
+
+    let res = memory_query(&db, tagged(&["urgent", "home"])).await.unwrap();
+    assert!(res.items.is_empty());
+  }
+
+  #[tokio::test]
+  async fn memory_update_edits_pinned_tags_in_place() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let req = MemoryStoreRequest {
+      r#type: "pinned".to_string(),
+      payload: serde_json::json!({ "text": "deploy notes", "tags": ["work"] }),
+    };
+    let stored = memory_store(&db, req).await.unwrap();
+
+    let update = |id: &str, payload: serde_json::Value| MemoryUpdateRequest {
+      r#type: "pinned".to_string(),
+      id: id.to_string(),
+      payload,
+    };
+    let res = memory_update(&db, update(&stored.id, serde_json::json!({ "tags": ["work", "urgent"] })))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(res.id, stored.id);
+    let updated_at: String = db
+      .lock()
+      .await
+      .query_row("SELECT updated_at FROM pinned WHERE id = ?1", params![stored.id], |row| row.get(0))
+      .unwrap();
+    assert_eq!(updated_at, res.updated_at);
+
+    let urgent = MemoryQueryRequest {
+      tags: Some(vec!["urgent".to_string()]),
+      ..Default::default()
+    };
+    let res = memory_query(&db, urgent).await.unwrap();
+    assert_eq!(res.items.len(), 1);
+    assert_eq!(res.items[0].payload["id"], stored.id.as_str());
+    assert_eq!(res.items[0].payload["created_at"], stored.stored_at.as_str());
+    assert_eq!(res.items[0].payload["text"], "deploy notes");
+
+    assert!(memory_update(&db, update("missing", serde_json::json!({ "text": "x" }))).await.unwrap().is_none());
+    let bad_tags = update(&stored.id, serde_json::json!({ "tags": "urgent" }));
+    assert!(matches!(memory_update(&db, bad_tags).await, Err(StorageError::Invalid(_))));
+    let history = MemoryUpdateRequest {
+      r#type: "history".to_string(),
+      ..update(&stored.id, serde_json::json!({}))
+    };
+    assert!(matches!(memory_update(&db, history).await, Err(StorageError::Invalid(_))));
+  }
+
+  #[tokio::test]
+  async fn memory_semantic_ranks_embedded_rows_by_similarity() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let car = memory_store(&db, pinned("my car needs new tyres")).await.unwrap();
+    let truck = memory_store(&db, pinned("the truck is in the garage")).await.unwrap();
+    let soup = memory_store(&db, pinned("tomato soup recipe")).await.unwrap();
+    memory_store(&db, pinned("never embedded")).await.unwrap();
+    set_embedding(&db, "pinned", &car.id, &[0.9, 0.1, 0.0]).await.unwrap();
+    set_embedding(&db, "pinned", &truck.id, &[0.7, 0.3, 0.1]).await.unwrap();
+    set_embedding(&db, "pinned", &soup.id, &[0.0, 0.1, 0.9]).await.unwrap();
+    assert!(set_embedding(&db, "preset", &car.id, &[1.0]).await.is_err());
+
+    let res = memory_semantic(&db, query("vehicle"), &[1.0, 0.2, 0.0]).await.unwrap();
+    assert_eq!(res.mode, SearchMode::Semantic);
+    assert_eq!(res.total, 3);
+    let ids: Vec<&str> = res.items.iter().map(|item| item.payload["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, vec![car.id.as_str(), truck.id.as_str(), soup.id.as_str()]);
+
+    // A vector from another model has a different length and is skipped.
+    let res = memory_semantic(&db, query("vehicle"), &[1.0, 0.2]).await.unwrap();
+    assert!(res.items.is_empty());
+
+    // Editing the text drops the now stale embedding.
+    let update = MemoryUpdateRequest {
+      r#type: "pinned".to_string(),
+      id: car.id.clone(),
+      payload: serde_json::json!({ "text": "sold the car" }),
+    };
+    memory_update(&db, update).await.unwrap();
+    let res = memory_semantic(&db, query("vehicle"), &[1.0, 0.2, 0.0]).await.unwrap();
+    assert_eq!(res.total, 2);
+  }
+
+  #[test]
+  fn date_range_rejects_bad_bounds() {
+    assert!(date_range(Some("yesterday"), None).is_err());
+    assert!(date_range(Some("2024-03-01T00:00:00Z"), Some("2024-02-01T00:00:00Z")).is_err());
+    let range = date_range(Some("2024-03-01T01:00:00+01:00"), None).unwrap();
+    assert_eq!(range.from.as_deref(), Some("2024-03-01T00:00:00.000000Z"));
+  }
+
+  fn delete(kind: &str, id: &str) -> MemoryDeleteRequest {
+    MemoryDeleteRequest {
+      r#type: kind.to_string(),
+      id: id.to_string(),
+    }
+  }
+
+  #[tokio::test]
+  async fn memory_delete_removes_row() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let stored = memory_store(&db, pinned("short lived")).await.unwrap();
+    assert!(memory_delete(&db, delete("pinned", &stored.id)).await.unwrap());
+    assert!(memory_query(&db, query("short")).await.unwrap().items.is_empty());
+  }
+
+  #[tokio::test]
+  async fn memory_delete_missing_id_reports_nothing_deleted() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    assert!(!memory_delete(&db, delete("history", "does-not-exist")).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn memory_delete_rejects_unknown_type() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    assert!(memory_delete(&db, delete("bogus", "x")).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn memory_delete_moves_history_to_trash_until_restored() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let entry = HistoryEntry {
+      assistant: "recoverable answer",
+      thread_id: Some("t1"),
+      ..Default::default()
+    };
+    let id = store_history(&db, entry).await.unwrap();
+
+    assert!(memory_delete(&db, delete("history", &id)).await.unwrap());
+    assert!(!memory_delete(&db, delete("history", &id)).await.unwrap());
+    let res = memory_query(&db, query("recoverable")).await.unwrap();
+    assert!(res.items.is_empty());
+    assert_eq!(res.total, 0);
+    assert!(memory_thread(&db, "t1").await.unwrap().is_empty());
+    let trash = memory_trash(&db).await.unwrap();
+    assert_eq!(trash[0].payload["id"], id.as_str());
+    assert!(trash[0].payload["deleted_at"].is_string());
+
+    assert!(memory_restore(&db, &id).await.unwrap());
+    assert!(!memory_restore(&db, &id).await.unwrap());
+    assert_eq!(memory_query(&db, query("recoverable")).await.unwrap().items.len(), 1);
+    assert!(memory_trash(&db).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn models_used_counts_rows_per_model() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let chats = [
+      ("openrouter:openai/gpt-4o-mini", "openrouter"),
+      ("ollama:llama3", "ollama"),
+      ("openrouter:openai/gpt-4o-mini", "openrouter"),
+      ("ollama:llama3", "ollama"),
+      ("openrouter:openai/gpt-4o-mini", "openrouter"),
+    ];
+    let mut ids = Vec::new();
+    for (model, provider) in chats {
+      let entry = HistoryEntry {
+        assistant: "reply",
+        model,
+        provider,
+        ..Default::default()
+      };
+      ids.push(store_history(&db, entry).await.unwrap());
+    }
+    let generic = MemoryStoreRequest {
+      r#type: "history".to_string(),
+      payload: serde_json::json!([{ "role": "user", "content": "imported" }]),
+    };
+    memory_store(&db, generic).await.unwrap();
+    memory_delete(&db, delete("history", &ids[1])).await.unwrap();
+
+    let counts = models_used(&db).await.unwrap();
+    let count = |model: &str, provider: &str, count| ModelUsageCount {
+      model: model.to_string(),
+      provider: provider.to_string(),
+      count,
+    };
+    assert_eq!(
+      counts,
+      vec![
+        count("openrouter:openai/gpt-4o-mini", "openrouter", 3),
+        count("ollama:llama3", "ollama", 1),
+        count("unknown", "unknown", 1),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn purge_trash_removes_old_trash_only() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let mut ids = Vec::new();
+    for text in ["old", "recent", "live"] {
+      let entry = HistoryEntry {
+        assistant: text,
+        ..Default::default()
+      };
+      ids.push(store_history(&db, entry).await.unwrap());
+    }
+    memory_delete(&db, delete("history", &ids[0])).await.unwrap();
+    memory_delete(&db, delete("history", &ids[1])).await.unwrap();
+    db.lock()
+      .await
+      .execute(
+        "UPDATE history SET deleted_at = '2020-01-01T00:00:00Z' WHERE id = ?1",
+        params![ids[0]],
+      )
+      .unwrap();
+
+    assert_eq!(purge_trash(&db, Some(30)).await.unwrap(), 1);
+    let trash = memory_trash(&db).await.unwrap();
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].payload["id"], ids[1].as_str());
+    assert_eq!(purge_trash(&db, None).await.unwrap(), 1);
+    assert_eq!(memory_export(&db).await.unwrap().history.len(), 1);
+  }
+
+  fn png(base64: &str) -> ImageData {
+    ImageData {
+      mime: "image/png".to_string(),
+      base64: base64.to_string(),
+    }
+  }
+
+  #[tokio::test]
+  async fn stored_images_round_trip_through_history() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let dir = std::env::temp_dir().join(format!("halodesk-test-images-{}", uuid::Uuid::new_v4()));
+    let svg = ImageData {
+      mime: "image/svg+xml".to_string(),
+      base64: "PHN2Zz4=".to_string(),
+    };
+    let images = save_images(&dir, &[&png("aGVsbG8="), &svg, &png("not base64!")]).unwrap();
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].bytes, 5);
+
+    let entry = HistoryEntry {
+      assistant: "saw a picture",
+      images: &images,
+      ..Default::default()
+    };
+    store_history(&db, entry).await.unwrap();
+    let res = memory_query(&db, query("picture")).await.unwrap();
+    let id = res.items[0].payload["images"][0]["id"].as_str().unwrap().to_string();
+    assert_eq!(id, images[0].id);
+
+    let loaded = load_image(&dir, &id).unwrap().unwrap();
+    assert_eq!(loaded.mime, "image/png");
+    assert_eq!(loaded.base64, "aGVsbG8=");
+    assert!(matches!(load_image(&dir, "../config.json"), Err(StorageError::Invalid(_))));
+    let missing = format!("{}.png", uuid::Uuid::new_v4());
+    assert!(load_image(&dir, &missing).unwrap().is_none());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[tokio::test]
+  async fn sweep_images_keeps_referenced_and_fresh_files() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let dir = std::env::temp_dir().join(format!("halodesk-test-images-{}", uuid::Uuid::new_v4()));
+    let kept = save_images(&dir, &[&png("a2VwdA==")]).unwrap();
+    let orphan = save_images(&dir, &[&png("b3JwaGFu")]).unwrap();
+    let entry = HistoryEntry {
+      images: &kept,
+      ..Default::default()
+    };
+    store_history(&db, entry).await.unwrap();
+
+    assert_eq!(sweep_images(&db, &dir).await.unwrap(), 0);
+    assert_eq!(sweep_images_older_than(&db, &dir, std::time::Duration::ZERO).await.unwrap(), 1);
+    assert!(dir.join(&kept[0].id).exists());
+    assert!(!dir.join(&orphan[0].id).exists());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[tokio::test]
+  async fn audit_log_round_trips_newest_first() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let usage = Usage::new(12, 34);
+    let ok = AuditEntry {
+      model: Some("openrouter:openai/gpt-4o-mini"),
+      provider: Some("openrouter"),
+      has_image: true,
+      latency_ms: 250,
+      status: "ok",
+      usage: Some(&usage),
+      ..Default::default()
+    };
+    audit_log(&db, ok).await.unwrap();
+    let failed = AuditEntry {
+      latency_ms: 1,
+      status: "error",
+      error_code: Some("key_missing"),
+      ..Default::default()
+    };
+    audit_log(&db, failed).await.unwrap();
+
+    let rows = audit_recent(&db, 10).await.unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].error_code.as_deref(), Some("key_missing"));
+    assert_eq!(rows[0].model, None);
+    assert!(rows[1].has_image);
+    assert_eq!(rows[1].status, "ok");
+    assert_eq!((rows[1].prompt_tokens, rows[1].completion_tokens), (Some(12), Some(34)));
+    assert_eq!(audit_recent(&db, 1).await.unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn load_chat_reads_messages_thread_and_images() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let messages = vec![Message {
+      role: "user".to_string(),
+      content: "describe this".to_string(),
+      ..Default::default()
+    }];
+    let images = vec![StoredImage {
+      id: format!("{}.png", uuid::Uuid::new_v4()),
+      mime: "image/png".to_string(),
+      bytes: 3,
+    }];
+    let entry = HistoryEntry {
+      messages: &messages,
+      assistant: "a cat",
+      model: "openrouter:openai/gpt-4o-mini",
+      provider: "openrouter",
+      thread_id: Some("t1"),
+      images: &images,
+      ..Default::default()
+    };
+    let id = store_history(&db, entry).await.unwrap();
+
+    let chat = load_chat(&db, &id).await.unwrap().unwrap();
+    assert_eq!(chat.model.as_deref(), Some("openrouter:openai/gpt-4o-mini"));
+    assert_eq!(chat.provider.as_deref(), Some("openrouter"));
+    let roles: Vec<_> = chat.messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["user", "assistant"]);
+    assert_eq!(chat.thread_id.as_deref(), Some("t1"));
+    assert_eq!(chat.images, images);
+    assert!(load_chat(&db, "missing").await.unwrap().is_none());
+
+    let raw = MemoryStoreRequest {
+      r#type: "history".to_string(),
+      payload: serde_json::json!({ "note": "not a chat" }),
+    };
+    let raw = memory_store(&db, raw).await.unwrap();
+    assert!(matches!(load_chat(&db, &raw.id).await, Err(StorageError::Invalid(_))));
+  }
+
+  #[tokio::test]
+  async fn memory_thread_returns_rows_in_order() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let entry = |assistant, thread_id| HistoryEntry {
+      assistant,
+      thread_id: Some(thread_id),
+      ..Default::default()
+    };
+    let first = store_history(&db, entry("first", "t1")).await.unwrap();
+    store_history(&db, entry("other", "t2")).await.unwrap();
+    let second = store_history(&db, entry("second", "t1")).await.unwrap();
+
+    let items = memory_thread(&db, "t1").await.unwrap();
+    let ids: Vec<_> = items.iter().map(|item| item.payload["id"].as_str().unwrap()).collect();
+    assert_eq!(ids, vec![first.as_str(), second.as_str()]);
+    assert_eq!(items[0].payload["thread_id"], "t1");
+    assert!(memory_thread(&db, "missing").await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn settings_set_upserts_by_key() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    assert_eq!(settings_get(&db, "theme").await.unwrap(), None);
+    settings_set(&db, "theme", &serde_json::json!("dark")).await.unwrap();
+    settings_set(&db, "theme", &serde_json::json!({ "mode": "light" })).await.unwrap();
+    assert_eq!(
+      settings_get(&db, "theme").await.unwrap(),
+      Some(serde_json::json!({ "mode": "light" }))
+    );
+    let count: i64 = db
+      .lock()
+      .await
+      .query_row("SELECT COUNT(*) FROM settings", [], |row| row.get(0))
+      .unwrap();
+    assert_eq!(count, 1);
+  }
+
+  #[tokio::test]
+  async fn init_db_keeps_newest_duplicate_setting() {
+    let path = temp_db_path();
+    {
+      let conn = Connection::open(&path).unwrap();
+      conn
+        .execute_batch(
+          "
+          CREATE TABLE settings (id TEXT PRIMARY KEY, created_at TEXT NOT NULL, key TEXT NOT NULL, value_json TEXT NOT NULL);
+          INSERT INTO settings VALUES ('a', '2024-01-01T00:00:00Z', 'theme', '\"old\"');
+          INSERT INTO settings VALUES ('b', '2024-03-01T00:00:00Z', 'theme', '\"new\"');
+          INSERT INTO settings VALUES ('c', '2024-02-01T00:00:00Z', 'theme', '\"middle\"');
+          ",
+        )
+        .unwrap();
+    }
+
+    let db = Mutex::new(init_db(&path).unwrap());
+    assert_eq!(settings_get(&db, "theme").await.unwrap(), Some(serde_json::json!("new")));
+    settings_set(&db, "theme", &serde_json::json!("newest")).await.unwrap();
+    assert_eq!(settings_get(&db, "theme").await.unwrap(), Some(serde_json::json!("newest")));
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn get_preset_reads_typed_fields() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let stored = memory_store(
+      &db,
+      MemoryStoreRequest {
+        r#type: "preset".to_string(),
+        payload: serde_json::json!({
+          "name": "Reviewer",
+          "system_prompt": "Review the code.",
+          "routing_policy": { "model": "anthropic:claude-3-5-sonnet-latest" }
+        }),
+      },
+    )
+    .await
+    .unwrap();
+
+    let preset = get_preset(&db, &stored.id).await.unwrap().expect("preset should exist");
+    assert_eq!(preset.name, "Reviewer");
+    assert_eq!(preset.system_prompt, "Review the code.");
+    assert_eq!(preset.routing_policy.model.as_deref(), Some("anthropic:claude-3-5-sonnet-latest"));
+    assert_eq!(preset.routing_policy.vision_model, None);
+    assert!(get_preset(&db, "missing").await.unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn save_preset_creates_then_replaces() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let created = save_preset(
+      &db,
+      PresetSaveRequest {
+        name: " Writer ".to_string(),
+        system_prompt: "Write well.".to_string(),
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(created.name, "Writer");
+    assert_eq!(created.constraints, serde_json::json!({}));
+
+    let updated = PresetSaveRequest {
+      id: Some(created.id.clone()),
+      name: "Editor".to_string(),
+      constraints: Some(serde_json::json!({ "max_tokens": 200 })),
+      ..Default::default()
+    };
+    save_preset(&db, updated).await.unwrap();
+    let presets = list_presets(&db).await.unwrap();
+    assert_eq!(presets.len(), 1);
+    assert_eq!(presets[0].name, "Editor");
+    assert_eq!(presets[0].system_prompt, "");
+    assert_eq!(presets[0].constraints["max_tokens"], 200);
+
+    let blank = PresetSaveRequest {
+      name: "  ".to_string(),
+      ..Default::default()
+    };
+    assert!(matches!(save_preset(&db, blank).await, Err(StorageError::Invalid(_))));
+    let bad_constraints = PresetSaveRequest {
+      name: "Odd".to_string(),
+      constraints: Some(serde_json::json!([1])),
+      ..Default::default()
+    };
+    assert!(matches!(save_preset(&db, bad_constraints).await, Err(StorageError::Invalid(_))));
+
+    assert!(delete_preset(&db, &created.id).await.unwrap());
+    assert!(!delete_preset(&db, &created.id).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn store_history_records_usage_and_latency() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let messages = vec![Message {
+      role: "user".to_string(),
+      content: "count my tokens".to_string(),
+      ..Default::default()
+    }];
+    let entry = HistoryEntry {
+      messages: &messages,
+      assistant: "sure",
+      model: "openrouter:m",
+      provider: "openrouter",
+      usage: Some(&Usage::new(12, 3)),
+      latency_ms: Some(840),
+      ..Default::default()
+    };
+    store_history(&db, entry).await.unwrap();
+
+    let res = memory_query(&db, query("tokens")).await.unwrap();
+    assert_eq!(res.items[0].payload["prompt_tokens"], 12);
+    assert_eq!(res.items[0].payload["completion_tokens"], 3);
+    assert_eq!(res.items[0].payload["latency_ms"], 840);
+  }
+
+  #[tokio::test]
+  async fn output_images_survive_updates_without_them() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let images = vec![OutputImage::Linked {
+      url: "https://cdn.example/cat.png".to_string(),
+    }];
+    let entry = HistoryEntry {
+      assistant: "a cat",
+      output_images: &images,
+      partial: true,
+      ..Default::default()
+    };
+    let id = store_history(&db, entry).await.unwrap();
+    let done = HistoryEntry {
+      assistant: "a cat, done",
+      ..Default::default()
+    };
+    update_history(&db, &id, done).await.unwrap();
+
+    let res = memory_query(&db, query("cat")).await.unwrap();
+    assert_eq!(res.items[0].payload["output_images"][0]["url"], "https://cdn.example/cat.png");
+    let exported = memory_export(&db).await.unwrap();
+    let stored = exported.history[0].output_images_json.as_deref().unwrap();
+    assert_eq!(serde_json::from_str::<Vec<OutputImage>>(stored).unwrap(), images);
+  }
+
+  #[tokio::test]
+  async fn store_history_records_tool_calls() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let tool_calls = vec![ToolCall {
+      id: "call_1".to_string(),
+      r#type: "function".to_string(),
+      function: ToolFunction {
+        name: "get_weather".to_string(),
+        arguments: r#"{"city":"Oslo"}"#.to_string(),
+      },
+    }];
+    let entry = HistoryEntry {
+      thread_id: Some("t1"),
+      tool_calls: &tool_calls,
+      ..Default::default()
+    };
+    store_history(&db, entry).await.unwrap();
+    store_history(&db, HistoryEntry { thread_id: Some("t1"), ..Default::default() }).await.unwrap();
+
+    let items = memory_thread(&db, "t1").await.unwrap();
+    assert_eq!(items[0].payload["tool_calls"][0]["function"]["name"], "get_weather");
+    assert_eq!(items[0].payload["tool_calls"][0]["function"]["arguments"], r#"{"city":"Oslo"}"#);
+    assert!(items[1].payload["tool_calls"].is_null());
+  }
+
+  #[tokio::test]
+  async fn init_db_adds_usage_columns_to_old_history() {
+    let path = temp_db_path();
+    {
+      let conn = Connection::open(&path).unwrap();
+      conn
+        .execute_batch(
+          "CREATE TABLE history (id TEXT PRIMARY KEY, created_at TEXT NOT NULL, messages_json TEXT NOT NULL, model TEXT, provider TEXT);",
+        )
+        .unwrap();
+    }
+
+    let db = Mutex::new(init_db(&path).unwrap());
+    let entry = HistoryEntry {
+      assistant: "hello",
+      usage: Some(&Usage::new(1, 2)),
+      latency_ms: Some(5),
+      ..Default::default()
+    };
+    store_history(&db, entry).await.unwrap();
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn init_db_backfills_existing_rows() {
+    let path = temp_db_path();
+    {
+      let conn = Connection::open(&path).unwrap();
+      conn
+        .execute_batch(
+          "
+          CREATE TABLE pinned (id TEXT PRIMARY KEY, created_at TEXT NOT NULL, text TEXT NOT NULL, tags_json TEXT);
+          INSERT INTO pinned VALUES ('p1', '2024-01-01T00:00:00Z', 'legacy note', '[]');
+          ",
+        )
+        .unwrap();
+    }
+
+    let db = Mutex::new(init_db(&path).unwrap());
+    let res = memory_query(&db, query("legacy")).await.unwrap();
+    assert_eq!(res.items.len(), 1);
+    assert_eq!(res.items[0].payload["id"], "p1");
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn compact_shrinks_file_and_keeps_search_working() {
+    let path = temp_db_path();
+    let db = Mutex::new(init_db(&path).unwrap());
+    {
+      let conn = db.lock().await;
+      let filler = "x".repeat(4096);
+      for i in 0..200 {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, '2024-01-01T00:00:00Z', ?2)",
+            params![format!("h{i}"), format!("note {i} {filler}")],
+          )
+          .unwrap();
+      }
+      conn.execute("DELETE FROM history WHERE id != 'h7'", []).unwrap();
+    }
+
+    let res = compact(&db).await.unwrap();
+    assert!(res.bytes_after < res.bytes_before, "{} -> {}", res.bytes_before, res.bytes_after);
+
+    let found = memory_query(&db, query("note")).await.unwrap();
+    assert_eq!(found.items.len(), 1);
+    assert_eq!(found.items[0].payload["id"], "h7");
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn prune_history_removes_old_and_excess_rows_only() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let recent = |minutes: i64| (Utc::now() - Duration::minutes(minutes)).to_rfc3339();
+    {
+      let conn = db.lock().await;
+      for (id, created_at) in [
+        ("ancient", "2020-01-01T00:00:00Z".to_string()),
+        ("h1", recent(3)),
+        ("h2", recent(2)),
+        ("h3", recent(1)),
+      ] {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, ?2, '[]')",
+            params![id, created_at],
+          )
+          .unwrap();
+      }
+      // Trashed rows neither count toward the cap nor get pruned by it.
+      conn
+        .execute(
+          "INSERT INTO history (id, created_at, messages_json, deleted_at) VALUES ('trashed', ?1, '[]', ?1)",
+          params![recent(0)],
+        )
+        .unwrap();
+      conn
+        .execute(
+          "INSERT INTO pinned (id, created_at, text, tags_json) VALUES ('p1', '2020-01-01T00:00:00Z', 'keep me', '[]')",
+          [],
+        )
+        .unwrap();
+    }
+
+    assert_eq!(prune_history(&db, Some(30), None).await.unwrap(), 1);
+    assert_eq!(prune_history(&db, None, Some(2)).await.unwrap(), 1);
+    assert_eq!(prune_history(&db, None, None).await.unwrap(), 0);
+
+    let conn = db.lock().await;
+    let ids: Vec<String> = conn
+      .prepare("SELECT id FROM history ORDER BY created_at")
+      .unwrap()
+      .query_map([], |row| row.get(0))
+      .unwrap()
+      .collect::<rusqlite::Result<_>>()
+      .unwrap();
+    assert_eq!(ids, vec!["h2", "h3", "trashed"]);
+    let pinned: i64 = conn.query_row("SELECT COUNT(*) FROM pinned", [], |row| row.get(0)).unwrap();
+    assert_eq!(pinned, 1);
+  }
+
+  #[tokio::test]
+  async fn memory_store_batch_inserts_many_rows_in_one_transaction() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    let note = |i: usize| MemoryStoreRequest {
+      r#type: "pinned".to_string(),
+      payload: serde_json::json!({ "text": format!("note {i}"), "tags": ["bulk"] }),
+    };
+
+    let started = std::time::Instant::now();
+    let res = memory_store_batch(&db, (0..1000).map(note).collect()).await.unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(res.ids.len(), 1000);
+    // One commit instead of a thousand; generous so slow CI machines pass.
+    assert!(elapsed < std::time::Duration::from_secs(5), "took {elapsed:?}");
+
+    let mut bad = vec![note(1000), note(1001)];
+    bad.push(MemoryStoreRequest {
+      r#type: "unknown".to_string(),
+      payload: serde_json::json!({}),
+    });
+    let Err(err) = memory_store_batch(&db, bad).await else {
+      panic!("a batch with a bad item should fail");
+    };
+    assert!(matches!(&err, StorageError::Invalid(msg) if msg.starts_with("item 2:")), "{err}");
+
+    let conn = db.lock().await;
+    let pinned: i64 = conn.query_row("SELECT COUNT(*) FROM pinned", [], |row| row.get(0)).unwrap();
+    assert_eq!(pinned, 1000);
+    let first: String = conn
+      .query_row("SELECT text FROM pinned WHERE id = ?1", params![res.ids[0]], |row| row.get(0))
+      .unwrap();
+    assert_eq!(first, "note 0");
+  }
+
+  #[tokio::test]
+  async fn favorites_sort_first_and_survive_pruning() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    {
+      let conn = db.lock().await;
+      for (id, created_at) in [("old", "2020-01-01T00:00:00Z"), ("mid", "2021-01-01T00:00:00Z")] {
+        conn
+          .execute(
+            "INSERT INTO history (id, created_at, messages_json) VALUES (?1, ?2, '[]')",
+            params![id, created_at],
+          )
+          .unwrap();
+      }
+      conn
+        .execute(
+          "INSERT INTO history (id, created_at, messages_json) VALUES ('new', ?1, '[]')",
+          params![Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+    }
+
+    assert_eq!(memory_favorite(&db, "old", None).await.unwrap(), Some(true));
+    assert_eq!(memory_favorite(&db, "mid", None).await.unwrap(), Some(true));
+    assert_eq!(memory_favorite(&db, "mid", None).await.unwrap(), Some(false));
+    assert_eq!(memory_favorite(&db, "mid", Some(false)).await.unwrap(), Some(false));
+    assert_eq!(memory_favorite(&db, "missing", Some(true)).await.unwrap(), None);
+
+    let ids = |res: MemoryQueryResponse| -> Vec<String> {
+      res.items.iter().map(|item| item.payload["id"].as_str().unwrap().to_string()).collect()
+    };
+    let res = memory_query(&db, MemoryQueryRequest::default()).await.unwrap();
+    assert_eq!(ids(res), vec!["old", "new", "mid"]);
+    let first_page = MemoryQueryRequest {
+      limit: Some(1),
+      ..Default::default()
+    };
+    assert_eq!(ids(memory_query(&db, first_page).await.unwrap())[0], "old");
+
+    memory_store(&db, pinned("not a favorite")).await.unwrap();
+    let favorites = MemoryQueryRequest {
+      favorites_only: true,
+      ..Default::default()
+    };
+    let res = memory_query(&db, favorites).await.unwrap();
+    assert_eq!(res.total, 1);
+    assert_eq!(ids(res), vec!["old"]);
+
+    assert_eq!(prune_history(&db, Some(30), None).await.unwrap(), 1);
+    assert_eq!(prune_history(&db, None, Some(1)).await.unwrap(), 0);
+    let res = memory_query(&db, MemoryQueryRequest::default()).await.unwrap();
+    let history: Vec<_> = res.items.iter().filter(|item| item.r#type == "history").collect();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].payload["favorite"], true);
+  }
+
+  #[test]
+  fn init_db_enables_wal() {
+    let path = temp_db_path();
+    let conn = init_db(&path).unwrap();
+    let mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+    assert_eq!(mode, "wal");
+    let synchronous: i64 = conn.query_row("PRAGMA synchronous", [], |row| row.get(0)).unwrap();
+    assert_eq!(synchronous, 1);
+    let busy: i64 = conn.query_row("PRAGMA busy_timeout", [], |row| row.get(0)).unwrap();
+    assert_eq!(busy, 5000);
+    drop(conn);
+    for suffix in ["", "-wal", "-shm"] {
+      let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+  }
+
+  #[tokio::test]
+  async fn init_db_upgrades_unversioned_schema_and_keeps_rows() {
+    let path = temp_db_path();
+    {
+      let conn = Connection::open(&path).unwrap();
+      conn
+        .execute_batch(
+          "
+          CREATE TABLE history (id TEXT PRIMARY KEY, created_at TEXT NOT NULL, messages_json TEXT NOT NULL, model TEXT, provider TEXT);
+          INSERT INTO history VALUES ('h1', '2024-01-01T00:00:00Z', '[\"old chat\"]', 'm', 'openrouter');
+          ",
+        )
+        .unwrap();
+    }
+
+    let conn = init_db(&path).unwrap();
+    assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+    let (messages, latency): (String, Option<i64>) = conn
+      .query_row("SELECT messages_json, latency_ms FROM history WHERE id = 'h1'", [], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+      })
+      .unwrap();
+    assert_eq!(messages, "[\"old chat\"]");
+    assert_eq!(latency, None);
+    drop(conn);
+
+    // Reopening an up-to-date database runs nothing and keeps the version.
+    let conn = init_db(&path).unwrap();
+    assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+    drop(conn);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn init_db_adds_images_column_to_databases_from_before_it() {
+    let path = temp_db_path();
+    {
+      // The schema as shipped before images_json existed: eight steps, stamped 8.
+      let conn = Connection::open(&path).unwrap();
+      for migration in &MIGRATIONS[..8] {
+        migration(&conn).unwrap();
+      }
+      conn.pragma_update(None, "user_version", 8).unwrap();
+    }
+
+    let conn = init_db(&path).unwrap();
+    assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+    let columns = conn
+      .prepare("PRAGMA table_info(history)")
+      .unwrap()
+      .query_map([], |row| row.get::<_, String>(1))
+      .unwrap()
+      .collect::<rusqlite::Result<Vec<_>>>()
+      .unwrap();
+    assert!(columns.iter().any(|name| name == "images_json"), "{columns:?}");
+    drop(conn);
+    let _ = std::fs::remove_file(&path);
+  }
+
+  async fn seed_all_tables(db: &Mutex<Connection>) {
+    let messages = vec![Message {
+      role: "user".to_string(),
+      content: "export me".to_string(),
+      ..Default::default()
+    }];
+    let tool_calls = vec![ToolCall {
+      id: "call_1".to_string(),
+      r#type: "function".to_string(),
+      function: ToolFunction {
+        name: "lookup".to_string(),
+        arguments: "{}".to_string(),
+      },
+    }];
+    let entry = HistoryEntry {
+      messages: &messages,
+      assistant: "done",
+      model: "openrouter:m",
+      provider: "openrouter",
+      usage: Some(&Usage::new(4, 2)),
+      thread_id: Some("t1"),
+      latency_ms: Some(10),
+      tool_calls: &tool_calls,
+      ..Default::default()
+    };
+    store_history(db, entry).await.unwrap();
+    memory_store(db, pinned("portable note")).await.unwrap();
+    let preset = MemoryStoreRequest {
+      r#type: "preset".to_string(),
+      payload: serde_json::json!({ "name": "Travel", "system_prompt": "Pack light." }),
+    };
+    memory_store(db, preset).await.unwrap();
+    settings_set(db, "theme", &serde_json::json!("dark")).await.unwrap();
+  }
+
+  fn import(mode: ImportMode, data: MemoryExport) -> MemoryImportRequest {
+    MemoryImportRequest { mode, data }
+  }
+
+  #[tokio::test]
+  async fn memory_export_import_round_trips() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    seed_all_tables(&db).await;
+    let exported = memory_export(&db).await.unwrap();
+    assert_eq!(exported.version, MIGRATIONS.len());
+    assert_eq!(exported.history.len(), 1);
+
+    let empty = MemoryExport {
+      history: vec![],
+      pinned: vec![],
+      presets: vec![],
+      settings: vec![],
+      ..exported.clone()
+    };
+    memory_import(&db, import(ImportMode::Overwrite, empty)).await.unwrap();
+    let wiped = memory_export(&db).await.unwrap();
+    assert!(wiped.history.is_empty() && wiped.pinned.is_empty());
+    assert!(wiped.presets.is_empty() && wiped.settings.is_empty());
+    assert!(memory_query(&db, query("portable")).await.unwrap().items.is_empty());
+
+    let res = memory_import(&db, import(ImportMode::Overwrite, exported.clone())).await.unwrap();
+    assert_eq!(res.reassigned_ids, 0);
+    let restored = memory_export(&db).await.unwrap();
+    assert_eq!(restored.history, exported.history);
+    assert_eq!(restored.pinned, exported.pinned);
+    assert_eq!(restored.presets, exported.presets);
+    assert_eq!(restored.settings, exported.settings);
+    // The FTS triggers index imported rows like any other insert.
+    assert_eq!(memory_query(&db, query("portable")).await.unwrap().items.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn memory_import_merge_reassigns_clashing_ids() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    seed_all_tables(&db).await;
+    let mut exported = memory_export(&db).await.unwrap();
+    exported.settings[0].value_json = "\"light\"".to_string();
+
+    let res = memory_import(&db, import(ImportMode::Merge, exported.clone())).await.unwrap();
+    assert_eq!(res.history, 1);
+    assert_eq!(res.reassigned_ids, 3);
+    let merged = memory_export(&db).await.unwrap();
+    assert_eq!(merged.history.len(), 2);
+    assert_ne!(merged.history[0].id, merged.history[1].id);
+    assert_eq!(merged.pinned.len(), 2);
+    assert_eq!(merged.settings.len(), 1);
+    assert_eq!(settings_get(&db, "theme").await.unwrap(), Some(serde_json::json!("light")));
+  }
+
+  #[tokio::test]
+  async fn memory_import_rejects_bad_documents_without_changes() {
+    let db = Mutex::new(init_db(Path::new(":memory:")).unwrap());
+    seed_all_tables(&db).await;
+    let exported = memory_export(&db).await.unwrap();
+
+    let mut newer = exported.clone();
+    newer.version = MIGRATIONS.len() + 1;
+    let mut bad_json = exported.clone();
+    bad_json.history[0].messages_json = "not json".to_string();
+    let mut duplicate = exported.clone();
+    duplicate.pinned.push(duplicate.pinned[0].clone());
+
+    for data in [newer, bad_json, duplicate] {
+      let err = memory_import(&db, import(ImportMode::Overwrite, data)).await.unwrap_err();
+      assert!(matches!(err, StorageError::Invalid(_)));
+    }
+    assert_eq!(memory_export(&db).await.unwrap().history, exported.history);
+  }
+}
@@ -0,0 +1,181 @@
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton};
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+
+use crate::models::{Message, TokenCountResponse};
+
+// OpenAI's chat framing: every message costs a few tokens beyond its text and
+// the reply is primed with three more.
+const TOKENS_PER_MESSAGE: usize = 3;
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+// `model` is the provider-free name, e.g. `openai/gpt-4o-mini`. Only the OpenAI
+// families have a published tokenizer; everything else gets the heuristic.
+pub fn count_tokens(model: &str, messages: &[Message]) -> TokenCountResponse {
+  let costs = message_costs(model, messages);
+  TokenCountResponse {
+    tokens: costs.total(),
+    method: costs.method.to_string(),
+  }
+}
+
+// Drops the oldest turns until the thread fits `budget`. A turn is a user
+// message with the replies and tool messages after it, so what is left never
+// opens with an assistant reply or an orphaned tool result. System messages
+// and the latest turn always stay, even if the thread still does not fit.
+// Returns how many messages were dropped.
+pub fn trim_messages(messages: &mut Vec<Message>, budget: usize, model: &str) -> usize {
+  let mut costs = message_costs(model, messages);
+  let last = messages.len().saturating_sub(1);
+  let latest_turn = messages.iter().rposition(|m| m.role == "user").unwrap_or(last).min(last);
+  let mut dropped = vec![false; messages.len()];
+  let mut index = 0;
+  while index < latest_turn && costs.total() > budget {
+    // Anything before the first user message is dropped as a turn of its own.
+    let end = (index + 1..latest_turn)
+      .find(|&next| messages[next].role == "user")
+      .unwrap_or(latest_turn);
+    for turn_index in index..end {
+      if messages[turn_index].role != "system" {
+        dropped[turn_index] = true;
+        costs.per_message[turn_index] = 0;
+      }
+    }
+    index = end;
+  }
+  let mut flags = dropped.iter();
+  messages.retain(|_| !flags.next().copied().unwrap_or_default());
+  dropped.iter().filter(|dropped| **dropped).count()
+}
+
+struct MessageCosts {
+  per_message: Vec<usize>,
+  // Tokens the thread costs beyond its messages.
+  overhead: usize,
+  method: &'static str,
+}
+
+impl MessageCosts {
+  fn total(&self) -> usize {
+    self.per_message.iter().sum::<usize>() + self.overhead
+  }
+}
+
+fn message_costs(model: &str, messages: &[Message]) -> MessageCosts {
+  let name = model.rsplit('/').next().unwrap_or(model);
+  let bpe = match get_tokenizer(name) {
+    Some(Tokenizer::O200kBase) => o200k_base_singleton(),
+    Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+    _ => return heuristic(messages),
+  };
+  let bpe = bpe.lock();
+  let per_message = messages
+    .iter()
+    .map(|m| {
+      let role = bpe.encode_with_special_tokens(&m.role).len();
+      let content = bpe.encode_with_special_tokens(&m.content).len();
+      TOKENS_PER_MESSAGE + role + content
+    })
+    .collect();
+  MessageCosts {
+    per_message,
+    overhead: REPLY_PRIMING_TOKENS,
+    method: "tiktoken",
+  }
+}
+
+// Roughly four characters per token for English text.
+fn heuristic(messages: &[Message]) -> MessageCosts {
+  MessageCosts {
+    per_message: messages.iter().map(|m| m.content.chars().count().div_ceil(4)).collect(),
+    overhead: 0,
+    method: "heuristic",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn message(role: &str, content: &str) -> Message {
+    Message {
+      role: role.to_string(),
+      content: content.to_string(),
+      ..Default::default()
+    }
+  }
+
+  fn user(content: &str) -> Message {
+    message("user", content)
+  }
+
+  #[test]
+  fn count_tokens_uses_tiktoken_for_openai_models() {
+    let res = count_tokens("openai/gpt-4o-mini", &[user("Hello world")]);
+    assert_eq!(res.method, "tiktoken");
+    // "user" and "Hello world" are one and two tokens in o200k.
+    assert_eq!(res.tokens, TOKENS_PER_MESSAGE + 1 + 2 + REPLY_PRIMING_TOKENS);
+  }
+
+  #[test]
+  fn count_tokens_falls_back_to_heuristic() {
+    let res = count_tokens("claude-3-5-sonnet-latest", &[user("abcdefghi")]);
+    assert_eq!(res.method, "heuristic");
+    assert_eq!(res.tokens, 3);
+  }
+
+  #[test]
+  fn trim_messages_keeps_system_prompt_and_latest_turn() {
+    let long = "x".repeat(400);
+    let mut messages = vec![
+      message("system", "Be brief."),
+      user(&long),
+      message("assistant", &long),
+      user("short question"),
+      message("assistant", "short answer"),
+      user("latest question"),
+    ];
+    // Heuristic costs: 3 + 100 + 100 + 4 + 3 + 4.
+    assert_eq!(trim_messages(&mut messages, 20, "llama3"), 2);
+    let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+    assert_eq!(messages.last().unwrap().content, "latest question");
+
+    // Nothing older is left to drop; an oversized latest message is still sent.
+    let mut messages = vec![message("system", "Be brief."), user(&long)];
+    assert_eq!(trim_messages(&mut messages, 10, "llama3"), 0);
+    assert_eq!(messages.len(), 2);
+
+    let mut fits = vec![user("hi"), message("assistant", "hello"), user("bye")];
+    assert_eq!(trim_messages(&mut fits, 100, "openai/gpt-4o-mini"), 0);
+    assert_eq!(fits.len(), 3);
+  }
+
+  #[test]
+  fn trim_messages_drops_whole_turns() {
+    let long = "x".repeat(400);
+    let mut messages = vec![
+      user(&long),
+      message("assistant", "calling a tool"),
+      message("tool", "tool result"),
+      message("assistant", "done"),
+      user("latest question"),
+    ];
+    // Dropping only the long user message would fit, but would leave the
+    // thread opening with an assistant reply and a tool result.
+    assert_eq!(trim_messages(&mut messages, 10, "llama3"), 4);
+    let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["user"]);
+
+    // A latest turn that ends in tool messages is kept whole.
+    let mut messages = vec![
+      user(&long),
+      message("assistant", "ok"),
+      user("use the tool"),
+      message("assistant", "calling"),
+      message("tool", "result"),
+    ];
+    assert_eq!(trim_messages(&mut messages, 10, "llama3"), 2);
+    let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, vec!["user", "assistant", "tool"]);
+  }
+}